@@ -0,0 +1,143 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for measuring the performance of channels: message generators, and round-trip and
+//! throughput scenarios. The benchmarks in `benches/` are built on these; they are public so that
+//! applications can measure their own message mixes on the backend of their platform, too.
+//!
+//! This is only built with the `bench` feature.
+
+use ipc::{self, IpcReceiver, IpcSender};
+use platform::{self, OsIpcReceiver, OsIpcSender};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Returns `size` bytes of a pattern that doesn't repeat every page.
+pub fn bytes(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+/// A small message with a bit of structure, like the control messages that make up most IPC
+/// traffic.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControlMessage {
+    pub id: u64,
+    pub name: String,
+    pub flags: Vec<bool>,
+}
+
+pub fn control_message(id: u64) -> ControlMessage {
+    ControlMessage {
+        id: id,
+        name: format!("control message #{}", id),
+        flags: vec![true, false, true, true],
+    }
+}
+
+/// A thread that sends every message it receives back, over typed channels. Dropping it stops
+/// the thread.
+pub struct Echo<T> where T: Deserialize + Serialize {
+    sender: IpcSender<T>,
+    receiver: IpcReceiver<T>,
+}
+
+impl<T> Echo<T> where T: Deserialize + Serialize + Send + 'static {
+    pub fn new() -> Result<Echo<T>,Error> {
+        let (sender, echo_receiver) = try!(ipc::channel());
+        let (echo_sender, receiver) = try!(ipc::channel());
+        thread::spawn(move || {
+            while let Ok(message) = echo_receiver.recv() {
+                if echo_sender.send(message).is_err() {
+                    break
+                }
+            }
+        });
+        Ok(Echo {
+            sender: sender,
+            receiver: receiver,
+        })
+    }
+
+    /// Sends `message` to the thread and waits for it to come back.
+    pub fn round_trip(&self, message: T) -> Result<T,Error> {
+        try!(self.sender.send(message));
+        self.receiver.recv().map_err(Error::from)
+    }
+}
+
+/// Like `Echo`, but over the channels of the platform backend, leaving serialization out of the
+/// picture.
+pub struct PlatformEcho {
+    sender: OsIpcSender,
+    receiver: OsIpcReceiver,
+}
+
+impl PlatformEcho {
+    pub fn new() -> Result<PlatformEcho,Error> {
+        let (sender, echo_receiver) = try!(platform::channel());
+        let (echo_sender, receiver) = try!(platform::channel());
+        thread::spawn(move || {
+            while let Ok((data, _, _)) = echo_receiver.recv() {
+                if echo_sender.send(&data[..], vec![], vec![]).is_err() {
+                    break
+                }
+            }
+        });
+        Ok(PlatformEcho {
+            sender: sender,
+            receiver: receiver,
+        })
+    }
+
+    /// Sends `data` to the thread and waits for it to come back.
+    pub fn round_trip(&self, data: &[u8]) -> Result<Vec<u8>,Error> {
+        try!(self.sender.send(data, vec![], vec![]));
+        let (data, _, _) = try!(self.receiver.recv());
+        Ok(data)
+    }
+}
+
+/// Sends `message` back and forth `iterations` times, and returns the mean round-trip time.
+/// There is no mean of nothing, so 0 `iterations` fails with `InvalidInput`.
+pub fn round_trip<T>(message: &T, iterations: u32) -> Result<Duration,Error>
+                     where T: Clone + Deserialize + Serialize + Send + 'static {
+    if iterations == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "can't time zero round trips"))
+    }
+    let echo = try!(Echo::new());
+    let start = Instant::now();
+    for _ in 0..iterations {
+        try!(echo.round_trip(message.clone()));
+    }
+    Ok(Instant::now().duration_since(start) / iterations)
+}
+
+/// Streams `count` messages of `size` bytes from another thread over a bytes channel, and
+/// returns the throughput in bytes per second.
+pub fn throughput(size: usize, count: u32) -> Result<f64,Error> {
+    let (sender, receiver) = try!(ipc::bytes_channel());
+    let data = bytes(size);
+    let start = Instant::now();
+    let thread = thread::spawn(move || {
+        for _ in 0..count {
+            if sender.send(&data[..]).is_err() {
+                break
+            }
+        }
+    });
+    for _ in 0..count {
+        try!(receiver.recv());
+    }
+    let elapsed = Instant::now().duration_since(start);
+    drop(thread.join());
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+    Ok((size as f64) * (count as f64) / seconds)
+}
@@ -0,0 +1,81 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The helpers these are built on only come with the `bench` feature:
+// `cargo bench --features bench`.
+#![cfg(feature = "bench")]
+#![feature(test)]
+
+extern crate ipc_channel;
+extern crate test;
+
+use ipc_channel::bench::{self, Echo, PlatformEcho};
+use test::Bencher;
+
+fn platform_round_trip(b: &mut Bencher, size: usize) {
+    let echo = PlatformEcho::new().unwrap();
+    let data = bench::bytes(size);
+    b.bytes = size as u64;
+    b.iter(|| echo.round_trip(&data[..]).unwrap());
+}
+
+fn typed_round_trip(b: &mut Bencher, size: usize) {
+    let echo = Echo::new().unwrap();
+    let data = bench::bytes(size);
+    b.bytes = size as u64;
+    b.iter(|| echo.round_trip(data.clone()).unwrap());
+}
+
+#[bench]
+fn platform_round_trip_16(b: &mut Bencher) {
+    platform_round_trip(b, 16)
+}
+
+#[bench]
+fn platform_round_trip_64k(b: &mut Bencher) {
+    platform_round_trip(b, 64 * 1024)
+}
+
+#[bench]
+fn platform_round_trip_1m(b: &mut Bencher) {
+    platform_round_trip(b, 1024 * 1024)
+}
+
+#[bench]
+fn typed_round_trip_16(b: &mut Bencher) {
+    typed_round_trip(b, 16)
+}
+
+#[bench]
+fn typed_round_trip_64k(b: &mut Bencher) {
+    typed_round_trip(b, 64 * 1024)
+}
+
+#[bench]
+fn typed_round_trip_1m(b: &mut Bencher) {
+    typed_round_trip(b, 1024 * 1024)
+}
+
+#[bench]
+fn typed_round_trip_16m(b: &mut Bencher) {
+    typed_round_trip(b, 16 * 1024 * 1024)
+}
+
+#[bench]
+fn control_message_round_trip(b: &mut Bencher) {
+    let echo = Echo::new().unwrap();
+    let message = bench::control_message(1);
+    b.iter(|| echo.round_trip(message.clone()).unwrap());
+}
+
+#[bench]
+fn bytes_throughput_4k(b: &mut Bencher) {
+    b.bytes = 4096 * 100;
+    b.iter(|| bench::throughput(4096, 100).unwrap());
+}
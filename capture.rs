@@ -0,0 +1,193 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Capturing every message a process sends and receives to a file, and reading captures back, so
+//! that a protocol bug seen in the field can be replayed offline; see `ipc::start_capture()`.
+//!
+//! A capture starts with `MAGIC`. Each frame follows as the seconds and nanoseconds since the Unix
+//! epoch at which it was captured (`u64`, `u32`), its direction (`u8`, 0 for sent), whether it has
+//! a channel ID (`u8`) and the ID (`i64`), how many channels came with it (`u32`), how many
+//! shared memory regions did (`u32`), the length and contents of each region, and the length and
+//! contents of its data; all little-endian.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ipc::MessageDirection;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &'static [u8] = b"ipc-channel capture 1\n";
+
+/// Whether a capture is running, so that we can skip copying messages when there isn't.
+static CAPTURING: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+/// A message as it was captured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedFrame {
+    /// When the message was sent or received, as the time since the Unix epoch.
+    pub timestamp: Duration,
+    /// Our end of the channel, as for `MessageMetadata::channel_id`.
+    pub channel_id: Option<i64>,
+    pub direction: MessageDirection,
+    /// The serialized message.
+    pub data: Vec<u8>,
+    /// The contents of the shared memory regions that came with the message.
+    pub shared_memory_regions: Vec<Vec<u8>>,
+    /// How many channels came with the message. They can't be captured, so a message that embeds
+    /// any can't be replayed.
+    pub channels: usize,
+}
+
+/// Starts writing every message this process sends or receives to a new file at `path`, replacing
+/// any capture that was already running.
+pub fn start<P>(path: P) -> Result<(),Error> where P: AsRef<Path> {
+    let mut file = BufWriter::new(try!(File::create(path)));
+    try!(file.write_all(MAGIC));
+    let previous = mem::replace(&mut *CAPTURE.lock().unwrap(), Some(file));
+    CAPTURING.store(true, Ordering::SeqCst);
+    match previous {
+        Some(mut previous) => previous.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Stops capturing, and flushes what was captured to the file.
+pub fn stop() -> Result<(),Error> {
+    CAPTURING.store(false, Ordering::SeqCst);
+    match CAPTURE.lock().unwrap().take() {
+        Some(mut file) => file.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Writes the frame that `frame` makes to the capture, if one is running. A capture that fails to
+/// write stops, rather than failing the message.
+pub fn record<F>(frame: F) where F: FnOnce(Duration) -> CapturedFrame {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    let frame = frame(timestamp);
+    let mut capture = CAPTURE.lock().unwrap();
+    let failed = match *capture {
+        Some(ref mut file) => write_frame(file, &frame).is_err(),
+        None => false,
+    };
+    if failed {
+        CAPTURING.store(false, Ordering::SeqCst);
+        *capture = None;
+    }
+}
+
+fn write_frame<W>(stream: &mut W, frame: &CapturedFrame) -> Result<(),Error> where W: Write {
+    try!(stream.write_u64::<LittleEndian>(frame.timestamp.as_secs()));
+    try!(stream.write_u32::<LittleEndian>(frame.timestamp.subsec_nanos()));
+    try!(stream.write_u8(match frame.direction {
+        MessageDirection::Sent => 0,
+        MessageDirection::Received => 1,
+    }));
+    try!(stream.write_u8(frame.channel_id.is_some() as u8));
+    try!(stream.write_i64::<LittleEndian>(frame.channel_id.unwrap_or(0)));
+    try!(stream.write_u32::<LittleEndian>(frame.channels as u32));
+    try!(stream.write_u32::<LittleEndian>(frame.shared_memory_regions.len() as u32));
+    for region in &frame.shared_memory_regions {
+        try!(stream.write_u64::<LittleEndian>(region.len() as u64));
+        try!(stream.write_all(region));
+    }
+    try!(stream.write_u64::<LittleEndian>(frame.data.len() as u64));
+    stream.write_all(&frame.data)
+}
+
+/// Reads the frames of a capture, in the order they were captured. A capture that is still running
+/// may end partway through a frame, which fails with `UnexpectedEof`.
+pub struct CaptureReader<R> where R: Read {
+    stream: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P>(path: P) -> Result<CaptureReader<BufReader<File>>,Error> where P: AsRef<Path> {
+        CaptureReader::new(BufReader::new(try!(File::open(path))))
+    }
+}
+
+impl<R> CaptureReader<R> where R: Read {
+    /// Reads a capture from `stream`, failing with `InvalidData` if it doesn't start like one.
+    pub fn new(mut stream: R) -> Result<CaptureReader<R>,Error> {
+        let mut magic = vec![0; MAGIC.len()];
+        try!(stream.read_exact(&mut magic));
+        if &magic[..] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not an ipc-channel capture"))
+        }
+        Ok(CaptureReader {
+            stream: stream,
+        })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<CapturedFrame>,Error> {
+        let secs = match self.stream.read_u64::<LittleEndian>() {
+            Ok(secs) => secs,
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let nanos = try!(self.stream.read_u32::<LittleEndian>());
+        let direction = match try!(self.stream.read_u8()) {
+            0 => MessageDirection::Sent,
+            _ => MessageDirection::Received,
+        };
+        let has_channel_id = try!(self.stream.read_u8()) != 0;
+        let channel_id = try!(self.stream.read_i64::<LittleEndian>());
+        let channels = try!(self.stream.read_u32::<LittleEndian>());
+        let region_count = try!(self.stream.read_u32::<LittleEndian>());
+        let mut shared_memory_regions = vec![];
+        for _ in 0..region_count {
+            let length = try!(self.stream.read_u64::<LittleEndian>());
+            shared_memory_regions.push(try!(read_exactly(&mut self.stream, length)));
+        }
+        let length = try!(self.stream.read_u64::<LittleEndian>());
+        let data = try!(read_exactly(&mut self.stream, length));
+        Ok(Some(CapturedFrame {
+            timestamp: Duration::new(secs, nanos),
+            channel_id: if has_channel_id { Some(channel_id) } else { None },
+            direction: direction,
+            data: data,
+            shared_memory_regions: shared_memory_regions,
+            channels: channels as usize,
+        }))
+    }
+}
+
+impl<R> Iterator for CaptureReader<R> where R: Read {
+    type Item = Result<CapturedFrame,Error>;
+
+    fn next(&mut self) -> Option<Result<CapturedFrame,Error>> {
+        match self.read_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => None,
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+/// Reads `length` bytes, without trusting `length` enough to allocate it all up front.
+fn read_exactly<R>(stream: &mut R, length: u64) -> Result<Vec<u8>,io::Error> where R: Read {
+    let mut buffer = vec![];
+    let read = try!((&mut *stream).take(length).read_to_end(&mut buffer));
+    if (read as u64) < length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "capture ends mid-frame"))
+    }
+    Ok(buffer)
+}
@@ -0,0 +1,363 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bridge between typed channels and the D-Bus, for talking to the Linux desktop without a
+//! second IPC layer.
+//!
+//! Each message travels as a single byte array argument (`ay`) holding its bincode encoding, so
+//! messages can't carry channels or shared memory. Incoming method calls and signals are decoded
+//! and sent on an `IpcSender`; messages received on an `IpcReceiver` go out as signals or as
+//! method calls on another service. Messages that can't go out are dropped, or handed to the
+//! queue given to `dead_letters()`.
+
+use bincode::{self, SizeLimit};
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, NameFlag};
+use ipc::{self, IpcReceiver, IpcSender, OpaqueIpcMessage};
+use router::{DeadLetter, DeadLetterReason, ROUTER};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// How long the bridge waits for D-Bus traffic before it looks for outgoing messages again.
+const POLL_TIMEOUT_MS: i32 = 10;
+
+/// The most a D-Bus byte array can hold, and so the biggest message the bridge decodes.
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+const ERROR_UNKNOWN_METHOD: &'static str = "org.freedesktop.DBus.Error.UnknownMethod";
+const ERROR_INVALID_ARGS: &'static str = "org.freedesktop.DBus.Error.InvalidArgs";
+const ERROR_FAILED: &'static str = "org.freedesktop.DBus.Error.Failed";
+
+/// Decodes the bytes of a message and sends it on its channel.
+type Handler = Box<Fn(&[u8]) -> Result<(),Error> + Send>;
+
+enum Outgoing {
+    Signal {
+        member: String,
+        data: Vec<u8>,
+    },
+    MethodCall {
+        destination: String,
+        path: String,
+        interface: String,
+        method: String,
+        data: Vec<u8>,
+    },
+}
+
+struct Subscription {
+    path: String,
+    interface: String,
+    member: String,
+    handler: Handler,
+}
+
+/// Builds a D-Bus service out of channels. Nothing touches the bus until `spawn()`.
+pub struct DBusBridge {
+    bus_type: BusType,
+    bus_name: String,
+    object_path: String,
+    interface: String,
+    methods: HashMap<String,Handler>,
+    subscriptions: Vec<Subscription>,
+    outgoing_sender: Sender<Outgoing>,
+    outgoing_receiver: Receiver<Outgoing>,
+    dead_letters: Option<Sender<DeadLetter>>,
+}
+
+impl DBusBridge {
+    /// Starts a service that will own `bus_name` on the session bus and answer at `object_path`
+    /// with the methods and signals of `interface`.
+    pub fn new(bus_name: &str, object_path: &str, interface: &str) -> DBusBridge {
+        let (outgoing_sender, outgoing_receiver) = mpsc::channel();
+        DBusBridge {
+            bus_type: BusType::Session,
+            bus_name: bus_name.to_owned(),
+            object_path: object_path.to_owned(),
+            interface: interface.to_owned(),
+            methods: HashMap::new(),
+            subscriptions: vec![],
+            outgoing_sender: outgoing_sender,
+            outgoing_receiver: outgoing_receiver,
+            dead_letters: None,
+        }
+    }
+
+    /// Connects to the system bus instead of the session bus.
+    pub fn system_bus(mut self) -> DBusBridge {
+        self.bus_type = BusType::System;
+        self
+    }
+
+    /// Hands the messages that can't go out on the bus to `dead_letters`, rather than dropping
+    /// them. This only applies to routes set up after it.
+    pub fn dead_letters(mut self, dead_letters: Sender<DeadLetter>) -> DBusBridge {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Answers calls to `method` by sending their argument on `sender`. Callers get an empty
+    /// reply once the message is sent, or an error if the argument doesn't decode.
+    pub fn expose_method<T>(mut self, method: &str, sender: IpcSender<T>) -> DBusBridge
+                            where T: Deserialize + Serialize + 'static {
+        self.methods.insert(method.to_owned(), handler(sender));
+        self
+    }
+
+    /// Emits every message received on `receiver` as the signal `member` of our interface.
+    /// Messages that carry channels or shared memory are rejected.
+    pub fn emit_signals<T>(self, member: &str, receiver: IpcReceiver<T>) -> DBusBridge
+                           where T: Deserialize + Serialize + 'static {
+        let member = member.to_owned();
+        self.route(receiver, move |data| {
+            Outgoing::Signal {
+                member: member.clone(),
+                data: data,
+            }
+        })
+    }
+
+    /// Sends the arguments of the signal `member` of `interface`, emitted at `path` by any
+    /// service, on `sender`.
+    pub fn subscribe_signal<T>(mut self,
+                               path: &str,
+                               interface: &str,
+                               member: &str,
+                               sender: IpcSender<T>)
+                               -> DBusBridge
+                               where T: Deserialize + Serialize + 'static {
+        self.subscriptions.push(Subscription {
+            path: path.to_owned(),
+            interface: interface.to_owned(),
+            member: member.to_owned(),
+            handler: handler(sender),
+        });
+        self
+    }
+
+    /// Calls `method` of `interface` on the object at `path` of `destination` with every message
+    /// received on `receiver`. Replies are ignored, and messages that carry channels or shared
+    /// memory are rejected.
+    pub fn forward_calls<T>(self,
+                            destination: &str,
+                            path: &str,
+                            interface: &str,
+                            method: &str,
+                            receiver: IpcReceiver<T>)
+                            -> DBusBridge
+                            where T: Deserialize + Serialize + 'static {
+        let (destination, path) = (destination.to_owned(), path.to_owned());
+        let (interface, method) = (interface.to_owned(), method.to_owned());
+        self.route(receiver, move |data| {
+            Outgoing::MethodCall {
+                destination: destination.clone(),
+                path: path.clone(),
+                interface: interface.clone(),
+                method: method.clone(),
+                data: data,
+            }
+        })
+    }
+
+    /// Connects to the bus and serves on a thread of its own, which runs until the bus goes
+    /// away. Returns once the bus name and object path are registered.
+    pub fn spawn(self) -> Result<(),Error> {
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The connection can't move between threads, so it's made on this one.
+            let connection = match self.connect() {
+                Ok(connection) => {
+                    drop(ready_sender.send(Ok(())));
+                    connection
+                }
+                Err(err) => {
+                    drop(ready_sender.send(Err(err)));
+                    return
+                }
+            };
+            self.run(&connection)
+        });
+        match ready_receiver.recv() {
+            Ok(result) => result,
+            Err(_) => {
+                Err(Error::new(ErrorKind::Other, "D-Bus bridge thread died while connecting"))
+            }
+        }
+    }
+
+    /// Passes the messages received on `receiver` to the bridge thread as they are: they're
+    /// already encoded the way the bus carries them.
+    fn route<T,F>(self, receiver: IpcReceiver<T>, outgoing: F) -> DBusBridge
+                  where T: Deserialize + Serialize + 'static,
+                        F: Fn(Vec<u8>) -> Outgoing + Send + 'static {
+        let outgoing_sender = self.outgoing_sender.clone();
+        let dead_letters = self.dead_letters.clone();
+        ROUTER.add_route(receiver.to_opaque(), Box::new(move |message| {
+            if message.carries_handles() {
+                let error = Error::new(ErrorKind::InvalidInput,
+                                       "channels and shared memory can't be sent over D-Bus");
+                return dead_letter(&dead_letters, ipc::Error::from(error), Some(message))
+            }
+            drop(outgoing_sender.send(outgoing(message.data().to_vec())))
+        }));
+        self
+    }
+
+    fn connect(&self) -> Result<Connection,Error> {
+        let connection = try!(Connection::get_private(self.bus_type).map_err(to_io_error));
+        try!(connection.register_name(&self.bus_name, NameFlag::DoNotQueue as u32)
+                       .map_err(to_io_error));
+        try!(connection.register_object_path(&self.object_path).map_err(to_io_error));
+        for subscription in &self.subscriptions {
+            let rule = format!("type='signal',path='{}',interface='{}',member='{}'",
+                               subscription.path,
+                               subscription.interface,
+                               subscription.member);
+            try!(connection.add_match(&rule).map_err(to_io_error));
+        }
+        Ok(connection)
+    }
+
+    fn run(&self, connection: &Connection) {
+        for item in connection.iter(POLL_TIMEOUT_MS) {
+            match item {
+                ConnectionItem::MethodCall(message) => self.answer(connection, &message),
+                ConnectionItem::Signal(message) => self.dispatch_signal(&message),
+                _ => {}
+            }
+            while let Ok(outgoing) = self.outgoing_receiver.try_recv() {
+                self.send(connection, outgoing)
+            }
+        }
+    }
+
+    fn answer(&self, connection: &Connection, call: &Message) {
+        let (_, path, interface, member) = call.headers();
+        if path.as_ref() != Some(&self.object_path) {
+            return
+        }
+        let handler = match (interface, member) {
+            (Some(ref interface), Some(ref member)) if *interface == self.interface => {
+                self.methods.get(member)
+            }
+            _ => None,
+        };
+        let reply = match handler {
+            None => Message::new_error(call, ERROR_UNKNOWN_METHOD, "No such method"),
+            Some(handler) => {
+                match argument(call) {
+                    None => Message::new_error(call, ERROR_INVALID_ARGS, "Expected a byte array"),
+                    Some(data) => {
+                        match handler(&data) {
+                            Ok(()) => Message::new_method_return(call),
+                            Err(ref err) if err.kind() == ErrorKind::InvalidData => {
+                                Message::new_error(call, ERROR_INVALID_ARGS, &err.to_string())
+                            }
+                            Err(err) => Message::new_error(call, ERROR_FAILED, &err.to_string()),
+                        }
+                    }
+                }
+            }
+        };
+        if let Some(reply) = reply {
+            drop(connection.send(reply))
+        }
+    }
+
+    fn dispatch_signal(&self, signal: &Message) {
+        let (_, path, interface, member) = signal.headers();
+        let data = match argument(signal) {
+            None => return,
+            Some(data) => data,
+        };
+        for subscription in &self.subscriptions {
+            if path.as_ref() == Some(&subscription.path) &&
+                    interface.as_ref() == Some(&subscription.interface) &&
+                    member.as_ref() == Some(&subscription.member) {
+                // Signals have nobody to report to, so malformed ones are dropped.
+                drop((subscription.handler)(&data))
+            }
+        }
+    }
+
+    fn send(&self, connection: &Connection, outgoing: Outgoing) {
+        let message = match outgoing {
+            Outgoing::Signal { member, data } => {
+                Message::new_signal(&*self.object_path, &*self.interface, &*member)
+                    .map(|message| message.append(bytes_item(data)))
+            }
+            Outgoing::MethodCall { destination, path, interface, method, data } => {
+                Message::new_method_call(&*destination, &*path, &*interface, &*method)
+                    .map(|message| message.append(bytes_item(data)))
+            }
+        };
+        // A bad name comes from the code building the bridge, but that's no reason to take down
+        // the bridge with it.
+        match message {
+            Ok(message) => drop(connection.send(message)),
+            Err(err) => {
+                let error = Error::new(ErrorKind::InvalidInput, err);
+                dead_letter(&self.dead_letters, ipc::Error::from(error), None)
+            }
+        }
+    }
+}
+
+/// Decodes messages with a bounded size, so that a message can't claim more memory than the bus
+/// could have carried. The bytes carry no channels or shared memory, so a `T` that has any fails
+/// to decode.
+fn handler<T>(sender: IpcSender<T>) -> Handler where T: Deserialize + Serialize + 'static {
+    Box::new(move |mut data| {
+        let message: T = {
+            let mut deserializer =
+                bincode::serde::Deserializer::new(&mut data, SizeLimit::Bounded(MAX_MESSAGE_SIZE));
+            try!(Deserialize::deserialize(&mut deserializer).map_err(|err| {
+                Error::new(ErrorKind::InvalidData, format!("{:?}", err))
+            }))
+        };
+        sender.send(message).map_err(Error::from)
+    })
+}
+
+fn dead_letter(dead_letters: &Option<Sender<DeadLetter>>,
+               error: ipc::Error,
+               message: Option<OpaqueIpcMessage>) {
+    if let Some(ref dead_letters) = *dead_letters {
+        drop(dead_letters.send(DeadLetter {
+            reason: DeadLetterReason::Rejected(error),
+            message: message,
+        }))
+    }
+}
+
+/// Returns the first argument of `message`, if it's a byte array.
+fn argument(message: &Message) -> Option<Vec<u8>> {
+    match message.get_items().into_iter().next() {
+        Some(MessageItem::Array(items, _)) => {
+            items.into_iter().map(|item| {
+                match item {
+                    MessageItem::Byte(byte) => Some(byte),
+                    _ => None,
+                }
+            }).collect()
+        }
+        _ => None,
+    }
+}
+
+fn bytes_item(data: Vec<u8>) -> MessageItem {
+    MessageItem::Array(data.into_iter().map(MessageItem::Byte).collect(), "y".into())
+}
+
+fn to_io_error<E>(err: E) -> Error where E: Debug {
+    Error::new(ErrorKind::Other, format!("{:?}", err))
+}
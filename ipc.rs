@@ -7,19 +7,66 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use platform::{self, OsIpcChannel, OsIpcReceiver, OsIpcReceiverSet, OsIpcSender};
-use platform::{OsIpcOneShotServer, OsIpcSelectionResult, OsIpcSharedMemory, OsOpaqueIpcChannel};
+// Everything here goes through the switchable types, which wrap either the native backend or the
+// in-process one.
+use platform::{self, StdioReceiver, StdioSender, TcpReceiver, TcpSender, TcpServer};
+use platform::{SwitchChannel as OsIpcChannel, SwitchReceiver as OsIpcReceiver};
+use platform::{SwitchReceiverSet as OsIpcReceiverSet, SwitchSender as OsIpcSender};
+use platform::{SwitchOneShotServer as OsIpcOneShotServer, SwitchServer as OsIpcServer};
+use platform::SwitchSelectionResult as OsIpcSelectionResult;
+use platform::SwitchSharedMemory as OsIpcSharedMemory;
+use platform::SwitchOpaqueChannel as OsOpaqueIpcChannel;
+use platform::SwitchError;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+use platform::OsIpcChannel as NativeIpcChannel;
+#[cfg(any(test, feature="fuzzing"))]
+use platform::{LoopbackReceiver, LoopbackSender};
+#[cfg(target_os="linux")]
+use platform::{VsockReceiver, VsockSender, VsockServer};
+#[cfg(feature="websocket")]
+use platform::{WebSocketReceiver, WebSocketSender, WebSocketServer};
+
+use capture;
 
 use bincode::{self, SizeLimit};
-use bincode::serde::DeserializeError;
+use rand::{self, OsRng, Rng, SeedableRng, XorShiftRng};
+use byteorder::{ByteOrder, LittleEndian};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+#[cfg(unix)]
+use libc;
+use bincode::serde::{DeserializeError, SerializeError};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::cell::{RefCell, BorrowState};
-use std::cmp::min;
-use std::fmt::{self, Debug, Formatter};
-use std::io::{Error, ErrorKind};
+use serde::de::Error as DeserializerError;
+use serde::ser::Error as SerializerError;
+use serde::bytes::{ByteBuf, Bytes};
+use std::cell::{Cell, RefCell, BorrowState};
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
 use std::marker::PhantomData;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::mem;
 use std::ops::Deref;
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle, RawHandle};
+#[cfg(windows)]
+use std::ptr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
+use std::usize;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 thread_local! {
     static OS_IPC_CHANNELS_FOR_DESERIALIZATION: RefCell<Vec<OsOpaqueIpcChannel>> =
@@ -27,57 +74,455 @@ thread_local! {
 }
 thread_local! {
     static OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION:
-        RefCell<Vec<Option<OsIpcSharedMemory>>> = RefCell::new(Vec::new())
+        RefCell<Vec<Arc<OsIpcSharedMemory>>> = RefCell::new(Vec::new())
 }
 thread_local! {
     static OS_IPC_CHANNELS_FOR_SERIALIZATION: RefCell<Vec<OsIpcChannel>> = RefCell::new(Vec::new())
 }
 thread_local! {
-    static OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION: RefCell<Vec<OsIpcSharedMemory>> =
-        RefCell::new(Vec::new())
+    static OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION:
+        RefCell<Vec<Arc<OsIpcSharedMemory>>> = RefCell::new(Vec::new())
+}
+thread_local! {
+    static IN_AUDIT_HOOK: Cell<bool> = Cell::new(false)
+}
+
+lazy_static! {
+    static ref AUDIT_HOOK: RwLock<Option<Arc<Fn(&MessageMetadata) + Send + Sync>>> =
+        RwLock::new(None);
+}
+
+lazy_static! {
+    static ref SHARED_MEMORY_THRESHOLD: AtomicUsize =
+        AtomicUsize::new(DEFAULT_SHARED_MEMORY_THRESHOLD);
 }
 
+const DEFAULT_SHARED_MEMORY_THRESHOLD: usize = 1024 * 1024;
+
+/// Whether an audit hook is installed, so that we can skip gathering metadata when there isn't.
+static AUDITING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether new senders and receivers are entered in the registry; see `enable_registry()`.
+static REGISTERING: AtomicBool = ATOMIC_BOOL_INIT;
+
+static NEXT_REGISTRATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<usize,RegistryEntry>> = Mutex::new(HashMap::new());
+}
+
+pub use capture::{CaptureReader, CapturedFrame};
+pub use platform::Backend;
+pub use platform::{Delivery, Schedule, Scheduler};
+pub use platform::{HandleKind, LeakCheckpoint, LeakScope, LiveHandle};
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::MachPortRight;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::SigpipeStrategy;
+
 pub fn channel<T>() -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
                   where T: Deserialize + Serialize {
-    let (os_sender, os_receiver) = try!(platform::channel());
-    let ipc_receiver = IpcReceiver {
-        os_receiver: os_receiver,
-        phantom: PhantomData,
-    };
-    let ipc_sender = IpcSender {
-        os_sender: os_sender,
-        phantom: PhantomData,
-    };
+    channel_with_backend(platform::backend())
+}
+
+/// Creates a channel on `backend`, whatever the current backend is.
+pub fn channel_with_backend<T>(backend: Backend) -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                               where T: Deserialize + Serialize {
+    let (os_sender, os_receiver) = try!(platform::channel_with_backend(backend));
+    Ok(from_os_channel(os_sender, os_receiver))
+}
+
+/// Creates an in-process channel whose deliveries `scheduler` records, or replays in the order it
+/// recorded them before, to rerun a test that fails only when its threads interleave just so.
+pub fn scheduled_channel<T>(scheduler: &Scheduler)
+                            -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                            where T: Deserialize + Serialize {
+    let (os_sender, os_receiver) = try!(platform::scheduled_channel(scheduler));
+    Ok(from_os_channel(os_sender, os_receiver))
+}
+
+fn from_os_channel<T>(os_sender: OsIpcSender, os_receiver: OsIpcReceiver)
+                      -> (IpcSender<T>, IpcReceiver<T>) where T: Deserialize + Serialize {
+    let ipc_receiver = IpcReceiver::from_os(os_receiver);
+    let ipc_sender = IpcSender::from_os(os_sender);
+    (ipc_sender, ipc_receiver)
+}
+
+/// Like `channel()`, but gives the channel a name, such as `script-to-compositor`, that its ends
+/// carry in their `Debug` output and `stats()`, and in the `Error::Io`s they return, and so in the
+/// panics of code that unwraps them, to tell which of many channels is which without keeping a
+/// table on the side. The name stays in this process: an end sent to another process arrives
+/// unnamed.
+pub fn channel_named<T>(name: &str) -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                        where T: Deserialize + Serialize {
+    let label = Some(Arc::new(name.to_owned()));
+    let (mut ipc_sender, mut ipc_receiver) =
+        try!(channel().map_err(|err| label_error(&label, err)));
+    ipc_sender.set_label(label.clone());
+    ipc_receiver.set_label(label);
     Ok((ipc_sender, ipc_receiver))
 }
 
+#[deprecated(since = "0.2.2", note = "renamed to `channel_named()`")]
+pub fn labeled_channel<T>(label: &str) -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                          where T: Deserialize + Serialize {
+    channel_named(label)
+}
+
+/// Builds a channel with settings of its own, rather than the process-wide ones that `channel()`
+/// uses. This lets one process mix in-process channels, which cost nothing, with native ones,
+/// which can be sent to other processes.
+///
+/// Channels to other machines aren't built here, as they are a different type altogether: see
+/// `IpcTcpServer` and `IpcTcpSender`.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelBuilder {
+    backend: Option<Backend>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    latency_histogram: bool,
+}
+
+impl ChannelBuilder {
+    pub fn new() -> ChannelBuilder {
+        ChannelBuilder {
+            backend: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            latency_histogram: false,
+        }
+    }
+
+    /// Creates the channel on `backend`, instead of the current one.
+    pub fn backend(mut self, backend: Backend) -> ChannelBuilder {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Sets the size of the sender's buffer; see `IpcSender::set_send_buffer_size()`.
+    pub fn send_buffer_size(mut self, size: usize) -> ChannelBuilder {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the receiver's buffer; see `IpcReceiver::set_recv_buffer_size()`.
+    pub fn recv_buffer_size(mut self, size: usize) -> ChannelBuilder {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Has the sender timestamp messages and the receiver keep a histogram of how long they took
+    /// to arrive; see `IpcReceiver::enable_latency_histogram()`.
+    pub fn latency_histogram(mut self) -> ChannelBuilder {
+        self.latency_histogram = true;
+        self
+    }
+
+    pub fn build<T>(&self) -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                    where T: Deserialize + Serialize {
+        let (mut sender, mut receiver) =
+            try!(channel_with_backend(self.backend.unwrap_or_else(platform::backend)));
+        if let Some(size) = self.send_buffer_size {
+            try!(sender.set_send_buffer_size(size));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(receiver.set_recv_buffer_size(size));
+        }
+        if self.latency_histogram {
+            sender.enable_timestamps();
+            receiver.enable_latency_histogram();
+        }
+        Ok((sender, receiver))
+    }
+}
+
+/// Sets how big a serialized message must be, in bytes, for `IpcSender::send()` to move it into
+/// a shared memory region attached to the message, rather than streaming it through the channel;
+/// `None` turns this off. The default is 1 MiB.
+///
+/// This is transparent to the receiver, except that the region counts against a
+/// `HandleLimitedReceiver`'s limit, and a `HandleRefusingReceiver` refuses such messages. Messages
+/// sent with `send_authenticated()` are never moved.
+pub fn set_shared_memory_threshold(threshold: Option<usize>) {
+    SHARED_MEMORY_THRESHOLD.store(threshold.unwrap_or(usize::MAX), Ordering::SeqCst)
+}
+
+/// Sets how big a message must be, in bytes, to be sent as out-of-line memory that the kernel
+/// maps into the receiver copy-on-write, rather than copied into the message and out again.
+/// `None` turns this off. The default is 32 KiB.
+///
+/// This only matters on macOS; elsewhere messages are never sent out of line.
+pub fn set_out_of_line_threshold(threshold: Option<usize>) {
+    platform::set_out_of_line_threshold(threshold)
+}
+
+/// Sets whether sends, receives, `select()`s and `accept()`s interrupted by a signal are made
+/// again, which is the default, or fail with an `Interrupted` error so that a signal handler can
+/// cut them short.
+///
+/// This only matters on Unix other than macOS; elsewhere nothing gets interrupted.
+pub fn set_retry_on_interrupt(retry: bool) {
+    platform::set_retry_on_interrupt(retry)
+}
+
+/// Caps how much memory the fragmented messages this process is receiving may take altogether,
+/// counting each from when its first fragment says how long it is until it's reassembled. A
+/// message that would go over fails to be received with an `Io` error, rather than being
+/// allocated; `IpcReceiver::set_max_message_size()` limits single messages. There is no budget by
+/// default.
+///
+/// This only matters on Unix other than macOS; elsewhere messages don't come in fragments.
+pub fn set_receive_memory_budget(bytes: Option<usize>) {
+    platform::set_receive_memory_budget(bytes)
+}
+
+/// Sets how long a receiver waits for each further fragment of a message whose first fragment
+/// has arrived. If the sender stalls for longer, receiving fails with `TimedOut`, the rest of the
+/// message is dropped, and the channel carries on with the next one. By default, receivers wait
+/// as long as the sending process is alive.
+///
+/// This only matters on Unix other than macOS; elsewhere messages don't come in fragments.
+pub fn set_reassembly_timeout(timeout: Option<Duration>) {
+    platform::set_reassembly_timeout(timeout)
+}
+
+/// Returns the OS handles that channels, servers, receiver sets and shared memory regions are
+/// holding open, each with a backtrace of where it was created, to track down the endpoint that
+/// leaks when a process runs out of descriptors. `LeakCheckpoint` narrows this down to the
+/// handles created since some point.
+///
+/// Handles are only tracked with the `leak-tracking` feature, and only by the Unix backend other
+/// than on macOS; elsewhere this returns nothing.
+pub fn live_handles() -> Vec<LiveHandle> {
+    platform::live_handles()
+}
+
+/// Picks how sends keep a peer that hung up from raising `SIGPIPE`, for processes that would
+/// rather not rely on the default. Either way, sending to such a peer fails with a `BrokenPipe`
+/// error, which `ipc::Error` takes for `Disconnected`. Fails with `Unsupported` if the platform
+/// doesn't have what the strategy needs.
+///
+/// Only channels of the Unix backend need this: Mach ports don't raise signals, and TCP
+/// connections always pass `MSG_NOSIGNAL` or set `SO_NOSIGPIPE`.
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub fn set_sigpipe_strategy(strategy: SigpipeStrategy) -> Result<(),Error> {
+    platform::set_sigpipe_strategy(strategy).map_err(|err| Error::from(io::Error::from(err)))
+}
+
+/// Sets the directory in which servers created with `IpcOneShotServer::with_name()` and
+/// `IpcServer::with_name()` put their sockets, and in which `IpcSender::connect()` looks for
+/// them. This defaults to the `IPC_CHANNEL_SOCKET_DIR` environment variable, or to `/tmp` if that
+/// isn't set.
+///
+/// Set it before creating or connecting to any named server, in the server and in the clients
+/// alike. It only matters on Linux; elsewhere servers don't live on the filesystem.
+pub fn set_server_socket_directory(directory: &str) {
+    platform::set_server_socket_directory(directory)
+}
+
+/// Sets how many receive buffers each thread keeps around for reuse, rather than allocating a
+/// fresh one for every message it receives. The default is 2; 0 turns pooling off.
+pub fn set_receive_buffer_pool_size(buffers: usize) {
+    platform::set_receive_buffer_pool_size(buffers)
+}
+
+/// Sets the backend on which channels, servers, receiver sets and shared memory regions are
+/// created from now on. It defaults to `Backend::InProcess` if the `IPC_CHANNEL_BACKEND`
+/// environment variable is `inprocess`, and to `Backend::Native` otherwise.
+///
+/// The in-process backend allocates no OS resources, so it suits unit tests and programs that
+/// only ever talk to themselves. Its channels can't be sent over native channels or added to
+/// native receiver sets, nor the other way around; and its servers are only found by
+/// `IpcSender::connect()` while it is the current backend. Set it early, before anything long-lived
+/// like the router is created.
+pub fn set_backend(backend: Backend) {
+    platform::set_backend(backend)
+}
+
 pub fn bytes_channel() -> Result<(IpcBytesSender, IpcBytesReceiver),Error> {
-    let (os_sender, os_receiver) = try!(platform::channel());
+    let (os_sender, os_receiver) = try!(platform::switch_channel());
     let ipc_bytes_receiver = IpcBytesReceiver {
         os_receiver: os_receiver,
+        peer_pid: PeerPid::new(),
     };
     let ipc_bytes_sender = IpcBytesSender {
         os_sender: os_sender,
+        peer_pid: PeerPid::new(),
     };
     Ok((ipc_bytes_sender, ipc_bytes_receiver))
 }
 
-#[derive(Debug)]
 pub struct IpcReceiver<T> where T: Deserialize + Serialize {
     os_receiver: OsIpcReceiver,
     phantom: PhantomData<T>,
+    stats: Arc<ChannelCounters>,
+    /// The channel's name; see `channel_named()`.
+    label: Option<Arc<String>>,
+    registration: Registration,
+    /// How long messages took to arrive, once `enable_latency_histogram()` was called.
+    latency: Option<LatencyCounters>,
+}
+
+impl<T> Debug for IpcReceiver<T> where T: Deserialize + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        formatter.debug_struct("IpcReceiver")
+                 .field("id", &self.os_receiver.endpoint_id())
+                 .field("label", &self.label())
+                 .finish()
+    }
 }
 
 impl<T> IpcReceiver<T> where T: Deserialize + Serialize {
-    pub fn recv(&self) -> Result<T,DeserializeError> {
-        let (data, os_ipc_channels, os_ipc_shared_memory_regions) = try!(self.os_receiver.recv());
-        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+    fn from_os(os_receiver: OsIpcReceiver) -> IpcReceiver<T> {
+        let stats = Arc::new(ChannelCounters::new());
+        IpcReceiver {
+            os_receiver: os_receiver,
+            phantom: PhantomData,
+            registration: Registration::new(EndpointKind::Receiver, &None, &stats),
+            stats: stats,
+            label: None,
+            latency: None,
+        }
     }
 
-    pub fn try_recv(&self) -> Result<T,DeserializeError> {
-        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
-            try!(self.os_receiver.try_recv());
-        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+    fn set_label(&mut self, label: Option<Arc<String>>) {
+        self.registration = Registration::new(EndpointKind::Receiver, &label, &self.stats);
+        self.label = label;
+    }
+
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) = try!(self.recv_raw());
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions)
+            .to()
+            .map_err(|err| label_error(&self.label, err))
+    }
+
+    /// Receives a message sent with `IpcSender::send_authenticated()`, failing with an `Io` error
+    /// of kind `InvalidData` if it doesn't check out against `authenticator`: because it was
+    /// tampered with, replayed, reordered, or sent by someone without the key. After one message
+    /// fails, so do all the others; see `MessageAuthenticator`.
+    pub fn recv_authenticated(&self, authenticator: &MessageAuthenticator)
+                              -> Result<T,Error> {
+        let (mut data, os_ipc_channels, mut os_ipc_shared_memory_regions) =
+            try!(self.recv_raw());
+        // Authenticated messages aren't moved into shared memory by us, but the backend may
+        // have moved one there to get it through; see `serialize_message()`.
+        if data.is_empty() {
+            if let Some(promoted_data) = os_ipc_shared_memory_regions.pop() {
+                data = promoted_data.to_vec()
+            }
+        }
+        let data = try!(authenticator.open(data,
+                                           os_ipc_channels.len(),
+                                           os_ipc_shared_memory_regions.len()).map_err(|err| {
+            label_error(&self.label, err)
+        }));
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions)
+            .to()
+            .map_err(|err| label_error(&self.label, err))
+    }
+
+    pub fn try_recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) = try!(self.try_recv_raw());
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions)
+            .to()
+            .map_err(|err| label_error(&self.label, err))
+    }
+
+    /// Like `try_recv()`, but tells a channel with no message waiting from one whose senders are
+    /// all gone, as `std::sync::mpsc::Receiver::try_recv()` does.
+    pub fn try_recv_checked(&self) -> Result<T,TryRecvError> {
+        self.try_recv().map_err(TryRecvError::from)
+    }
+
+    fn recv_raw(&self)
+                -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                          DeserializeError> {
+        crash_point();
+        let mut message = try!(self.os_receiver.recv().map_err(|err| {
+            DeserializeError::IoError(label_io_error(&self.label, err.into()))
+        }));
+        self.record_latency(&mut message.0);
+        audit_received(&self.os_receiver,
+                       &self.stats.peer_pid,
+                       &message.0,
+                       message.1.len() + message.2.len());
+        capture_message(self.os_receiver.endpoint_id(),
+                        MessageDirection::Received,
+                        &[&message.0[..]],
+                        message.1.len(),
+                        &message.2);
+        self.stats.count(message.0.len(), message.1.len() + message.2.len());
+        Ok(message)
+    }
+
+    fn try_recv_raw(&self)
+                    -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                              DeserializeError> {
+        crash_point();
+        let mut message = try!(self.os_receiver.try_recv().map_err(|err| {
+            DeserializeError::IoError(label_io_error(&self.label, err.into()))
+        }));
+        self.record_latency(&mut message.0);
+        audit_received(&self.os_receiver,
+                       &self.stats.peer_pid,
+                       &message.0,
+                       message.1.len() + message.2.len());
+        capture_message(self.os_receiver.endpoint_id(),
+                        MessageDirection::Received,
+                        &[&message.0[..]],
+                        message.1.len(),
+                        &message.2);
+        self.stats.count(message.0.len(), message.1.len() + message.2.len());
+        Ok(message)
+    }
+
+    /// Returns the name the channel was created with; see `channel_named()`.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_ref().map(|label| label.as_str())
+    }
+
+    /// Returns how much this receiver has received so far, and how much is waiting for it.
+    pub fn stats(&self) -> ChannelStats {
+        let mut stats = self.stats.snapshot(self.label(), self.os_receiver.queued_bytes().ok());
+        stats.latency = self.latency.as_ref().map(LatencyCounters::snapshot);
+        stats
+    }
+
+    /// Expects every message to end with the time it was sent, as from a sender with
+    /// `IpcSender::enable_timestamps()`, and keeps a histogram of how long messages took to
+    /// arrive, which `stats()` returns. Messages are timed from when they were sent until they
+    /// were received, so this includes the time they sat in the channel. Across processes, this
+    /// relies on their clocks agreeing, which they do on one machine.
+    ///
+    /// Only this receiver's own receives are timed: it can't be turned into an opaque receiver,
+    /// or routed, or added to a receiver set, without the timestamps being left in the messages.
+    pub fn enable_latency_histogram(&mut self) {
+        if self.latency.is_none() {
+            self.latency = Some(LatencyCounters::new())
+        }
+    }
+
+    /// Takes the time it was sent off the end of a message, and records how long it took, if
+    /// this receiver keeps a latency histogram.
+    fn record_latency(&self, data: &mut Vec<u8>) {
+        let latency = match self.latency {
+            Some(ref latency) => latency,
+            None => return,
+        };
+        if data.len() < TIMESTAMP_SIZE {
+            return
+        }
+        let end = data.len() - TIMESTAMP_SIZE;
+        let sent = LittleEndian::read_u64(&data[end..]);
+        data.truncate(end);
+        latency.record(timestamp_now().saturating_sub(sent))
     }
 
     pub fn to_opaque(self) -> OpaqueIpcReceiver {
@@ -85,380 +530,3734 @@ impl<T> IpcReceiver<T> where T: Deserialize + Serialize {
             os_receiver: self.os_receiver,
         }
     }
-}
 
-impl<T> Deserialize for IpcReceiver<T> where T: Deserialize + Serialize {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
-        let index: usize = try!(Deserialize::deserialize(deserializer));
-        let os_receiver =
-            OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
-                // FIXME(pcwalton): This could panic if the data was corrupt and the index was out
-                // of bounds. We should return an `Err` result instead.
-                os_ipc_channels_for_deserialization.borrow_mut()[index].to_receiver()
-            });
-        Ok(IpcReceiver {
-            os_receiver: os_receiver,
-            phantom: PhantomData,
-        })
+    /// Turns this receiver into one that refuses messages carrying channels or shared memory;
+    /// see `HandleRefusingReceiver`.
+    pub fn refuse_handles(self) -> HandleRefusingReceiver<T> {
+        HandleRefusingReceiver {
+            receiver: self,
+        }
+    }
+
+    /// Turns this receiver into one that caps how many OS handles the peer may transfer; see
+    /// `HandleLimitedReceiver`.
+    pub fn limit_handles(self, limit: HandleLimit) -> HandleLimitedReceiver<T> {
+        HandleLimitedReceiver {
+            receiver: self,
+            limit: limit,
+            handles_received: Cell::new(0),
+        }
+    }
+
+    /// Turns this receiver into one that holds the peer to `limit`; see `RateLimitedReceiver`.
+    pub fn rate_limit(self, limit: RateLimit) -> RateLimitedReceiver<T> {
+        RateLimitedReceiver {
+            receiver: self,
+            limit: limit,
+            window_start: Cell::new(Instant::now()),
+            messages_in_window: Cell::new(0),
+            bytes_in_window: Cell::new(0),
+        }
+    }
+
+    /// Returns a token with which a child process started with `exec` can take this receiver
+    /// over; see `IpcSender::inheritable_token()`.
+    pub fn inheritable_token(&self) -> Result<String,Error> {
+        Ok(try!(self.os_receiver.inheritable_token()))
+    }
+
+    /// Takes over a receiver that our parent process passed down to us with
+    /// `IpcReceiver::inheritable_token()`.
+    pub fn from_inherited_token(token: &str) -> Result<IpcReceiver<T>,Error> {
+        Ok(IpcReceiver::from_os(try!(OsIpcReceiver::from_inherited_token(token))))
+    }
+
+    /// Like `from_inherited_token()`, but reads the token from the environment variable `key`.
+    pub fn from_inherited_env(key: &str) -> Result<IpcReceiver<T>,Error> {
+        IpcReceiver::from_inherited_token(&try!(inherited_token_from_env(key)))
+    }
+
+    /// Takes over a socket made by other code -- with `socketpair()`, say, or by systemd's socket
+    /// activation -- as the receiving end of a channel. It must be a Unix domain socket of type
+    /// `SOCK_SEQPACKET`, and the other end must speak our framing.
+    ///
+    /// This is only supported on Linux and the other platforms whose channels are Unix sockets;
+    /// elsewhere it fails. On failure, `fd` is left open.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<IpcReceiver<T>,Error> {
+        Ok(IpcReceiver::from_os(try!(OsIpcReceiver::from_raw_fd(fd))))
+    }
+
+    /// The counterpart of `from_raw_fd()` for Windows. Channels on Windows are in-process for now,
+    /// so this always fails.
+    #[cfg(windows)]
+    pub unsafe fn from_raw_handle(handle: RawHandle) -> Result<IpcReceiver<T>,Error> {
+        Ok(IpcReceiver::from_os(try!(OsIpcReceiver::from_raw_handle(handle))))
+    }
+
+    /// Gives up the socket underneath this receiver without closing it, to be left open across
+    /// `exec` or handed to code that isn't Rust.
+    ///
+    /// Whatever reads from it has to speak our framing. The socket is a Unix domain socket of type
+    /// `SOCK_SEQPACKET`. Each packet starts with two little-endian `u32`s, and a message that fits
+    /// in one packet has a 0 in the second, followed by its bincode encoding. The first `u32` then
+    /// counts the descriptors in the packet's `SCM_RIGHTS` control message that are channels or
+    /// `IpcFd`s, which come before those of shared memory regions. Bigger messages arrive in
+    /// fragments, the rest of which come over a channel sent along with the first one, and are
+    /// best left to this crate; `wire::validate()` checks that packets follow these rules.
+    ///
+    /// This is only supported where channels are Unix sockets; elsewhere it fails, and the
+    /// receiver is closed.
+    #[cfg(unix)]
+    pub fn into_raw_fd(self) -> Result<RawFd,Error> {
+        Ok(try!(self.os_receiver.into_raw_fd()))
+    }
+
+    /// The counterpart of `into_raw_fd()` for Windows. Channels on Windows are in-process for now,
+    /// so this always fails.
+    #[cfg(windows)]
+    pub fn into_raw_handle(self) -> Result<RawHandle,Error> {
+        Ok(try!(self.os_receiver.into_raw_handle()))
+    }
+
+    /// Returns the credentials of the process on the other end: the client that connected, for a
+    /// receiver returned by a server, or else the process that created the channel. Privileged
+    /// brokers can use this to decide which requests to honor.
+    ///
+    /// This is only supported on Linux; elsewhere it fails.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials,Error> {
+        peer_credentials(&self.os_receiver).map_err(Error::from)
+    }
+
+    /// Attaches to a receiver in the running process `pid`; see `IpcSender::from_process()`.
+    pub fn from_process(pid: u32, token: &str) -> Result<IpcReceiver<T>,Error> {
+        Ok(IpcReceiver::from_os(try!(OsIpcReceiver::from_process(pid, token))))
+    }
+
+    /// Returns the size of the kernel's receive buffer for this channel.
+    pub fn recv_buffer_size(&self) -> Result<usize,Error> {
+        Ok(try!(self.os_receiver.recv_buffer_size()))
+    }
+
+    /// Resizes the kernel's receive buffer for this channel; see
+    /// `IpcSender::set_send_buffer_size()`. Messages that arrive in packets bigger than the
+    /// receive buffer fail to come through.
+    ///
+    /// This is only supported on Linux; elsewhere it fails.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(),Error> {
+        Ok(try!(self.os_receiver.set_recv_buffer_size(size)))
+    }
+
+    /// Refuses messages bigger than `size` bytes, which fail to be received with
+    /// `MessageTooLarge`. On the Unix backend, big messages arrive in fragments, and the first
+    /// says how long the whole message is; that length is checked against this limit before any
+    /// memory is allocated for it, so that a corrupt or hostile sender can't make us abort. The
+    /// limit carries over into receiver sets, but not to other processes.
+    ///
+    /// Elsewhere, the kernel or the sender has already made room for a message by the time we see
+    /// it, and this does nothing.
+    pub fn set_max_message_size(&mut self, size: Option<usize>) {
+        self.os_receiver.set_max_message_size(size)
     }
 }
 
-impl<T> Serialize for IpcReceiver<T> where T: Deserialize + Serialize {
+/// Elements of a `ChunkedVec` are grouped into chunks of about this many serialized bytes.
+const PARALLEL_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Messages smaller than this aren't worth handing out to other threads.
+const PARALLEL_THRESHOLD: usize = 4 * PARALLEL_CHUNK_SIZE;
+
+/// A vector that is serialized in independent chunks, so that `IpcReceiver::recv_parallel()` can
+/// deserialize a huge one on several threads at once. Received any other way, it deserializes
+/// like a plain vector would.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkedVec<T>(pub Vec<T>);
+
+impl<T> Serialize for ChunkedVec<T> where T: Serialize {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
-        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
-            let mut os_ipc_channels_for_serialization =
-                os_ipc_channels_for_serialization.borrow_mut();
-            let index = os_ipc_channels_for_serialization.len();
-            os_ipc_channels_for_serialization.push(OsIpcChannel::Receiver(self.os_receiver
-                                                                              .consume()));
-            index
-        });
-        index.serialize(serializer)
+        // Each chunk is the number of elements in it, followed by the elements, just like a
+        // serialized vector.
+        let (mut chunks, mut chunk, mut count) = (vec![], vec![0; 8], 0);
+        for element in &self.0 {
+            {
+                let mut element_serializer = bincode::serde::Serializer::new(&mut chunk);
+                try!(element.serialize(&mut element_serializer)
+                            .map_err(|err| S::Error::custom(err.to_string())));
+            }
+            count += 1;
+            if chunk.len() >= PARALLEL_CHUNK_SIZE {
+                LittleEndian::write_u64(&mut chunk[..8], count);
+                chunks.push(mem::replace(&mut chunk, vec![0; 8]));
+                count = 0;
+            }
+        }
+        if count != 0 {
+            LittleEndian::write_u64(&mut chunk[..8], count);
+            chunks.push(chunk);
+        }
+        let chunks: Vec<Bytes> = chunks.iter().map(|chunk| Bytes::from(&chunk[..])).collect();
+        chunks.serialize(serializer)
     }
 }
 
-#[derive(Debug)]
-pub struct IpcSender<T> where T: Serialize {
-    os_sender: OsIpcSender,
-    phantom: PhantomData<T>,
+impl<T> Deserialize for ChunkedVec<T> where T: Deserialize {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        // Each chunk comes with its length in bytes, which we only need to find the chunks
+        // without deserializing them.
+        let chunks: Vec<(u64, Vec<T>)> = try!(Deserialize::deserialize(deserializer));
+        Ok(ChunkedVec(chunks.into_iter().flat_map(|(_, elements)| elements).collect()))
+    }
 }
 
-impl<T> Clone for IpcSender<T> where T: Serialize {
-    fn clone(&self) -> IpcSender<T> {
-        IpcSender {
-            os_sender: self.os_sender.clone(),
-            phantom: PhantomData,
+impl<T> IpcReceiver<ChunkedVec<T>> where T: Deserialize + Serialize + Send + 'static {
+    /// Receives a `ChunkedVec`, deserializing its chunks on up to `threads` threads at once if
+    /// the message is big enough to make that worthwhile.
+    ///
+    /// Elements that embed channels or shared memory regions can only be deserialized on the
+    /// receiving thread, so messages carrying any are deserialized there as usual.
+    pub fn recv_parallel(&self, threads: usize) -> Result<ChunkedVec<T>,Error> {
+        let (data, os_ipc_channels, mut os_ipc_shared_memory_regions) = try!(self.recv_raw());
+        if os_ipc_channels.is_empty() {
+            if os_ipc_shared_memory_regions.is_empty() && data.len() >= PARALLEL_THRESHOLD {
+                return deserialize_chunks_in_parallel(Arc::new(data), threads)
+                    .map_err(|err| label_error(&self.label, err))
+            }
+            // See `serialize_message()`.
+            if data.is_empty() && os_ipc_shared_memory_regions.len() == 1 &&
+                    os_ipc_shared_memory_regions[0].len() >= PARALLEL_THRESHOLD {
+                let promoted_data = os_ipc_shared_memory_regions.pop().unwrap();
+                return deserialize_chunks_in_parallel(Arc::new(promoted_data), threads)
+                    .map_err(|err| label_error(&self.label, err))
+            }
         }
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions)
+            .to()
+            .map_err(|err| label_error(&self.label, err))
     }
 }
 
-impl<T> IpcSender<T> where T: Serialize {
-    pub fn connect(name: String) -> Result<IpcSender<T>,Error> {
-        Ok(IpcSender {
-            os_sender: try!(OsIpcSender::connect(name)),
-            phantom: PhantomData,
-        })
+fn deserialize_chunks_in_parallel<B, T>(data: Arc<B>, threads: usize)
+                                        -> Result<ChunkedVec<T>,DeserializeError>
+                                        where B: Deref<Target=[u8]> + Send + Sync + 'static,
+                                              T: Deserialize + Send + 'static {
+    let invalid_data = || {
+        let error = io::Error::new(ErrorKind::InvalidData, "malformed chunked vector");
+        DeserializeError::IoError(error)
+    };
+
+    // Find the chunks: a count, then each chunk prefixed with its length.
+    if data.len() < 8 {
+        return Err(invalid_data())
+    }
+    let chunk_count = LittleEndian::read_u64(&data[..8]);
+    let (mut chunks, mut offset) = (vec![], 8);
+    for _ in 0..chunk_count {
+        if data.len() - offset < 8 {
+            return Err(invalid_data())
+        }
+        let length = LittleEndian::read_u64(&data[offset..offset + 8]);
+        offset += 8;
+        if length > (data.len() - offset) as u64 {
+            return Err(invalid_data())
+        }
+        chunks.push((offset, offset + length as usize));
+        offset += length as usize;
     }
 
-    pub fn send(&self, data: T) -> Result<(),Error> {
-        let mut bytes = Vec::with_capacity(4096);
-        OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
-            OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
-                    |os_ipc_shared_memory_regions_for_serialization| {
-                if os_ipc_channels_for_serialization.borrow_state() != BorrowState::Unused {
-                    return Err(recursive_io_error());
-                }
+    // Give each thread a run of consecutive chunks, so that the results just need concatenating.
+    let chunks_per_thread = max(1, (chunks.len() + max(threads, 1) - 1) / max(threads, 1));
+    let workers: Vec<_> = chunks.chunks(chunks_per_thread).map(|chunks| {
+        let (data, chunks) = (data.clone(), chunks.to_vec());
+        thread::spawn(move || -> Result<Vec<T>,DeserializeError> {
+            let mut elements = vec![];
+            for (start, end) in chunks {
+                let mut chunk = &data[start..end];
+                let mut deserializer = bincode::serde::Deserializer::new(&mut chunk,
+                                                                         SizeLimit::Infinite);
+                let chunk_elements: Vec<T> = try!(Deserialize::deserialize(&mut deserializer));
+                elements.extend(chunk_elements);
+            }
+            Ok(elements)
+        })
+    }).collect();
+    let mut elements = vec![];
+    for worker in workers {
+        match worker.join() {
+            Ok(worker_elements) => elements.extend(try!(worker_elements)),
+            Err(_) => return Err(invalid_data()),
+        }
+    }
+    Ok(ChunkedVec(elements))
+}
 
-                if os_ipc_shared_memory_regions_for_serialization.borrow_state() != BorrowState::Unused {
-                    return Err(recursive_io_error());
-                }
+/// A value that travels in serialized form, and is only deserialized when it's first asked for.
+/// Routers and forwarders that pass messages on without looking at them this way skip decoding
+/// them entirely: sending an `IpcLazy` that was never looked at just sends its bytes again.
+///
+/// The value can't embed channels or shared memory regions, since those have to be taken out of
+/// a message when it's received.
+pub struct IpcLazy<T> where T: Deserialize + Serialize {
+    bytes: Vec<u8>,
+    value: Option<T>,
+}
 
-                let old_os_ipc_channels =
-                    mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(), Vec::new());
-                let old_os_ipc_shared_memory_regions =
-                    mem::replace(&mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut(),
-                                 Vec::new());
-                let os_ipc_shared_memory_regions;
-                let os_ipc_channels;
-                {
-                    let mut serializer = bincode::serde::Serializer::new(&mut bytes);
-                    data.serialize(&mut serializer).unwrap();
-                    os_ipc_channels =
-                        mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(),
-                                     old_os_ipc_channels);
-                    os_ipc_shared_memory_regions = mem::replace(
-                        &mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut(),
-                        old_os_ipc_shared_memory_regions);
-                };
-                self.os_sender.send(&bytes[..],
-                                    os_ipc_channels,
-                                    os_ipc_shared_memory_regions).map_err(|e| Error::from(e))
-            })
+impl<T> IpcLazy<T> where T: Deserialize + Serialize {
+    /// Serializes `value` right away. Fails if it embeds any channels or shared memory regions.
+    pub fn new(value: T) -> Result<IpcLazy<T>,Error> {
+        let mut bytes = vec![];
+        let (os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(serialize_with_handles(&value, &mut bytes));
+        if !os_ipc_channels.is_empty() || !os_ipc_shared_memory_regions.is_empty() {
+            let message = "lazy values can't hold channels or shared memory";
+            return Err(Error::from(io::Error::new(ErrorKind::InvalidInput, message)))
+        }
+        Ok(IpcLazy {
+            bytes: bytes,
+            value: Some(value),
         })
     }
 
-    pub fn to_opaque(self) -> OpaqueIpcSender {
-        OpaqueIpcSender {
-            os_sender: self.os_sender,
+    /// Returns the value, deserializing it if this is the first time.
+    pub fn get(&mut self) -> Result<&T,Error> {
+        if self.value.is_none() {
+            self.value = Some(try!(self.deserialize()));
         }
+        Ok(self.value.as_ref().unwrap())
     }
-}
 
-impl<T> Deserialize for IpcSender<T> where T: Serialize {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
-        let os_sender = try!(deserialize_os_ipc_sender(deserializer));
-        Ok(IpcSender {
-            os_sender: os_sender,
-            phantom: PhantomData,
-        })
+    pub fn into_inner(self) -> Result<T,Error> {
+        match self.value {
+            Some(value) => Ok(value),
+            None => self.deserialize().map_err(Error::from),
+        }
+    }
+
+    /// Returns the value in serialized form.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn deserialize(&self) -> Result<T,DeserializeError> {
+        deserialize_message(&self.bytes, &mut vec![], &mut vec![])
     }
 }
 
-impl<T> Serialize for IpcSender<T> where T: Serialize {
+impl<T> Serialize for IpcLazy<T> where T: Deserialize + Serialize {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
-        serialize_os_ipc_sender(&self.os_sender, serializer)
+        Bytes::from(&self.bytes[..]).serialize(serializer)
     }
 }
 
-pub struct IpcReceiverSet {
-    os_receiver_set: OsIpcReceiverSet,
+impl<T> Deserialize for IpcLazy<T> where T: Deserialize + Serialize {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let bytes: ByteBuf = try!(Deserialize::deserialize(deserializer));
+        Ok(IpcLazy {
+            bytes: bytes.into(),
+            value: None,
+        })
+    }
 }
 
-impl IpcReceiverSet {
-    pub fn new() -> Result<IpcReceiverSet,Error> {
-        Ok(IpcReceiverSet {
-            os_receiver_set: try!(OsIpcReceiverSet::new()),
-        })
+/// A receiver for brokers that must never take OS handles from a less trusted peer, such as a
+/// sandboxed child process. Messages carrying channels or shared memory regions are refused:
+/// whatever the peer sent is released right away, and receiving fails with a `PermissionDenied`
+/// error. Other messages go through as usual.
+pub struct HandleRefusingReceiver<T> where T: Deserialize + Serialize {
+    receiver: IpcReceiver<T>,
+}
+
+impl<T> HandleRefusingReceiver<T> where T: Deserialize + Serialize {
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.recv_raw());
+        try!(refuse_handles(os_ipc_channels, os_ipc_shared_memory_regions));
+        OpaqueIpcMessage::new(data, vec![], vec![]).to()
     }
 
-    pub fn add<T>(&mut self, receiver: IpcReceiver<T>) -> Result<i64,Error>
-                  where T: Deserialize + Serialize {
-        Ok(try!(self.os_receiver_set.add(receiver.os_receiver)))
+    pub fn try_recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.try_recv_raw());
+        try!(refuse_handles(os_ipc_channels, os_ipc_shared_memory_regions));
+        OpaqueIpcMessage::new(data, vec![], vec![]).to()
     }
 
-    pub fn add_opaque(&mut self, receiver: OpaqueIpcReceiver) -> Result<i64,Error> {
-        Ok(try!(self.os_receiver_set.add(receiver.os_receiver)))
+    pub fn into_inner(self) -> IpcReceiver<T> {
+        self.receiver
     }
+}
 
-    pub fn select(&mut self) -> Result<Vec<IpcSelectionResult>,Error> {
-        let results = try!(self.os_receiver_set.select());
-        Ok(results.into_iter().map(|result| {
-            match result {
-                OsIpcSelectionResult::DataReceived(os_receiver_id,
-                                                   data,
-                                                   os_ipc_channels,
-                                                   os_ipc_shared_memory_regions) => {
-                    IpcSelectionResult::MessageReceived(os_receiver_id, OpaqueIpcMessage {
-                        data: data,
-                        os_ipc_channels: os_ipc_channels,
-                        os_ipc_shared_memory_regions:
-                            os_ipc_shared_memory_regions.into_iter().map(
-                                |os_ipc_shared_memory_region| {
-                                    Some(os_ipc_shared_memory_region)
-                                }).collect(),
-                    })
-                }
-                OsIpcSelectionResult::ChannelClosed(os_receiver_id) => {
-                    IpcSelectionResult::ChannelClosed(os_receiver_id)
-                }
-            }
-        }).collect())
+fn refuse_handles(os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+                  os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
+                  -> Result<(),DeserializeError> {
+    if os_ipc_channels.is_empty() && os_ipc_shared_memory_regions.is_empty() {
+        return Ok(())
     }
+    release_handles(os_ipc_channels, os_ipc_shared_memory_regions);
+    Err(DeserializeError::IoError(io::Error::new(ErrorKind::PermissionDenied,
+                                             "peer tried to transfer OS handles")))
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct IpcSharedMemory {
-    os_shared_memory: OsIpcSharedMemory,
+fn release_handles(mut os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+                   os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>) {
+    // Claim the channels, so that dropping them releases them.
+    for os_ipc_channel in os_ipc_channels.iter_mut() {
+        drop(os_ipc_channel.to_channel());
+    }
+    drop(os_ipc_shared_memory_regions);
 }
 
-impl Deref for IpcSharedMemory {
+/// How many OS handles (channels and shared memory regions) a peer may transfer, in a single
+/// message and over the lifetime of the receiver. `None` means no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandleLimit {
+    pub per_message: Option<usize>,
+    pub per_connection: Option<usize>,
+}
+
+/// The error a `HandleLimitedReceiver` fails with, wrapped in an `Error::Io` of kind
+/// `PermissionDenied`, when the peer goes over its limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandleLimitExceeded {
+    /// A single message carried `count` handles, more than the `limit` per message.
+    PerMessage { count: usize, limit: usize },
+    /// The peer would have transferred `count` handles in total, more than the `limit` per
+    /// connection.
+    PerConnection { count: usize, limit: usize },
+}
+
+impl Display for HandleLimitExceeded {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        match *self {
+            HandleLimitExceeded::PerMessage { count, limit } => {
+                write!(formatter,
+                       "message carried {} OS handles, over the limit of {}",
+                       count,
+                       limit)
+            }
+            HandleLimitExceeded::PerConnection { count, limit } => {
+                write!(formatter,
+                       "peer transferred {} OS handles, over the limit of {}",
+                       count,
+                       limit)
+            }
+        }
+    }
+}
+
+impl error::Error for HandleLimitExceeded {
+    fn description(&self) -> &str {
+        "peer exceeded its limit on transferred OS handles"
+    }
+}
+
+/// A receiver for brokers that take OS handles from less trusted peers, capping how many they may
+/// transfer so that a misbehaving peer can't exhaust our file descriptors. A message that would
+/// go over the limit is dropped, its handles are released right away, and receiving fails with a
+/// `PermissionDenied` error wrapping a `HandleLimitExceeded`.
+pub struct HandleLimitedReceiver<T> where T: Deserialize + Serialize {
+    receiver: IpcReceiver<T>,
+    limit: HandleLimit,
+    handles_received: Cell<usize>,
+}
+
+impl<T> HandleLimitedReceiver<T> where T: Deserialize + Serialize {
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.recv_raw());
+        self.admit(data, os_ipc_channels, os_ipc_shared_memory_regions)
+    }
+
+    pub fn try_recv(&self) -> Result<T,Error> {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.try_recv_raw());
+        self.admit(data, os_ipc_channels, os_ipc_shared_memory_regions)
+    }
+
+    pub fn into_inner(self) -> IpcReceiver<T> {
+        self.receiver
+    }
+
+    fn admit(&self,
+             data: Vec<u8>,
+             os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+             os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
+             -> Result<T,Error> {
+        let count = os_ipc_channels.len() + os_ipc_shared_memory_regions.len();
+        let total = self.handles_received.get() + count;
+        let exceeded = match (self.limit.per_message, self.limit.per_connection) {
+            (Some(limit), _) if count > limit => {
+                Some(HandleLimitExceeded::PerMessage { count: count, limit: limit })
+            }
+            (_, Some(limit)) if total > limit => {
+                Some(HandleLimitExceeded::PerConnection { count: total, limit: limit })
+            }
+            _ => None,
+        };
+        if let Some(exceeded) = exceeded {
+            release_handles(os_ipc_channels, os_ipc_shared_memory_regions);
+            return Err(Error::Io(io::Error::new(ErrorKind::PermissionDenied, exceeded)))
+        }
+        self.handles_received.set(total);
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+    }
+}
+
+/// What a `RateLimitedReceiver` does about a peer that goes over its limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Stop receiving until the current interval is over. The peer's messages back up in the
+    /// kernel, so that it eventually blocks in `send()`.
+    Throttle,
+    /// Drop messages over the limit, failing with a `WouldBlock` error for each of them.
+    Refuse,
+}
+
+/// How many messages and bytes a peer may send per `interval`. `None` means no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub max_messages: Option<u32>,
+    pub max_bytes: Option<usize>,
+    pub interval: Duration,
+    pub policy: RateLimitPolicy,
+}
+
+/// A receiver that protects its consumer from a runaway peer flooding the channel, by holding
+/// the peer to a `RateLimit`.
+pub struct RateLimitedReceiver<T> where T: Deserialize + Serialize {
+    receiver: IpcReceiver<T>,
+    limit: RateLimit,
+    window_start: Cell<Instant>,
+    messages_in_window: Cell<u32>,
+    bytes_in_window: Cell<usize>,
+}
+
+impl<T> RateLimitedReceiver<T> where T: Deserialize + Serialize {
+    pub fn recv(&self) -> Result<T,Error> {
+        while self.limit.policy == RateLimitPolicy::Throttle && self.limit_reached() {
+            let elapsed = Instant::now().duration_since(self.window_start.get());
+            if elapsed < self.limit.interval {
+                thread::sleep(self.limit.interval - elapsed);
+            }
+        }
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.recv_raw());
+        self.admit(data, os_ipc_channels, os_ipc_shared_memory_regions)
+    }
+
+    pub fn try_recv(&self) -> Result<T,Error> {
+        if self.limit.policy == RateLimitPolicy::Throttle && self.limit_reached() {
+            return Err(Error::WouldBlock)
+        }
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(self.receiver.try_recv_raw());
+        self.admit(data, os_ipc_channels, os_ipc_shared_memory_regions)
+    }
+
+    pub fn into_inner(self) -> IpcReceiver<T> {
+        self.receiver
+    }
+
+    /// Starts a new interval if the current one is over, and returns whether the peer has used
+    /// up its allowance for the current one.
+    fn limit_reached(&self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start.get()) >= self.limit.interval {
+            self.window_start.set(now);
+            self.messages_in_window.set(0);
+            self.bytes_in_window.set(0);
+        }
+        self.limit.max_messages.map_or(false, |max| self.messages_in_window.get() >= max) ||
+            self.limit.max_bytes.map_or(false, |max| self.bytes_in_window.get() >= max)
+    }
+
+    fn admit(&self,
+             data: Vec<u8>,
+             os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+             os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
+             -> Result<T,Error> {
+        if self.limit.policy == RateLimitPolicy::Refuse && self.limit_reached() {
+            release_handles(os_ipc_channels, os_ipc_shared_memory_regions);
+            return Err(Error::WouldBlock)
+        }
+        self.messages_in_window.set(self.messages_in_window.get() + 1);
+        self.bytes_in_window.set(self.bytes_in_window.get() + data.len());
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+    }
+}
+
+impl<T> Deserialize for IpcReceiver<T> where T: Deserialize + Serialize {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let os_receiver =
+            OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+                // FIXME(pcwalton): This could panic if the data was corrupt and the index was out
+                // of bounds. We should return an `Err` result instead.
+                os_ipc_channels_for_deserialization.borrow_mut()[index].to_receiver()
+            });
+        Ok(IpcReceiver::from_os(os_receiver))
+    }
+}
+
+impl<T> Serialize for IpcReceiver<T> where T: Deserialize + Serialize {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            os_ipc_channels_for_serialization.push(OsIpcChannel::Receiver(self.os_receiver
+                                                                              .consume()));
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+pub struct IpcSender<T> where T: Serialize {
+    os_sender: OsIpcSender,
+    phantom: PhantomData<T>,
+    /// Shared with clones, which send on the same channel.
+    stats: Arc<ChannelCounters>,
+    /// The channel's name; see `channel_named()`.
+    label: Option<Arc<String>>,
+    registration: Registration,
+    /// Whether messages end with the time they were sent; see `enable_timestamps()`.
+    timestamps: bool,
+}
+
+impl<T> Clone for IpcSender<T> where T: Serialize {
+    fn clone(&self) -> IpcSender<T> {
+        IpcSender {
+            os_sender: self.os_sender.clone(),
+            phantom: PhantomData,
+            stats: self.stats.clone(),
+            label: self.label.clone(),
+            registration: Registration::new(EndpointKind::Sender, &self.label, &self.stats),
+            timestamps: self.timestamps,
+        }
+    }
+}
+
+/// Shows the channel's ID, as for `MessageMetadata::channel_id`, its name, and whether its
+/// receiver is still there, if the backend can tell.
+impl<T> Debug for IpcSender<T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        formatter.debug_struct("IpcSender")
+                 .field("id", &self.os_sender.endpoint_id())
+                 .field("label", &self.label())
+                 .field("connected", &sender_is_connected(&self.os_sender))
+                 .finish()
+    }
+}
+
+impl<T> IpcSender<T> where T: Serialize {
+    fn from_os(os_sender: OsIpcSender) -> IpcSender<T> {
+        let stats = Arc::new(ChannelCounters::new());
+        IpcSender {
+            os_sender: os_sender,
+            phantom: PhantomData,
+            registration: Registration::new(EndpointKind::Sender, &None, &stats),
+            stats: stats,
+            label: None,
+            timestamps: false,
+        }
+    }
+
+    fn set_label(&mut self, label: Option<Arc<String>>) {
+        self.registration = Registration::new(EndpointKind::Sender, &label, &self.stats);
+        self.label = label;
+    }
+
+    pub fn connect(name: String) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender::from_os(try!(OsIpcSender::connect(name))))
+    }
+
+    /// Returns a token with which a child process started with `exec` can take this sender over,
+    /// using `IpcSender::from_inherited_token()`. This way parent and child can share a channel
+    /// without going through a named server.
+    ///
+    /// Hand the token to the child on its command line, or in an environment variable for use with
+    /// `IpcSender::from_inherited_env()`. Once the child is running, drop this sender in the
+    /// parent. Other children spawned in the meantime inherit it too.
+    ///
+    /// This is only supported on Linux; elsewhere it fails.
+    pub fn inheritable_token(&self) -> Result<String,Error> {
+        Ok(try!(self.os_sender.inheritable_token()))
+    }
+
+    /// Takes over a sender that our parent process passed down to us with
+    /// `IpcSender::inheritable_token()`.
+    pub fn from_inherited_token(token: &str) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender::from_os(try!(OsIpcSender::from_inherited_token(token))))
+    }
+
+    /// Like `from_inherited_token()`, but reads the token from the environment variable `key`.
+    pub fn from_inherited_env(key: &str) -> Result<IpcSender<T>,Error> {
+        IpcSender::from_inherited_token(&try!(inherited_token_from_env(key)))
+    }
+
+    /// Takes over a socket made by other code as the sending end of a channel; see
+    /// `IpcReceiver::from_raw_fd()`.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender::from_os(try!(OsIpcSender::from_raw_fd(fd))))
+    }
+
+    /// See `IpcReceiver::from_raw_handle()`.
+    #[cfg(windows)]
+    pub unsafe fn from_raw_handle(handle: RawHandle) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender::from_os(try!(OsIpcSender::from_raw_handle(handle))))
+    }
+
+    /// Gives up the socket underneath this sender without closing it. Whatever writes to it has
+    /// to frame messages as described for `IpcReceiver::into_raw_fd()`. Other clones of this
+    /// sender have sockets of their own, and keep working.
+    #[cfg(unix)]
+    pub fn into_raw_fd(self) -> Result<RawFd,Error> {
+        Ok(try!(self.os_sender.into_raw_fd()))
+    }
+
+    /// See `IpcReceiver::into_raw_handle()`.
+    #[cfg(windows)]
+    pub fn into_raw_handle(self) -> Result<RawHandle,Error> {
+        Ok(try!(self.os_sender.into_raw_handle()))
+    }
+
+    /// Attaches to a sender in the already running process `pid`, which handed out the token for
+    /// it from `IpcSender::inheritable_token()` -- over a supervisory channel, say. The process
+    /// doesn't have to be our child, so this lets tools attach to running processes.
+    ///
+    /// This uses `pidfd_getfd()`, so it requires Linux 5.6 or later and permission to `ptrace`
+    /// the process. Elsewhere it fails.
+    pub fn from_process(pid: u32, token: &str) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender::from_os(try!(OsIpcSender::from_process(pid, token))))
+    }
+
+    /// Returns the size of the kernel's send buffer for this channel.
+    pub fn send_buffer_size(&self) -> Result<usize,Error> {
+        Ok(try!(self.os_sender.send_buffer_size()))
+    }
+
+    /// Resizes the kernel's send buffer for this channel: larger buffers let high-throughput
+    /// channels send bigger messages in one piece and queue up more of them, smaller ones save
+    /// memory. The kernel may adjust the size; check `send_buffer_size()` for the outcome. This
+    /// affects all clones of this sender.
+    ///
+    /// Messages are cut into packets that fit the send buffer, so make sure the receiver's buffer
+    /// is at least as large; see `IpcReceiver::set_recv_buffer_size()`.
+    ///
+    /// This is only supported on Linux; elsewhere it fails.
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(),Error> {
+        Ok(try!(self.os_sender.set_send_buffer_size(size)))
+    }
+
+    /// Like `connect()`, but if there is no server by that name yet, or it isn't accepting
+    /// connections, keeps trying until `timeout` has elapsed before failing with a `TimedOut`
+    /// error.
+    ///
+    /// This is useful for a child process connecting back to its parent, which may not have set
+    /// up its server yet -- or may have died before getting around to it.
+    pub fn connect_timeout(name: String, timeout: Duration) -> Result<IpcSender<T>,Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match IpcSender::connect(name.clone()) {
+                Err(ref error) if is_server_unavailable(error) => {}
+                result => return result,
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::TimedOut)
+            }
+            thread::sleep(min(deadline - now, Duration::from_millis(CONNECT_RETRY_INTERVAL_MS)));
+        }
+    }
+
+    /// Like `connect_timeout()`, but backs off between attempts as `policy` says, and fails with
+    /// the error of the last attempt once it has made as many as it allows.
+    pub fn connect_with_retry(name: String, policy: RetryPolicy) -> Result<IpcSender<T>,Error> {
+        let (mut attempts, mut delay) = (0, policy.initial_delay);
+        loop {
+            attempts += 1;
+            match IpcSender::connect(name.clone()) {
+                Err(ref error) if is_server_unavailable(error) &&
+                                  policy.max_attempts.map_or(true, |max| attempts < max) => {}
+                result => return result,
+            }
+            thread::sleep(delay);
+            let multiplier = max(policy.multiplier, 1);
+            delay = if delay >= policy.max_delay / multiplier {
+                policy.max_delay
+            } else {
+                delay * multiplier
+            };
+        }
+    }
+
+    /// Connects to a server that requires clients to present a shared secret, as checked by
+    /// `accept_with_token()`. Fails with a `ConnectionRefused` error if the token is wrong.
+    ///
+    /// This keeps out other processes that come across the server's name. The session ID that
+    /// comes back is the same the server gets, and goes into the keys of
+    /// `MessageAuthenticator::for_session()`.
+    pub fn connect_with_token(name: String, token: &str)
+                              -> Result<(IpcSender<T>, SessionId),Error> {
+        IpcSender::connect_with_session(name, token.to_owned())
+    }
+
+    /// Connects to a server like `connect()`, but first introduces ourselves with the given
+    /// handshake payload: a protocol name and version, an authentication token, and so on. The
+    /// server checks it in `accept_with_handshake()`; if the server rejects it, this fails with a
+    /// `ConnectionRefused` error carrying the server's reason.
+    pub fn connect_with_handshake<H>(name: String, handshake: H) -> Result<IpcSender<T>,Error>
+                                     where H: Deserialize + Serialize {
+        let (sender, _) = try!(IpcSender::connect_with_session(name, handshake));
+        Ok(sender)
+    }
+
+    fn connect_with_session<H>(name: String, handshake: H)
+                               -> Result<(IpcSender<T>, SessionId),Error>
+                               where H: Deserialize + Serialize {
+        let handshake_sender = IpcSender::from_os(try!(OsIpcSender::connect(name)));
+        let (reply_sender, reply_receiver) = try!(channel());
+        let nonce = try!(session_nonce());
+        try!(handshake_sender.send(Handshake {
+            payload: handshake,
+            nonce: nonce.clone(),
+            reply: reply_sender,
+        }));
+        match reply_receiver.recv() {
+            Ok(HandshakeReply { rejection: None, nonce: server_nonce }) => {
+                Ok((IpcSender::from_os(handshake_sender.os_sender),
+                    SessionId::new(nonce, &server_nonce)))
+            }
+            Ok(HandshakeReply { rejection: Some(reason), .. }) => {
+                Err(Error::Io(io::Error::new(ErrorKind::ConnectionRefused, reason)))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Sends `data`, which is serialized in full before anything goes out. If serializing it
+    /// fails, or panics, nothing is sent, any channels it embedded are closed, and the sender can
+    /// go on being used.
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        self.send_ref(&data)
+    }
+
+    /// Sends a message that the receiver checks with `IpcReceiver::recv_authenticated()`. It
+    /// carries an HMAC of its contents and its position in the stream, keyed by `authenticator`.
+    ///
+    /// The channels and shared memory regions embedded in the message are passed on by the
+    /// kernel, so only how many of each there are is authenticated along with the data.
+    pub fn send_authenticated(&self, data: T, authenticator: &MessageAuthenticator)
+                              -> Result<(),Error> {
+        self.serialize_and_send(&data, Some(authenticator))
+            .map_err(|err| label_error(&self.label, err))
+    }
+
+    fn send_ref(&self, data: &T) -> Result<(),Error> {
+        self.serialize_and_send(data, None).map_err(|err| label_error(&self.label, err))
+    }
+
+    fn serialize_and_send(&self, data: &T, authenticator: Option<&MessageAuthenticator>)
+                          -> Result<(),io::Error> {
+        let message = try!(serialize_message(data, authenticator));
+        self.send_outgoing(message)
+    }
+
+    fn send_outgoing(&self, mut message: OutgoingMessage) -> Result<(),io::Error> {
+        crash_point();
+        let (size, handles) = (message.size, message.handles());
+        capture_message(self.os_sender.endpoint_id(),
+                        MessageDirection::Sent,
+                        &[message.data.as_slice()],
+                        message.os_ipc_channels.len(),
+                        &message.os_ipc_shared_memory_regions);
+        if self.timestamps {
+            message.data.write_all(&timestamp()).unwrap();
+        }
+        try!(self.os_sender.send(message.data.as_slice(),
+                                 message.os_ipc_channels,
+                                 message.os_ipc_shared_memory_regions));
+        audit_sent(&self.os_sender, &self.stats.peer_pid, size, handles);
+        self.stats.count(size, handles);
+        Ok(())
+    }
+
+    /// Returns true once the receiver has been dropped, or its process has died, so that callers
+    /// can notice a dead peer without waiting for a send to fail. This is not supported on the
+    /// in-process backend.
+    pub fn receiver_is_closed(&self) -> Result<bool,Error> {
+        self.os_sender.receiver_is_closed().map_err(|err| label_error(&self.label, err))
+    }
+
+    /// Returns the name the channel was created with; see `channel_named()`.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_ref().map(|label| label.as_str())
+    }
+
+    /// Returns how much this sender and its clones have sent so far, and how much of it the
+    /// receiver has yet to pick up.
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.snapshot(self.label(), self.os_sender.queued_bytes().ok())
+    }
+
+    /// Ends every message with the time it was sent, for a receiver that keeps a latency
+    /// histogram; see `IpcReceiver::enable_latency_histogram()`. The receiver must expect them.
+    /// Clones made from now on send timestamps too, but a sender passed to another process in a
+    /// message doesn't until this is called on it there.
+    pub fn enable_timestamps(&mut self) {
+        self.timestamps = true
+    }
+
+    /// Turns this sender into one that drops, delays, duplicates, reorders or truncates messages
+    /// as `faults` says; see `FaultInjectingSender`.
+    pub fn inject_faults(self, faults: Faults) -> FaultInjectingSender<T> {
+        let rng = match faults.seed {
+            Some(seed) => XorShiftRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]),
+            None => rand::weak_rng(),
+        };
+        FaultInjectingSender {
+            sender: self,
+            faults: faults,
+            rng: RefCell::new(rng),
+            scheduled: RefCell::new(VecDeque::new()),
+            held_back: RefCell::new(None),
+        }
+    }
+
+    /// Returns a guard that queues up the messages sent through it, and sends them all at once
+    /// when uncorked or dropped. This saves system calls for code that sends bursts of small
+    /// messages.
+    pub fn cork(&self) -> BatchGuard<T> {
+        BatchGuard {
+            sender: self,
+            messages: vec![],
+        }
+    }
+
+    pub fn to_opaque(self) -> OpaqueIpcSender {
+        OpaqueIpcSender {
+            os_sender: self.os_sender,
+            peer_pid: PeerPid::new(),
+        }
+    }
+}
+
+impl<H> IpcSender<(H, Vec<u8>)> where H: Serialize {
+    /// Sends `header` followed by `payload`, as if sending `(header, payload.to_vec())`, but
+    /// without copying the payload into the serialized message first. This suits protocols that
+    /// wrap big blobs of bytes in a small header.
+    pub fn send_with_payload(&self, header: &H, payload: &[u8]) -> Result<(),Error> {
+        // Serialized, a `Vec<u8>` is its length as a `u64` followed by its bytes; so this is
+        // everything up to the payload's bytes.
+        let mut bytes = MessageBuffer::new();
+        let (os_ipc_channels, os_ipc_shared_memory_regions) =
+            try!(serialize_with_handles(&(header, payload.len() as u64), &mut bytes)
+                     .map_err(|err| label_io_error(&self.label, err)));
+        let handles = os_ipc_channels.len() + os_ipc_shared_memory_regions.len();
+        capture_message(self.os_sender.endpoint_id(),
+                        MessageDirection::Sent,
+                        &[bytes.as_slice(), payload],
+                        os_ipc_channels.len(),
+                        &os_ipc_shared_memory_regions);
+        let stamp = timestamp();
+        let stamp = if self.timestamps { &stamp[..] } else { &[][..] };
+        try!(self.os_sender.send_vectored(&[bytes.as_slice(), payload, stamp],
+                                          os_ipc_channels,
+                                          os_ipc_shared_memory_regions)
+                 .map_err(|err| label_io_error(&self.label, err.into())));
+        audit_sent(&self.os_sender, &self.stats.peer_pid, bytes.len() + payload.len(), handles);
+        self.stats.count(bytes.len() + payload.len(), handles);
+        Ok(())
+    }
+}
+
+/// Queues up messages to send them all at once; see `IpcSender::cork()`. Dropping the guard sends
+/// the queued messages too, but ignores any error.
+pub struct BatchGuard<'a, T> where T: Serialize + 'a {
+    sender: &'a IpcSender<T>,
+    messages: Vec<OutgoingMessage>,
+}
+
+impl<'a, T> BatchGuard<'a, T> where T: Serialize {
+    /// Queues `data` up. It's serialized right away, so any channels in it are taken over now.
+    pub fn send(&mut self, data: T) -> Result<(),Error> {
+        self.messages.push(try!(serialize_message(&data, None).map_err(|err| {
+            label_io_error(&self.sender.label, err)
+        })));
+        Ok(())
+    }
+
+    /// Sends the queued messages.
+    pub fn uncork(mut self) -> Result<(),Error> {
+        self.flush().map_err(|err| label_error(&self.sender.label, err))
+    }
+
+    fn flush(&mut self) -> Result<(),io::Error> {
+        if self.messages.is_empty() {
+            return Ok(())
+        }
+        let messages = mem::replace(&mut self.messages, vec![]);
+        let metadata: Vec<_> =
+            messages.iter().map(|message| (message.size, message.handles())).collect();
+        for message in &messages {
+            capture_message(self.sender.os_sender.endpoint_id(),
+                            MessageDirection::Sent,
+                            &[message.data.as_slice()],
+                            message.os_ipc_channels.len(),
+                            &message.os_ipc_shared_memory_regions);
+        }
+        let timestamps = self.sender.timestamps;
+        try!(self.sender.os_sender.send_batch(messages.into_iter().map(|message| {
+            let mut data = message.data.into_vec();
+            if timestamps {
+                data.extend_from_slice(&timestamp());
+            }
+            (data, message.os_ipc_channels, message.os_ipc_shared_memory_regions)
+        }).collect()));
+        for (size, handles) in metadata {
+            audit_sent(&self.sender.os_sender, &self.sender.stats.peer_pid, size, handles);
+            self.sender.stats.count(size, handles);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T> Drop for BatchGuard<'a, T> where T: Serialize {
+    fn drop(&mut self) {
+        drop(self.flush())
+    }
+}
+
+/// Something that `FaultInjectingSender` does to a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The message is never sent.
+    Drop,
+    /// The message is sent after the sending thread sleeps this long.
+    Delay(Duration),
+    /// The message is sent twice. One that carries channels is sent once, as channels can't be
+    /// duplicated.
+    Duplicate,
+    /// The message is held back, and sent right after the next one.
+    Reorder,
+    /// Only the first so many bytes of the message are sent, so that it fails to deserialize. A
+    /// message moved into shared memory is sent whole.
+    Truncate(usize),
+}
+
+/// How likely `FaultInjectingSender` is to inflict each fault on a message, from 0 for never to 1
+/// for always. A message suffers one fault at most: they are tried in the order of the fields.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Faults {
+    pub drop: f64,
+    pub delay: f64,
+    /// How long delayed messages are held up.
+    pub delay_by: Duration,
+    pub duplicate: f64,
+    pub reorder: f64,
+    /// Truncated messages are cut to a random length.
+    pub truncate: f64,
+    /// Seeds the random choices, so that a run can be repeated; `None` seeds them at random.
+    pub seed: Option<u32>,
+}
+
+impl Default for Faults {
+    /// No faults at all, which suits senders that only follow a schedule of faults.
+    fn default() -> Faults {
+        Faults {
+            drop: 0.0,
+            delay: 0.0,
+            delay_by: Duration::from_millis(10),
+            duplicate: 0.0,
+            reorder: 0.0,
+            truncate: 0.0,
+            seed: None,
+        }
+    }
+}
+
+/// A sender that misbehaves on purpose, for testing how the receiving side copes with a peer that
+/// loses, repeats, reorders or mangles messages, without writing a mock of it. Faults are drawn
+/// at random, as `Faults` says, except for those scheduled with `schedule_faults()`. A message
+/// held back for reordering that no other message follows is sent when the sender is dropped.
+pub struct FaultInjectingSender<T> where T: Serialize {
+    sender: IpcSender<T>,
+    faults: Faults,
+    rng: RefCell<XorShiftRng>,
+    scheduled: RefCell<VecDeque<Option<Fault>>>,
+    held_back: RefCell<Option<OutgoingMessage>>,
+}
+
+impl<T> FaultInjectingSender<T> where T: Serialize {
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let mut message = try!(serialize_message(&data, None).map_err(|err| {
+            label_io_error(&self.sender.label, err)
+        }));
+        match self.next_fault(message.data.len()) {
+            Some(Fault::Drop) => return Ok(()),
+            Some(Fault::Delay(delay)) => thread::sleep(delay),
+            Some(Fault::Duplicate) => {
+                if let Some(copy) = message.try_clone() {
+                    try!(self.send_message(copy));
+                }
+            }
+            Some(Fault::Reorder) => {
+                return match mem::replace(&mut *self.held_back.borrow_mut(), Some(message)) {
+                    Some(previous) => self.send_message(previous),
+                    None => Ok(()),
+                }
+            }
+            Some(Fault::Truncate(length)) => {
+                if message.data.len() > length {
+                    message.data.spill().truncate(length);
+                    message.size = length;
+                }
+            }
+            None => {}
+        }
+        try!(self.send_message(message));
+        match self.held_back.borrow_mut().take() {
+            Some(held_back) => self.send_message(held_back),
+            None => Ok(()),
+        }
+    }
+
+    /// Inflicts `faults` on the next messages, one each and in order, before going back to
+    /// random faults. `None` lets a message through untouched.
+    pub fn schedule_faults(&self, faults: Vec<Option<Fault>>) {
+        self.scheduled.borrow_mut().extend(faults)
+    }
+
+    fn next_fault(&self, length: usize) -> Option<Fault> {
+        if let Some(fault) = self.scheduled.borrow_mut().pop_front() {
+            return fault
+        }
+        let mut rng = self.rng.borrow_mut();
+        if rng.gen::<f64>() < self.faults.drop {
+            Some(Fault::Drop)
+        } else if rng.gen::<f64>() < self.faults.delay {
+            Some(Fault::Delay(self.faults.delay_by))
+        } else if rng.gen::<f64>() < self.faults.duplicate {
+            Some(Fault::Duplicate)
+        } else if rng.gen::<f64>() < self.faults.reorder {
+            Some(Fault::Reorder)
+        } else if rng.gen::<f64>() < self.faults.truncate && length > 0 {
+            Some(Fault::Truncate(rng.gen_range(0, length)))
+        } else {
+            None
+        }
+    }
+
+    fn send_message(&self, message: OutgoingMessage) -> Result<(),Error> {
+        self.sender
+            .send_outgoing(message)
+            .map_err(|err| label_error(&self.sender.label, err))
+    }
+}
+
+impl<T> Drop for FaultInjectingSender<T> where T: Serialize {
+    fn drop(&mut self) {
+        if let Some(held_back) = self.held_back.borrow_mut().take() {
+            drop(self.send_message(held_back))
+        }
+    }
+}
+
+/// What went through one end of a channel; see `IpcSender::stats()` and `IpcReceiver::stats()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelStats {
+    /// The channel's name, if it has one; see `channel_named()`.
+    pub label: Option<String>,
+    /// The number of messages sent or received.
+    pub messages: usize,
+    /// Their total serialized size.
+    pub bytes: usize,
+    /// The number of channels and shared memory regions transferred with them.
+    pub handles: usize,
+    /// How many bytes are sitting in the channel, waiting to be received. This is only known on
+    /// Linux.
+    pub queued_bytes: Option<usize>,
+    /// How long the messages took to arrive, for a receiver that keeps track; see
+    /// `IpcReceiver::enable_latency_histogram()`.
+    pub latency: Option<LatencyHistogram>,
+}
+
+#[derive(Debug)]
+struct ChannelCounters {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    handles: AtomicUsize,
+    /// Kept along with the counters, since they are per connection as well.
+    peer_pid: PeerPid,
+}
+
+impl ChannelCounters {
+    fn new() -> ChannelCounters {
+        ChannelCounters {
+            messages: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            handles: AtomicUsize::new(0),
+            peer_pid: PeerPid::new(),
+        }
+    }
+
+    fn count(&self, size: usize, handles: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.handles.fetch_add(handles, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, label: Option<&str>, queued_bytes: Option<usize>) -> ChannelStats {
+        ChannelStats {
+            label: label.map(str::to_owned),
+            messages: self.messages.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            handles: self.handles.load(Ordering::Relaxed),
+            queued_bytes: queued_bytes,
+            latency: None,
+        }
+    }
+}
+
+/// The size of the time that timestamped messages end with: the nanoseconds since the Unix epoch,
+/// as a little-endian `u64`.
+const TIMESTAMP_SIZE: usize = 8;
+
+/// The number of buckets in a `LatencyHistogram`, the last of which takes everything over half an
+/// hour or so.
+const LATENCY_BUCKETS: usize = 32;
+
+fn timestamp_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64
+}
+
+/// Returns the time to end a timestamped message with.
+fn timestamp() -> [u8; TIMESTAMP_SIZE] {
+    let mut stamp = [0; TIMESTAMP_SIZE];
+    LittleEndian::write_u64(&mut stamp, timestamp_now());
+    stamp
+}
+
+/// How long the messages a receiver got took to arrive, counted in buckets whose limits double:
+/// the first counts those that took under a microsecond, and each after it those that took up to
+/// twice as long as the one before allows. See `IpcReceiver::enable_latency_histogram()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyHistogram {
+    /// How many messages fell into each bucket. The last one also counts those that took longer
+    /// than its limit.
+    pub buckets: Vec<usize>,
+    /// How long all the messages took together.
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Returns how long the messages counted in bucket `index` took at most: 2^`index`
+    /// microseconds.
+    pub fn bucket_limit(index: usize) -> Duration {
+        let micros = 1u64 << index;
+        Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+    }
+
+    /// Returns how many messages were timed.
+    pub fn messages(&self) -> usize {
+        self.buckets.iter().fold(0, |total, &count| total + count)
+    }
+
+    /// Returns how long messages took on average, if any were timed.
+    pub fn mean(&self) -> Option<Duration> {
+        match self.messages() {
+            0 => None,
+            messages => Some(self.total / messages as u32),
+        }
+    }
+
+    /// Returns the limit of the bucket that holds the `percentile`th percentile, such as 50 for
+    /// the median or 99: at least that many percent of messages arrived within it. Returns `None`
+    /// if no messages were timed.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let messages = self.messages();
+        if messages == 0 {
+            return None
+        }
+        let rank = max((percentile / 100.0 * messages as f64).ceil() as usize, 1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(LatencyHistogram::bucket_limit(index))
+            }
+        }
+        Some(LatencyHistogram::bucket_limit(self.buckets.len() - 1))
+    }
+}
+
+#[derive(Debug)]
+struct LatencyCounters {
+    buckets: Vec<AtomicUsize>,
+    total_micros: AtomicUsize,
+}
+
+impl LatencyCounters {
+    fn new() -> LatencyCounters {
+        LatencyCounters {
+            buckets: (0..LATENCY_BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+            total_micros: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        let micros = nanos / 1000;
+        let bucket = min(64 - micros.leading_zeros() as usize, LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros as usize, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let micros = self.total_micros.load(Ordering::Relaxed) as u64;
+        LatencyHistogram {
+            buckets: self.buckets.iter().map(|count| count.load(Ordering::Relaxed)).collect(),
+            total: Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000),
+        }
+    }
+}
+
+/// Which end of a channel an `EndpointInfo` describes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointKind {
+    Sender,
+    Receiver,
+}
+
+/// A live sender or receiver, as `registered_endpoints()` reports it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointInfo {
+    /// Tells endpoints apart, except that a sender and its clones, which share their counts,
+    /// share this too.
+    pub id: usize,
+    pub kind: EndpointKind,
+    /// When it was created, or received.
+    pub created: SystemTime,
+    /// What went through it so far, and its name, if it has one. A sender's counts include its
+    /// clones'.
+    pub stats: ChannelStats,
+}
+
+struct RegistryEntry {
+    kind: EndpointKind,
+    label: Option<Arc<String>>,
+    created: SystemTime,
+    stats: Arc<ChannelCounters>,
+}
+
+/// An endpoint's place in the registry, which it gives up when dropped.
+#[derive(Debug)]
+struct Registration {
+    id: Option<usize>,
+}
+
+impl Registration {
+    fn new(kind: EndpointKind, label: &Option<Arc<String>>, stats: &Arc<ChannelCounters>)
+           -> Registration {
+        if !REGISTERING.load(Ordering::Relaxed) {
+            return Registration {
+                id: None,
+            }
+        }
+        let id = NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed);
+        REGISTRY.lock().unwrap().insert(id, RegistryEntry {
+            kind: kind,
+            label: label.clone(),
+            created: SystemTime::now(),
+            stats: stats.clone(),
+        });
+        Registration {
+            id: Some(id),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            REGISTRY.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// Starts entering every `IpcSender` and `IpcReceiver` created or received from now on in a
+/// process-wide registry, until it's dropped, so that `registered_endpoints()` can tell where a
+/// long-running process's channels are going, e.g. on a diagnostics page. Registering costs a
+/// lock and an allocation per endpoint, which is why it's off until enabled.
+pub fn enable_registry() {
+    REGISTERING.store(true, Ordering::SeqCst)
+}
+
+/// Stops registering new endpoints. Those already registered stay in the registry until they're
+/// dropped.
+pub fn disable_registry() {
+    REGISTERING.store(false, Ordering::SeqCst)
+}
+
+/// Returns the endpoints in the registry, oldest first; see `enable_registry()`.
+pub fn registered_endpoints() -> Vec<EndpointInfo> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut ids: Vec<&usize> = registry.keys().collect();
+    ids.sort();
+    ids.into_iter().map(|id| {
+        let entry = &registry[id];
+        EndpointInfo {
+            id: &*entry.stats as *const ChannelCounters as usize,
+            kind: entry.kind,
+            created: entry.created,
+            stats: entry.stats.snapshot(entry.label.as_ref().map(|label| label.as_str()), None),
+        }
+    }).collect()
+}
+
+/// A message serialized and ready to go.
+struct OutgoingMessage {
+    data: MessageBuffer,
+    os_ipc_channels: Vec<OsIpcChannel>,
+    os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>,
+    /// The size of the serialized message, even if it was moved into shared memory.
+    size: usize,
+}
+
+impl OutgoingMessage {
+    fn handles(&self) -> usize {
+        self.os_ipc_channels.len() + self.os_ipc_shared_memory_regions.len()
+    }
+
+    /// Copies the message, unless it carries channels, which can't be copied.
+    fn try_clone(&self) -> Option<OutgoingMessage> {
+        if !self.os_ipc_channels.is_empty() {
+            return None
+        }
+        let mut data = MessageBuffer::new();
+        data.write_all(self.data.as_slice()).unwrap();
+        Some(OutgoingMessage {
+            data: data,
+            os_ipc_channels: vec![],
+            os_ipc_shared_memory_regions: self.os_ipc_shared_memory_regions.clone(),
+            size: self.size,
+        })
+    }
+}
+
+fn serialize_message<T>(data: &T, authenticator: Option<&MessageAuthenticator>)
+                        -> Result<OutgoingMessage,io::Error> where T: Serialize {
+    let mut bytes = MessageBuffer::new();
+    if authenticator.is_some() {
+        bytes.write_all(&[0; AUTHENTICATION_HEADER_SIZE]).unwrap();
+    }
+    let (os_ipc_channels, mut os_ipc_shared_memory_regions) =
+        try!(serialize_with_handles(data, &mut bytes));
+    if let Some(authenticator) = authenticator {
+        authenticator.seal(bytes.spill(),
+                           os_ipc_channels.len(),
+                           os_ipc_shared_memory_regions.len());
+    }
+    let size = bytes.len();
+    // A message with no data but a shared memory region can't occur otherwise, since embedding a
+    // region serializes its index; so this is how the receiver tells that the message was moved
+    // into the last region.
+    if authenticator.is_none() && size != 0 &&
+            size >= SHARED_MEMORY_THRESHOLD.load(Ordering::Relaxed) {
+        os_ipc_shared_memory_regions.push(OsIpcSharedMemory::from_bytes(bytes.as_slice()));
+        bytes = MessageBuffer::new();
+    }
+    Ok(OutgoingMessage {
+        data: bytes,
+        os_ipc_channels: os_ipc_channels,
+        os_ipc_shared_memory_regions: os_ipc_shared_memory_regions,
+        size: size,
+    })
+}
+
+/// Serializes `message` as `IpcSender::send()` does, returning its data, the number of channels
+/// in it, and the contents of its shared memory regions, for `wire::encode()`. The channels are
+/// closed.
+#[doc(hidden)]
+pub fn serialize_message_parts<T>(message: &T) -> Result<(Vec<u8>, usize, Vec<Vec<u8>>),io::Error>
+                                  where T: Serialize {
+    let message = try!(serialize_message(message, None));
+    let shared_memory_regions =
+        message.os_ipc_shared_memory_regions.iter().map(|region| region.to_vec()).collect();
+    Ok((message.data.as_slice().to_vec(), message.os_ipc_channels.len(), shared_memory_regions))
+}
+
+/// Messages up to this size are serialized into a buffer on the stack, so that sending the small
+/// control messages that make up most traffic doesn't allocate.
+const INLINE_MESSAGE_SIZE: usize = 256;
+
+/// Holds a serialized message: inline if it's small, on the heap once it outgrows that.
+struct MessageBuffer {
+    inline: [u8; INLINE_MESSAGE_SIZE],
+    inline_length: usize,
+    heap: Option<Vec<u8>>,
+}
+
+impl MessageBuffer {
+    fn new() -> MessageBuffer {
+        MessageBuffer {
+            inline: [0; INLINE_MESSAGE_SIZE],
+            inline_length: 0,
+            heap: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self.heap {
+            Some(ref heap) => &heap[..],
+            None => &self.inline[..self.inline_length],
+        }
+    }
+
+    /// Moves the contents to the heap, if they aren't there already.
+    fn spill(&mut self) -> &mut Vec<u8> {
+        if self.heap.is_none() {
+            let mut heap = Vec::with_capacity(4096);
+            heap.extend_from_slice(&self.inline[..self.inline_length]);
+            self.heap = Some(heap);
+        }
+        self.heap.as_mut().unwrap()
+    }
+
+    fn into_vec(mut self) -> Vec<u8> {
+        self.spill();
+        self.heap.unwrap()
+    }
+}
+
+impl Write for MessageBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.heap.is_none() && self.inline_length + buf.len() <= INLINE_MESSAGE_SIZE {
+            let start = self.inline_length;
+            let mut slot = &mut self.inline[start..start + buf.len()];
+            try!(slot.write_all(buf));
+            self.inline_length += buf.len();
+        } else {
+            self.spill().extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes `data` onto the end of `bytes`, returning the channels and shared memory regions
+/// embedded in it.
+fn serialize_with_handles<T, W>(data: &T, bytes: &mut W)
+                                -> Result<(Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>),io::Error>
+                                where T: Serialize, W: Write {
+    OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+        OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
+                |os_ipc_shared_memory_regions_for_serialization| {
+            if os_ipc_channels_for_serialization.borrow_state() != BorrowState::Unused {
+                return Err(recursive_io_error());
+            }
+
+            if os_ipc_shared_memory_regions_for_serialization.borrow_state() !=
+                    BorrowState::Unused {
+                return Err(recursive_io_error());
+            }
+
+            let scope = SerializationScope::enter(os_ipc_channels_for_serialization,
+                                                  os_ipc_shared_memory_regions_for_serialization);
+            let result = {
+                let mut serializer = bincode::serde::Serializer::new(&mut *bytes);
+                data.serialize(&mut serializer)
+            };
+            let (os_ipc_channels, os_ipc_shared_memory_regions) = scope.finish();
+            // Nothing has been sent yet, so dropping what the message had embedded so far leaves
+            // the channel as it was.
+            try!(result.map_err(serialize_error_to_io_error));
+            // Each distinct region is transferred exactly once, no matter how many times it
+            // was embedded in the message.
+            let os_ipc_shared_memory_regions =
+                os_ipc_shared_memory_regions.iter()
+                                            .map(|os_ipc_shared_memory_region| {
+                    (**os_ipc_shared_memory_region).clone()
+                }).collect();
+            Ok((os_ipc_channels, os_ipc_shared_memory_regions))
+        })
+    })
+}
+
+/// Collects the channels and shared memory regions embedded in one message, setting aside those of
+/// any message being serialized further up the stack. If serialization panics, dropping the scope
+/// puts those back, and closes the handles collected so far, so that the thread can go on
+/// sending.
+struct SerializationScope<'a> {
+    os_ipc_channels: &'a RefCell<Vec<OsIpcChannel>>,
+    os_ipc_shared_memory_regions: &'a RefCell<Vec<Arc<OsIpcSharedMemory>>>,
+    set_aside: Option<(Vec<OsIpcChannel>, Vec<Arc<OsIpcSharedMemory>>)>,
+}
+
+impl<'a> SerializationScope<'a> {
+    fn enter(os_ipc_channels: &'a RefCell<Vec<OsIpcChannel>>,
+             os_ipc_shared_memory_regions: &'a RefCell<Vec<Arc<OsIpcSharedMemory>>>)
+             -> SerializationScope<'a> {
+        let set_aside = (mem::replace(&mut *os_ipc_channels.borrow_mut(), Vec::new()),
+                         mem::replace(&mut *os_ipc_shared_memory_regions.borrow_mut(), Vec::new()));
+        SerializationScope {
+            os_ipc_channels: os_ipc_channels,
+            os_ipc_shared_memory_regions: os_ipc_shared_memory_regions,
+            set_aside: Some(set_aside),
+        }
+    }
+
+    /// Returns what the message embedded.
+    fn finish(mut self) -> (Vec<OsIpcChannel>, Vec<Arc<OsIpcSharedMemory>>) {
+        let (os_ipc_channels, os_ipc_shared_memory_regions) = self.set_aside.take().unwrap();
+        (mem::replace(&mut *self.os_ipc_channels.borrow_mut(), os_ipc_channels),
+         mem::replace(&mut *self.os_ipc_shared_memory_regions.borrow_mut(),
+                      os_ipc_shared_memory_regions))
+    }
+}
+
+impl<'a> Drop for SerializationScope<'a> {
+    fn drop(&mut self) {
+        if let Some((os_ipc_channels, os_ipc_shared_memory_regions)) = self.set_aside.take() {
+            *self.os_ipc_channels.borrow_mut() = os_ipc_channels;
+            *self.os_ipc_shared_memory_regions.borrow_mut() = os_ipc_shared_memory_regions;
+        }
+    }
+}
+
+impl<T> Deserialize for IpcSender<T> where T: Serialize {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let os_sender = try!(deserialize_os_ipc_sender(deserializer));
+        Ok(IpcSender::from_os(os_sender))
+    }
+}
+
+impl<T> Serialize for IpcSender<T> where T: Serialize {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        serialize_os_ipc_sender(&self.os_sender, serializer)
+    }
+}
+
+/// What a `ReconnectingSender` does with messages while its server cannot be reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReconnectPolicy {
+    /// Fail the `send()` call.
+    Error,
+    /// Queue up to the given number of messages, to be delivered in order once the server can be
+    /// reached again. Sends fail once the queue is full.
+    Buffer(usize),
+}
+
+/// How `IpcSender::connect_with_retry()` keeps trying to connect to a server that isn't there
+/// yet: waiting `initial_delay` after the first attempt, and `multiplier` times longer after each
+/// attempt after that, up to `max_delay`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// How many attempts to make in all, or `None` to keep trying forever.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+    /// Ten attempts, 10ms apart at first and doubling from there, which gives a server about 3
+    /// seconds to come up.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2,
+        }
+    }
+}
+
+/// A sender that connects to a named server, and connects again whenever the server goes away
+/// and comes back under the same name -- for example because it is a supervised child process
+/// that got respawned.
+///
+/// A message is only resent if handing it to the old connection failed outright. Messages that
+/// the old connection accepted but the peer never read before going away are lost.
+pub struct ReconnectingSender<T> where T: Serialize {
+    name: String,
+    policy: ReconnectPolicy,
+    sender: Option<IpcSender<T>>,
+    pending: VecDeque<T>,
+}
+
+impl<T> ReconnectingSender<T> where T: Serialize {
+    /// Creates a sender for the server with the given name. No connection is made until the
+    /// first message is sent.
+    pub fn new(name: String, policy: ReconnectPolicy) -> ReconnectingSender<T> {
+        ReconnectingSender {
+            name: name,
+            policy: policy,
+            sender: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Drops the current connection, if any, and connects to the server again.
+    pub fn reconnect(&mut self) -> Result<(),Error> {
+        self.sender = None;
+        self.sender = Some(try!(IpcSender::connect(self.name.clone())));
+        Ok(())
+    }
+
+    /// Sends `data` after any messages that are queued up. If an older message fails for a reason
+    /// other than the server being unreachable, that message is dropped and its error returned,
+    /// and `data` stays queued up behind the rest.
+    pub fn send(&mut self, data: T) -> Result<(),Error> {
+        self.pending.push_back(data);
+        match self.flush() {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                if !is_disconnection(&error) {
+                    // `flush()` has already dropped the message that failed.
+                    return Err(error)
+                }
+                match self.policy {
+                    ReconnectPolicy::Buffer(limit) if self.pending.len() <= limit => Ok(()),
+                    _ => {
+                        // There's no room to queue this message up, so it's given up on.
+                        self.pending.pop_back();
+                        Err(error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tries to deliver any messages that were queued up while the server was unreachable. A
+    /// message that fails to send because the server still can't be reached stays at the front
+    /// of the queue, for the next try; one that fails for another reason is dropped, so that it
+    /// doesn't hold up the rest.
+    pub fn flush(&mut self) -> Result<(),Error> {
+        while let Some(data) = self.pending.pop_front() {
+            if let Err(error) = self.send_now(&data) {
+                if is_disconnection(&error) {
+                    self.pending.push_front(data);
+                }
+                return Err(error)
+            }
+        }
+        Ok(())
+    }
+
+    fn send_now(&mut self, data: &T) -> Result<(),Error> {
+        if self.sender.is_none() {
+            try!(self.reconnect());
+        }
+        let result = self.sender.as_ref().unwrap().send_ref(data);
+        match result {
+            Err(ref error) if is_disconnection(error) => {}
+            result => return result,
+        }
+
+        // The server may have been restarted since we last connected; try a fresh connection.
+        try!(self.reconnect());
+        self.sender.as_ref().unwrap().send_ref(data)
+    }
+}
+
+fn inherited_token_from_env(key: &str) -> Result<String,io::Error> {
+    env::var(key).map_err(|_| {
+        io::Error::new(ErrorKind::NotFound,
+                   format!("no inherited IPC channel in environment variable {}", key))
+    })
+}
+
+/// How long `IpcSender::connect_timeout()` waits between attempts.
+const CONNECT_RETRY_INTERVAL_MS: u64 = 10;
+
+/// Returns true if connecting failed because the server isn't there (yet).
+fn is_server_unavailable(error: &Error) -> bool {
+    match *error.unlabeled() {
+        Error::Io(ref error) => {
+            match error.kind() {
+                ErrorKind::ConnectionRefused | ErrorKind::NotFound => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if the error means that the peer went away or was never there to begin with.
+fn is_disconnection(error: &Error) -> bool {
+    match *error.unlabeled() {
+        Error::Disconnected => true,
+        _ => is_server_unavailable(error),
+    }
+}
+
+pub struct IpcReceiverSet {
+    os_receiver_set: OsIpcReceiverSet,
+}
+
+impl IpcReceiverSet {
+    pub fn new() -> Result<IpcReceiverSet,Error> {
+        Ok(IpcReceiverSet {
+            os_receiver_set: try!(OsIpcReceiverSet::new()),
+        })
+    }
+
+    pub fn add<T>(&mut self, receiver: IpcReceiver<T>) -> Result<i64,Error>
+                  where T: Deserialize + Serialize {
+        Ok(try!(self.os_receiver_set.add(receiver.os_receiver)))
+    }
+
+    pub fn add_opaque(&mut self, receiver: OpaqueIpcReceiver) -> Result<i64,Error> {
+        Ok(try!(self.os_receiver_set.add(receiver.os_receiver)))
+    }
+
+    pub fn select(&mut self) -> Result<Vec<IpcSelectionResult>,Error> {
+        crash_point();
+        let results = try!(self.os_receiver_set.select());
+        Ok(results.into_iter().map(|result| {
+            match result {
+                OsIpcSelectionResult::DataReceived(os_receiver_id,
+                                                   data,
+                                                   os_ipc_channels,
+                                                   os_ipc_shared_memory_regions) => {
+                    audit(|| {
+                        MessageMetadata {
+                            channel_id: Some(os_receiver_id),
+                            direction: MessageDirection::Received,
+                            size: data.len(),
+                            handles: os_ipc_channels.len() + os_ipc_shared_memory_regions.len(),
+                            peer_pid: None,
+                        }
+                    });
+                    capture_message(Some(os_receiver_id),
+                                    MessageDirection::Received,
+                                    &[&data[..]],
+                                    os_ipc_channels.len(),
+                                    &os_ipc_shared_memory_regions);
+                    IpcSelectionResult::MessageReceived(os_receiver_id,
+                                                        OpaqueIpcMessage::new(
+                                                            data,
+                                                            os_ipc_channels,
+                                                            os_ipc_shared_memory_regions))
+                }
+                OsIpcSelectionResult::ChannelClosed(os_receiver_id) => {
+                    IpcSelectionResult::ChannelClosed(os_receiver_id)
+                }
+                OsIpcSelectionResult::ReceiveFailed(os_receiver_id, error) => {
+                    IpcSelectionResult::ReceiveFailed(os_receiver_id, Error::from(error))
+                }
+            }
+        }).collect())
+    }
+}
+
+/// A region of memory shared between processes.
+///
+/// Cloning an `IpcSharedMemory` is cheap: clones refer to the same mapping. When the same region
+/// is embedded several times in one message, it is transferred and mapped only once, and all the
+/// copies on the receiving side share that mapping.
+#[derive(Clone, PartialEq)]
+pub struct IpcSharedMemory {
+    os_shared_memory: Arc<OsIpcSharedMemory>,
+}
+
+/// Shows the region's ID and length, rather than all of its contents.
+impl Debug for IpcSharedMemory {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        formatter.debug_struct("IpcSharedMemory")
+                 .field("id", &self.os_shared_memory.endpoint_id())
+                 .field("length", &self.len())
+                 .finish()
+    }
+}
+
+impl Deref for IpcSharedMemory {
     type Target = [u8];
 
-    #[inline]
-    fn deref(&self) -> &[u8] {
-        &*self.os_shared_memory
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &**self.os_shared_memory
+    }
+}
+
+impl Deserialize for IpcSharedMemory {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let os_shared_memory = OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
+            |os_ipc_shared_memory_regions_for_deserialization| {
+                // The index is out of bounds if the data is corrupt, or if it came over a
+                // transport that only carries bytes.
+                os_ipc_shared_memory_regions_for_deserialization.borrow().get(index).cloned()
+            });
+        let os_shared_memory = match os_shared_memory {
+            Some(os_shared_memory) => os_shared_memory,
+            None => {
+                return Err(D::Error::custom(format!("no shared memory region at index {}", index)))
+            }
+        };
+        Ok(IpcSharedMemory {
+            os_shared_memory: os_shared_memory,
+        })
+    }
+}
+
+impl Serialize for IpcSharedMemory {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let index = OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
+            |os_ipc_shared_memory_regions_for_serialization| {
+                let mut os_ipc_shared_memory_regions_for_serialization =
+                    os_ipc_shared_memory_regions_for_serialization.borrow_mut();
+                let existing_index =
+                    os_ipc_shared_memory_regions_for_serialization.iter().position(
+                        |os_ipc_shared_memory_region| {
+                            &**os_ipc_shared_memory_region as *const OsIpcSharedMemory ==
+                                &*self.os_shared_memory as *const OsIpcSharedMemory
+                        });
+                match existing_index {
+                    Some(index) => index,
+                    None => {
+                        let index = os_ipc_shared_memory_regions_for_serialization.len();
+                        os_ipc_shared_memory_regions_for_serialization.push(
+                            self.os_shared_memory.clone());
+                        index
+                    }
+                }
+            });
+        index.serialize(serializer)
+    }
+}
+
+impl IpcSharedMemory {
+    pub fn from_bytes(bytes: &[u8]) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: Arc::new(OsIpcSharedMemory::from_bytes(bytes)),
+        }
+    }
+
+    pub fn from_byte(byte: u8, length: usize) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: Arc::new(OsIpcSharedMemory::from_byte(byte, length)),
+        }
+    }
+}
+
+/// A file descriptor that can be embedded in a message, to hand whatever it refers to -- a
+/// socket, a pipe, a memfd or an open file -- over to the receiving process.
+///
+/// An `IpcFd` owns its descriptor and closes it when dropped. Sending one sends a duplicate, so
+/// the descriptor stays open on this side too, and the receiver gets one of its own. Only native
+/// channels carry descriptors: sending one over an in-process channel fails, as does serializing
+/// one on a backend that can't transfer them at all.
+#[cfg(unix)]
+#[derive(Debug, PartialEq)]
+pub struct IpcFd {
+    fd: RawFd,
+}
+
+#[cfg(unix)]
+impl Drop for IpcFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl IpcFd {
+    /// Takes ownership of the descriptor of `object`, e.g. a `File` or a `UnixStream`.
+    pub fn new<F>(object: F) -> IpcFd where F: IntoRawFd {
+        IpcFd {
+            fd: object.into_raw_fd(),
+        }
+    }
+
+    /// Returns a new `IpcFd` that refers to the same file.
+    pub fn try_clone(&self) -> Result<IpcFd,Error> {
+        let fd = unsafe {
+            libc::dup(self.fd)
+        };
+        if fd < 0 {
+            return Err(Error::from(io::Error::last_os_error()))
+        }
+        Ok(IpcFd {
+            fd: fd,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for IpcFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(unix)]
+impl FromRawFd for IpcFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> IpcFd {
+        IpcFd {
+            fd: fd,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for IpcFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        mem::forget(self);
+        fd
+    }
+}
+
+#[cfg(unix)]
+impl Deserialize for IpcFd {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let fd = try!(convert_os_ipc_channel::<D,_,_>(index, |os_channel| os_channel.to_fd()));
+        Ok(IpcFd {
+            fd: fd,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Serialize for IpcFd {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let fd = try!(self.try_clone().map_err(|err| S::Error::custom(err.to_string())));
+        let os_ipc_channel = try!(OsIpcChannel::from_fd(fd.into_raw_fd()).map_err(|err| {
+            S::Error::custom(io::Error::from(err).to_string())
+        }));
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            os_ipc_channels_for_serialization.push(os_ipc_channel);
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+/// A Windows handle that can be embedded in a message, to hand whatever it refers to -- an
+/// event, a section, a file -- over to the receiver. This mirrors `IpcFd` on Unix.
+///
+/// An `IpcHandle` owns its handle and closes it when dropped. Sending one sends a duplicate made
+/// with `DuplicateHandle()`, so the handle stays open on this side too. Windows channels are
+/// in-process for now, so the duplicate is made in this process, and travels with the message
+/// the way channels do; it's closed if the message is never received. Transports that carry only
+/// bytes refuse messages with handles in them, as they do those with channels.
+#[cfg(windows)]
+#[derive(Debug, PartialEq)]
+pub struct IpcHandle {
+    handle: RawHandle,
+}
+
+// Handles are valid on every thread of the process that owns them.
+#[cfg(windows)]
+unsafe impl Send for IpcHandle {}
+
+#[cfg(windows)]
+impl Drop for IpcHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl IpcHandle {
+    /// Takes ownership of the handle of `object`, e.g. a `File`.
+    pub fn new<H>(object: H) -> IpcHandle where H: IntoRawHandle {
+        IpcHandle {
+            handle: object.into_raw_handle(),
+        }
+    }
+
+    /// Returns a new `IpcHandle` that refers to the same object.
+    pub fn try_clone(&self) -> Result<IpcHandle,Error> {
+        let mut handle = ptr::null_mut();
+        let result = unsafe {
+            let process = GetCurrentProcess();
+            DuplicateHandle(process,
+                            self.handle,
+                            process,
+                            &mut handle,
+                            0,
+                            0,
+                            DUPLICATE_SAME_ACCESS)
+        };
+        if result == 0 {
+            return Err(Error::from(io::Error::last_os_error()))
+        }
+        Ok(IpcHandle {
+            handle: handle,
+        })
+    }
+}
+
+#[cfg(windows)]
+impl AsRawHandle for IpcHandle {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle
+    }
+}
+
+#[cfg(windows)]
+impl FromRawHandle for IpcHandle {
+    unsafe fn from_raw_handle(handle: RawHandle) -> IpcHandle {
+        IpcHandle {
+            handle: handle,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl IntoRawHandle for IpcHandle {
+    fn into_raw_handle(self) -> RawHandle {
+        let handle = self.handle;
+        mem::forget(self);
+        handle
+    }
+}
+
+#[cfg(windows)]
+impl Deserialize for IpcHandle {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let handle = try!(convert_os_ipc_channel::<D,_,_>(index, |os_channel| {
+            os_channel.to_handle()
+        }));
+        Ok(IpcHandle {
+            handle: handle,
+        })
+    }
+}
+
+#[cfg(windows)]
+impl Serialize for IpcHandle {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let handle = try!(self.try_clone().map_err(|err| S::Error::custom(err.to_string())));
+        let os_ipc_channel = OsIpcChannel::from_handle(handle.into_raw_handle());
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            os_ipc_channels_for_serialization.push(os_ipc_channel);
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+#[cfg(windows)]
+const DUPLICATE_SAME_ACCESS: u32 = 0x00000002;
+
+#[cfg(windows)]
+extern "system" {
+    fn CloseHandle(handle: RawHandle) -> i32;
+    fn DuplicateHandle(source_process: RawHandle,
+                       source_handle: RawHandle,
+                       target_process: RawHandle,
+                       target_handle: *mut RawHandle,
+                       desired_access: u32,
+                       inherit_handle: i32,
+                       options: u32)
+                       -> i32;
+    fn GetCurrentProcess() -> RawHandle;
+}
+
+/// A right to a Mach port that can be embedded in a message, for handing ports obtained from
+/// other Mach-based APIs (IOSurface, XPC and so on) to the receiver.
+///
+/// An `IpcMachPort` owns one user reference to its right and releases it when dropped. Sending a
+/// send right sends a new reference, so the right stays usable on this side too, while sending a
+/// receive right moves it. The receiver gets a name of its own for the right.
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+#[derive(Debug, PartialEq)]
+pub struct IpcMachPort {
+    os_channel: NativeIpcChannel,
+}
+
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+impl IpcMachPort {
+    /// Takes ownership of one user reference to the given right to the port named `name`.
+    pub unsafe fn new(name: u32, right: MachPortRight) -> IpcMachPort {
+        IpcMachPort {
+            os_channel: NativeIpcChannel::from_port(name, right),
+        }
+    }
+
+    /// The name of the port in this task.
+    pub fn name(&self) -> u32 {
+        self.os_channel.port()
+    }
+
+    pub fn right(&self) -> MachPortRight {
+        self.os_channel.right()
+    }
+
+    /// Gives up ownership of the right, leaving the caller to release it.
+    pub fn into_name(self) -> u32 {
+        self.os_channel.into_port()
+    }
+}
+
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+impl Deserialize for IpcMachPort {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let os_channel = try!(convert_os_ipc_channel::<D,_,_>(index, |os_channel| {
+            os_channel.to_channel().and_then(OsIpcChannel::into_native)
+        }));
+        Ok(IpcMachPort {
+            os_channel: os_channel,
+        })
+    }
+}
+
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+impl Serialize for IpcMachPort {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let os_channel = match self.os_channel {
+            NativeIpcChannel::Sender(ref sender) => NativeIpcChannel::Sender(sender.clone()),
+            NativeIpcChannel::Receiver(ref receiver) => {
+                NativeIpcChannel::Receiver(receiver.consume())
+            }
+        };
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            os_ipc_channels_for_serialization.push(OsIpcChannel::native(os_channel));
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+pub enum IpcSelectionResult {
+    MessageReceived(i64, OpaqueIpcMessage),
+    ChannelClosed(i64),
+    /// A message arrived on the receiver but couldn't be received, e.g. because it was bigger
+    /// than `IpcReceiver::set_max_message_size()` allows. The other messages in the same
+    /// `select()` are unaffected. If the receiver itself broke, a `ChannelClosed` for it follows,
+    /// and it's removed from the set.
+    ReceiveFailed(i64, Error),
+}
+
+impl IpcSelectionResult {
+    pub fn unwrap(self) -> (i64, OpaqueIpcMessage) {
+        match self {
+            IpcSelectionResult::MessageReceived(id, message) => (id, message),
+            IpcSelectionResult::ChannelClosed(id) => {
+                panic!("IpcSelectionResult::unwrap(): channel {} closed", id)
+            }
+            IpcSelectionResult::ReceiveFailed(id, error) => {
+                panic!("IpcSelectionResult::unwrap(): channel {} failed: {}", id, error)
+            }
+        }
+    }
+}
+
+pub struct OpaqueIpcMessage {
+    data: Vec<u8>,
+    os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+    os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>,
+}
+
+impl Debug for OpaqueIpcMessage {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match String::from_utf8(self.data.clone()) {
+            Ok(string) => string.chars().take(256).collect::<String>().fmt(formatter),
+            Err(..) => self.data[0..min(self.data.len(), 256)].fmt(formatter),
+        }
+    }
+}
+
+impl OpaqueIpcMessage {
+    fn new(data: Vec<u8>,
+           os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+           os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
+           -> OpaqueIpcMessage {
+        OpaqueIpcMessage {
+            data: data,
+            os_ipc_channels: os_ipc_channels,
+            os_ipc_shared_memory_regions: os_ipc_shared_memory_regions,
+        }
+    }
+
+    /// Returns the serialized payload of this message. A big message is moved into a shared memory
+    /// region on the way, which is still the last of `os_ipc_shared_memory_regions()`; the
+    /// payload is read from there.
+    pub fn data(&self) -> &[u8] {
+        match self.os_ipc_shared_memory_regions.last() {
+            Some(promoted_data) if self.data.is_empty() => &**promoted_data,
+            _ => &self.data,
+        }
+    }
+
+    /// Whether channels or shared memory regions came along with the payload, not counting a
+    /// region the payload itself was moved into.
+    pub fn carries_handles(&self) -> bool {
+        let promoted = if self.data.is_empty() { 1 } else { 0 };
+        !self.os_ipc_channels.is_empty() || self.os_ipc_shared_memory_regions.len() > promoted
+    }
+
+    /// Returns the OS channels that were transferred along with this message.
+    pub fn os_ipc_channels(&self) -> &[OsOpaqueIpcChannel] {
+        &self.os_ipc_channels
+    }
+
+    /// Returns the shared memory regions that were transferred along with this message.
+    pub fn os_ipc_shared_memory_regions(&self) -> &[OsIpcSharedMemory] {
+        &self.os_ipc_shared_memory_regions
+    }
+
+    /// Consumes this message, returning its serialized payload along with the OS channels and
+    /// shared memory regions that were transferred with it. Unlike `data()`, this leaves a payload
+    /// that was moved into shared memory where it is, with the data empty.
+    pub fn into_raw_parts(self)
+                          -> (Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>) {
+        (self.data, self.os_ipc_channels, self.os_ipc_shared_memory_regions)
+    }
+
+    pub fn to<T>(self) -> Result<T,Error> where T: Deserialize + Serialize {
+        self.try_to().map_err(|(err, _)| err)
+    }
+
+    /// Like `to()`, but gives the message back if it doesn't deserialize, so that it can be
+    /// looked at or passed on. Channels that were taken out of it before deserialization failed
+    /// are gone, and show up as consumed.
+    pub fn try_to<T>(mut self) -> Result<T,(Error, OpaqueIpcMessage)>
+                     where T: Deserialize + Serialize {
+        // See `serialize_message()`.
+        let promoted_data = if self.data.is_empty() {
+            self.os_ipc_shared_memory_regions.pop()
+        } else {
+            None
+        };
+        let mut os_ipc_shared_memory_regions: Vec<_> =
+            mem::replace(&mut self.os_ipc_shared_memory_regions, vec![])
+                .into_iter()
+                .map(Arc::new)
+                .collect();
+        let result = {
+            let data = match promoted_data {
+                Some(ref promoted_data) => &**promoted_data,
+                None => &*self.data,
+            };
+            deserialize_message(data,
+                                &mut self.os_ipc_channels,
+                                &mut os_ipc_shared_memory_regions)
+        };
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                // Nothing holds on to the regions once deserialization is over.
+                self.os_ipc_shared_memory_regions =
+                    os_ipc_shared_memory_regions.into_iter().map(|region| {
+                        Arc::try_unwrap(region).unwrap_or_else(|region| (*region).clone())
+                    }).collect();
+                self.os_ipc_shared_memory_regions.extend(promoted_data);
+                Err((Error::from(err), self))
+            }
+        }
+    }
+
+    /// Like `to()`, but leaves the message alone, so that it can still be forwarded with
+    /// `OpaqueIpcSender::send_opaque()` afterwards. This suits routers that look at a message to
+    /// decide where it goes.
+    ///
+    /// Channels can't be taken out of a message that stays intact, so this fails for messages
+    /// that carry any.
+    pub fn to_ref<T>(&self) -> Result<T,Error> where T: Deserialize + Serialize {
+        if !self.os_ipc_channels.is_empty() {
+            return Err(Error::Io(io::Error::new(ErrorKind::InvalidInput,
+                                                "message carries channels")))
+        }
+        let (data, os_ipc_shared_memory_regions) = match self.os_ipc_shared_memory_regions
+                                                             .split_last() {
+            Some((promoted_data, os_ipc_shared_memory_regions)) if self.data.is_empty() => {
+                (&**promoted_data, os_ipc_shared_memory_regions)
+            }
+            _ => (&*self.data, &self.os_ipc_shared_memory_regions[..]),
+        };
+        let mut os_ipc_shared_memory_regions: Vec<_> =
+            os_ipc_shared_memory_regions.iter().cloned().map(Arc::new).collect();
+        deserialize_message(data, &mut vec![], &mut os_ipc_shared_memory_regions)
+            .map_err(Error::from)
+    }
+}
+
+/// Deserializes a message straight from `data`, handing out `os_ipc_channels` and
+/// `os_ipc_shared_memory_regions` to the channels and regions embedded in it. Channels that are
+/// taken are left behind in `os_ipc_channels` as consumed.
+fn deserialize_message<T>(mut data: &[u8],
+                          os_ipc_channels: &mut Vec<OsOpaqueIpcChannel>,
+                          os_ipc_shared_memory_regions: &mut Vec<Arc<OsIpcSharedMemory>>)
+                          -> Result<T,DeserializeError> where T: Deserialize {
+    OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+        OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
+                |os_ipc_shared_memory_regions_for_deserialization| {
+            if os_ipc_channels_for_deserialization.borrow_state() != BorrowState::Unused {
+                return Err(DeserializeError::IoError(recursive_io_error()));
+            }
+
+            if os_ipc_shared_memory_regions_for_deserialization.borrow_state() !=
+                    BorrowState::Unused {
+                return Err(DeserializeError::IoError(recursive_io_error()));
+            }
+
+            mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(), os_ipc_channels);
+            mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
+                      os_ipc_shared_memory_regions);
+            let result = {
+                let mut deserializer = bincode::serde::Deserializer::new(&mut data,
+                                                                         SizeLimit::Infinite);
+                Deserialize::deserialize(&mut deserializer)
+            };
+            // Put things back even if deserialization failed, so that they don't turn up in the
+            // next message.
+            mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
+                      os_ipc_shared_memory_regions);
+            mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(), os_ipc_channels);
+            result
+        })
+    })
+}
+
+#[derive(Clone)]
+pub struct OpaqueIpcSender {
+    os_sender: OsIpcSender,
+    peer_pid: PeerPid,
+}
+
+impl Debug for OpaqueIpcSender {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        formatter.debug_struct("OpaqueIpcSender")
+                 .field("id", &self.os_sender.endpoint_id())
+                 .field("connected", &sender_is_connected(&self.os_sender))
+                 .finish()
+    }
+}
+
+/// Whether `os_sender`'s receiver is still there, or `None` if the backend can't tell.
+fn sender_is_connected(os_sender: &OsIpcSender) -> Option<bool> {
+    os_sender.receiver_is_closed().ok().map(|closed| !closed)
+}
+
+impl OpaqueIpcSender {
+    pub fn to<T>(self) -> IpcSender<T> where T: Deserialize + Serialize {
+        IpcSender::from_os(self.os_sender)
+    }
+
+    /// Sends a previously received message on this channel verbatim, without deserializing and
+    /// reserializing it. Any channels and shared memory regions embedded in the message are
+    /// transferred along with it.
+    pub fn send_opaque(&self, message: OpaqueIpcMessage) -> Result<(),Error> {
+        let (data, mut os_ipc_channels, os_ipc_shared_memory_regions) = message.into_raw_parts();
+        let mut os_channels = Vec::with_capacity(os_ipc_channels.len());
+        for os_ipc_channel in os_ipc_channels.iter_mut() {
+            os_channels.push(try!(os_ipc_channel.to_channel()));
+        }
+        let handles = os_channels.len() + os_ipc_shared_memory_regions.len();
+        capture_message(self.os_sender.endpoint_id(),
+                        MessageDirection::Sent,
+                        &[&data[..]],
+                        os_channels.len(),
+                        &os_ipc_shared_memory_regions);
+        try!(self.os_sender.send(&data[..], os_channels, os_ipc_shared_memory_regions));
+        audit_sent(&self.os_sender, &self.peer_pid, data.len(), handles);
+        Ok(())
+    }
+}
+
+impl Deserialize for OpaqueIpcSender {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let os_sender = try!(deserialize_os_ipc_sender(deserializer));
+        Ok(OpaqueIpcSender {
+            os_sender: os_sender,
+            peer_pid: PeerPid::new(),
+        })
+    }
+}
+
+impl Serialize for OpaqueIpcSender {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        serialize_os_ipc_sender(&self.os_sender, serializer)
+    }
+}
+
+pub struct OpaqueIpcReceiver {
+    os_receiver: OsIpcReceiver,
+}
+
+impl Debug for OpaqueIpcReceiver {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        formatter.debug_struct("OpaqueIpcReceiver")
+                 .field("id", &self.os_receiver.endpoint_id())
+                 .finish()
+    }
+}
+
+pub struct IpcOneShotServer<T> {
+    os_server: OsIpcOneShotServer,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcOneShotServer<T> where T: Deserialize + Serialize {
+    pub fn new() -> Result<(IpcOneShotServer<T>, String),Error> {
+        let (os_server, name) = try!(OsIpcOneShotServer::new());
+        Ok((IpcOneShotServer {
+            os_server: os_server,
+            phantom: PhantomData,
+        }, name))
+    }
+
+    /// Creates a server under a name of the caller's choosing, such as `"org.myapp.gpu"`, so
+    /// that clients can connect to it without having to be told the name first.
+    ///
+    /// Fails if another server is already using the name.
+    pub fn with_name(name: &str) -> Result<IpcOneShotServer<T>,Error> {
+        Ok(IpcOneShotServer {
+            os_server: try!(OsIpcOneShotServer::with_name(name)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Like `with_name()`, but only lets clients connect if `mode`, a set of Unix file
+    /// permission bits such as `0o600`, allows them to. Clients can't connect before the
+    /// permissions are in place.
+    ///
+    /// This only has an effect on Linux, where the server is a socket on the filesystem.
+    /// Elsewhere the mode is ignored: Mach bootstrap names are only visible within the user's
+    /// login session, and in-process servers can't be reached from other processes anyway.
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<IpcOneShotServer<T>,Error> {
+        Ok(IpcOneShotServer {
+            os_server: try!(OsIpcOneShotServer::with_name_and_mode(name, mode)),
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn accept(self) -> Result<(IpcReceiver<T>,T),Error> {
+        let (os_receiver, data, os_channels, os_shared_memory_regions) =
+            try!(self.os_server.accept());
+        let value = try!(OpaqueIpcMessage::new(data, os_channels, os_shared_memory_regions).to());
+        Ok((IpcReceiver::from_os(os_receiver), value))
+    }
+
+    /// Like `accept()`, but gives up if no client connects within `timeout`. In that case this
+    /// fails with `TimedOut`, and the server is gone, so the client can no longer connect.
+    ///
+    /// This lets a parent process find out that a child it spawned failed to start up.
+    pub fn accept_timeout(self, timeout: Duration)
+                          -> Result<(IpcReceiver<T>,T),Error> {
+        let (os_receiver, data, os_channels, os_shared_memory_regions) =
+            try!(self.os_server.accept_timeout(timeout));
+        let value = try!(OpaqueIpcMessage::new(data, os_channels, os_shared_memory_regions).to());
+        Ok((IpcReceiver::from_os(os_receiver), value))
+    }
+
+    /// Like `accept()`, but also returns the credentials of the client process, so that the
+    /// server can decide whether to trust it.
+    ///
+    /// This is only supported on Linux; elsewhere it fails.
+    pub fn accept_with_credentials(self)
+                                   -> Result<(IpcReceiver<T>,T,PeerCredentials),Error> {
+        let (receiver, value) = try!(self.accept());
+        let credentials = try!(peer_credentials(&receiver.os_receiver));
+        Ok((receiver, value, credentials))
+    }
+
+    /// Accepts a client that connected with `IpcSender::connect_with_handshake()`, returning its
+    /// channel along with the handshake payload it sent.
+    ///
+    /// `validate` decides whether to let the client in: it returns `Err` with a reason, which is
+    /// passed on to the client, in order to reject it. In that case this fails with a
+    /// `ConnectionRefused` error as well.
+    pub fn accept_with_handshake<H,F>(self, validate: F)
+                                      -> Result<(IpcReceiver<T>,H),Error>
+                                      where H: Deserialize + Serialize,
+                                            F: FnOnce(&H) -> Result<(),String> {
+        let (receiver, handshake, _) = try!(self.accept_with_session(validate));
+        Ok((receiver, handshake))
+    }
+
+    /// Accepts a client that connected with `IpcSender::connect_with_token()`, provided that it
+    /// presented `token`. Otherwise the client is turned away, and this fails with a
+    /// `ConnectionRefused` error. The session ID is the one the client got as well.
+    pub fn accept_with_token(self, token: &str)
+                             -> Result<(IpcReceiver<T>, SessionId),Error> {
+        let (receiver, _, session) = try!(self.accept_with_session(|presented_token: &String| {
+            check_token(presented_token, token)
+        }));
+        Ok((receiver, session))
+    }
+
+    fn accept_with_session<H,F>(self, validate: F)
+                                -> Result<(IpcReceiver<T>,H,SessionId),Error>
+                                where H: Deserialize + Serialize,
+                                      F: FnOnce(&H) -> Result<(),String> {
+        let (os_receiver, data, os_channels, os_shared_memory_regions) =
+            try!(self.os_server.accept());
+        let message = OpaqueIpcMessage::new(data, os_channels, os_shared_memory_regions);
+        let handshake = try!(message.to());
+        match try!(accept_handshake(os_receiver, handshake, validate)) {
+            Some(result) => Ok(result),
+            None => {
+                Err(Error::Io(io::Error::new(ErrorKind::ConnectionRefused,
+                                             "client failed the handshake")))
+            }
+        }
+    }
+}
+
+fn check_token(presented_token: &str, token: &str) -> Result<(),String> {
+    if !fixed_time_eq(presented_token.as_bytes(), token.as_bytes()) {
+        return Err("invalid authentication token".to_owned())
+    }
+    Ok(())
+}
+
+/// The size of the HMAC-SHA256 tags that `MessageAuthenticator` puts on messages.
+const TAG_SIZE: usize = 32;
+
+/// The size of the tag and payload length that `MessageAuthenticator` puts in front of each
+/// message. The length is needed because some transports pad messages.
+const AUTHENTICATION_HEADER_SIZE: usize = TAG_SIZE + 8;
+
+/// The size of the random values that each end contributes to a `SessionId`.
+const NONCE_SIZE: usize = 16;
+
+/// Identifies a session set up by `IpcSender::connect_with_token()` and `accept_with_token()`. It
+/// is made of random values that the client and the server each contributed to the handshake, so
+/// that no two sessions authenticate their messages with the same keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionId {
+    nonces: Vec<u8>,
+}
+
+impl SessionId {
+    fn new(mut client_nonce: Vec<u8>, server_nonce: &[u8]) -> SessionId {
+        client_nonce.extend_from_slice(server_nonce);
+        SessionId {
+            nonces: client_nonce,
+        }
+    }
+}
+
+fn session_nonce() -> Result<Vec<u8>,io::Error> {
+    let mut nonce = vec![0; NONCE_SIZE];
+    try!(OsRng::new()).fill_bytes(&mut nonce);
+    Ok(nonce)
+}
+
+/// Which end of a session we are; see `MessageAuthenticator::for_session()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionRole {
+    Client,
+    Server,
+}
+
+/// Keeps track of the key and message count for authenticating the messages sent over a channel in
+/// one direction; see `IpcSender::send_authenticated()`. The sender and the receiver each need an
+/// authenticator of their own, made from the same key.
+///
+/// Each direction needs a key of its own, as `for_session()` derives them: since both count their
+/// messages from 0, a message that was sent one way would otherwise check out when sent back.
+///
+/// This guards against tampering and cross-talk where channels go beyond a single machine; local
+/// channels are already protected by the kernel.
+///
+/// Once a received message fails to check out, the receiving authenticator can't tell whether it
+/// took a place in the sender's count (a message that was tampered with) or not (one that was
+/// slipped in), so it doesn't know which position the next message should be at. Rather than
+/// guess, every message it checks after that fails with an `InvalidData` error wrapping an
+/// `OutOfStep`, and the session has to be set up again.
+pub struct MessageAuthenticator {
+    key: Vec<u8>,
+    sequence_number: Cell<u64>,
+    out_of_step: Cell<bool>,
+}
+
+impl MessageAuthenticator {
+    pub fn new(key: &[u8]) -> MessageAuthenticator {
+        MessageAuthenticator {
+            key: key.to_vec(),
+            sequence_number: Cell::new(0),
+            out_of_step: Cell::new(false),
+        }
+    }
+
+    /// Derives the keys for both directions of a session from the token the client presented
+    /// and the session's ID, with HKDF-SHA256. Returns the authenticator for the messages we
+    /// send, and the one for the messages we receive, which are the other way round on the other
+    /// end.
+    pub fn for_session(token: &str, session: &SessionId, role: SessionRole)
+                       -> (MessageAuthenticator, MessageAuthenticator) {
+        let mut pseudorandom_key = [0; TAG_SIZE];
+        hkdf_extract(Sha256::new(), &session.nonces, token.as_bytes(), &mut pseudorandom_key);
+        let derive = |info: &[u8]| {
+            let mut key = [0; TAG_SIZE];
+            hkdf_expand(Sha256::new(), &pseudorandom_key, info, &mut key);
+            MessageAuthenticator::new(&key)
+        };
+        let client_to_server = derive(b"ipc-channel client to server");
+        let server_to_client = derive(b"ipc-channel server to client");
+        match role {
+            SessionRole::Client => (client_to_server, server_to_client),
+            SessionRole::Server => (server_to_client, client_to_server),
+        }
+    }
+
+    /// Computes the tag of a message, which covers its position in the stream, the number of
+    /// channels and shared memory regions that came with it, and its payload.
+    fn tag(&self, payload: &[u8], channels: usize, shared_memory_regions: usize) -> MacResult {
+        let mut header = [0; 24];
+        LittleEndian::write_u64(&mut header[0..8], self.sequence_number.get());
+        LittleEndian::write_u64(&mut header[8..16], channels as u64);
+        LittleEndian::write_u64(&mut header[16..24], shared_memory_regions as u64);
+        let mut hmac = Hmac::new(Sha256::new(), &self.key);
+        hmac.input(&header);
+        hmac.input(payload);
+        hmac.result()
+    }
+
+    /// Fills in the header reserved at the start of `bytes`.
+    fn seal(&self, bytes: &mut Vec<u8>, channels: usize, shared_memory_regions: usize) {
+        let tag = self.tag(&bytes[AUTHENTICATION_HEADER_SIZE..], channels, shared_memory_regions);
+        let payload_length = (bytes.len() - AUTHENTICATION_HEADER_SIZE) as u64;
+        for (byte, tag_byte) in bytes.iter_mut().zip(tag.code().iter()) {
+            *byte = *tag_byte;
+        }
+        LittleEndian::write_u64(&mut bytes[TAG_SIZE..AUTHENTICATION_HEADER_SIZE], payload_length);
+        self.sequence_number.set(self.sequence_number.get() + 1);
+    }
+
+    /// Checks the header of a received message, returning the payload. If it doesn't check out,
+    /// this and every later call fail; see `MessageAuthenticator`.
+    fn open(&self, data: Vec<u8>, channels: usize, shared_memory_regions: usize)
+            -> Result<Vec<u8>,DeserializeError> {
+        if self.out_of_step.get() {
+            return Err(DeserializeError::IoError(io::Error::new(ErrorKind::InvalidData,
+                                                                OutOfStep)))
+        }
+        let payload = match self.check(&data, channels, shared_memory_regions) {
+            Some(payload) => payload,
+            None => {
+                self.out_of_step.set(true);
+                let error = io::Error::new(ErrorKind::InvalidData, "message failed authentication");
+                return Err(DeserializeError::IoError(error))
+            }
+        };
+        self.sequence_number.set(self.sequence_number.get() + 1);
+        Ok(payload.to_vec())
+    }
+
+    fn check<'a>(&self, data: &'a [u8], channels: usize, shared_memory_regions: usize)
+                 -> Option<&'a [u8]> {
+        if data.len() < AUTHENTICATION_HEADER_SIZE {
+            return None
+        }
+        let payload_length =
+            LittleEndian::read_u64(&data[TAG_SIZE..AUTHENTICATION_HEADER_SIZE]) as usize;
+        if payload_length > data.len() - AUTHENTICATION_HEADER_SIZE {
+            return None
+        }
+        let payload = &data[AUTHENTICATION_HEADER_SIZE..][..payload_length];
+        let tag = self.tag(payload, channels, shared_memory_regions);
+        if !fixed_time_eq(tag.code(), &data[..TAG_SIZE]) {
+            return None
+        }
+        Some(payload)
+    }
+}
+
+/// A `MessageAuthenticator` has already had a message fail to check out, so it no longer knows
+/// where in the stream it is.
+#[derive(Debug)]
+pub struct OutOfStep;
+
+impl Display for OutOfStep {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "an earlier message failed authentication, so this one can't be checked")
+    }
+}
+
+impl error::Error for OutOfStep {
+    fn description(&self) -> &str {
+        "an earlier message failed authentication, so this one can't be checked"
+    }
+}
+
+/// Identifies the process on the other end of a connection, as vouched for by the kernel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// The SELinux or AppArmor label of the process, if a security module that labels sockets is
+    /// active.
+    pub security_label: Option<String>,
+}
+
+fn peer_credentials(os_receiver: &OsIpcReceiver) -> Result<PeerCredentials,io::Error> {
+    let (pid, uid, gid) = try!(os_receiver.peer_credentials());
+    Ok(PeerCredentials {
+        pid: pid,
+        uid: uid,
+        gid: gid,
+        security_label: try!(os_receiver.peer_security_label()),
+    })
+}
+
+/// Which way a message went, from the point of view of this process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageDirection {
+    Sent,
+    Received,
+}
+
+/// What the audit hook is told about each message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MessageMetadata {
+    /// Identifies our end of the channel among the live channels of this process, where the
+    /// platform can: on Linux and macOS, this is the same ID that `IpcReceiverSet` reports for a
+    /// receiver.
+    pub channel_id: Option<i64>,
+    pub direction: MessageDirection,
+    /// The size of the serialized message, in bytes.
+    pub size: usize,
+    /// How many channels and shared memory regions came along with the message.
+    pub handles: usize,
+    /// The process on the other end, where the platform can tell; see `PeerCredentials`.
+    pub peer_pid: Option<u32>,
+}
+
+/// Installs `hook`, replacing any previous one, to be called with the metadata of every message
+/// this process sends or receives, so that brokers can audit their traffic in one place. The hook
+/// runs on the thread sending or receiving the message, and should be quick. Messages that the
+/// hook itself sends or receives aren't audited. The hook may replace or clear itself; calls
+/// already under way finish with the old hook.
+pub fn set_audit_hook<F>(hook: F) where F: Fn(&MessageMetadata) + Send + Sync + 'static {
+    *AUDIT_HOOK.write().unwrap() = Some(Arc::new(hook));
+    AUDITING.store(true, Ordering::SeqCst);
+}
+
+/// Removes the hook installed with `set_audit_hook()`, if any.
+pub fn clear_audit_hook() {
+    AUDITING.store(false, Ordering::SeqCst);
+    *AUDIT_HOOK.write().unwrap() = None;
+}
+
+fn audit<F>(metadata: F) where F: FnOnce() -> MessageMetadata {
+    if !AUDITING.load(Ordering::Relaxed) ||
+            IN_AUDIT_HOOK.with(|in_audit_hook| in_audit_hook.get()) {
+        return
+    }
+    // Don't hold the lock while the hook runs, in case it replaces itself.
+    let hook = match *AUDIT_HOOK.read().unwrap() {
+        Some(ref hook) => hook.clone(),
+        None => return,
+    };
+    let metadata = metadata();
+    let _in_audit_hook = InAuditHook::enter();
+    hook(&metadata);
+}
+
+/// Marks this thread as running the audit hook until dropped, even if the hook panics.
+struct InAuditHook;
+
+impl InAuditHook {
+    fn enter() -> InAuditHook {
+        IN_AUDIT_HOOK.with(|in_audit_hook| in_audit_hook.set(true));
+        InAuditHook
+    }
+}
+
+impl Drop for InAuditHook {
+    fn drop(&mut self) {
+        IN_AUDIT_HOOK.with(|in_audit_hook| in_audit_hook.set(false));
+    }
+}
+
+/// The pid of the process on the other end of a connection, looked up the first time the audit
+/// hook needs it rather than for every message. Clones share it.
+#[derive(Clone, Debug)]
+struct PeerPid(Arc<Mutex<Option<Option<u32>>>>);
+
+impl PeerPid {
+    fn new() -> PeerPid {
+        PeerPid(Arc::new(Mutex::new(None)))
+    }
+
+    fn get<F>(&self, look_up: F) -> Option<u32> where F: FnOnce() -> Option<u32> {
+        let mut peer_pid = self.0.lock().unwrap();
+        if peer_pid.is_none() {
+            *peer_pid = Some(look_up())
+        }
+        peer_pid.unwrap()
+    }
+}
+
+fn audit_sent(os_sender: &OsIpcSender, peer_pid: &PeerPid, size: usize, handles: usize) {
+    audit(|| {
+        MessageMetadata {
+            channel_id: os_sender.endpoint_id(),
+            direction: MessageDirection::Sent,
+            size: size,
+            handles: handles,
+            peer_pid: peer_pid.get(|| {
+                os_sender.peer_credentials().ok().map(|(pid, _, _)| pid)
+            }),
+        }
+    })
+}
+
+fn audit_received(os_receiver: &OsIpcReceiver, peer_pid: &PeerPid, data: &[u8], handles: usize) {
+    audit(|| {
+        MessageMetadata {
+            channel_id: os_receiver.endpoint_id(),
+            direction: MessageDirection::Received,
+            size: data.len(),
+            handles: handles,
+            peer_pid: peer_pid.get(|| {
+                os_receiver.peer_credentials().ok().map(|(pid, _, _)| pid)
+            }),
+        }
+    })
+}
+
+/// Starts writing every message this process sends or receives to a new file at `path`, replacing
+/// any capture already running, so that a protocol bug reported from the field can be reproduced
+/// offline: read the capture back with `CaptureReader`, and feed it to receivers with
+/// `replay_capture()`. Sends are captured as they are attempted, whether or not they succeed.
+/// Capturing copies every message, so it's meant for tracking a problem down, not to be left on.
+pub fn start_capture<P>(path: P) -> Result<(),Error> where P: AsRef<Path> {
+    capture::start(path).map_err(Error::from)
+}
+
+/// Stops the capture started with `start_capture()`, if any, and flushes it.
+pub fn stop_capture() -> Result<(),Error> {
+    capture::stop().map_err(Error::from)
+}
+
+/// Sends each of `frames` on `sender` in turn, e.g. those a `CaptureReader` read and that were
+/// received on one channel, so that the receiver sees the same messages it saw when they were
+/// captured. Fails with `InvalidInput`, before sending it, on a frame that came with channels,
+/// since those can't be captured.
+pub fn replay_capture<I>(frames: I, sender: &OpaqueIpcSender) -> Result<(),Error>
+                         where I: IntoIterator<Item=CapturedFrame> {
+    for frame in frames {
+        if frame.channels > 0 {
+            return Err(Error::Io(io::Error::new(ErrorKind::InvalidInput,
+                                                "captured message came with channels")))
+        }
+        try!(sender.send_opaque(OpaqueIpcMessage::from(frame)));
+    }
+    Ok(())
+}
+
+impl From<CapturedFrame> for OpaqueIpcMessage {
+    /// Rebuilds a captured message, without the channels that came with it, if any.
+    fn from(frame: CapturedFrame) -> OpaqueIpcMessage {
+        let os_ipc_shared_memory_regions = frame.shared_memory_regions.iter().map(|region| {
+            OsIpcSharedMemory::from_bytes(region)
+        }).collect();
+        OpaqueIpcMessage::new(frame.data, vec![], os_ipc_shared_memory_regions)
+    }
+}
+
+fn capture_message(channel_id: Option<i64>,
+                   direction: MessageDirection,
+                   data: &[&[u8]],
+                   channels: usize,
+                   os_ipc_shared_memory_regions: &[OsIpcSharedMemory]) {
+    capture::record(|timestamp| {
+        CapturedFrame {
+            timestamp: timestamp,
+            channel_id: channel_id,
+            direction: direction,
+            data: data.concat(),
+            shared_memory_regions: os_ipc_shared_memory_regions.iter().map(|region| {
+                region.to_vec()
+            }).collect(),
+            channels: channels,
+        }
+    })
+}
+
+/// The first message sent by `IpcSender::connect_with_handshake()`.
+#[derive(Serialize, Deserialize)]
+struct Handshake<H> {
+    payload: H,
+    /// The client's half of the `SessionId`.
+    nonce: Vec<u8>,
+    reply: IpcSender<HandshakeReply>,
+}
+
+/// The server's answer to a `Handshake`.
+#[derive(Serialize, Deserialize)]
+struct HandshakeReply {
+    /// `None` if the server accepts the handshake, or the reason why it doesn't.
+    rejection: Option<String>,
+    /// The server's half of the `SessionId`.
+    nonce: Vec<u8>,
+}
+
+/// Checks the handshake that a client sent as its first message, and lets the client know
+/// whether it got in. Returns `None` if the client was rejected. Errors are our own, not the
+/// client's, so they aren't confined to this one connection.
+fn accept_handshake<T,H,F>(os_receiver: OsIpcReceiver, handshake: Handshake<H>, validate: F)
+                           -> Result<Option<(IpcReceiver<T>,H,SessionId)>,Error>
+                           where T: Deserialize + Serialize,
+                                 H: Deserialize + Serialize,
+                                 F: FnOnce(&H) -> Result<(),String> {
+    let nonce = try!(session_nonce());
+    let verdict = validate(&handshake.payload);
+    let accepted = verdict.is_ok();
+    // The client may have given up on us already, which is no reason to fail here.
+    drop(handshake.reply.send(HandshakeReply {
+        rejection: verdict.err(),
+        nonce: nonce.clone(),
+    }));
+    if !accepted {
+        return Ok(None)
+    }
+    Ok(Some((IpcReceiver::from_os(os_receiver),
+             handshake.payload,
+             SessionId::new(handshake.nonce, &nonce))))
+}
+
+/// Sets up a pair of channels, one in each direction, with a process that connects with
+/// `ipc::channel_pair_for()`. This takes a single rendezvous, whereas using an `IpcOneShotServer`
+/// in each process would take two.
+///
+/// The type parameters are from each side's own point of view: `S` is what this side sends and
+/// `R` what it receives. So they swap places on the other side, and an
+/// `IpcChannelPairServer<S,R>` pairs up with `channel_pair_for::<R,S>()`.
+pub struct IpcChannelPairServer<S,R> where S: Serialize {
+    server: IpcOneShotServer<IpcSender<S>>,
+    phantom: PhantomData<R>,
+}
+
+impl<S,R> IpcChannelPairServer<S,R> where S: Deserialize + Serialize,
+                                          R: Deserialize + Serialize {
+    pub fn new() -> Result<(IpcChannelPairServer<S,R>, String),Error> {
+        let (server, name) = try!(IpcOneShotServer::new());
+        Ok((IpcChannelPairServer {
+            server: server,
+            phantom: PhantomData,
+        }, name))
+    }
+
+    /// Waits for the other process to connect, returning our ends of the two channels.
+    pub fn accept(self) -> Result<(IpcSender<S>, IpcReceiver<R>),Error> {
+        let (receiver, sender) = try!(self.server.accept());
+        Ok((sender, IpcReceiver::from_os(receiver.os_receiver)))
+    }
+}
+
+/// Connects to the `IpcChannelPairServer` with the given name, returning our ends of the two
+/// channels: a sender for messages to the server process and a receiver for messages from it.
+///
+/// As with the server, `S` is what we send and `R` what we receive, so for an
+/// `IpcChannelPairServer<A,B>` this is `channel_pair_for::<B,A>()`.
+pub fn channel_pair_for<S,R>(name: String) -> Result<(IpcSender<S>, IpcReceiver<R>),Error>
+                             where S: Serialize, R: Deserialize + Serialize {
+    let (sender, receiver) = try!(channel::<R>());
+    // The server's receiver comes with the connection; our sender is the first message on it.
+    let connection: IpcSender<IpcSender<R>> = try!(IpcSender::connect(name));
+    try!(connection.send(sender));
+    Ok((IpcSender::from_os(connection.os_sender), receiver))
+}
+
+/// A server that, unlike `IpcOneShotServer`, can accept any number of clients under the same
+/// name. Each client connects with `IpcSender::connect()` and gets a channel of its own.
+pub struct IpcServer<T> {
+    os_server: OsIpcServer,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcServer<T> where T: Deserialize + Serialize {
+    pub fn new() -> Result<(IpcServer<T>, String),Error> {
+        let (os_server, name) = try!(OsIpcServer::new());
+        Ok((IpcServer {
+            os_server: os_server,
+            phantom: PhantomData,
+        }, name))
+    }
+
+    /// Creates a server under a name of the caller's choosing. See
+    /// `IpcOneShotServer::with_name()`.
+    pub fn with_name(name: &str) -> Result<IpcServer<T>,Error> {
+        Ok(IpcServer {
+            os_server: try!(OsIpcServer::with_name(name)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Creates a server under a name of the caller's choosing that only the clients allowed by
+    /// `mode` can connect to. See `IpcOneShotServer::with_name_and_mode()`.
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<IpcServer<T>,Error> {
+        Ok(IpcServer {
+            os_server: try!(OsIpcServer::with_name_and_mode(name, mode)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Waits for the next client to connect, returning the receiving end of its channel along
+    /// with the first message it sent.
+    pub fn accept(&self) -> Result<(IpcReceiver<T>,T),Error> {
+        let (os_receiver, data, os_channels, os_shared_memory_regions) =
+            try!(self.os_server.accept());
+        let value = try!(OpaqueIpcMessage::new(data, os_channels, os_shared_memory_regions).to());
+        Ok((IpcReceiver::from_os(os_receiver), value))
+    }
+
+    /// Like `accept()`, but also returns the credentials of the client process. See
+    /// `IpcOneShotServer::accept_with_credentials()`.
+    pub fn accept_with_credentials(&self)
+                                   -> Result<(IpcReceiver<T>,T,PeerCredentials),Error> {
+        let (receiver, value) = try!(self.accept());
+        let credentials = try!(peer_credentials(&receiver.os_receiver));
+        Ok((receiver, value, credentials))
+    }
+
+    /// Waits for the next client that connects with `IpcSender::connect_with_handshake()` and
+    /// passes `validate`. See `IpcOneShotServer::accept_with_handshake()`.
+    ///
+    /// Clients that are rejected, or that don't send a well-formed handshake, are disconnected,
+    /// and we keep waiting for the next one. Failures on our side, such as the listening socket
+    /// breaking or being unable to generate a session nonce, are returned instead.
+    pub fn accept_with_handshake<H,F>(&self, validate: F)
+                                      -> Result<(IpcReceiver<T>,H),Error>
+                                      where H: Deserialize + Serialize,
+                                            F: FnMut(&H) -> Result<(),String> {
+        let (receiver, handshake, _) = try!(self.accept_with_session(validate));
+        Ok((receiver, handshake))
+    }
+
+    /// Waits for the next client that connects with `IpcSender::connect_with_token()` and
+    /// presents `token`. Other clients are turned away. The session ID is the one the client got
+    /// as well.
+    pub fn accept_with_token(&self, token: &str)
+                             -> Result<(IpcReceiver<T>, SessionId),Error> {
+        let (receiver, _, session) = try!(self.accept_with_session(|presented_token: &String| {
+            check_token(presented_token, token)
+        }));
+        Ok((receiver, session))
+    }
+
+    fn accept_with_session<H,F>(&self, mut validate: F)
+                                -> Result<(IpcReceiver<T>,H,SessionId),Error>
+                                where H: Deserialize + Serialize,
+                                      F: FnMut(&H) -> Result<(),String> {
+        loop {
+            let (os_receiver, data, os_channels, os_shared_memory_regions) =
+                match self.os_server.accept() {
+                    Ok(client) => client,
+                    Err(error) => {
+                        // A client that hangs up before saying anything is no reason to stop
+                        // listening, but a broken listening socket is.
+                        let error = Error::from(error);
+                        match *error.unlabeled() {
+                            Error::Disconnected => continue,
+                            _ => return Err(error),
+                        }
+                    }
+                };
+            let message = OpaqueIpcMessage::new(data, os_channels, os_shared_memory_regions);
+            // A client that sends garbage only spoils its own connection.
+            let handshake = match message.to() {
+                Ok(handshake) => handshake,
+                Err(_) => continue,
+            };
+            if let Some(result) = try!(accept_handshake(os_receiver,
+                                                         handshake,
+                                                         |handshake| validate(handshake))) {
+                return Ok(result)
+            }
+        }
+    }
+}
+
+/// Listens for `IpcTcpSender`s, which may be on other machines. Unlike an `IpcServer`, anyone who
+/// can reach the address can connect, so protocols exposed this way should authenticate their
+/// clients, e.g. with `MessageAuthenticator`.
+pub struct IpcTcpServer<T> {
+    os_server: TcpServer,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcTcpServer<T> where T: Deserialize + Serialize {
+    pub fn bind<A>(address: A) -> Result<IpcTcpServer<T>,Error> where A: ToSocketAddrs {
+        Ok(IpcTcpServer {
+            os_server: try!(TcpServer::bind(address)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Returns the address the server is listening on, which tells what port it got when bound
+    /// to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr,Error> {
+        self.os_server.local_addr().map_err(Error::from)
+    }
+
+    /// Waits for the next client to connect, returning the receiving end of its channel.
+    pub fn accept(&self) -> Result<IpcTcpReceiver<T>,Error> {
+        Ok(IpcTcpReceiver {
+            os_receiver: try!(self.os_server.accept()),
+            phantom: PhantomData,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct IpcTcpReceiver<T> where T: Deserialize + Serialize {
+    os_receiver: TcpReceiver,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcTcpReceiver<T> where T: Deserialize + Serialize {
+    /// Expects every message to carry a checksum, as sent by an `IpcTcpSender` with
+    /// `with_checksums()`, and fails to receive those that don't match it with `Corrupted`.
+    pub fn with_checksums(mut self) -> IpcTcpReceiver<T> {
+        self.os_receiver.enable_checksums();
+        self
+    }
+
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, shared_memory_regions) =
+            try!(self.os_receiver.recv());
+        deserialize_copied_message(data, shared_memory_regions)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr,Error> {
+        self.os_receiver.peer_addr().map_err(Error::from)
+    }
+}
+
+/// The sending end of a channel over TCP. Messages can carry shared memory, which is copied to
+/// the receiver, but not channels: sending a message that embeds one fails, and the channel is
+/// lost.
+#[derive(Debug)]
+pub struct IpcTcpSender<T> where T: Serialize {
+    os_sender: TcpSender,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Clone for IpcTcpSender<T> where T: Serialize {
+    fn clone(&self) -> IpcTcpSender<T> {
+        IpcTcpSender {
+            os_sender: self.os_sender.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> IpcTcpSender<T> where T: Serialize {
+    pub fn connect<A>(address: A) -> Result<IpcTcpSender<T>,Error> where A: ToSocketAddrs {
+        Ok(IpcTcpSender {
+            os_sender: try!(TcpSender::connect(address)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Follows every message with a CRC-32 of it, so that the receiver can tell if it was
+    /// corrupted on the way. TCP's own checksum misses some corruption, e.g. by middleboxes. The
+    /// receiver must be told to expect checksums too.
+    pub fn with_checksums(mut self) -> IpcTcpSender<T> {
+        self.os_sender.enable_checksums();
+        self
+    }
+
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let message = try!(serialize_message_without_channels(&data));
+        let shared_memory_regions: Vec<&[u8]> =
+            message.os_ipc_shared_memory_regions.iter().map(|region| &region[..]).collect();
+        self.os_sender.send(message.data.as_slice(), &shared_memory_regions[..])
+            .map_err(Error::from)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr,Error> {
+        self.os_sender.peer_addr().map_err(Error::from)
+    }
+}
+
+/// Creates a channel for fuzzing, whose messages go through the framing of the byte stream
+/// transports and can be mutated on the way; see `IpcLoopbackSender::set_mutator()`. Delivery is
+/// in order and within the process, so runs are reproducible. As on the TCP transport, messages
+/// can carry shared memory, which is copied, but not channels.
+#[cfg(any(test, feature="fuzzing"))]
+pub fn loopback_channel<T>() -> (IpcLoopbackSender<T>, IpcLoopbackReceiver<T>)
+                            where T: Deserialize + Serialize {
+    let (os_sender, os_receiver) = platform::loopback_channel();
+    (IpcLoopbackSender {
+        os_sender: os_sender,
+        phantom: PhantomData,
+    }, IpcLoopbackReceiver {
+        os_receiver: os_receiver,
+        phantom: PhantomData,
+    })
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+#[derive(Debug)]
+pub struct IpcLoopbackReceiver<T> where T: Deserialize + Serialize {
+    os_receiver: LoopbackReceiver,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+impl<T> IpcLoopbackReceiver<T> where T: Deserialize + Serialize {
+    /// Receives the next message, failing with `WouldBlock` if none has been sent.
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, shared_memory_regions) =
+            try!(self.os_receiver.recv());
+        deserialize_copied_message(data, shared_memory_regions)
+    }
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+#[derive(Debug)]
+pub struct IpcLoopbackSender<T> where T: Serialize {
+    os_sender: LoopbackSender,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+impl<T> Clone for IpcLoopbackSender<T> where T: Serialize {
+    fn clone(&self) -> IpcLoopbackSender<T> {
+        IpcLoopbackSender {
+            os_sender: self.os_sender.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+impl<T> IpcLoopbackSender<T> where T: Serialize {
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let message = try!(serialize_message_without_channels(&data));
+        let shared_memory_regions: Vec<&[u8]> =
+            message.os_ipc_shared_memory_regions.iter().map(|region| &region[..]).collect();
+        self.os_sender.send(message.data.as_slice(), &shared_memory_regions[..])
+            .map_err(Error::from)
+    }
+
+    /// Queues a raw frame, e.g. one straight from the fuzzer's input, for the receiver to parse.
+    pub fn send_frame(&self, frame: Vec<u8>) {
+        self.os_sender.send_frame(frame)
+    }
+
+    /// Calls `mutator` on the frame of each message sent from now on, before it's delivered. A
+    /// frame holds the length of the message's data, the number of its shared memory regions, the
+    /// length and contents of each region, and then the data, all little-endian.
+    pub fn set_mutator<F>(&self, mutator: F) where F: FnMut(&mut Vec<u8>) + Send + 'static {
+        self.os_sender.set_mutator(mutator)
+    }
+}
+
+/// One of the channels that `fuzz_decode()` puts in the message it decodes.
+#[cfg(any(test, feature="fuzzing"))]
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntheticChannel {
+    Sender,
+    Receiver,
+}
+
+/// Deserializes `data` as a `T` as if it had been received along with `channels` and shared
+/// memory regions holding `shared_memory_regions`, for fuzzing the decoding of messages without
+/// a transport. The channels are in-process ones whose other ends are gone.
+#[cfg(any(test, feature="fuzzing"))]
+#[doc(hidden)]
+pub fn fuzz_decode<T>(data: &[u8],
+                      channels: &[SyntheticChannel],
+                      shared_memory_regions: &[&[u8]])
+                      -> Result<T,Error>
+                      where T: Deserialize + Serialize {
+    let mut os_ipc_channels = Vec::with_capacity(channels.len());
+    for channel in channels {
+        let sender = *channel == SyntheticChannel::Sender;
+        os_ipc_channels.push(try!(OsOpaqueIpcChannel::in_process(sender)));
+    }
+    let os_ipc_shared_memory_regions = shared_memory_regions.iter().map(|region| {
+        OsIpcSharedMemory::from_bytes(region)
+    }).collect();
+    OpaqueIpcMessage::new(data.to_vec(), os_ipc_channels, os_ipc_shared_memory_regions).to()
+}
+
+/// Reassembles a message from the packets the Unix backend would have received it in, the first
+/// one and then each trailing fragment in turn, for fuzzing its parsing of fragments without
+/// sockets.
+#[cfg(all(any(test, feature="fuzzing"), not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+#[doc(hidden)]
+pub fn fuzz_reassemble(packets: &[&[u8]], max_message_size: Option<usize>)
+                       -> Result<Vec<u8>,Error> {
+    platform::reassemble_fragments(packets, max_message_size)
+        .map_err(|err| Error::from(io::Error::from(err)))
+}
+
+/// Listens for `IpcVsockSender`s in virtual machines, or on their host. As with `IpcTcpServer`,
+/// anyone who can reach the port can connect.
+#[cfg(target_os="linux")]
+pub struct IpcVsockServer<T> {
+    os_server: VsockServer,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(target_os="linux")]
+impl<T> IpcVsockServer<T> where T: Deserialize + Serialize {
+    pub fn bind(port: u32) -> Result<IpcVsockServer<T>,Error> {
+        Ok(IpcVsockServer {
+            os_server: try!(VsockServer::bind(port)),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Waits for the next client to connect, returning the receiving end of its channel.
+    pub fn accept(&self) -> Result<IpcVsockReceiver<T>,Error> {
+        Ok(IpcVsockReceiver {
+            os_receiver: try!(self.os_server.accept()),
+            phantom: PhantomData,
+        })
+    }
+}
+
+#[cfg(target_os="linux")]
+#[derive(Debug)]
+pub struct IpcVsockReceiver<T> where T: Deserialize + Serialize {
+    os_receiver: VsockReceiver,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(target_os="linux")]
+impl<T> IpcVsockReceiver<T> where T: Deserialize + Serialize {
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, shared_memory_regions) =
+            try!(self.os_receiver.recv());
+        deserialize_copied_message(data, shared_memory_regions)
+    }
+
+    /// Returns the context ID of the machine the sender is on.
+    pub fn peer_cid(&self) -> u32 {
+        self.os_receiver.peer_cid()
+    }
+}
+
+/// The sending end of a channel between a virtual machine and its host, with the same
+/// restrictions as an `IpcTcpSender`.
+#[cfg(target_os="linux")]
+#[derive(Debug)]
+pub struct IpcVsockSender<T> where T: Serialize {
+    os_sender: VsockSender,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(target_os="linux")]
+impl<T> Clone for IpcVsockSender<T> where T: Serialize {
+    fn clone(&self) -> IpcVsockSender<T> {
+        IpcVsockSender {
+            os_sender: self.os_sender.clone(),
+            phantom: PhantomData,
+        }
     }
 }
 
-impl Deserialize for IpcSharedMemory {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
-        let index: usize = try!(Deserialize::deserialize(deserializer));
-        let os_shared_memory = OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
-            |os_ipc_shared_memory_regions_for_deserialization| {
-                // FIXME(pcwalton): This could panic if the data was corrupt and the index was out
-                // of bounds. We should return an `Err` result instead.
-                mem::replace(
-                    &mut os_ipc_shared_memory_regions_for_deserialization.borrow_mut()[index],
-                    None).unwrap()
-            });
-        Ok(IpcSharedMemory {
-            os_shared_memory: os_shared_memory,
+#[cfg(target_os="linux")]
+impl<T> IpcVsockSender<T> where T: Serialize {
+    /// Connects to the server listening on `port` of the machine with context ID `cid`, such as
+    /// `VMADDR_CID_HOST`.
+    pub fn connect(cid: u32, port: u32) -> Result<IpcVsockSender<T>,Error> {
+        Ok(IpcVsockSender {
+            os_sender: try!(VsockSender::connect(cid, port)),
+            phantom: PhantomData,
         })
     }
-}
 
-impl Serialize for IpcSharedMemory {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
-        let index = OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
-            |os_ipc_shared_memory_regions_for_serialization| {
-                let mut os_ipc_shared_memory_regions_for_serialization =
-                    os_ipc_shared_memory_regions_for_serialization.borrow_mut();
-                let index = os_ipc_shared_memory_regions_for_serialization.len();
-                os_ipc_shared_memory_regions_for_serialization.push(self.os_shared_memory
-                                                                        .clone());
-                index
-            });
-        index.serialize(serializer)
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let message = try!(serialize_message_without_channels(&data));
+        let shared_memory_regions: Vec<&[u8]> =
+            message.os_ipc_shared_memory_regions.iter().map(|region| &region[..]).collect();
+        self.os_sender.send(message.data.as_slice(), &shared_memory_regions[..])
+            .map_err(Error::from)
     }
 }
 
-impl IpcSharedMemory {
-    pub fn from_bytes(bytes: &[u8]) -> IpcSharedMemory {
-        IpcSharedMemory {
-            os_shared_memory: OsIpcSharedMemory::from_bytes(bytes),
+/// The receiving end of a channel over a byte stream, such as a child process's stdout, or our
+/// own stdin.
+#[derive(Debug)]
+pub struct IpcStdioReceiver<T> where T: Deserialize + Serialize {
+    os_receiver: StdioReceiver,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcStdioReceiver<T> where T: Deserialize + Serialize {
+    pub fn new<R>(stream: R) -> IpcStdioReceiver<T> where R: Read + Send + 'static {
+        IpcStdioReceiver {
+            os_receiver: StdioReceiver::new(stream),
+            phantom: PhantomData,
         }
     }
 
-    pub fn from_byte(byte: u8, length: usize) -> IpcSharedMemory {
-        IpcSharedMemory {
-            os_shared_memory: OsIpcSharedMemory::from_byte(byte, length),
+    /// Receives what our parent writes to our stdin.
+    pub fn stdin() -> IpcStdioReceiver<T> {
+        IpcStdioReceiver {
+            os_receiver: StdioReceiver::stdin(),
+            phantom: PhantomData,
         }
     }
-}
 
-pub enum IpcSelectionResult {
-    MessageReceived(i64, OpaqueIpcMessage),
-    ChannelClosed(i64),
-}
+    /// Expects every message to carry a checksum, as `IpcTcpReceiver::with_checksums()` does.
+    pub fn with_checksums(mut self) -> IpcStdioReceiver<T> {
+        self.os_receiver.enable_checksums();
+        self
+    }
 
-impl IpcSelectionResult {
-    pub fn unwrap(self) -> (i64, OpaqueIpcMessage) {
-        match self {
-            IpcSelectionResult::MessageReceived(id, message) => (id, message),
-            IpcSelectionResult::ChannelClosed(id) => {
-                panic!("IpcSelectionResult::unwrap(): channel {} closed", id)
-            }
-        }
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, shared_memory_regions) =
+            try!(self.os_receiver.recv());
+        deserialize_copied_message(data, shared_memory_regions)
     }
 }
 
-pub struct OpaqueIpcMessage {
-    data: Vec<u8>,
-    os_ipc_channels: Vec<OsOpaqueIpcChannel>,
-    os_ipc_shared_memory_regions: Vec<Option<OsIpcSharedMemory>>,
+/// The sending end of a channel over a byte stream, such as a child process's stdin, or our own
+/// stdout. It has the same restrictions as an `IpcTcpSender`, and nothing else may write to the
+/// stream.
+#[derive(Debug)]
+pub struct IpcStdioSender<T> where T: Serialize {
+    os_sender: StdioSender,
+    phantom: PhantomData<T>,
 }
 
-impl Debug for OpaqueIpcMessage {
-    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
-        match String::from_utf8(self.data.clone()) {
-            Ok(string) => string.chars().take(256).collect::<String>().fmt(formatter),
-            Err(..) => self.data[0..min(self.data.len(), 256)].fmt(formatter),
+impl<T> Clone for IpcStdioSender<T> where T: Serialize {
+    fn clone(&self) -> IpcStdioSender<T> {
+        IpcStdioSender {
+            os_sender: self.os_sender.clone(),
+            phantom: PhantomData,
         }
     }
 }
 
-impl OpaqueIpcMessage {
-    fn new(data: Vec<u8>,
-           os_ipc_channels: Vec<OsOpaqueIpcChannel>,
-           os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
-           -> OpaqueIpcMessage {
-        OpaqueIpcMessage {
-            data: data,
-            os_ipc_channels: os_ipc_channels,
-            os_ipc_shared_memory_regions:
-                os_ipc_shared_memory_regions.into_iter()
-                                            .map(|os_ipc_shared_memory_region| {
-                    Some(os_ipc_shared_memory_region)
-                }).collect(),
+impl<T> IpcStdioSender<T> where T: Serialize {
+    pub fn new<W>(stream: W) -> IpcStdioSender<T> where W: Write + Send + 'static {
+        IpcStdioSender {
+            os_sender: StdioSender::new(stream),
+            phantom: PhantomData,
         }
     }
 
-    pub fn to<T>(mut self) -> Result<T,DeserializeError> where T: Deserialize + Serialize {
-        OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
-            OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
-                    |os_ipc_shared_memory_regions_for_deserialization| {
-               if os_ipc_channels_for_deserialization.borrow_state() != BorrowState::Unused {
-                    return Err(DeserializeError::IoError(recursive_io_error()));
-                }
+    /// Sends to our parent over our stdout.
+    pub fn stdout() -> IpcStdioSender<T> {
+        IpcStdioSender {
+            os_sender: StdioSender::stdout(),
+            phantom: PhantomData,
+        }
+    }
 
-                if os_ipc_shared_memory_regions_for_deserialization.borrow_state() != BorrowState::Unused {
-                    return Err(DeserializeError::IoError(recursive_io_error()));
-                }
+    /// Follows every message with a checksum, as `IpcTcpSender::with_checksums()` does. Worth it
+    /// on streams that can flip bits, such as serial lines.
+    pub fn with_checksums(mut self) -> IpcStdioSender<T> {
+        self.os_sender.enable_checksums();
+        self
+    }
 
-                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_channels);
-                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_shared_memory_regions);
-                let mut data = &*self.data;
-                let mut deserializer = bincode::serde::Deserializer::new(&mut data,
-                                                                         SizeLimit::Infinite);
-                let result = try!(Deserialize::deserialize(&mut deserializer));
-                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_shared_memory_regions);
-                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_channels);
-                Ok(result)
-            })
-        })
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let message = try!(serialize_message_without_channels(&data));
+        let shared_memory_regions: Vec<&[u8]> =
+            message.os_ipc_shared_memory_regions.iter().map(|region| &region[..]).collect();
+        self.os_sender.send(message.data.as_slice(), &shared_memory_regions[..])
+            .map_err(Error::from)
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct OpaqueIpcSender {
-    os_sender: OsIpcSender,
+/// Listens for WebSocket connections, typically from a frontend running in a browser. Each
+/// connection carries messages both ways. As with `IpcTcpServer`, anyone who can reach the
+/// address can connect, though pages in browsers only from the origins allowed with
+/// `allow_origin()`.
+#[cfg(feature="websocket")]
+pub struct IpcWebSocketServer {
+    os_server: WebSocketServer,
 }
 
-impl OpaqueIpcSender {
-    pub fn to<T>(self) -> IpcSender<T> where T: Deserialize + Serialize {
-        IpcSender {
-            os_sender: self.os_sender,
-            phantom: PhantomData,
-        }
+#[cfg(feature="websocket")]
+impl IpcWebSocketServer {
+    pub fn bind<A>(address: A) -> Result<IpcWebSocketServer,Error> where A: ToSocketAddrs {
+        Ok(IpcWebSocketServer {
+            os_server: try!(WebSocketServer::bind(address)),
+        })
     }
-}
 
-impl Deserialize for OpaqueIpcSender {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
-        let os_sender = try!(deserialize_os_ipc_sender(deserializer));
-        Ok(OpaqueIpcSender {
-            os_sender: os_sender,
-        })
+    pub fn local_addr(&self) -> Result<SocketAddr,Error> {
+        self.os_server.local_addr().map_err(Error::from)
     }
-}
 
-impl Serialize for OpaqueIpcSender {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
-        serialize_os_ipc_sender(&self.os_sender, serializer)
+    /// Lets pages from `origin`, such as `https://example.com`, connect. Until this is called, no
+    /// page in a browser can; clients that aren't browsers always can.
+    pub fn allow_origin(&mut self, origin: &str) {
+        self.os_server.allow_origin(origin)
+    }
+
+    /// Waits for the next client to open a WebSocket, returning a sender of messages to it and a
+    /// receiver of messages from it.
+    pub fn accept<T,U>(&self) -> Result<(IpcWebSocketSender<T>, IpcWebSocketReceiver<U>),Error>
+                       where T: Serialize, U: Deserialize + Serialize {
+        let (os_sender, os_receiver) = try!(self.os_server.accept());
+        Ok((IpcWebSocketSender {
+            os_sender: os_sender,
+            phantom: PhantomData,
+        }, IpcWebSocketReceiver {
+            os_receiver: os_receiver,
+            phantom: PhantomData,
+        }))
     }
 }
 
+#[cfg(feature="websocket")]
 #[derive(Debug)]
-pub struct OpaqueIpcReceiver {
-    os_receiver: OsIpcReceiver,
+pub struct IpcWebSocketReceiver<T> where T: Deserialize + Serialize {
+    os_receiver: WebSocketReceiver,
+    phantom: PhantomData<T>,
 }
 
-pub struct IpcOneShotServer<T> {
-    os_server: OsIpcOneShotServer,
+#[cfg(feature="websocket")]
+impl<T> IpcWebSocketReceiver<T> where T: Deserialize + Serialize {
+    pub fn recv(&self) -> Result<T,Error> {
+        let (data, shared_memory_regions) =
+            try!(self.os_receiver.recv());
+        deserialize_copied_message(data, shared_memory_regions)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.os_receiver.peer_addr()
+    }
+}
+
+/// The sending end of a WebSocket connection, with the same restrictions as an `IpcTcpSender`.
+#[cfg(feature="websocket")]
+#[derive(Debug)]
+pub struct IpcWebSocketSender<T> where T: Serialize {
+    os_sender: WebSocketSender,
     phantom: PhantomData<T>,
 }
 
-impl<T> IpcOneShotServer<T> where T: Deserialize + Serialize {
-    pub fn new() -> Result<(IpcOneShotServer<T>, String),Error> {
-        let (os_server, name) = try!(OsIpcOneShotServer::new());
-        Ok((IpcOneShotServer {
-            os_server: os_server,
+#[cfg(feature="websocket")]
+impl<T> Clone for IpcWebSocketSender<T> where T: Serialize {
+    fn clone(&self) -> IpcWebSocketSender<T> {
+        IpcWebSocketSender {
+            os_sender: self.os_sender.clone(),
             phantom: PhantomData,
-        }, name))
+        }
     }
+}
 
-    pub fn accept(self) -> Result<(IpcReceiver<T>,T),DeserializeError> {
-        let (os_receiver, data, os_channels, os_shared_memory_regions) =
-            try!(self.os_server.accept());
-        let value = try!(OpaqueIpcMessage {
-            data: data,
-            os_ipc_channels: os_channels,
-            os_ipc_shared_memory_regions: os_shared_memory_regions.into_iter()
-                                                                  .map(|os_shared_memory_region| {
-                Some(os_shared_memory_region)
-            }).collect(),
-        }.to());
-        Ok((IpcReceiver {
+#[cfg(feature="websocket")]
+impl<T> IpcWebSocketSender<T> where T: Serialize {
+    /// Opens a WebSocket to the server at `address`, asking for `path`. Returns a sender of
+    /// messages to the server and a receiver of messages from it.
+    pub fn connect<A,U>(address: A, path: &str)
+                        -> Result<(IpcWebSocketSender<T>, IpcWebSocketReceiver<U>),Error>
+                        where A: ToSocketAddrs, U: Deserialize + Serialize {
+        let (os_sender, os_receiver) = try!(WebSocketSender::connect(address, path));
+        Ok((IpcWebSocketSender {
+            os_sender: os_sender,
+            phantom: PhantomData,
+        }, IpcWebSocketReceiver {
             os_receiver: os_receiver,
             phantom: PhantomData,
-        }, value))
+        }))
+    }
+
+    pub fn send(&self, data: T) -> Result<(),Error> {
+        let message = try!(serialize_message_without_channels(&data));
+        let shared_memory_regions: Vec<&[u8]> =
+            message.os_ipc_shared_memory_regions.iter().map(|region| &region[..]).collect();
+        self.os_sender.send(message.data.as_slice(), &shared_memory_regions[..])
+            .map_err(Error::from)
+    }
+}
+
+/// Serializes a message for a transport that can only carry bytes, which fails if it embeds any
+/// channels.
+fn serialize_message_without_channels<T>(data: &T) -> Result<OutgoingMessage,io::Error>
+                                         where T: Serialize {
+    let message = try!(serialize_message(data, None));
+    if !message.os_ipc_channels.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput,
+                              "channels can only be sent over native channels"))
     }
+    Ok(message)
+}
+
+/// Deserializes a message that came over a transport that can only carry bytes, turning the
+/// copied contents of its shared memory regions back into regions.
+fn deserialize_copied_message<T>(data: Vec<u8>, shared_memory_regions: Vec<Vec<u8>>)
+                                 -> Result<T,Error> where T: Deserialize {
+    let mut os_ipc_shared_memory_regions: Vec<_> =
+        shared_memory_regions.iter().map(|region| {
+            Arc::new(OsIpcSharedMemory::from_bytes(&region[..]))
+        }).collect();
+    // As with native channels, a message with no data was moved into its last region.
+    let data = match os_ipc_shared_memory_regions.pop() {
+        Some(region) if data.is_empty() => region.to_vec(),
+        Some(region) => {
+            os_ipc_shared_memory_regions.push(region);
+            data
+        }
+        None => data,
+    };
+    deserialize_message(&data[..], &mut vec![], &mut os_ipc_shared_memory_regions)
+        .map_err(Error::from)
 }
 
 #[derive(Debug)]
 pub struct IpcBytesReceiver {
     os_receiver: OsIpcReceiver,
+    peer_pid: PeerPid,
 }
 
 impl IpcBytesReceiver {
     #[inline]
-    pub fn recv(&self) -> Result<Vec<u8>,DeserializeError> {
+    pub fn recv(&self) -> Result<Vec<u8>,Error> {
         match self.os_receiver.recv() {
-            Ok((data, _, _)) => Ok(data),
+            // Sent with `send_file()`.
+            Ok((ref data, _, ref os_ipc_shared_memory_regions))
+                    if data.is_empty() && os_ipc_shared_memory_regions.len() == 1 => {
+                let data = os_ipc_shared_memory_regions[0].to_vec();
+                audit_received(&self.os_receiver, &self.peer_pid, &data, 1);
+                capture_message(self.os_receiver.endpoint_id(),
+                                MessageDirection::Received,
+                                &[],
+                                0,
+                                os_ipc_shared_memory_regions);
+                Ok(data)
+            }
+            Ok((data, _, _)) => {
+                audit_received(&self.os_receiver, &self.peer_pid, &data, 0);
+                capture_message(self.os_receiver.endpoint_id(),
+                                MessageDirection::Received,
+                                &[&data[..]],
+                                0,
+                                &[]);
+                Ok(data)
+            }
             Err(err) => Err(err.into()),
         }
     }
@@ -475,6 +4274,7 @@ impl Deserialize for IpcBytesReceiver {
             });
         Ok(IpcBytesReceiver {
             os_receiver: os_receiver,
+            peer_pid: PeerPid::new(),
         })
     }
 }
@@ -496,12 +4296,14 @@ impl Serialize for IpcBytesReceiver {
 #[derive(Debug)]
 pub struct IpcBytesSender {
     os_sender: OsIpcSender,
+    peer_pid: PeerPid,
 }
 
 impl Clone for IpcBytesSender {
     fn clone(&self) -> IpcBytesSender {
         IpcBytesSender {
             os_sender: self.os_sender.clone(),
+            peer_pid: self.peer_pid.clone(),
         }
     }
 }
@@ -511,6 +4313,7 @@ impl Deserialize for IpcBytesSender {
         let os_sender = try!(deserialize_os_ipc_sender(deserializer));
         Ok(IpcBytesSender {
             os_sender: os_sender,
+            peer_pid: PeerPid::new(),
         })
     }
 }
@@ -524,7 +4327,31 @@ impl Serialize for IpcBytesSender {
 impl IpcBytesSender {
     #[inline]
     pub fn send(&self, data: &[u8]) -> Result<(),Error> {
-        self.os_sender.send(data, vec![], vec![]).map_err(|e| Error::from(e))
+        capture_message(self.os_sender.endpoint_id(), MessageDirection::Sent, &[data], 0, &[]);
+        try!(self.os_sender.send(data, vec![], vec![]));
+        audit_sent(&self.os_sender, &self.peer_pid, data.len(), 0);
+        Ok(())
+    }
+
+    /// Sends the concatenation of `data` as a single message, without concatenating it in
+    /// memory first where the platform can gather it straight from the slices.
+    pub fn send_vectored(&self, data: &[&[u8]]) -> Result<(),Error> {
+        capture_message(self.os_sender.endpoint_id(), MessageDirection::Sent, data, 0, &[]);
+        try!(self.os_sender.send_vectored(data, vec![], vec![]));
+        let size = data.iter().fold(0, |size, slice| size + slice.len());
+        audit_sent(&self.os_sender, &self.peer_pid, size, 0);
+        Ok(())
+    }
+
+    /// Sends the next `length` bytes of `file` as a single message. On Linux, they are spliced
+    /// into shared memory inside the kernel and never copied through this process, which makes
+    /// this the cheap way to forward large files; elsewhere, they are read in and sent as usual.
+    pub fn send_file(&self, file: &File, length: usize) -> Result<(),Error> {
+        let regions = vec![try!(OsIpcSharedMemory::from_file(file, length))];
+        capture_message(self.os_sender.endpoint_id(), MessageDirection::Sent, &[], 0, &regions);
+        try!(self.os_sender.send(&[], vec![], regions));
+        audit_sent(&self.os_sender, &self.peer_pid, length, 1);
+        Ok(())
     }
 }
 
@@ -540,17 +4367,364 @@ fn serialize_os_ipc_sender<S>(os_ipc_sender: &OsIpcSender, serializer: &mut S)
     index.serialize(serializer)
 }
 
+/// Converts the `index`th channel of the message being deserialized with `convert`, failing
+/// rather than panicking if the message doesn't have that many, as when it is corrupt.
+#[cfg(any(unix, windows))]
+fn convert_os_ipc_channel<D,F,T>(index: usize, convert: F) -> Result<T,D::Error>
+                                 where D: Deserializer,
+                                       F: FnOnce(&mut OsOpaqueIpcChannel) -> Result<T,SwitchError> {
+    OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+        let mut os_ipc_channels_for_deserialization =
+            os_ipc_channels_for_deserialization.borrow_mut();
+        let os_channel = match os_ipc_channels_for_deserialization.get_mut(index) {
+            Some(os_channel) => os_channel,
+            None => return Err(D::Error::custom(format!("no channel at index {}", index))),
+        };
+        convert(os_channel).map_err(|err| D::Error::custom(io::Error::from(err).to_string()))
+    })
+}
+
 fn deserialize_os_ipc_sender<D>(deserializer: &mut D)
                                 -> Result<OsIpcSender, D::Error> where D: Deserializer {
     let index: usize = try!(Deserialize::deserialize(deserializer));
     OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
-        // FIXME(pcwalton): This could panic if the data was corrupt and the index was out of
-        // bounds. We should return an `Err` result instead.
-        Ok(os_ipc_channels_for_deserialization.borrow_mut()[index].to_sender())
+        // As with shared memory, the index is out of bounds if the data is corrupt, or if it came
+        // over a transport that only carries bytes.
+        match os_ipc_channels_for_deserialization.borrow_mut().get_mut(index) {
+            Some(os_channel) => Ok(os_channel.to_sender()),
+            None => Err(D::Error::custom(format!("no channel at index {}", index))),
+        }
     })
 }
 
-fn recursive_io_error() -> Error {
-    Error::new(ErrorKind::Other, "recursive IPC channel use during serialization")
+/// Why an IPC operation failed, for callers that need to act on the cause.
+///
+/// Channels, servers and the other transports fail with it. The `io::Error`s and
+/// `DeserializeError`s underneath convert into it with `From`, and it converts back into an
+/// `io::Error` for code that carries on with those. Errors from a labeled channel come wrapped in
+/// `Labeled`; `unlabeled()` gets at the cause underneath.
+#[derive(Debug)]
+pub enum Error {
+    /// The other end of the channel is gone.
+    Disconnected,
+    /// The operation would have had to wait, and wasn't allowed to.
+    WouldBlock,
+    /// The operation didn't finish in the time it was given.
+    TimedOut,
+    /// A signal interrupted the operation; see `set_retry_on_interrupt()`.
+    Interrupted,
+    /// The message is bigger than the channel can carry.
+    MessageTooLarge,
+    /// The sender of a message gave up partway through sending it. None of the message was
+    /// delivered, and the channel carries on with the next one.
+    TruncatedMessage,
+    /// Like `TruncatedMessage`, but because the process sending the message died.
+    PeerDiedMidMessage,
+    /// A message arrived, but doesn't match the checksum it was sent with; see
+    /// `IpcTcpSender::with_checksums()`.
+    Corrupted,
+    /// A message arrived, but didn't decode as the type the receiver expects.
+    Deserialize(DeserializeError),
+    /// The backend in use can't do this.
+    Unsupported,
+    /// Any other failure, as reported by the OS or the transport.
+    Io(io::Error),
+    /// One of the above, from a channel made with `channel_named()`.
+    Labeled(LabeledError),
+}
+
+impl Error {
+    /// Returns the kind of the `io::Error` this converts into, for code that used to look at
+    /// those.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Disconnected => ErrorKind::BrokenPipe,
+            Error::WouldBlock => ErrorKind::WouldBlock,
+            Error::TimedOut => ErrorKind::TimedOut,
+            Error::Interrupted => ErrorKind::Interrupted,
+            Error::MessageTooLarge => ErrorKind::InvalidInput,
+            Error::TruncatedMessage | Error::PeerDiedMidMessage | Error::Corrupted => {
+                ErrorKind::InvalidData
+            }
+            Error::Deserialize(DeserializeError::IoError(ref error)) => error.kind(),
+            Error::Deserialize(_) => ErrorKind::InvalidData,
+            Error::Unsupported => ErrorKind::Other,
+            Error::Io(ref error) => error.kind(),
+            Error::Labeled(ref error) => error.error.kind(),
+        }
+    }
+
+    /// Returns the label of the channel the error came from, if it has one.
+    pub fn label(&self) -> Option<&str> {
+        match *self {
+            Error::Labeled(ref error) => Some(error.label()),
+            _ => None,
+        }
+    }
+
+    /// Returns the error underneath the label, if there is one, to match on its cause.
+    pub fn unlabeled(&self) -> &Error {
+        match *self {
+            Error::Labeled(ref error) => error.error(),
+            ref error => error,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        if error.get_ref().map_or(false, |inner| inner.is::<LabeledError>()) {
+            return Error::Labeled(*error.into_inner().unwrap().downcast::<LabeledError>().unwrap())
+        }
+        match error.kind() {
+            ErrorKind::BrokenPipe |
+            ErrorKind::ConnectionReset |
+            ErrorKind::ConnectionAborted |
+            ErrorKind::NotConnected => return Error::Disconnected,
+            ErrorKind::WouldBlock => return Error::WouldBlock,
+            ErrorKind::TimedOut => return Error::TimedOut,
+            ErrorKind::Interrupted => return Error::Interrupted,
+            _ => {}
+        }
+        if error.get_ref().map_or(false, |inner| inner.is::<platform::Unsupported>()) {
+            return Error::Unsupported
+        }
+        if error.get_ref().map_or(false, |inner| inner.is::<platform::ChecksumMismatch>()) {
+            return Error::Corrupted
+        }
+        match os_error_cause(&error) {
+            Some(cause) => cause,
+            None => Error::Io(error),
+        }
+    }
+}
+
+/// Why `IpcReceiver::try_recv_checked()` didn't return a message.
+#[derive(Debug)]
+pub enum TryRecvError {
+    /// No message is waiting, but more may arrive.
+    Empty,
+    /// All senders are gone, and every message they sent has been received.
+    Disconnected,
+    /// Receiving failed for another reason.
+    Error(Error),
+}
+
+impl From<Error> for TryRecvError {
+    fn from(error: Error) -> TryRecvError {
+        match *error.unlabeled() {
+            // Mach reports an empty port as a receive that timed out right away.
+            Error::WouldBlock | Error::TimedOut => return TryRecvError::Empty,
+            Error::Disconnected => return TryRecvError::Disconnected,
+            _ => {}
+        }
+        TryRecvError::Error(error)
+    }
+}
+
+impl Display for TryRecvError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        match *self {
+            TryRecvError::Empty => write!(formatter, "no message is waiting"),
+            TryRecvError::Disconnected => write!(formatter, "all senders are gone"),
+            TryRecvError::Error(ref error) => write!(formatter, "{}", error),
+        }
+    }
+}
+
+impl error::Error for TryRecvError {
+    fn description(&self) -> &str {
+        match *self {
+            TryRecvError::Empty => "no message is waiting",
+            TryRecvError::Disconnected => "all senders are gone",
+            TryRecvError::Error(ref error) => error::Error::description(error),
+        }
+    }
+}
+
+/// An error from a channel made with `channel_named()`, which says which channel it came from.
+/// Labeled channels return it as `Error::Labeled`, and wrap it in an `io::Error` of the same kind
+/// where they return those.
+#[derive(Debug)]
+pub struct LabeledError {
+    label: Arc<String>,
+    error: Box<Error>,
+}
+
+impl LabeledError {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns the error as it would have been without the label.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl Display for LabeledError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "channel \"{}\": {}", self.label, self.error)
+    }
+}
+
+impl error::Error for LabeledError {
+    fn description(&self) -> &str {
+        error::Error::description(&*self.error)
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&*self.error)
+    }
+}
+
+/// Wraps `error` in a `LabeledError`, if there's a label and it isn't labeled already.
+fn label_io_error(label: &Option<Arc<String>>, error: io::Error) -> io::Error {
+    match *label {
+        Some(_) => io::Error::from(label_error(label, error)),
+        None => error,
+    }
+}
+
+/// Converts `error` into an `Error`, and wraps it in `Error::Labeled` if there's a label and it
+/// isn't labeled already.
+fn label_error<E>(label: &Option<Arc<String>>, error: E) -> Error where Error: From<E> {
+    match (label, Error::from(error)) {
+        (_, error @ Error::Labeled(_)) | (&None, error) => error,
+        (&Some(ref label), error) => {
+            Error::Labeled(LabeledError {
+                label: label.clone(),
+                error: Box::new(error),
+            })
+        }
+    }
+}
+
+#[cfg(unix)]
+fn os_error_cause(error: &io::Error) -> Option<Error> {
+    match error.raw_os_error() {
+        Some(libc::EMSGSIZE) => Some(Error::MessageTooLarge),
+        Some(libc::EBADMSG) => Some(Error::TruncatedMessage),
+        Some(libc::EOWNERDEAD) => Some(Error::PeerDiedMidMessage),
+        Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Some(Error::Unsupported),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn os_error_cause(_: &io::Error) -> Option<Error> {
+    None
+}
+
+impl From<SwitchError> for Error {
+    fn from(error: SwitchError) -> Error {
+        Error::from(io::Error::from(error))
+    }
+}
+
+impl From<DeserializeError> for Error {
+    fn from(error: DeserializeError) -> Error {
+        match error {
+            DeserializeError::IoError(error) => error.into(),
+            DeserializeError::SizeLimit => Error::MessageTooLarge,
+            error => Error::Deserialize(error),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Disconnected => io::Error::new(ErrorKind::BrokenPipe, "channel disconnected"),
+            Error::WouldBlock => io::Error::new(ErrorKind::WouldBlock, "no message is waiting"),
+            Error::TimedOut => io::Error::new(ErrorKind::TimedOut, "timed out"),
+            Error::Interrupted => io::Error::new(ErrorKind::Interrupted, "interrupted by a signal"),
+            Error::MessageTooLarge => {
+                io::Error::new(ErrorKind::InvalidInput, "message too large for the channel")
+            }
+            Error::TruncatedMessage => {
+                io::Error::new(ErrorKind::InvalidData, "sender gave up partway through a message")
+            }
+            Error::PeerDiedMidMessage => {
+                io::Error::new(ErrorKind::InvalidData, "sender died partway through a message")
+            }
+            Error::Corrupted => {
+                io::Error::new(ErrorKind::InvalidData, "message doesn't match its checksum")
+            }
+            Error::Deserialize(error) => deserialize_error_to_io_error(error),
+            Error::Unsupported => {
+                io::Error::new(ErrorKind::Other, "operation not supported by this backend")
+            }
+            Error::Io(error) => error,
+            Error::Labeled(error) => io::Error::new(error.error.kind(), error),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        match *self {
+            Error::Deserialize(ref error) => write!(formatter, "{}", error),
+            Error::Io(ref error) => write!(formatter, "{}", error),
+            Error::Labeled(ref error) => write!(formatter, "{}", error),
+            _ => write!(formatter, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Disconnected => "channel disconnected",
+            Error::WouldBlock => "no message is waiting",
+            Error::TimedOut => "timed out",
+            Error::Interrupted => "interrupted by a signal",
+            Error::MessageTooLarge => "message too large for the channel",
+            Error::TruncatedMessage => "sender gave up partway through a message",
+            Error::PeerDiedMidMessage => "sender died partway through a message",
+            Error::Corrupted => "message doesn't match its checksum",
+            Error::Deserialize(ref error) => error::Error::description(error),
+            Error::Unsupported => "operation not supported by this backend",
+            Error::Io(ref error) => error::Error::description(error),
+            Error::Labeled(ref error) => error::Error::description(error),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Deserialize(ref error) => Some(error),
+            Error::Io(ref error) => Some(error),
+            Error::Labeled(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn deserialize_error_to_io_error(error: DeserializeError) -> io::Error {
+    match error {
+        DeserializeError::IoError(error) => error,
+        error => io::Error::new(ErrorKind::InvalidData, format!("{}", error)),
+    }
+}
+
+fn serialize_error_to_io_error(error: SerializeError) -> io::Error {
+    match error {
+        SerializeError::IoError(error) => error,
+        error => io::Error::new(ErrorKind::InvalidInput, format!("{}", error)),
+    }
+}
+
+fn recursive_io_error() -> io::Error {
+    io::Error::new(ErrorKind::Other, "recursive IPC channel use during serialization")
+}
+
+/// Unwinds the thread of a virtual process that was crashed; see `test_support`.
+#[cfg(any(test, feature = "test-support"))]
+#[inline]
+fn crash_point() {
+    test_support::crash_point()
 }
 
+#[cfg(not(any(test, feature = "test-support")))]
+#[inline]
+fn crash_point() {}
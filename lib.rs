@@ -8,23 +8,41 @@
 // except according to those terms.
 
 #![feature(custom_derive, plugin, slice_patterns)]
-#![feature(mpsc_select, borrow_state)]
-#![cfg_attr(test, feature(time2))]
+#![feature(mpsc_select, borrow_state, time2)]
 #![plugin(serde_macros)]
 
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(feature = "leak-tracking")]
+extern crate backtrace;
+#[cfg(feature = "websocket")]
+extern crate base64;
 extern crate bincode;
 extern crate byteorder;
+extern crate crc;
+extern crate crypto;
+#[cfg(feature = "dbus-bridge")]
+extern crate dbus;
 extern crate libc;
 extern crate rand;
 extern crate serde;
 extern crate uuid;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+mod capture;
+#[cfg(feature = "dbus-bridge")]
+pub mod dbus_bridge;
 pub mod ipc;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod platform;
+pub mod process;
 pub mod router;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod wire;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,179 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Channel and router counters as samples for metrics systems, when built with the `metrics`
+//! feature: hand `channel_samples()` and `router_samples()` to a recorder of the `metrics` crate,
+//! or serve them to Prometheus as `prometheus_text()`. The counters behind them are kept anyway,
+//! with relaxed atomics; gathering samples is what costs, and only happens when asked for.
+//!
+//! Channels are only sampled while they are in the registry, so call `ipc::enable_registry()`
+//! before creating the channels to chart. Endpoints are summed up by kind and name.
+
+use ipc::{self, EndpointKind};
+use router::RouterProxy;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Only ever goes up, e.g. the number of messages sent.
+    Counter,
+    /// Goes up and down, e.g. the number of live endpoints.
+    Gauge,
+}
+
+/// One value of one metric.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The metric's name, in the Prometheus style, e.g. `ipc_channel_messages_total`.
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    /// The names and values of the labels that tell this sample apart from others of its metric.
+    pub labels: Vec<(&'static str, String)>,
+    pub value: u64,
+}
+
+/// Totals for the endpoints of one kind and name.
+#[derive(Default)]
+struct Totals {
+    endpoints: u64,
+    messages: u64,
+    bytes: u64,
+    handles: u64,
+}
+
+/// Returns samples of the number of live endpoints, and of what went through them.
+pub fn channel_samples() -> Vec<Sample> {
+    let mut totals: BTreeMap<(&'static str, String),Totals> = BTreeMap::new();
+    // A sender's clones share its counts, which mustn't be added up twice.
+    let mut counted = HashSet::new();
+    for endpoint in ipc::registered_endpoints() {
+        let kind = match endpoint.kind {
+            EndpointKind::Sender => "sender",
+            EndpointKind::Receiver => "receiver",
+        };
+        let label = endpoint.stats.label.clone().unwrap_or(String::new());
+        let entry = totals.entry((kind, label)).or_insert_with(Totals::default);
+        entry.endpoints += 1;
+        if counted.insert(endpoint.id) {
+            entry.messages += endpoint.stats.messages as u64;
+            entry.bytes += endpoint.stats.bytes as u64;
+            entry.handles += endpoint.stats.handles as u64;
+        }
+    }
+
+    let mut samples = vec![];
+    for ((kind, label), totals) in totals {
+        let labels = vec![("kind", kind.to_owned()), ("channel", label)];
+        samples.push(Sample {
+            name: "ipc_channel_endpoints",
+            help: "Live senders and receivers.",
+            kind: MetricKind::Gauge,
+            labels: labels.clone(),
+            value: totals.endpoints,
+        });
+        samples.push(Sample {
+            name: "ipc_channel_messages_total",
+            help: "Messages sent or received.",
+            kind: MetricKind::Counter,
+            labels: labels.clone(),
+            value: totals.messages,
+        });
+        samples.push(Sample {
+            name: "ipc_channel_bytes_total",
+            help: "Serialized size of the messages sent or received.",
+            kind: MetricKind::Counter,
+            labels: labels.clone(),
+            value: totals.bytes,
+        });
+        samples.push(Sample {
+            name: "ipc_channel_handles_total",
+            help: "Channels and shared memory regions sent or received with messages.",
+            kind: MetricKind::Counter,
+            labels: labels,
+            value: totals.handles,
+        });
+    }
+    samples
+}
+
+/// Returns samples of what `router` has done, labeled with `name` to tell routers apart.
+pub fn router_samples(router: &RouterProxy, name: &str) -> Vec<Sample> {
+    let stats = router.stats();
+    let labels = vec![("router", name.to_owned())];
+    vec![
+        Sample {
+            name: "ipc_router_routes",
+            help: "Receivers being routed.",
+            kind: MetricKind::Gauge,
+            labels: labels.clone(),
+            value: stats.routes as u64,
+        },
+        Sample {
+            name: "ipc_router_messages_total",
+            help: "Messages handed to routes.",
+            kind: MetricKind::Counter,
+            labels: labels.clone(),
+            value: stats.messages as u64,
+        },
+        Sample {
+            name: "ipc_router_bytes_total",
+            help: "Size of the messages handed to routes.",
+            kind: MetricKind::Counter,
+            labels: labels.clone(),
+            value: stats.bytes as u64,
+        },
+        Sample {
+            name: "ipc_router_dead_letters_total",
+            help: "Messages the router couldn't deliver.",
+            kind: MetricKind::Counter,
+            labels: labels,
+            value: stats.dead_letters as u64,
+        },
+    ]
+}
+
+/// Formats `samples` in the Prometheus text exposition format, grouped by metric.
+pub fn prometheus_text(samples: &[Sample]) -> String {
+    let mut names: Vec<&'static str> = vec![];
+    for sample in samples {
+        if !names.contains(&sample.name) {
+            names.push(sample.name)
+        }
+    }
+    let mut text = String::new();
+    for name in names {
+        let mut samples = samples.iter().filter(|sample| sample.name == name).peekable();
+        {
+            let first = samples.peek().unwrap();
+            let kind = match first.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            writeln!(text, "# HELP {} {}", name, first.help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+        }
+        for sample in samples {
+            text.push_str(name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample.labels.iter().map(|&(label, ref value)| {
+                    format!("{}=\"{}\"", label, escape_label_value(value))
+                }).collect();
+                write!(text, "{{{}}}", labels.join(",")).unwrap();
+            }
+            writeln!(text, " {}", sample.value).unwrap();
+        }
+    }
+    text
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -7,30 +7,57 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! The backend for Windows, where channels don't yet cross process boundaries. It also serves
+//! `wasm32` targets, WASI included, which have no other way to talk between processes; and any
+//! platform when the `force-inprocess` feature is on, so that code written against channels can
+//! be run and tested within a single process. Servers are looked up by name in a table of this
+//! process, and shared memory regions are reference-counted buffers.
+//!
+//! Each message is handed over whole, as one item on an mpsc channel, so message boundaries are
+//! kept as they are and there is no fragmentation or reassembly to go wrong.
+//!
+//! A real Windows backend should likewise keep boundaries natively, by using message-mode named
+//! pipes rather than framing messages on a byte stream.
+//!
+//! Channels made by a `Scheduler` also record the order in which their messages are delivered,
+//! or replay a recorded order: receivers wait for their turn, and set aside messages that arrive
+//! ahead of theirs, so that the threads of a test see the exact interleaving they saw before.
+
 use bincode::serde::DeserializeError;
+use libc::c_int;
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
 use std::collections::hash_map::HashMap;
 use std::cell::{RefCell};
-use std::io::{Error, ErrorKind};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
 use std::slice;
 use std::fmt::{self, Debug, Formatter};
-use std::cmp::{PartialEq};
+use std::cmp::{self, PartialEq};
 use std::ops::Deref;
 use std::mem;
+#[cfg(windows)]
+use std::os::windows::io::RawHandle;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use uuid::Uuid;
 
 struct ServerRecord {
+    /// Identifies the `MpscOneShotServer` that created this record.
+    server_id: Uuid,
     sender: MpscSender,
     conn_sender: mpsc::Sender<bool>,
     conn_receiver: Mutex<mpsc::Receiver<bool>>,
 }
 
 impl ServerRecord {
-    fn new(sender: MpscSender) -> ServerRecord {
+    fn new(server_id: Uuid, sender: MpscSender) -> ServerRecord {
         let (tx, rx) = mpsc::channel::<bool>();
         ServerRecord {
+            server_id: server_id,
             sender: sender,
             conn_sender: tx,
             conn_receiver: Mutex::new(rx),
@@ -50,15 +77,210 @@ lazy_static! {
     static ref ONE_SHOT_SERVERS: Mutex<HashMap<String,ServerRecord>> = Mutex::new(HashMap::new());
 }
 
-struct MpscChannelMessage(Vec<u8>, Vec<MpscChannel>, Vec<MpscSharedMemory>);
+lazy_static! {
+    static ref SERVERS: Mutex<HashMap<String,mpsc::Sender<MpscReceiver>>> =
+        Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    /// The namespace this thread names servers in; see `set_server_namespace()`.
+    static SERVER_NAMESPACE: RefCell<Option<String>> = RefCell::new(None)
+}
+
+/// Puts the servers this thread names from now on in `namespace`, as if the thread were a process
+/// with, e.g., a socket directory of its own: their names don't collide with the same names
+/// elsewhere, where they are known as `namespace/name`. Clients on the thread look a name up in
+/// its namespace first, and then among all names. Servers named by `new()` have unique names
+/// anyway, and are in no namespace.
+pub fn set_server_namespace(namespace: Option<String>) {
+    SERVER_NAMESPACE.with(|current| *current.borrow_mut() = namespace)
+}
+
+/// Returns `name` as it's known outside this thread's namespace.
+fn qualified_name(name: &str) -> String {
+    SERVER_NAMESPACE.with(|namespace| {
+        match *namespace.borrow() {
+            Some(ref namespace) => format!("{}/{}", namespace, name),
+            None => name.to_owned(),
+        }
+    })
+}
+
+/// Returns the name of the server that a client on this thread means by `name`.
+fn resolve_name(name: String) -> String {
+    let qualified_name = qualified_name(&name);
+    if qualified_name != name &&
+            (ONE_SHOT_SERVERS.lock().unwrap().contains_key(&qualified_name) ||
+             SERVERS.lock().unwrap().contains_key(&qualified_name)) {
+        return qualified_name
+    }
+    name
+}
+
+/// Identifies a message sent on a channel of a `Scheduler`: the number of its sender, and how
+/// many messages that sender had sent before it.
+type Stamp = (usize, usize);
+
+struct MpscChannelMessage(Vec<u8>, Vec<MpscChannel>, Vec<MpscSharedMemory>, Option<Stamp>);
+
+impl MpscChannelMessage {
+    fn into_parts(self) -> (Vec<u8>, Vec<OpaqueMpscChannel>, Vec<MpscSharedMemory>) {
+        let MpscChannelMessage(data, channels, shared_memory_regions, _) = self;
+        (data, channels.into_iter().map(OpaqueMpscChannel::new).collect(), shared_memory_regions)
+    }
+}
 
 pub fn channel() -> Result<(MpscSender, MpscReceiver),MpscError> {
+    Ok(new_channel(None))
+}
+
+fn new_channel(scheduler: Option<&Scheduler>) -> (MpscSender, MpscReceiver) {
     let (base_sender, base_receiver) = mpsc::channel::<MpscChannelMessage>();
-    Ok((MpscSender::new(base_sender), MpscReceiver::new(base_receiver)))
+    let channel = scheduler.map_or(0, |scheduler| {
+        scheduler.state.next_channel.fetch_add(1, Ordering::SeqCst)
+    });
+    (MpscSender::new(base_sender, scheduler.cloned()),
+     MpscReceiver::new(base_receiver, scheduler.cloned(), channel))
+}
+
+/// One delivery in a `Schedule`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    /// The channel the message was delivered on, numbered in the order that the scheduler made
+    /// channels.
+    pub channel: usize,
+    /// The message: the number of its sender, in the order that the scheduler's channels made
+    /// senders and their clones, and how many messages that sender had sent before it. `None`
+    /// where the receiver found that its senders were all gone.
+    pub message: Option<(usize, usize)>,
+}
+
+/// The order in which messages were delivered on the channels of a `Scheduler`. It can be
+/// serialized, to keep it alongside a test that it makes fail.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub deliveries: Vec<Delivery>,
+}
+
+enum ScheduleMode {
+    Recording(Vec<Delivery>),
+    Replaying(VecDeque<Delivery>),
+}
+
+struct SchedulerState {
+    mode: Mutex<ScheduleMode>,
+    /// Signalled on every delivery, for receivers waiting for their turn.
+    advanced: Condvar,
+    next_channel: AtomicUsize,
+    next_sender: AtomicUsize,
+}
+
+/// Records the order in which messages are delivered on the channels it makes, or makes them
+/// deliver in a recorded order, so that a test whose outcome depends on how its threads
+/// interleave can be rerun exactly as it ran before. Channels and senders are told apart by the
+/// order they were made in, so the test must make them in the same order every time, e.g. before
+/// it starts its threads; if it strays from the schedule, receiving panics. Once a replay runs
+/// out of deliveries, channels deliver freely again.
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<SchedulerState>,
+}
+
+/// Whose turn a receiver has.
+enum Turn {
+    /// No schedule is being replayed, so anyone's.
+    Free,
+    /// The receiver's, to receive the given message, or find that its senders are gone.
+    Ours(Option<Stamp>),
+    /// Another receiver's.
+    Theirs,
+}
+
+impl Scheduler {
+    fn new(mode: ScheduleMode) -> Scheduler {
+        Scheduler {
+            state: Arc::new(SchedulerState {
+                mode: Mutex::new(mode),
+                advanced: Condvar::new(),
+                next_channel: AtomicUsize::new(0),
+                next_sender: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns a scheduler that records the deliveries on its channels.
+    pub fn record() -> Scheduler {
+        Scheduler::new(ScheduleMode::Recording(vec![]))
+    }
+
+    /// Returns a scheduler whose channels deliver in the order of `schedule`.
+    pub fn replay(schedule: Schedule) -> Scheduler {
+        Scheduler::new(ScheduleMode::Replaying(schedule.deliveries.into_iter().collect()))
+    }
+
+    /// Returns the deliveries recorded so far, or those still to be replayed.
+    pub fn schedule(&self) -> Schedule {
+        let deliveries = match *self.state.mode.lock().unwrap() {
+            ScheduleMode::Recording(ref deliveries) => deliveries.clone(),
+            ScheduleMode::Replaying(ref deliveries) => deliveries.iter().cloned().collect(),
+        };
+        Schedule {
+            deliveries: deliveries,
+        }
+    }
+
+    pub fn channel(&self) -> Result<(MpscSender, MpscReceiver),MpscError> {
+        Ok(new_channel(Some(self)))
+    }
+
+    fn is_replaying(&self) -> bool {
+        match *self.state.mode.lock().unwrap() {
+            ScheduleMode::Recording(_) => false,
+            ScheduleMode::Replaying(ref deliveries) => !deliveries.is_empty(),
+        }
+    }
+
+    /// Returns whose turn it is to receive, waiting for `channel`'s if `block` is set.
+    fn turn(&self, channel: usize, block: bool) -> Turn {
+        let mut mode = self.state.mode.lock().unwrap();
+        loop {
+            let next = match *mode {
+                ScheduleMode::Recording(_) => None,
+                ScheduleMode::Replaying(ref deliveries) => deliveries.front().cloned(),
+            };
+            match next {
+                None => return Turn::Free,
+                Some(delivery) if delivery.channel == channel => {
+                    return Turn::Ours(delivery.message)
+                }
+                Some(_) if !block => return Turn::Theirs,
+                Some(_) => mode = self.state.advanced.wait(mode).unwrap(),
+            }
+        }
+    }
+
+    /// Notes that `message` was delivered on `channel`, and passes the turn on.
+    fn delivered(&self, channel: usize, message: Option<Stamp>) {
+        match *self.state.mode.lock().unwrap() {
+            ScheduleMode::Recording(ref mut deliveries) => {
+                deliveries.push(Delivery {
+                    channel: channel,
+                    message: message,
+                })
+            }
+            ScheduleMode::Replaying(ref mut deliveries) => drop(deliveries.pop_front()),
+        }
+        self.state.advanced.notify_all();
+    }
 }
 
 pub struct MpscReceiver {
     receiver: RefCell<Option<mpsc::Receiver<MpscChannelMessage>>>,
+    scheduler: Option<Scheduler>,
+    /// Our number among the scheduler's channels.
+    channel: usize,
+    /// Messages that arrived while a replay had us wait for an earlier one.
+    stash: RefCell<VecDeque<MpscChannelMessage>>,
 }
 
 impl PartialEq for MpscReceiver {
@@ -77,34 +299,149 @@ impl fmt::Debug for MpscReceiver {
 }
 
 impl MpscReceiver {
-    fn new(receiver: mpsc::Receiver<MpscChannelMessage>) -> MpscReceiver {
+    fn new(receiver: mpsc::Receiver<MpscChannelMessage>,
+           scheduler: Option<Scheduler>,
+           channel: usize)
+           -> MpscReceiver {
         MpscReceiver {
             receiver: RefCell::new(Some(receiver)),
+            scheduler: scheduler,
+            channel: channel,
+            stash: RefCell::new(VecDeque::new()),
         }
     }
 
     pub fn consume(&self) -> MpscReceiver {
-        let receiver = self.receiver.borrow_mut().take();
-        MpscReceiver::new(receiver.unwrap())
+        let receiver = self.receiver.borrow_mut().take().unwrap();
+        let consumed = MpscReceiver::new(receiver, self.scheduler.clone(), self.channel);
+        mem::swap(&mut *consumed.stash.borrow_mut(), &mut *self.stash.borrow_mut());
+        consumed
+    }
+
+    /// There are no other processes to inherit anything.
+    pub fn inheritable_token(&self) -> Result<String,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_inherited_token(_: &str) -> Result<MpscReceiver,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_process(_: u32, _: &str) -> Result<MpscReceiver,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_raw_fd(_: c_int) -> Result<MpscReceiver,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn peer_security_label(&self) -> Result<Option<String>,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn queued_bytes(&self) -> Result<usize,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn recv_buffer_size(&self) -> Result<usize,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn set_recv_buffer_size(&self, _: usize) -> Result<(),MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    /// Messages were allocated by their senders, in this process, before we see them.
+    pub fn set_max_message_size(&mut self, _: Option<usize>) {}
+
+    /// Channels have no identity of their own here.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        None
     }
 
     pub fn recv(&self) -> Result<(Vec<u8>, Vec<OpaqueMpscChannel>, Vec<MpscSharedMemory>),MpscError> {
-        let r = self.receiver.borrow();
-        match r.as_ref().unwrap().recv() {
-            Ok(MpscChannelMessage(d,c,s)) => Ok((d,
-                                                 c.into_iter().map(OpaqueMpscChannel::new).collect(),
-                                                 s)),
-            Err(_) => Err(MpscError::ChannelClosedError),
-        }
+        self.receive(true).map(MpscChannelMessage::into_parts)
     }
 
     pub fn try_recv(&self) -> Result<(Vec<u8>, Vec<OpaqueMpscChannel>, Vec<MpscSharedMemory>),MpscError> {
+        self.receive(false).map(MpscChannelMessage::into_parts)
+    }
+
+    fn is_replaying(&self) -> bool {
+        self.scheduler.as_ref().map_or(false, Scheduler::is_replaying)
+    }
+
+    /// Receives the next message, when the scheduler, if any, gives us the turn.
+    fn receive(&self, block: bool) -> Result<MpscChannelMessage,MpscError> {
+        let scheduler = match self.scheduler {
+            Some(ref scheduler) => scheduler,
+            None => return self.take(block),
+        };
+        match scheduler.turn(self.channel, block) {
+            Turn::Free => {
+                let result = self.take(block);
+                match result {
+                    Ok(ref message) => scheduler.delivered(self.channel, message.3),
+                    Err(MpscError::ChannelClosedError) => scheduler.delivered(self.channel, None),
+                    Err(_) => {}
+                }
+                result
+            }
+            Turn::Ours(stamp) => {
+                let result = self.take_scheduled(stamp);
+                scheduler.delivered(self.channel, stamp);
+                result
+            }
+            Turn::Theirs => Err(MpscError::WouldBlockError),
+        }
+    }
+
+    fn take(&self, block: bool) -> Result<MpscChannelMessage,MpscError> {
+        if let Some(message) = self.stash.borrow_mut().pop_front() {
+            return Ok(message)
+        }
         let r = self.receiver.borrow();
+        if block {
+            return r.as_ref().unwrap().recv().map_err(|_| MpscError::ChannelClosedError)
+        }
         match r.as_ref().unwrap().try_recv() {
-            Ok(MpscChannelMessage(d,c,s)) => Ok((d,
-                                                 c.into_iter().map(OpaqueMpscChannel::new).collect(),
-                                                 s)),
-            Err(_) => Err(MpscError::ChannelClosedError),
+            Ok(message) => Ok(message),
+            Err(mpsc::TryRecvError::Empty) => Err(MpscError::WouldBlockError),
+            Err(mpsc::TryRecvError::Disconnected) => Err(MpscError::ChannelClosedError),
+        }
+    }
+
+    /// Takes the message stamped `stamp`, setting aside those that arrive before it; or, for
+    /// `None`, waits for the senders to be gone.
+    fn take_scheduled(&self, stamp: Option<Stamp>) -> Result<MpscChannelMessage,MpscError> {
+        let position = self.stash.borrow().iter().position(|message| {
+            stamp.is_some() && message.3 == stamp
+        });
+        if let Some(position) = position {
+            return Ok(self.stash.borrow_mut().remove(position).unwrap())
+        }
+        loop {
+            let result = self.receiver.borrow().as_ref().unwrap().recv();
+            match (result, stamp) {
+                (Ok(message), Some(_)) => {
+                    if message.3 == stamp {
+                        return Ok(message)
+                    }
+                    self.stash.borrow_mut().push_back(message)
+                }
+                (Err(_), None) if self.stash.borrow().is_empty() => {
+                    return Err(MpscError::ChannelClosedError)
+                }
+                _ => panic!("channel {} strayed from the schedule being replayed", self.channel),
+            }
         }
     }
 }
@@ -112,9 +449,20 @@ impl MpscReceiver {
 unsafe impl Send for MpscReceiver { }
 unsafe impl Sync for MpscReceiver { }
 
-#[derive(Clone)]
 pub struct MpscSender {
     sender: RefCell<mpsc::Sender<MpscChannelMessage>>,
+    scheduler: Option<Scheduler>,
+    /// Our number among the senders of the scheduler's channels.
+    id: usize,
+    /// How many messages we sent, to stamp them for the scheduler.
+    sent: AtomicUsize,
+}
+
+impl Clone for MpscSender {
+    /// A clone is a sender of its own, as far as the scheduler is concerned.
+    fn clone(&self) -> MpscSender {
+        MpscSender::new(self.sender.borrow().clone(), self.scheduler.clone())
+    }
 }
 
 impl PartialEq for MpscSender {
@@ -136,16 +484,92 @@ unsafe impl Send for MpscSender { }
 unsafe impl Sync for MpscSender { }
 
 impl MpscSender {
-    fn new(sender: mpsc::Sender<MpscChannelMessage>) -> MpscSender {
+    fn new(sender: mpsc::Sender<MpscChannelMessage>, scheduler: Option<Scheduler>) -> MpscSender {
+        let id = scheduler.as_ref().map_or(0, |scheduler| {
+            scheduler.state.next_sender.fetch_add(1, Ordering::SeqCst)
+        });
         MpscSender {
             sender: RefCell::new(sender),
+            scheduler: scheduler,
+            id: id,
+            sent: AtomicUsize::new(0),
         }
     }
 
+    fn stamp(&self) -> Option<Stamp> {
+        self.scheduler.as_ref().map(|_| (self.id, self.sent.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    /// There are no other processes to inherit anything.
+    pub fn inheritable_token(&self) -> Result<String,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_inherited_token(_: &str) -> Result<MpscSender,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_process(_: u32, _: &str) -> Result<MpscSender,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn from_raw_fd(_: c_int) -> Result<MpscSender,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn queued_bytes(&self) -> Result<usize,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    /// An mpsc sender can't tell without sending.
+    pub fn receiver_is_closed(&self) -> Result<bool,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn send_buffer_size(&self) -> Result<usize,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn set_send_buffer_size(&self, _: usize) -> Result<(),MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        None
+    }
+
     pub fn connect(name: String) -> Result<MpscSender,MpscError> {
-        let record = ONE_SHOT_SERVERS.lock().unwrap().remove(&name).unwrap();
-        record.connect();
-        Ok(record.sender)
+        // Windows has no named pipe backend yet, so pipes on other machines, named like
+        // `\\server\pipe\name`, can't be reached. Say so, rather than that no such server
+        // exists; the TCP transport is the way to other machines for now.
+        if name.starts_with(r"\\") && !name.starts_with(r"\\.\") {
+            return Err(MpscError::UnsupportedError)
+        }
+
+        let name = resolve_name(name);
+        let record = ONE_SHOT_SERVERS.lock().unwrap().remove(&name);
+        if let Some(record) = record {
+            record.connect();
+            return Ok(record.sender)
+        }
+
+        let connection_sender = match SERVERS.lock().unwrap().get(&name) {
+            Some(connection_sender) => connection_sender.clone(),
+            None => return Err(MpscError::ServerNotFoundError),
+        };
+        let (sender, receiver) = try!(channel());
+        match connection_sender.send(receiver) {
+            Err(_) => Err(MpscError::ChannelClosedError),
+            Ok(_) => Ok(sender),
+        }
     }
 
     pub fn send(&self,
@@ -154,11 +578,42 @@ impl MpscSender {
                 shared_memory_regions: Vec<MpscSharedMemory>)
                 -> Result<(),MpscError>
     {
-        match self.sender.borrow().send(MpscChannelMessage(data.to_vec(), ports, shared_memory_regions)) {
+        let message =
+            MpscChannelMessage(data.to_vec(), ports, shared_memory_regions, self.stamp());
+        match self.sender.borrow().send(message) {
+            Err(_) => Err(MpscError::ChannelClosedError),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    pub fn send_vectored(&self,
+                         data: &[&[u8]],
+                         ports: Vec<MpscChannel>,
+                         shared_memory_regions: Vec<MpscSharedMemory>)
+                         -> Result<(),MpscError> {
+        let mut buffer = Vec::with_capacity(data.iter().fold(0, |size, slice| size + slice.len()));
+        for slice in data {
+            buffer.extend_from_slice(slice);
+        }
+        let message = MpscChannelMessage(buffer, ports, shared_memory_regions, self.stamp());
+        match self.sender.borrow().send(message) {
             Err(_) => Err(MpscError::ChannelClosedError),
             Ok(_) => Ok(()),
         }
     }
+
+    pub fn send_batch(&self, messages: Vec<(Vec<u8>, Vec<MpscChannel>, Vec<MpscSharedMemory>)>)
+                      -> Result<(),MpscError> {
+        // The messages are already ours, so hand them over without copying them.
+        let sender = self.sender.borrow();
+        for (data, ports, shared_memory_regions) in messages {
+            let message = MpscChannelMessage(data, ports, shared_memory_regions, self.stamp());
+            if sender.send(message).is_err() {
+                return Err(MpscError::ChannelClosedError)
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct MpscReceiverSet {
@@ -184,6 +639,14 @@ impl MpscReceiverSet {
     }
 
     pub fn select(&mut self) -> Result<Vec<MpscSelectionResult>,MpscError> {
+        // A receiver replaying a schedule may have to wait for its turn, which an mpsc select
+        // knows nothing of; and the messages it set aside aren't in its channel any more.
+        if self.receivers.iter().any(|receiver| {
+            receiver.is_replaying() || !receiver.stash.borrow().is_empty()
+        }) {
+            return self.poll()
+        }
+
         let mut receivers: Vec<Option<mpsc::Receiver<MpscChannelMessage>>> = Vec::with_capacity(self.receivers.len());
         let mut r_id: i64 = -1;
         let mut r_index: usize = 0;
@@ -239,6 +702,31 @@ impl MpscReceiverSet {
     }
 }
 
+impl MpscReceiverSet {
+    /// Selects by trying each receiver in turn, until one has something for us.
+    fn poll(&mut self) -> Result<Vec<MpscSelectionResult>,MpscError> {
+        loop {
+            for index in 0..self.receivers.len() {
+                let id = self.receiver_ids[index] as i64;
+                match self.receivers[index].try_recv() {
+                    Ok((data, channels, shmems)) => {
+                        let result = MpscSelectionResult::DataReceived(id, data, channels, shmems);
+                        return Ok(vec![result])
+                    }
+                    Err(MpscError::ChannelClosedError) => {
+                        self.receivers.remove(index);
+                        self.receiver_ids.remove(index);
+                        return Ok(vec![MpscSelectionResult::ChannelClosed(id)])
+                    }
+                    Err(MpscError::WouldBlockError) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
 pub enum MpscSelectionResult {
     DataReceived(i64, Vec<u8>, Vec<OpaqueMpscChannel>, Vec<MpscSharedMemory>),
     ChannelClosed(i64),
@@ -257,11 +745,49 @@ impl MpscSelectionResult {
     }
 }
 
+/// Servers only exist in memory, so there is no directory to configure.
+pub fn set_server_socket_directory(_: &str) {}
+
+/// Messages are handed over as they are, so there are no buffers to pool.
+pub fn set_receive_buffer_pool_size(_: usize) {}
+
+/// Messages are handed over as they are, so there is nothing to send out of line.
+pub fn set_out_of_line_threshold(_: Option<usize>) {}
+
+/// Waiting on an MPSC channel isn't a system call that a signal could interrupt.
+pub fn set_retry_on_interrupt(_: bool) {}
+
+/// See `MpscReceiver::set_max_message_size()`.
+pub fn set_receive_memory_budget(_: Option<usize>) {}
+
+/// Messages are handed over whole, so there are no fragments to wait for.
+pub fn set_reassembly_timeout(_: Option<Duration>) {}
+
+pub fn set_thread_affinity(_: &[usize]) -> Result<(),MpscError> {
+    Err(MpscError::UnsupportedError)
+}
+
+pub fn set_thread_priority(_: i32) -> Result<(),MpscError> {
+    Err(MpscError::UnsupportedError)
+}
+
 pub struct MpscOneShotServer {
+    id: Uuid,
     receiver: RefCell<Option<MpscReceiver>>,
     name: String,
 }
 
+impl Drop for MpscOneShotServer {
+    fn drop(&mut self) {
+        // Unless a client already took our record, remove it, so the name can be reused. By now
+        // another server may have taken the name over, though.
+        let mut one_shot_servers = ONE_SHOT_SERVERS.lock().unwrap();
+        if one_shot_servers.get(&self.name).map_or(false, |record| record.server_id == self.id) {
+            one_shot_servers.remove(&self.name);
+        }
+    }
+}
+
 impl MpscOneShotServer {
     pub fn new() -> Result<(MpscOneShotServer, String),MpscError> {
         let (sender, receiver) = match channel() {
@@ -269,15 +795,38 @@ impl MpscOneShotServer {
             Err(err) => return Err(err),
         };
 
+        let id = Uuid::new_v4();
         let name = Uuid::new_v4().to_string();
-        let record = ServerRecord::new(sender);
+        let record = ServerRecord::new(id, sender);
         ONE_SHOT_SERVERS.lock().unwrap().insert(name.clone(), record);
         Ok((MpscOneShotServer {
+            id: id,
             receiver: RefCell::new(Some(receiver)),
             name: name.clone(),
         },name.clone()))
     }
 
+    pub fn with_name(name: &str) -> Result<MpscOneShotServer,MpscError> {
+        let name = qualified_name(name);
+        let (sender, receiver) = try!(channel());
+        let mut one_shot_servers = ONE_SHOT_SERVERS.lock().unwrap();
+        if one_shot_servers.contains_key(&name) || SERVERS.lock().unwrap().contains_key(&name) {
+            return Err(MpscError::NameInUseError)
+        }
+        let id = Uuid::new_v4();
+        one_shot_servers.insert(name.clone(), ServerRecord::new(id, sender));
+        Ok(MpscOneShotServer {
+            id: id,
+            receiver: RefCell::new(Some(receiver)),
+            name: name,
+        })
+    }
+
+    /// Servers can't be reached from other processes anyway, so the mode is ignored.
+    pub fn with_name_and_mode(name: &str, _: u32) -> Result<MpscOneShotServer,MpscError> {
+        MpscOneShotServer::with_name(name)
+    }
+
     pub fn accept(&self) -> Result<(MpscReceiver,
                                     Vec<u8>,
                                     Vec<OpaqueMpscChannel>,
@@ -288,12 +837,148 @@ impl MpscOneShotServer {
         let (data, channels, shmems) = receiver.recv().unwrap();
         Ok((receiver, data, channels, shmems))
     }
+
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<(MpscReceiver,
+                                                               Vec<u8>,
+                                                               Vec<OpaqueMpscChannel>,
+                                                               Vec<MpscSharedMemory>),MpscError>
+    {
+        // The client's first message tells us that it connected.
+        let deadline = Instant::now() + timeout;
+        let receiver = self.receiver.borrow_mut().take().unwrap();
+        loop {
+            let result = receiver.receiver.borrow().as_ref().unwrap().try_recv();
+            match result {
+                Ok(message) => {
+                    let (data, channels, shmems) = message.into_parts();
+                    return Ok((receiver, data, channels, shmems))
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return Err(MpscError::ChannelClosedError),
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(MpscError::TimedOutError)
+            }
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(1)));
+        }
+    }
+}
+
+/// A server that can accept any number of clients, each on its own channel.
+pub struct MpscServer {
+    connection_receiver: mpsc::Receiver<MpscReceiver>,
+    name: String,
+}
+
+impl Drop for MpscServer {
+    fn drop(&mut self) {
+        SERVERS.lock().unwrap().remove(&self.name);
+    }
+}
+
+impl MpscServer {
+    pub fn new() -> Result<(MpscServer, String),MpscError> {
+        let (connection_sender, connection_receiver) = mpsc::channel();
+        let name = Uuid::new_v4().to_string();
+        SERVERS.lock().unwrap().insert(name.clone(), connection_sender);
+        Ok((MpscServer {
+            connection_receiver: connection_receiver,
+            name: name.clone(),
+        }, name))
+    }
+
+    pub fn with_name(name: &str) -> Result<MpscServer,MpscError> {
+        let name = qualified_name(name);
+        let (connection_sender, connection_receiver) = mpsc::channel();
+        let one_shot_servers = ONE_SHOT_SERVERS.lock().unwrap();
+        let mut servers = SERVERS.lock().unwrap();
+        if one_shot_servers.contains_key(&name) || servers.contains_key(&name) {
+            return Err(MpscError::NameInUseError)
+        }
+        servers.insert(name.clone(), connection_sender);
+        Ok(MpscServer {
+            connection_receiver: connection_receiver,
+            name: name,
+        })
+    }
+
+    /// Servers can't be reached from other processes anyway, so the mode is ignored.
+    pub fn with_name_and_mode(name: &str, _: u32) -> Result<MpscServer,MpscError> {
+        MpscServer::with_name(name)
+    }
+
+    pub fn accept(&self) -> Result<(MpscReceiver,
+                                    Vec<u8>,
+                                    Vec<OpaqueMpscChannel>,
+                                    Vec<MpscSharedMemory>),MpscError>
+    {
+        let receiver = match self.connection_receiver.recv() {
+            Ok(receiver) => receiver,
+            Err(_) => return Err(MpscError::ChannelClosedError),
+        };
+        let (data, channels, shmems) = try!(receiver.recv());
+        Ok((receiver, data, channels, shmems))
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub enum MpscChannel {
     Sender(MpscSender),
     Receiver(MpscReceiver),
+    /// A Windows handle sent along with a message; see `MpscHandle`.
+    #[cfg(windows)]
+    Handle(MpscHandle),
+}
+
+impl MpscChannel {
+    /// Messages never leave the process, so there is no kernel to pass descriptors on.
+    pub fn from_fd(_: c_int) -> Result<MpscChannel,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+}
+
+/// A Windows handle on its way to a receiver. Messages never leave the process, so the handle is
+/// passed on as it is. It's closed if the message is dropped without being received.
+#[cfg(windows)]
+#[derive(PartialEq, Debug)]
+pub struct MpscHandle {
+    handle: RawHandle,
+}
+
+// Handles are valid on every thread of the process that owns them.
+#[cfg(windows)]
+unsafe impl Send for MpscHandle {}
+
+#[cfg(windows)]
+impl Drop for MpscHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl MpscHandle {
+    /// Takes ownership of `handle`.
+    pub fn new(handle: RawHandle) -> MpscHandle {
+        MpscHandle {
+            handle: handle,
+        }
+    }
+
+    /// Gives up ownership of the handle, leaving the caller to close it.
+    pub fn into_raw_handle(self) -> RawHandle {
+        let handle = self.handle;
+        mem::forget(self);
+        handle
+    }
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn CloseHandle(handle: RawHandle) -> i32;
 }
 
 #[derive(PartialEq, Debug)]
@@ -302,7 +987,7 @@ pub struct OpaqueMpscChannel {
 }
 
 impl OpaqueMpscChannel {
-    fn new(channel: MpscChannel) -> OpaqueMpscChannel {
+    pub fn new(channel: MpscChannel) -> OpaqueMpscChannel {
         OpaqueMpscChannel {
             channel: RefCell::new(Some(channel))
         }
@@ -311,7 +996,9 @@ impl OpaqueMpscChannel {
     pub fn to_receiver(&self) -> MpscReceiver {
         match self.channel.borrow_mut().take().unwrap() {
             MpscChannel::Sender(_) => panic!("Opaque channel is not a receiver!"),
-            MpscChannel::Receiver(r) => r
+            MpscChannel::Receiver(r) => r,
+            #[cfg(windows)]
+            MpscChannel::Handle(_) => panic!("Opaque channel is not a receiver!"),
         }
     }
     
@@ -319,6 +1006,30 @@ impl OpaqueMpscChannel {
         match self.channel.borrow_mut().take().unwrap() {
             MpscChannel::Sender(s) => s,
             MpscChannel::Receiver(_) => panic!("Opaque channel is not a sender!"),
+            #[cfg(windows)]
+            MpscChannel::Handle(_) => panic!("Opaque channel is not a sender!"),
+        }
+    }
+
+    pub fn to_channel(&self) -> Result<MpscChannel,MpscError> {
+        Ok(self.channel.borrow_mut().take().unwrap())
+    }
+
+    pub fn to_fd(&self) -> Result<c_int,MpscError> {
+        Err(MpscError::UnsupportedError)
+    }
+
+    /// Takes out the handle that was sent in this channel's place. Other channels aren't handles,
+    /// and fail.
+    #[cfg(windows)]
+    pub fn to_handle(&self) -> Result<RawHandle,MpscError> {
+        let mut channel = self.channel.borrow_mut();
+        match channel.take() {
+            Some(MpscChannel::Handle(handle)) => Ok(handle.into_raw_handle()),
+            other => {
+                *channel = other;
+                Err(MpscError::UnsupportedError)
+            }
         }
     }
 }
@@ -386,11 +1097,28 @@ impl MpscSharedMemory {
             data: v
         }
     }
+
+    /// Reads `length` bytes from the current position of `file` into a new region.
+    pub fn from_file(file: &File, length: usize) -> Result<MpscSharedMemory,Error> {
+        let mut bytes = vec![0; length];
+        try!((&*file).read_exact(&mut bytes[..]));
+        Ok(MpscSharedMemory::from_bytes(&bytes[..]))
+    }
+
+    /// Regions have no identity of their own here.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        None
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum MpscError {
     ChannelClosedError,
+    NameInUseError,
+    ServerNotFoundError,
+    TimedOutError,
+    WouldBlockError,
+    UnsupportedError,
     UnknownError,
 }
 
@@ -399,6 +1127,10 @@ impl MpscError {
     pub fn channel_is_closed(&self) -> bool {
         *self == MpscError::ChannelClosedError
     }
+
+    pub fn is_unsupported(&self) -> bool {
+        *self == MpscError::UnsupportedError
+    }
 }
 
 impl From<MpscError> for DeserializeError {
@@ -413,6 +1145,19 @@ impl From<MpscError> for Error {
             MpscError::ChannelClosedError => {
                 Error::new(ErrorKind::BrokenPipe, "MPSC channel closed")
             }
+            MpscError::NameInUseError => {
+                Error::new(ErrorKind::AddrInUse, "MPSC server name already in use")
+            }
+            MpscError::ServerNotFoundError => {
+                Error::new(ErrorKind::NotFound, "No MPSC server with this name")
+            }
+            MpscError::TimedOutError => Error::new(ErrorKind::TimedOut, "MPSC operation timed out"),
+            MpscError::WouldBlockError => {
+                Error::new(ErrorKind::WouldBlock, "No message is waiting in the MPSC channel")
+            }
+            MpscError::UnsupportedError => {
+                Error::new(ErrorKind::Other, "Not supported by MPSC channels")
+            }
             MpscError::UnknownError => Error::new(ErrorKind::Other, "Other MPSC channel error"),
         }
     }
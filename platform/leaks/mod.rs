@@ -0,0 +1,237 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bookkeeping of the OS handles that backends create, for tracking down leaks: an endpoint that
+//! is never dropped keeps its descriptor open, and enough of them make every later call fail with
+//! `EMFILE`. With the `leak-tracking` feature, every descriptor the Unix backend opens for a
+//! channel, server, receiver set or shared memory region is recorded along with a backtrace of
+//! where it was made, until it's closed. Taking the backtrace makes opening one much slower, so
+//! without the feature nothing is recorded, and no handles are reported.
+//!
+//! Each handle is also recorded in the scope of the thread that made it: one of its own per
+//! thread, or the innermost `LeakScope` open on it. A test can then tell its own handles from
+//! those of tests running alongside it.
+//!
+//! Descriptors are recorded by number, so one that is closed behind the backend's back, e.g.
+//! after `into_raw_fd()` handed it to other code, stays on record until its number is reused.
+
+#[cfg(feature = "leak-tracking")]
+use backtrace::Backtrace;
+#[cfg(feature = "leak-tracking")]
+use std::cell::Cell;
+#[cfg(feature = "leak-tracking")]
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+#[cfg(feature = "leak-tracking")]
+use std::sync::Mutex;
+#[cfg(feature = "leak-tracking")]
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+/// What a live handle is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandleKind {
+    /// Either end of a channel, or one received in a message but not yet taken out of it.
+    Channel,
+    /// The listening socket of a named or one-shot server.
+    Server,
+    /// A receiver that was added to a receiver set.
+    ReceiverSet,
+    /// A shared memory region, with its mapping.
+    SharedMemory,
+}
+
+/// A handle that was still open when `live_handles()` or `LeakCheckpoint::leaks()` was called.
+pub struct LiveHandle {
+    pub kind: HandleKind,
+    /// The descriptor number.
+    pub handle: i64,
+    /// Where the handle was created.
+    pub backtrace: String,
+    sequence: usize,
+    scope: usize,
+}
+
+impl Debug for LiveHandle {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        try!(writeln!(formatter, "{:?} handle {}, created at:", self.kind, self.handle));
+        write!(formatter, "{}", self.backtrace)
+    }
+}
+
+#[cfg(feature = "leak-tracking")]
+struct Record {
+    sequence: usize,
+    scope: usize,
+    backtrace: Backtrace,
+}
+
+#[cfg(feature = "leak-tracking")]
+lazy_static! {
+    static ref LIVE_HANDLES: Mutex<HashMap<(HandleKind, i64),Record>> = Mutex::new(HashMap::new());
+}
+
+/// Counts handles as they are created, so that a checkpoint can tell which came after it.
+#[cfg(feature = "leak-tracking")]
+static NEXT_SEQUENCE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(feature = "leak-tracking")]
+static NEXT_SCOPE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(feature = "leak-tracking")]
+thread_local! {
+    /// The scope that handles made on this thread are recorded in.
+    static SCOPE: Cell<usize> = Cell::new(NEXT_SCOPE.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Records that `handle` was just created.
+#[cfg(feature = "leak-tracking")]
+pub fn track(kind: HandleKind, handle: i64) {
+    let record = Record {
+        sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst),
+        scope: SCOPE.with(|scope| scope.get()),
+        backtrace: Backtrace::new(),
+    };
+    LIVE_HANDLES.lock().unwrap().insert((kind, handle), record);
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+#[inline]
+pub fn track(_: HandleKind, _: i64) {}
+
+/// Records that `handle` was just closed, or given up.
+#[cfg(feature = "leak-tracking")]
+pub fn untrack(kind: HandleKind, handle: i64) {
+    LIVE_HANDLES.lock().unwrap().remove(&(kind, handle));
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+#[inline]
+pub fn untrack(_: HandleKind, _: i64) {}
+
+/// Returns every handle that is open, oldest first.
+#[cfg(feature = "leak-tracking")]
+pub fn live_handles() -> Vec<LiveHandle> {
+    let mut live_handles: Vec<LiveHandle> =
+        LIVE_HANDLES.lock().unwrap().iter().map(|(&(kind, handle), record)| {
+            LiveHandle {
+                kind: kind,
+                handle: handle,
+                backtrace: format!("{:?}", record.backtrace),
+                sequence: record.sequence,
+                scope: record.scope,
+            }
+        }).collect();
+    live_handles.sort_by_key(|live_handle| live_handle.sequence);
+    live_handles
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+pub fn live_handles() -> Vec<LiveHandle> {
+    vec![]
+}
+
+/// Remembers which handles were already open, so that those created afterwards can be checked
+/// for leaks: e.g. take a checkpoint before a test or a unit of work, and check that nothing it
+/// created is still open after it's done.
+#[derive(Debug)]
+pub struct LeakCheckpoint {
+    sequence: usize,
+}
+
+impl LeakCheckpoint {
+    #[cfg(feature = "leak-tracking")]
+    pub fn new() -> LeakCheckpoint {
+        LeakCheckpoint {
+            sequence: NEXT_SEQUENCE.load(Ordering::SeqCst),
+        }
+    }
+
+    #[cfg(not(feature = "leak-tracking"))]
+    pub fn new() -> LeakCheckpoint {
+        LeakCheckpoint {
+            sequence: 0,
+        }
+    }
+
+    /// Returns the handles created since the checkpoint that are still open, oldest first.
+    pub fn leaks(&self) -> Vec<LiveHandle> {
+        live_handles().into_iter()
+                      .filter(|live_handle| live_handle.sequence >= self.sequence)
+                      .collect()
+    }
+}
+
+/// Returns the handles made in this thread's current scope that are still open, oldest first.
+#[cfg(feature = "leak-tracking")]
+pub fn scope_leaks() -> Vec<LiveHandle> {
+    let scope = SCOPE.with(|scope| scope.get());
+    live_handles().into_iter().filter(|live_handle| live_handle.scope == scope).collect()
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+pub fn scope_leaks() -> Vec<LiveHandle> {
+    vec![]
+}
+
+/// Records the handles this thread makes in a scope of their own, until it's dropped. Handles
+/// made by other threads, including ones this thread spawns, are not in the scope.
+#[derive(Debug)]
+pub struct LeakScope {
+    scope: usize,
+    previous: usize,
+    /// Scopes belong to the thread that opened them.
+    phantom: PhantomData<*mut ()>,
+}
+
+impl LeakScope {
+    #[cfg(feature = "leak-tracking")]
+    pub fn new() -> LeakScope {
+        let scope = NEXT_SCOPE.fetch_add(1, Ordering::SeqCst);
+        let previous = SCOPE.with(|current| {
+            let previous = current.get();
+            current.set(scope);
+            previous
+        });
+        LeakScope {
+            scope: scope,
+            previous: previous,
+            phantom: PhantomData,
+        }
+    }
+
+    #[cfg(not(feature = "leak-tracking"))]
+    pub fn new() -> LeakScope {
+        LeakScope {
+            scope: 0,
+            previous: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the handles made in this scope that are still open, oldest first.
+    pub fn leaks(&self) -> Vec<LiveHandle> {
+        live_handles().into_iter().filter(|live_handle| live_handle.scope == self.scope).collect()
+    }
+}
+
+impl Drop for LeakScope {
+    fn drop(&mut self) {
+        restore_scope(self.previous)
+    }
+}
+
+#[cfg(feature = "leak-tracking")]
+fn restore_scope(previous: usize) {
+    SCOPE.with(|scope| scope.set(previous))
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+#[inline]
+fn restore_scope(_: usize) {}
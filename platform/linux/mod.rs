@@ -7,21 +7,74 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! Channels are pairs of Unix domain sockets of type `SOCK_SEQPACKET`, so the kernel preserves
+//! message boundaries for us: every `recvmsg()` returns exactly one packet, and there is no
+//! length-prefix framing to parse. Only messages bigger than the socket's send buffer are split
+//! into fragments; see `UnixSender::send()`. `SOCK_SEQPACKET` has been supported for Unix domain
+//! sockets since Linux 2.6.4, so there is nothing to fall back to.
+//!
+//! A fragmented message is still delivered whole or not at all. If its sender gives up partway
+//! through, the receiver drops the fragments it got and fails with `EBADMSG`, or with
+//! `EOWNERDEAD` if the sending process died; and the channel goes on with the next message.
+//!
+//! When the kernel is short of buffer space, as the BSDs and macOS often are with big packets,
+//! fragments are made smaller; a message that still can't go out is moved into shared memory,
+//! and trailing fragments wait for room.
+//!
+//! `MSG_ZEROCOPY` is no use here: the kernel only supports it for TCP and UDP sockets, and
+//! refuses `SO_ZEROCOPY` on Unix domain sockets. To keep multi-megabyte messages from being copied
+//! through the socket, send them in shared memory instead; `ipc::set_shared_memory_threshold()`
+//! does that automatically.
+//!
+//! This backend serves Android too, where shared memory regions are ashmem rather than files.
+//!
+//! It serves FreeBSD, OpenBSD and NetBSD as well. There, receiver sets wait on a kqueue rather
+//! than `poll()`ing every socket, shared memory regions are anonymous POSIX shared memory objects,
+//! and servers' sockets always live on the filesystem, since only Linux has abstract socket names.
+//! The BSDs lay out control messages differently from Linux, so `SCM_RIGHTS` messages are always
+//! built and taken apart with `CMSG_LEN()`, `CMSG_SPACE()` and `CMSG_DATA()`.
+//!
+//! It serves illumos and Solaris too, which have no abstract socket names either, and use POSIX
+//! shared memory. Their receiver sets `poll()` as on Linux, but without `recvmmsg()` and
+//! `sendmmsg()`, messages are taken off and handed over one at a time. Control messages there
+//! are aligned to an `int` rather than a `long`.
+//!
+//! QNX Neutrino is served through its Unix domain socket emulation in `io-pkt`, which descends
+//! from NetBSD's network stack and shares its constants and its `LOCAL_PEEREID` credentials. As on
+//! illumos, receiver sets `poll()`, messages go one at a time, and shared memory regions are named
+//! POSIX shared memory objects that are unlinked straight away.
+//!
+//! Haiku's socket layer has Unix domain sockets with `SCM_RIGHTS` and `SO_PEERCRED` much like
+//! Linux, though with constants of its own. It is handled like QNX otherwise, with one message at a
+//! time and named shared memory objects.
+
 use bincode::serde::DeserializeError;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use libc::{self, MAP_SHARED, PROT_READ, PROT_WRITE, c_char, c_int, c_short, c_ulong};
-use libc::{c_ushort, c_void, mode_t, off_t, size_t, sockaddr, sockaddr_un, socklen_t, ssize_t};
+use byteorder::{ByteOrder, LittleEndian};
+use libc::{self, MAP_SHARED, PROT_READ, PROT_WRITE, c_char, c_int, c_short, c_uint, c_ulong};
+use libc::{c_void, mode_t, off_t, size_t, sockaddr, sockaddr_un, socklen_t, ssize_t};
+use platform::leaks::{self, HandleKind};
+use std::cell::RefCell;
 use std::cmp;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ffi::{CStr, CString};
 use std::fmt::{self, Debug, Formatter};
-use std::io::{Error, Write};
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+#[cfg(not(target_os="linux"))]
+use std::io::Read;
 use std::mem;
 use std::ops::Deref;
+#[cfg(target_os="linux")]
+use std::os::unix::io::AsRawFd;
 use std::ptr;
 use std::slice;
-use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
+use std::usize;
+use uuid::Uuid;
 
 const MAX_FDS_IN_CMSG: u32 = 64;
 
@@ -30,6 +83,53 @@ const MAP_FAILED: *mut u8 = (!0usize) as *mut u8;
 
 static LAST_FRAGMENT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
+/// Whether blocking calls interrupted by a signal fail with `EINTR`, rather than being made again.
+static FAIL_ON_INTERRUPT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How sends keep a peer that hung up from raising `SIGPIPE`: 0 for the platform's default, or
+/// one more than a `SigpipeStrategy`.
+static SIGPIPE_STRATEGY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How many bytes all the messages being reassembled at once may take, or 0 for no limit.
+static RECEIVE_MEMORY_BUDGET: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How many bytes of `RECEIVE_MEMORY_BUDGET` messages being reassembled are taking.
+static RECEIVE_MEMORY_IN_USE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How long a receiver waits for each further fragment of a message, in milliseconds, or 0 to
+/// wait as long as the sender is alive.
+static REASSEMBLY_TIMEOUT_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How often a receiver waiting for the next fragment of a message checks that its sender is
+/// still alive.
+const SENDER_CHECK_INTERVAL_MS: c_int = 100;
+
+/// When the kernel can't find buffer space for a fragment, it's halved, down to this size. Below
+/// it, smaller packets are no more likely to get through.
+const MIN_FRAGMENT_SIZE: usize = 2000;
+
+/// How many times a fragment of the minimum size is retried while the kernel is short of buffer
+/// space, waiting twice as long each time, starting from `BUFFER_SHORTAGE_DELAY_MS`.
+const BUFFER_SHORTAGE_RETRIES: u32 = 8;
+const BUFFER_SHORTAGE_DELAY_MS: u64 = 1;
+
+/// Every packet starts with the ID of the fragment it holds and the ID of the next one, as
+/// little-endian `u32`s. The next ID is 0 in the last fragment, and messages that fit in a single
+/// packet have no others.
+///
+/// The first fragment has no ID of its own. Instead, it counts the descriptors it carries that are
+/// channels, or other descriptors embedded in the message; the shared memory regions come after
+/// them. The kernel passes on every kind of descriptor alike, so only the sender can tell which
+/// is which.
+const FRAGMENT_HEADER_SIZE: usize = 8;
+
+fn fragment_header(this_fragment_id: u32, next_fragment_id: u32) -> [u8; FRAGMENT_HEADER_SIZE] {
+    let mut header = [0; FRAGMENT_HEADER_SIZE];
+    LittleEndian::write_u32(&mut header[0..4], this_fragment_id);
+    LittleEndian::write_u32(&mut header[4..8], next_fragment_id);
+    header
+}
+
 pub fn channel() -> Result<(UnixSender, UnixReceiver),UnixError> {
     let mut results = [0, 0];
     unsafe {
@@ -44,10 +144,13 @@ pub fn channel() -> Result<(UnixSender, UnixReceiver),UnixError> {
 #[derive(PartialEq, Debug)]
 pub struct UnixReceiver {
     fd: c_int,
+    /// The largest message this receiver takes; see `set_max_message_size()`.
+    max_message_size: Option<usize>,
 }
 
 impl Drop for UnixReceiver {
     fn drop(&mut self) {
+        leaks::untrack(HandleKind::Channel, self.fd as i64);
         unsafe {
             //assert!(libc::close(self.fd) == 0)
             libc::close(self.fd);
@@ -57,8 +160,10 @@ impl Drop for UnixReceiver {
 
 impl UnixReceiver {
     fn from_fd(fd: c_int) -> UnixReceiver {
+        leaks::track(HandleKind::Channel, fd as i64);
         UnixReceiver {
             fd: fd,
+            max_message_size: None,
         }
     }
 
@@ -72,14 +177,113 @@ impl UnixReceiver {
         UnixReceiver::from_fd(self.consume_fd())
     }
 
+    pub fn inheritable_token(&self) -> Result<String,UnixError> {
+        make_inheritable(self.fd)
+    }
+
+    pub fn from_inherited_token(token: &str) -> Result<UnixReceiver,UnixError> {
+        Ok(UnixReceiver::from_fd(try!(take_inherited(token))))
+    }
+
+    pub fn from_process(pid: u32, token: &str) -> Result<UnixReceiver,UnixError> {
+        Ok(UnixReceiver::from_fd(try!(take_from_process(pid, token))))
+    }
+
+    /// Adopts a socket made elsewhere; see `adopt_fd()`.
+    pub fn from_raw_fd(fd: c_int) -> Result<UnixReceiver,UnixError> {
+        Ok(UnixReceiver::from_fd(try!(adopt_fd(fd))))
+    }
+
+    /// Gives up the socket without closing it.
+    pub fn into_raw_fd(self) -> Result<c_int,UnixError> {
+        let fd = self.fd;
+        leaks::untrack(HandleKind::Channel, fd as i64);
+        mem::forget(self);
+        Ok(fd)
+    }
+
+    /// Returns the pid, uid and gid of the process that connected this socket -- or, for a
+    /// channel made with `channel()`, of the process that created it.
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),UnixError> {
+        peer_credentials(self.fd)
+    }
+
+    /// Identifies this receiver among the live channels of this process. This is the same ID
+    /// that `UnixReceiverSet::add()` returns for it.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        Some(self.fd as i64)
+    }
+
+    /// Returns the SELinux or AppArmor label of the peer process, or `None` if no security
+    /// module that labels sockets is active.
+    #[cfg(any(target_os="linux", target_os="android"))]
+    pub fn peer_security_label(&self) -> Result<Option<String>,UnixError> {
+        let mut label = vec![0u8; 256];
+        loop {
+            let mut label_len = label.len() as socklen_t;
+            let result = unsafe {
+                getsockopt(self.fd,
+                           SOL_SOCKET,
+                           SO_PEERSEC,
+                           label.as_mut_ptr() as *mut c_void,
+                           &mut label_len as *mut socklen_t)
+            };
+            if result == 0 {
+                label.truncate(label_len as usize);
+                // Some security modules include the terminating NUL, some don't.
+                if label.last() == Some(&0) {
+                    label.pop();
+                }
+                return Ok(Some(String::from_utf8_lossy(&label).into_owned()))
+            }
+            match UnixError::last() {
+                UnixError(libc::ENOPROTOOPT) => return Ok(None),
+                // The kernel told us how much room the label needs.
+                UnixError(libc::ERANGE) if label_len as usize > label.len() => {
+                    label.resize(label_len as usize, 0)
+                }
+                error => return Err(error),
+            }
+        }
+    }
+
+    /// Only Linux labels sockets.
+    #[cfg(not(any(target_os="linux", target_os="android")))]
+    pub fn peer_security_label(&self) -> Result<Option<String>,UnixError> {
+        Ok(None)
+    }
+
+    /// Returns the size of the receive buffer, which is also the largest packet we can take.
+    pub fn recv_buffer_size(&self) -> Result<usize,UnixError> {
+        buffer_size(self.fd, libc::SO_RCVBUF)
+    }
+
+    /// Returns how many bytes are waiting to be received.
+    pub fn queued_bytes(&self) -> Result<usize,UnixError> {
+        queued_bytes(self.fd, FIONREAD)
+    }
+
+    /// Asks the kernel for a receive buffer of `size` bytes; the same caveats apply as for
+    /// `UnixSender::set_send_buffer_size()`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(),UnixError> {
+        set_buffer_size(self.fd, libc::SO_RCVBUF, size)
+    }
+
+    /// Fails to receive messages bigger than `size` with `EMSGSIZE`, checking the length a
+    /// fragmented message claims before allocating room for it. The limit belongs to this
+    /// receiver, and doesn't travel with it to other processes.
+    pub fn set_max_message_size(&mut self, size: Option<usize>) {
+        self.max_message_size = size
+    }
+
     pub fn recv(&self)
                 -> Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),UnixError> {
-        recv(self.fd, BlockingMode::Blocking)
+        recv(self.fd, BlockingMode::Blocking, self.max_message_size)
     }
 
     pub fn try_recv(&self)
                     -> Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),UnixError> {
-        recv(self.fd, BlockingMode::Nonblocking)
+        recv(self.fd, BlockingMode::Nonblocking, self.max_message_size)
     }
 }
 
@@ -90,6 +294,7 @@ pub struct UnixSender {
 
 impl Drop for UnixSender {
     fn drop(&mut self) {
+        leaks::untrack(HandleKind::Channel, self.fd as i64);
         unsafe {
             let result = libc::close(self.fd);
             assert!(thread::panicking() || result == 0);
@@ -100,220 +305,342 @@ impl Drop for UnixSender {
 impl Clone for UnixSender {
     fn clone(&self) -> UnixSender {
         unsafe {
-            UnixSender {
-                fd: libc::dup(self.fd)
-            }
+            UnixSender::from_fd(libc::dup(self.fd))
         }
     }
 }
 
 impl UnixSender {
     fn from_fd(fd: c_int) -> UnixSender {
+        leaks::track(HandleKind::Channel, fd as i64);
         UnixSender {
             fd: fd,
         }
     }
 
+    pub fn inheritable_token(&self) -> Result<String,UnixError> {
+        make_inheritable(self.fd)
+    }
+
+    pub fn from_inherited_token(token: &str) -> Result<UnixSender,UnixError> {
+        Ok(UnixSender::from_fd(try!(take_inherited(token))))
+    }
+
+    pub fn from_process(pid: u32, token: &str) -> Result<UnixSender,UnixError> {
+        Ok(UnixSender::from_fd(try!(take_from_process(pid, token))))
+    }
+
+    /// Adopts a socket made elsewhere; see `adopt_fd()`.
+    pub fn from_raw_fd(fd: c_int) -> Result<UnixSender,UnixError> {
+        Ok(UnixSender::from_fd(try!(adopt_fd(fd))))
+    }
+
+    /// Gives up the socket without closing it.
+    pub fn into_raw_fd(self) -> Result<c_int,UnixError> {
+        let fd = self.fd;
+        leaks::untrack(HandleKind::Channel, fd as i64);
+        mem::forget(self);
+        Ok(fd)
+    }
+
+    /// Returns the pid, uid and gid of the process this sender is connected to -- or, for a
+    /// channel made with `channel()`, of the process that created it.
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),UnixError> {
+        peer_credentials(self.fd)
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        Some(self.fd as i64)
+    }
+
     /// Maximum total data size that can be transferred over this channel in a single packet.
     pub fn get_maximum_send_size(&self) -> Result<usize,UnixError> {
+        buffer_size(self.fd, libc::SO_SNDBUF)
+    }
+
+    pub fn send_buffer_size(&self) -> Result<usize,UnixError> {
+        self.get_maximum_send_size()
+    }
+
+    /// Returns how many of the bytes we sent the receiver has yet to pick up.
+    #[cfg(any(target_os="linux", target_os="android"))]
+    pub fn queued_bytes(&self) -> Result<usize,UnixError> {
+        queued_bytes(self.fd, TIOCOUTQ)
+    }
+
+    /// Outside Linux, what we send goes straight into the receiving socket's buffer, and there is
+    /// no asking the sending end how much of it is still there.
+    #[cfg(not(any(target_os="linux", target_os="android")))]
+    pub fn queued_bytes(&self) -> Result<usize,UnixError> {
+        Err(UnixError(libc::EOPNOTSUPP))
+    }
+
+    /// Returns true once the receiving end has been closed, which hangs up our socket.
+    pub fn receiver_is_closed(&self) -> Result<bool,UnixError> {
+        let mut pollfd = pollfd {
+            fd: self.fd,
+            events: 0,
+            revents: 0,
+        };
         unsafe {
-            let mut maximum_send_size: usize = 0;
-            let mut maximum_send_size_len = mem::size_of::<usize>() as socklen_t;
-            if getsockopt(self.fd,
-                          libc::SOL_SOCKET,
-                          libc::SO_SNDBUF,
-                          &mut maximum_send_size as *mut usize as *mut c_void,
-                          &mut maximum_send_size_len as *mut socklen_t) < 0 {
+            if retry_on_interrupt(|| poll(&mut pollfd, 1, 0)) < 0 {
                 return Err(UnixError::last())
             }
-            Ok(maximum_send_size)
         }
+        Ok((pollfd.revents & POLLHUP) != 0)
+    }
+
+    /// Asks the kernel for a send buffer of `size` bytes. Linux doubles the size to allow for
+    /// bookkeeping, and clamps it to `net.core.wmem_max`; so read it back with
+    /// `send_buffer_size()` to see what we got.
+    ///
+    /// Since packets are sized to fit the send buffer, the receiving end must have a receive
+    /// buffer at least as large, or big messages will fail to come through.
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(),UnixError> {
+        set_buffer_size(self.fd, libc::SO_SNDBUF, size)
     }
 
     pub fn send(&self,
                 data: &[u8],
                 mut channels: Vec<UnixChannel>,
-                shared_memory_regions: Vec<UnixSharedMemory>)
+                mut shared_memory_regions: Vec<UnixSharedMemory>)
                 -> Result<(),UnixError> {
-        let mut data_buffer = vec![0; data.len() + mem::size_of::<u32>() * 2];
-        {
-            let mut data_buffer = &mut data_buffer[..];
-            data_buffer.write_u32::<LittleEndian>(0u32).unwrap();
-            data_buffer.write_u32::<LittleEndian>(0u32).unwrap();
-            data_buffer.write(data).unwrap();
+        let mut downsize = false;
+        let header = fragment_header(channels.len() as u32, 0);
+        match send_packet(self.fd, &[&header[..], data], &channels, &shared_memory_regions) {
+            Ok(()) => return Ok(()),
+            Err(ref error) if error.is_buffer_shortage() => {
+                // If we get this error,
+                // it means the message was small enough to fit the maximum send size,
+                // but the kernel failed to allocate a buffer large enough
+                // to actually transfer the message --
+                // so we have to proceed with a fragmented send nevertheless.
+                //
+                // The flag indicates that packets need to be smaller
+                // than the ordinary maximum send size.
+                downsize = true;
+            }
+            Err(UnixError(libc::EMSGSIZE)) => {}
+            Err(error) => return Err(error),
         }
 
-        unsafe {
-            unsafe fn construct_header(channels: &[UnixChannel],
-                                       shared_memory_regions: &[UnixSharedMemory],
-                                       data_buffer: &[u8])
-                                       -> (msghdr, Box<iovec>) {
-                let cmsg_length =
-                    (channels.len() + shared_memory_regions.len()) * mem::size_of::<c_int>();
-                let cmsg_buffer = libc::malloc(CMSG_SPACE(cmsg_length as size_t)) as *mut cmsghdr;
-                (*cmsg_buffer).cmsg_len = CMSG_LEN(cmsg_length as size_t);
-                (*cmsg_buffer).cmsg_level = libc::SOL_SOCKET;
-                (*cmsg_buffer).cmsg_type = SCM_RIGHTS;
-
-                let mut fds = Vec::new();
-                for channel in channels.iter() {
-                    fds.push(channel.fd());
-                }
-                for shared_memory_region in shared_memory_regions.iter() {
-                    fds.push(shared_memory_region.fd);
-                }
-                ptr::copy_nonoverlapping(fds.as_ptr(),
-                                         cmsg_buffer.offset(1) as *mut _ as *mut c_int,
-                                         fds.len());
-
-                // Put this on the heap so address remains stable across function return.
-                let mut iovec = Box::new(iovec {
-                    iov_base: data_buffer.as_ptr() as *const c_char as *mut c_char,
-                    iov_len: data_buffer.len() as size_t,
-                });
-
-                let msghdr = msghdr {
-                    msg_name: ptr::null_mut(),
-                    msg_namelen: 0,
-                    msg_iov: &mut *iovec,
-                    msg_iovlen: 1,
-                    msg_control: cmsg_buffer as *mut c_void,
-                    msg_controllen: CMSG_SPACE(cmsg_length as size_t),
-                    msg_flags: 0,
-                };
+        // The packet is too big. Fragmentation time!
+        //
+        // Create dedicated channel to send all but the first fragment.
+        // This way we avoid fragments of different messages interleaving in the receiver.
+        //
+        // The receiver end of the channel is sent with the first fragment
+        // along any other file descriptors that are to be transferred in the message.
+        //
+        // Each fragment is sent straight out of `data`, without copying it anywhere first.
+        let (dedicated_tx, dedicated_rx) = try!(channel());
+        channels.push(UnixChannel::Receiver(dedicated_rx));
+
+        let cmsg_length = (channels.len() + shared_memory_regions.len()) * mem::size_of::<c_int>();
+        let mut bytes_per_fragment = try!(self.get_maximum_send_size())
+                                     - (FRAGMENT_HEADER_SIZE + mem::size_of::<u64>()
+                                        + CMSG_SPACE(cmsg_length as size_t) as usize + 256);
+
+        // The first fragment starts with the length of the whole message, so that the receiver
+        // can make room for all of it up front.
+        let mut total_length = [0; 8];
+        LittleEndian::write_u64(&mut total_length, data.len() as u64);
+
+        // Split up the packet into fragments. The first one counts the channels instead of
+        // having an ID.
+        let mut byte_position = 0;
+        let mut this_fragment_id = channels.len() as u32;
+        let mut shortage_retries = 0;
+        while byte_position < data.len() {
+            if downsize {
+                // We got ENOBUFS. Retry send with half the packet size.
+                bytes_per_fragment /= 2;
+                downsize = false;
+            }
 
-                // Be sure to always return iovec -- whether the caller uses it or not --
-                // to prevent premature deallocation!
-                (msghdr, iovec)
+            let end_byte_position = cmp::min(data.len(), byte_position + bytes_per_fragment);
+            let next_fragment_id = if end_byte_position == data.len() {
+                0
+            } else {
+                (LAST_FRAGMENT_ID.fetch_add(1, Ordering::SeqCst) + 1) as u32
             };
 
-            let (msghdr, _iovec) = construct_header(&channels, &shared_memory_regions, &data_buffer);
-
-            let result = sendmsg(self.fd, &msghdr, 0);
-            libc::free(msghdr.msg_control);
-
-            let mut downsize = false;
-
-            if result > 0 {
-                return Ok(())
+            let header = fragment_header(this_fragment_id, next_fragment_id);
+            let fragment = &data[byte_position..end_byte_position];
+            let result = if byte_position == 0 {
+                // First one. This fragment includes the file descriptors.
+                send_packet(self.fd,
+                            &[&header[..], &total_length[..], fragment],
+                            &channels,
+                            &shared_memory_regions)
             } else {
-                let error = UnixError::last();
-                if error.0 == libc::ENOBUFS {
-                    // If we get this error,
-                    // it means the message was small enough to fit the maximum send size,
-                    // but the kernel failed to allocate a buffer large enough
-                    // to actually transfer the message --
-                    // so we have to proceed with a fragmented send nevertheless.
-                    //
-                    // The flag indicates that packets need to be smaller
-                    // than the ordinary maximum send size.
+                // Trailing fragment.
+                send_packet(dedicated_tx.fd, &[&header[..], fragment], &[], &[])
+            };
+
+            if let Err(error) = result {
+                if !error.is_buffer_shortage() {
+                    return Err(error)
+                }
+                if fragment.len() > MIN_FRAGMENT_SIZE {
+                    // If the kernel failed to allocate a buffer large enough for the packet,
+                    // retry with a smaller size.
                     downsize = true;
-                } else if error.0 != libc::EMSGSIZE {
+                    continue
+                }
+                if byte_position == 0 {
+                    // Not even a small packet gets through, but nothing has gone out yet: move
+                    // the data into shared memory, where it costs the socket no buffer space, the
+                    // same way `ipc::set_shared_memory_threshold()` does.
+                    drop(channels.pop());
+                    shared_memory_regions.push(UnixSharedMemory::from_bytes(data));
+                    let header = fragment_header(channels.len() as u32, 0);
+                    return send_packet(self.fd, &[&header[..]], &channels, &shared_memory_regions)
+                }
+                // The rest of the message has to follow on the dedicated channel, so wait for
+                // the kernel to free up some buffer space.
+                if shortage_retries == BUFFER_SHORTAGE_RETRIES {
                     return Err(error)
                 }
+                thread::sleep(Duration::from_millis(BUFFER_SHORTAGE_DELAY_MS << shortage_retries));
+                shortage_retries += 1;
+                continue
             }
 
-            // The packet is too big. Fragmentation time!
-            //
-            // Create dedicated channel to send all but the first fragment.
-            // This way we avoid fragments of different messages interleaving in the receiver.
-            //
-            // The receiver end of the channel is sent with the first fragment
-            // along any other file descriptors that are to be transferred in the message.
-            let (dedicated_tx, dedicated_rx) = try!(channel());
-            channels.push(UnixChannel::Receiver(dedicated_rx));
-            let (msghdr, mut iovec) = construct_header(&channels, &shared_memory_regions, &data_buffer);
-
-            let mut bytes_per_fragment = try!(self.get_maximum_send_size())
-                                         - (mem::size_of::<u32>() * 2
-                                            + msghdr.msg_controllen as usize + 256);
-
-            // Split up the packet into fragments.
-            let mut byte_position = 0;
-            let mut this_fragment_id = 0;
-            while byte_position < data.len() {
-                if downsize {
-                    // We got ENOBUFS. Retry send with half the packet size.
-                    bytes_per_fragment /= 2;
-                    downsize = false;
-                }
+            byte_position = end_byte_position;
+            this_fragment_id = next_fragment_id;
+            shortage_retries = 0;
+        }
 
-                let end_byte_position = cmp::min(data.len(), byte_position + bytes_per_fragment);
-                let next_fragment_id = if end_byte_position == data.len() {
-                    0
-                } else {
-                    (LAST_FRAGMENT_ID.fetch_add(1, Ordering::SeqCst) + 1) as u32
-                };
+        Ok(())
+    }
 
-                {
-                    let mut data_buffer = &mut data_buffer[..];
-                    data_buffer.write_u32::<LittleEndian>(this_fragment_id).unwrap();
-                    data_buffer.write_u32::<LittleEndian>(next_fragment_id).unwrap();
-                    data_buffer.write(&data[byte_position..end_byte_position]).unwrap();
-                }
+    /// Like `send()`, but gathers the data from several slices, so that the caller doesn't have
+    /// to concatenate them first. Only messages too big for a single packet get concatenated,
+    /// since fragmenting them needs the data in one piece anyway.
+    pub fn send_vectored(&self,
+                         data: &[&[u8]],
+                         channels: Vec<UnixChannel>,
+                         shared_memory_regions: Vec<UnixSharedMemory>)
+                         -> Result<(),UnixError> {
+        let header = fragment_header(channels.len() as u32, 0);
+        let mut slices = Vec::with_capacity(data.len() + 1);
+        slices.push(&header[..]);
+        slices.extend_from_slice(data);
+        match send_packet(self.fd, &slices[..], &channels, &shared_memory_regions) {
+            Ok(()) => return Ok(()),
+            Err(UnixError(libc::EMSGSIZE)) => {}
+            Err(ref error) if error.is_buffer_shortage() => {}
+            Err(error) => return Err(error),
+        }
 
-                let bytes_to_send = end_byte_position - byte_position + mem::size_of::<u32>() * 2;
-                let result = if byte_position == 0 {
-                    // First one. This fragment includes the file descriptors.
-
-                    // Better reset this in case `data_buffer` moved around -- iterator
-                    // invalidation!
-                    iovec.iov_base = data_buffer.as_ptr() as *const c_char as *mut c_char;
-                    iovec.iov_len = bytes_to_send as size_t;
-
-                    sendmsg(self.fd, &msghdr, 0)
-                } else {
-                    // Trailing fragment.
-                    libc::send(dedicated_tx.fd,
-                               data_buffer.as_ptr() as *const c_void,
-                               bytes_to_send as size_t,
-                               0)
-                };
+        let mut buffer = Vec::with_capacity(data.iter().fold(0, |size, slice| size + slice.len()));
+        for slice in data {
+            buffer.extend_from_slice(slice);
+        }
+        self.send(&buffer[..], channels, shared_memory_regions)
+    }
 
-                if result <= 0 {
-                    let error = UnixError::last();
-                    if error.0 == libc::ENOBUFS && bytes_to_send > 2000 {
-                        // If the kernel failed to allocate a buffer large enough for the packet,
-                        // retry with a smaller size.
-                        //
-                        // (If the packet was already significantly smaller
-                        // than the memory page size though,
-                        // if means something else must have gone wrong;
-                        // so there is no point in further downsizing,
-                        // and we error out instead.)
-                        downsize = true;
+    /// Sends several messages in order, handing as many of them as possible to the kernel in a
+    /// single `sendmmsg()` call. Messages too big for a single packet are fragmented as usual.
+    #[cfg(not(any(target_os="illumos", target_os="solaris", target_os="nto",
+                  target_os="haiku")))]
+    pub fn send_batch(&self,
+                      mut messages: Vec<(Vec<u8>, Vec<UnixChannel>, Vec<UnixSharedMemory>)>)
+                      -> Result<(),UnixError> {
+        // Each message is its own first and last fragment.
+        let headers: Vec<_> = messages.iter().map(|&(_, ref channels, _)| {
+            fragment_header(channels.len() as u32, 0)
+        }).collect();
+        let mut iovecs = Vec::with_capacity(messages.len() * 2);
+        for (header, &(ref data, _, _)) in headers.iter().zip(messages.iter()) {
+            iovecs.push(iovec {
+                iov_base: header.as_ptr() as *const c_char as *mut c_char,
+                iov_len: header.len() as size_t,
+            });
+            iovecs.push(iovec {
+                iov_base: data.as_ptr() as *const c_char as *mut c_char,
+                iov_len: data.len() as size_t,
+            });
+        }
+
+        unsafe {
+            let mut mmsghdrs = Vec::with_capacity(messages.len());
+            for (index, &(_, ref channels, ref shared_memory_regions)) in
+                    messages.iter().enumerate() {
+                let (cmsg_buffer, cmsg_space) = construct_cmsg(channels, shared_memory_regions);
+                mmsghdrs.push(mmsghdr {
+                    msg_hdr: msghdr {
+                        msg_name: ptr::null_mut(),
+                        msg_namelen: 0,
+                        msg_iov: iovecs.as_mut_ptr().offset(index as isize * 2),
+                        msg_iovlen: 2,
+                        msg_control: cmsg_buffer as *mut c_void,
+                        msg_controllen: cmsg_space,
+                        msg_flags: 0,
+                    },
+                    msg_len: 0,
+                });
+            }
+
+            let mut result = Ok(());
+            let mut sent = 0;
+            while sent < mmsghdrs.len() {
+                let count = send_without_sigpipe(self.fd, |flags| {
+                    sendmmsg(self.fd,
+                             mmsghdrs.as_mut_ptr().offset(sent as isize),
+                             (mmsghdrs.len() - sent) as MmsgCount,
+                             flags)
+                });
+                let error = match count {
+                    Ok(count) if count > 0 => {
+                        sent += count as usize;
                         continue
-                    } else {
-                        libc::free(msghdr.msg_control);
-                        return Err(error)
                     }
+                    Ok(_) => UnixError::last(),
+                    Err(error) => error,
+                };
+                if error.0 != libc::EMSGSIZE && !error.is_buffer_shortage() {
+                    result = Err(error);
+                    break
+                }
+                // The next message needs fragmenting.
+                let channels = mem::replace(&mut messages[sent].1, vec![]);
+                let shared_memory_regions = mem::replace(&mut messages[sent].2, vec![]);
+                result = self.send(&messages[sent].0[..], channels, shared_memory_regions);
+                if result.is_err() {
+                    break
                 }
+                sent += 1;
+            }
 
-                byte_position += bytes_per_fragment;
-                this_fragment_id = next_fragment_id;
+            for mmsghdr in mmsghdrs.iter() {
+                libc::free(mmsghdr.msg_hdr.msg_control);
             }
+            result
+        }
+    }
 
-            libc::free(msghdr.msg_control);
-            Ok(())
+    /// There's no `sendmmsg()` on illumos, QNX or Haiku, so the messages go out one at a time.
+    #[cfg(any(target_os="illumos", target_os="solaris", target_os="nto", target_os="haiku"))]
+    pub fn send_batch(&self,
+                      messages: Vec<(Vec<u8>, Vec<UnixChannel>, Vec<UnixSharedMemory>)>)
+                      -> Result<(),UnixError> {
+        for (data, channels, shared_memory_regions) in messages {
+            try!(self.send(&data[..], channels, shared_memory_regions));
         }
+        Ok(())
     }
 
     pub fn connect(name: String) -> Result<UnixSender,UnixError> {
-        let name = CString::new(name).unwrap();
+        let path = CString::new(socket_path(&name)).unwrap();
         unsafe {
             let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
-            let mut sockaddr = sockaddr_un {
-                sun_family: libc::AF_UNIX as u16,
-                sun_path: [ 0; 108 ],
-            };
-            libc::strncpy(sockaddr.sun_path.as_mut_ptr(),
-                          name.as_ptr(),
-                          sockaddr.sun_path.len() as size_t - 1);
-
-            let len = mem::size_of::<c_short>() +
-                (libc::strlen(sockaddr.sun_path.as_ptr()) as usize);
-            if libc::connect(fd, &sockaddr as *const _ as *const sockaddr, len as socklen_t) < 0 {
-                return Err(UnixError::last())
+            let (sockaddr, len) = sockaddr_for_path(&path);
+            if libc::connect(fd, &sockaddr as *const _ as *const sockaddr, len) < 0 {
+                let error = UnixError::last();
+                libc::close(fd);
+                return Err(error)
             }
 
             Ok(UnixSender::from_fd(fd))
@@ -328,6 +655,12 @@ pub enum UnixChannel {
 }
 
 impl UnixChannel {
+    /// Wraps an arbitrary file descriptor, taking ownership of it, so that it can be sent along
+    /// with a message. The kernel passes any kind of descriptor on just as it does sockets.
+    pub fn from_fd(fd: c_int) -> Result<UnixChannel,UnixError> {
+        Ok(UnixChannel::Sender(UnixSender::from_fd(fd)))
+    }
+
     fn fd(&self) -> c_int {
         match *self {
             UnixChannel::Sender(ref sender) => sender.fd,
@@ -336,14 +669,21 @@ impl UnixChannel {
     }
 }
 
+#[cfg(not(any(target_os="freebsd", target_os="openbsd", target_os="netbsd")))]
 pub struct UnixReceiverSet {
     pollfds: Vec<pollfd>,
+    /// The message size limits of the receivers that had one.
+    max_message_sizes: HashMap<c_int,usize>,
+    /// Buffers for `recv_batch()`, kept around from one `select()` to the next.
+    batch_buffers: Vec<Vec<u8>>,
 }
 
+#[cfg(not(any(target_os="freebsd", target_os="openbsd", target_os="netbsd")))]
 impl Drop for UnixReceiverSet {
     fn drop(&mut self) {
         unsafe {
             for pollfd in self.pollfds.iter() {
+                leaks::untrack(HandleKind::ReceiverSet, pollfd.fd as i64);
                 let result = libc::close(pollfd.fd);
                 assert!(thread::panicking() || result == 0);
             }
@@ -351,15 +691,22 @@ impl Drop for UnixReceiverSet {
     }
 }
 
+#[cfg(not(any(target_os="freebsd", target_os="openbsd", target_os="netbsd")))]
 impl UnixReceiverSet {
     pub fn new() -> Result<UnixReceiverSet,UnixError> {
         Ok(UnixReceiverSet {
             pollfds: Vec::new(),
+            max_message_sizes: HashMap::new(),
+            batch_buffers: Vec::new(),
         })
     }
 
     pub fn add(&mut self, receiver: UnixReceiver) -> Result<i64,UnixError> {
         let fd = receiver.consume_fd();
+        leaks::track(HandleKind::ReceiverSet, fd as i64);
+        if let Some(size) = receiver.max_message_size {
+            self.max_message_sizes.insert(fd, size);
+        }
         self.pollfds.push(pollfd {
             fd: fd,
             events: POLLIN,
@@ -370,8 +717,11 @@ impl UnixReceiverSet {
 
     pub fn select(&mut self) -> Result<Vec<UnixSelectionResult>,UnixError> {
         let mut selection_results = Vec::new();
-        let result = unsafe {
-            poll(self.pollfds.as_mut_ptr(), self.pollfds.len() as nfds_t, -1)
+        let result = {
+            let pollfds = &mut self.pollfds;
+            retry_on_interrupt(|| unsafe {
+                poll(pollfds.as_mut_ptr(), pollfds.len() as nfds_t, -1)
+            })
         };
         if result <= 0 {
             return Err(UnixError::last())
@@ -380,20 +730,25 @@ impl UnixReceiverSet {
         let mut hangups = HashSet::new();
         for pollfd in self.pollfds.iter_mut() {
             if (pollfd.revents & POLLIN) != 0 {
-                match recv(pollfd.fd, BlockingMode::Blocking) {
-                    Ok((data, channels, shared_memory_regions)) => {
-                        selection_results.push(UnixSelectionResult::DataReceived(
-                                pollfd.fd as i64,
-                                data,
-                                channels,
-                                shared_memory_regions));
+                let max_message_size = self.max_message_sizes.get(&pollfd.fd).cloned();
+                match recv_batch(pollfd.fd, &mut self.batch_buffers, max_message_size) {
+                    Ok(messages) => {
+                        for message in messages {
+                            selection_results.push(selection_result(pollfd.fd, message))
+                        }
                     }
-                    Err(err) if err.channel_is_closed() => {
+                    Err(err) => {
+                        // The socket itself is broken, so there's no getting anything more out
+                        // of it.
+                        if !err.channel_is_closed() {
+                            selection_results.push(UnixSelectionResult::ReceiveFailed(
+                                        pollfd.fd as i64,
+                                        err));
+                        }
                         hangups.insert(pollfd.fd);
                         selection_results.push(UnixSelectionResult::ChannelClosed(
                                     pollfd.fd as i64))
                     }
-                    Err(err) => return Err(err),
                 }
                 pollfd.revents = pollfd.revents & !POLLIN
             }
@@ -401,6 +756,126 @@ impl UnixReceiverSet {
 
         if !hangups.is_empty() {
             self.pollfds.retain(|pollfd| !hangups.contains(&pollfd.fd));
+            for &fd in &hangups {
+                self.max_message_sizes.remove(&fd);
+                leaks::untrack(HandleKind::ReceiverSet, fd as i64);
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+
+        Ok(selection_results)
+    }
+}
+
+/// On the BSDs, the sockets of a receiver set are registered with a kqueue once, when they're
+/// added, rather than handed to the kernel again on every `select()`.
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+pub struct UnixReceiverSet {
+    kqueue: c_int,
+    fds: HashSet<c_int>,
+    /// The message size limits of the receivers that had one.
+    max_message_sizes: HashMap<c_int,usize>,
+    /// Buffers for `recv_batch()`, kept around from one `select()` to the next.
+    batch_buffers: Vec<Vec<u8>>,
+}
+
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+impl Drop for UnixReceiverSet {
+    fn drop(&mut self) {
+        unsafe {
+            for &fd in self.fds.iter() {
+                leaks::untrack(HandleKind::ReceiverSet, fd as i64);
+                let result = libc::close(fd);
+                assert!(thread::panicking() || result == 0);
+            }
+            let result = libc::close(self.kqueue);
+            assert!(thread::panicking() || result == 0);
+        }
+    }
+}
+
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+impl UnixReceiverSet {
+    pub fn new() -> Result<UnixReceiverSet,UnixError> {
+        let kqueue = unsafe {
+            kqueue()
+        };
+        if kqueue < 0 {
+            return Err(UnixError::last())
+        }
+        Ok(UnixReceiverSet {
+            kqueue: kqueue,
+            fds: HashSet::new(),
+            max_message_sizes: HashMap::new(),
+            batch_buffers: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, receiver: UnixReceiver) -> Result<i64,UnixError> {
+        let fd = receiver.consume_fd();
+        unsafe {
+            let mut change: kevent = mem::zeroed();
+            change.ident = fd as usize;
+            change.filter = EVFILT_READ;
+            change.flags = EV_ADD;
+            if kevent(self.kqueue, &change, 1, ptr::null_mut(), 0, ptr::null()) < 0 {
+                let error = UnixError::last();
+                libc::close(fd);
+                return Err(error)
+            }
+        }
+        leaks::track(HandleKind::ReceiverSet, fd as i64);
+        self.fds.insert(fd);
+        if let Some(size) = receiver.max_message_size {
+            self.max_message_sizes.insert(fd, size);
+        }
+        Ok(fd as i64)
+    }
+
+    pub fn select(&mut self) -> Result<Vec<UnixSelectionResult>,UnixError> {
+        let mut selection_results = Vec::new();
+        let mut events: Vec<kevent> = Vec::with_capacity(cmp::max(self.fds.len(), 1));
+        unsafe {
+            let count = retry_on_interrupt(|| {
+                kevent(self.kqueue,
+                       ptr::null(),
+                       0,
+                       events.as_mut_ptr(),
+                       events.capacity() as KeventCount,
+                       ptr::null())
+            });
+            if count <= 0 {
+                return Err(UnixError::last())
+            }
+            events.set_len(count as usize);
+        }
+
+        for event in events.iter() {
+            let fd = event.ident as c_int;
+            let max_message_size = self.max_message_sizes.get(&fd).cloned();
+            match recv_batch(fd, &mut self.batch_buffers, max_message_size) {
+                Ok(messages) => {
+                    for message in messages {
+                        selection_results.push(selection_result(fd, message))
+                    }
+                }
+                Err(err) => {
+                    // As above, a broken socket is closed.
+                    if !err.channel_is_closed() {
+                        selection_results.push(UnixSelectionResult::ReceiveFailed(fd as i64, err))
+                    }
+                    // Closing the socket takes it out of the kqueue too.
+                    self.fds.remove(&fd);
+                    self.max_message_sizes.remove(&fd);
+                    leaks::untrack(HandleKind::ReceiverSet, fd as i64);
+                    unsafe {
+                        libc::close(fd);
+                    }
+                    selection_results.push(UnixSelectionResult::ChannelClosed(fd as i64))
+                }
+            }
         }
 
         Ok(selection_results)
@@ -410,6 +885,22 @@ impl UnixReceiverSet {
 pub enum UnixSelectionResult {
     DataReceived(i64, Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),
     ChannelClosed(i64),
+    /// A message couldn't be received. If the socket is still usable, the receiver carries on
+    /// with the next message; otherwise a `ChannelClosed` for it follows.
+    ReceiveFailed(i64, UnixError),
+}
+
+/// Turns one of the messages that `recv_batch()` took off `fd` into a selection result.
+fn selection_result(fd: c_int,
+                    message: Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),
+                                    UnixError>)
+                    -> UnixSelectionResult {
+    match message {
+        Ok((data, channels, shared_memory_regions)) => {
+            UnixSelectionResult::DataReceived(fd as i64, data, channels, shared_memory_regions)
+        }
+        Err(err) => UnixSelectionResult::ReceiveFailed(fd as i64, err),
+    }
 }
 
 impl UnixSelectionResult {
@@ -421,6 +912,9 @@ impl UnixSelectionResult {
             UnixSelectionResult::ChannelClosed(id) => {
                 panic!("UnixSelectionResult::unwrap(): receiver ID {} was closed!", id)
             }
+            UnixSelectionResult::ReceiveFailed(id, err) => {
+                panic!("UnixSelectionResult::unwrap(): receiver ID {} failed: {:?}", id, err)
+            }
         }
     }
 }
@@ -432,6 +926,7 @@ pub struct OpaqueUnixChannel {
 
 impl Drop for OpaqueUnixChannel {
     fn drop(&mut self) {
+        leaks::untrack(HandleKind::Channel, self.fd as i64);
         unsafe {
             libc::close(self.fd); 
         }
@@ -440,6 +935,7 @@ impl Drop for OpaqueUnixChannel {
 
 impl OpaqueUnixChannel {
     fn from_fd(fd: c_int) -> OpaqueUnixChannel {
+        leaks::track(HandleKind::Channel, fd as i64);
         OpaqueUnixChannel {
             fd: fd,
         }
@@ -456,135 +952,569 @@ impl OpaqueUnixChannel {
             UnixReceiver::from_fd(libc::dup(self.fd))
         }
     }
+
+    /// Converts this channel into one that can be sent along with another message.
+    ///
+    /// On Unix both ends of a channel are just sockets, so it makes no difference which variant
+    /// we pick here.
+    pub fn to_channel(&mut self) -> Result<UnixChannel,UnixError> {
+        Ok(UnixChannel::Sender(self.to_sender()))
+    }
+
+    /// Returns a new descriptor for whatever this channel refers to, which need not be a socket.
+    pub fn to_fd(&mut self) -> Result<c_int,UnixError> {
+        let fd = unsafe {
+            libc::dup(self.fd)
+        };
+        if fd < 0 {
+            return Err(UnixError::last())
+        }
+        Ok(fd)
+    }
 }
 
 pub struct UnixOneShotServer {
     fd: c_int,
+    path: CString,
 }
 
 impl Drop for UnixOneShotServer {
     fn drop(&mut self) {
-        unsafe {
-            let result = libc::close(self.fd);
-            assert!(thread::panicking() || result == 0);
-        }
+        close_server_socket(self.fd, &self.path)
     }
 }
 
 impl UnixOneShotServer {
     pub fn new() -> Result<(UnixOneShotServer, String),UnixError> {
-        unsafe {
-            let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
-            let mut path: Vec<u8>;
-            loop {
-                let path_string = CString::new(b"/tmp/rust-ipc-socket.XXXXXX" as &[u8]).unwrap();
-                path = path_string.as_bytes_with_nul().iter().cloned().collect();
-                if *mktemp(path.as_mut_ptr() as *mut c_char) == 0 {
-                    return Err(UnixError::last())
-                }
-
-                let mut sockaddr = sockaddr_un {
-                    sun_family: libc::AF_UNIX as c_ushort,
-                    sun_path: [ 0; 108 ],
-                };
-                libc::strncpy(sockaddr.sun_path.as_mut_ptr(),
-                              path.as_ptr() as *const c_char,
-                              sockaddr.sun_path.len() as size_t - 1);
-
-                let len = mem::size_of::<c_short>() + (libc::strlen(sockaddr.sun_path.as_ptr()) as
-                                                       usize);
-                if libc::bind(fd, &sockaddr as *const _ as *const sockaddr, len as socklen_t) == 0 {
-                    break
-                }
-
-                let errno = UnixError::last();
-                if errno.0 != libc::EINVAL {
-                    return Err(errno)
-                }
-            }
+        let (fd, path) = try!(bind_server_socket(None, None));
+        let name = String::from_utf8(path.as_bytes().to_vec()).unwrap();
+        Ok((UnixOneShotServer {
+            fd: fd,
+            path: path,
+        }, name))
+    }
 
-            if libc::listen(fd, 10) != 0 {
-                return Err(UnixError::last())
-            }
+    pub fn with_name(name: &str) -> Result<UnixOneShotServer,UnixError> {
+        let (fd, path) = try!(bind_server_socket(Some(name), None));
+        Ok(UnixOneShotServer {
+            fd: fd,
+            path: path,
+        })
+    }
 
-            Ok((UnixOneShotServer {
-                fd: fd,
-            }, String::from_utf8(CStr::from_ptr(path.as_ptr() as
-                                                *const c_char).to_bytes().to_owned()).unwrap()))
-        }
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<UnixOneShotServer,UnixError> {
+        let (fd, path) = try!(bind_server_socket(Some(name), Some(mode as mode_t)));
+        Ok(UnixOneShotServer {
+            fd: fd,
+            path: path,
+        })
     }
 
     pub fn accept(self) -> Result<(UnixReceiver,
                                    Vec<u8>,
                                    Vec<OpaqueUnixChannel>,
                                    Vec<UnixSharedMemory>),UnixError> {
-        unsafe {
-            let sockaddr: *mut sockaddr = ptr::null_mut();
-            let sockaddr_len: *mut socklen_t = ptr::null_mut();
-            let client_fd = libc::accept(self.fd, sockaddr, sockaddr_len);
-            if client_fd < 0 {
-                return Err(UnixError::last())
-            }
-            try!(make_socket_lingering(client_fd));
-
-            let receiver = UnixReceiver {
-                fd: client_fd,
-            };
-            let (data, channels, shared_memory_regions) = try!(receiver.recv());
-            Ok((receiver, data, channels, shared_memory_regions))
-        }
+        accept_client(self.fd, None)
     }
-}
 
-// Make sure that the kernel doesn't return errors to readers if there's still data left after we
-// close our end.
-//
-// See, for example, https://github.com/servo/ipc-channel/issues/29
-fn make_socket_lingering(sockfd: c_int) -> Result<(),UnixError> {
-    let linger = linger {
-        l_onoff: 1,
-        l_linger: 30,
-    };
-    let err = unsafe {
-        setsockopt(sockfd,
-                   SOL_SOCKET,
-                   SO_LINGER,
-                   &linger as *const linger as *const c_void,
-                   mem::size_of::<linger>() as socklen_t)
-    };
-    if err < 0 {
-        return Err(UnixError::last())
+    /// Like `accept()`, but gives up after `timeout`, whether no client connected in that time or
+    /// the one that did hasn't started sending its first message.
+    pub fn accept_timeout(self, timeout: Duration) -> Result<(UnixReceiver,
+                                                              Vec<u8>,
+                                                              Vec<OpaqueUnixChannel>,
+                                                              Vec<UnixSharedMemory>),UnixError> {
+        let deadline = Instant::now() + timeout;
+        try!(wait_readable(self.fd, deadline));
+        accept_client(self.fd, Some(deadline))
     }
-    Ok(())
 }
 
-pub struct UnixSharedMemory {
-    ptr: *mut u8,
-    length: usize,
+/// A server that can accept any number of clients, each on its own connection.
+pub struct UnixServer {
     fd: c_int,
+    path: CString,
 }
 
-unsafe impl Send for UnixSharedMemory {}
-unsafe impl Sync for UnixSharedMemory {}
-
-impl Drop for UnixSharedMemory {
+impl Drop for UnixServer {
     fn drop(&mut self) {
-        unsafe {
-            if !self.ptr.is_null() {
-                let result = libc::munmap(self.ptr as *mut c_void, self.length as size_t);
-                assert!(thread::panicking() || result == 0);
-            }
-            let result = libc::close(self.fd);
-            assert!(thread::panicking() || result == 0);
-        }
+        close_server_socket(self.fd, &self.path)
     }
 }
 
-impl Clone for UnixSharedMemory {
-    fn clone(&self) -> UnixSharedMemory {
-        unsafe {
-            let new_fd = libc::dup(self.fd);
-            let (address, _) = map_file(new_fd, Some(self.length as size_t));
+impl UnixServer {
+    pub fn new() -> Result<(UnixServer, String),UnixError> {
+        let (fd, path) = try!(bind_server_socket(None, None));
+        let name = String::from_utf8(path.as_bytes().to_vec()).unwrap();
+        Ok((UnixServer {
+            fd: fd,
+            path: path,
+        }, name))
+    }
+
+    pub fn with_name(name: &str) -> Result<UnixServer,UnixError> {
+        let (fd, path) = try!(bind_server_socket(Some(name), None));
+        Ok(UnixServer {
+            fd: fd,
+            path: path,
+        })
+    }
+
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<UnixServer,UnixError> {
+        let (fd, path) = try!(bind_server_socket(Some(name), Some(mode as mode_t)));
+        Ok(UnixServer {
+            fd: fd,
+            path: path,
+        })
+    }
+
+    pub fn accept(&self) -> Result<(UnixReceiver,
+                                    Vec<u8>,
+                                    Vec<OpaqueUnixChannel>,
+                                    Vec<UnixSharedMemory>),UnixError> {
+        accept_client(self.fd, None)
+    }
+}
+
+/// Makes sure that `fd` survives `exec`, returning the token under which a child process finds it.
+fn make_inheritable(fd: c_int) -> Result<String,UnixError> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(fd.to_string())
+}
+
+/// Returns the descriptor identified by a token from `make_inheritable()` in our parent process.
+fn take_inherited(token: &str) -> Result<c_int,UnixError> {
+    let fd = try!(parse_token(token));
+    // Make sure we actually have the descriptor.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(fd)
+}
+
+/// Duplicates the descriptor identified by a token from `make_inheritable()` in the running
+/// process `pid`, by way of `pidfd_getfd()`. This requires permission to `ptrace` that process.
+#[cfg(any(target_os="linux", target_os="android"))]
+fn take_from_process(pid: u32, token: &str) -> Result<c_int,UnixError> {
+    let target_fd = try!(parse_token(token));
+    unsafe {
+        let pidfd = libc::syscall(SYS_PIDFD_OPEN, pid as libc::pid_t, 0);
+        if pidfd < 0 {
+            return Err(UnixError::last())
+        }
+        let fd = libc::syscall(SYS_PIDFD_GETFD, pidfd as c_int, target_fd, 0);
+        let error = UnixError::last();
+        libc::close(pidfd as c_int);
+        if fd < 0 {
+            return Err(error)
+        }
+        Ok(fd as c_int)
+    }
+}
+
+/// Only Linux has a way to reach into another process for a descriptor; elsewhere it has to be
+/// sent.
+#[cfg(not(any(target_os="linux", target_os="android")))]
+fn take_from_process(_: u32, _: &str) -> Result<c_int,UnixError> {
+    Err(UnixError(libc::ENOSYS))
+}
+
+fn parse_token(token: &str) -> Result<c_int,UnixError> {
+    match token.parse::<c_int>() {
+        Ok(fd) if fd >= 0 => Ok(fd),
+        _ => Err(UnixError(libc::EBADF)),
+    }
+}
+
+/// Checks a descriptor that other code made, e.g. with `socketpair()` or through systemd's socket
+/// activation, before we take it over. It should be a Unix domain socket of type `SOCK_SEQPACKET`,
+/// like ours; anything that isn't a socket at all is turned away, and left open.
+fn adopt_fd(fd: c_int) -> Result<c_int,UnixError> {
+    if fd < 0 {
+        return Err(UnixError(libc::EBADF))
+    }
+    if !is_socket(fd) {
+        return Err(UnixError(libc::ENOTSOCK))
+    }
+    Ok(fd)
+}
+
+/// Environment variable that overrides `DEFAULT_SERVER_SOCKET_DIRECTORY`. Child processes inherit
+/// it, so they find the servers of their parent.
+const SERVER_SOCKET_DIRECTORY_ENV_VAR: &'static str = "IPC_CHANNEL_SOCKET_DIR";
+
+const DEFAULT_SERVER_SOCKET_DIRECTORY: &'static str = "/tmp";
+
+lazy_static! {
+    /// Directory in which servers with caller-chosen names create their sockets.
+    static ref SERVER_SOCKET_DIRECTORY: Mutex<String> = {
+        let directory = env::var(SERVER_SOCKET_DIRECTORY_ENV_VAR)
+            .unwrap_or_else(|_| DEFAULT_SERVER_SOCKET_DIRECTORY.to_owned());
+        Mutex::new(directory)
+    };
+}
+
+pub fn set_server_socket_directory(directory: &str) {
+    *SERVER_SOCKET_DIRECTORY.lock().unwrap() = directory.to_owned();
+}
+
+/// Abstract socket names live in a namespace of their own rather than on the filesystem, and go
+/// away along with their socket. We spell them with a leading `@`, like `ss` and `netstat` do.
+#[cfg(any(target_os="linux", target_os="android"))]
+const ABSTRACT_SOCKET_PREFIX: u8 = b'@';
+
+/// Returns the address of the socket for the server with the given name.
+///
+/// Abstract names, such as the ones generated by `UnixOneShotServer::new()`, and absolute paths
+/// are used as they are; other names are looked up in `SERVER_SOCKET_DIRECTORY`.
+fn socket_path(name: &str) -> String {
+    if is_abstract_name(name.as_bytes()) || name.contains('/') {
+        name.to_owned()
+    } else {
+        format!("{}/{}", *SERVER_SOCKET_DIRECTORY.lock().unwrap(), name)
+    }
+}
+
+fn is_abstract_socket_path(path: &CStr) -> bool {
+    is_abstract_name(path.to_bytes())
+}
+
+#[cfg(any(target_os="linux", target_os="android"))]
+fn is_abstract_name(name: &[u8]) -> bool {
+    name.first() == Some(&ABSTRACT_SOCKET_PREFIX)
+}
+
+#[cfg(not(any(target_os="linux", target_os="android")))]
+fn is_abstract_name(_: &[u8]) -> bool {
+    false
+}
+
+/// Returns a fresh name for a server that wasn't given one. On Linux it's abstract, so nothing is
+/// left on the filesystem; elsewhere the socket goes into `SERVER_SOCKET_DIRECTORY`, and is
+/// removed again along with the server.
+#[cfg(any(target_os="linux", target_os="android"))]
+fn generated_socket_path() -> String {
+    format!("@rust-ipc-socket.{}", Uuid::new_v4())
+}
+
+#[cfg(not(any(target_os="linux", target_os="android")))]
+fn generated_socket_path() -> String {
+    format!("{}/rust-ipc-socket.{}", *SERVER_SOCKET_DIRECTORY.lock().unwrap(), Uuid::new_v4())
+}
+
+fn sockaddr_for_path(path: &CStr) -> (sockaddr_un, socklen_t) {
+    let mut sockaddr: sockaddr_un = unsafe {
+        mem::zeroed()
+    };
+    sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // Leave room for the terminating NUL of filesystem paths.
+    let bytes = path.to_bytes();
+    let length = cmp::min(bytes.len(), sockaddr.sun_path.len() - 1);
+    for (dest, &byte) in sockaddr.sun_path.iter_mut().zip(bytes[..length].iter()) {
+        *dest = byte as c_char;
+    }
+    // Abstract names start with a NUL instead, and the length of the address delimits them.
+    if is_abstract_socket_path(path) {
+        sockaddr.sun_path[0] = 0;
+    }
+
+    let len = mem::size_of::<c_short>() + length;
+    set_sockaddr_len(&mut sockaddr, len);
+    (sockaddr, len as socklen_t)
+}
+
+#[cfg(not(any(target_os="freebsd", target_os="openbsd", target_os="netbsd", target_os="nto",
+              target_os="haiku")))]
+fn set_sockaddr_len(_: &mut sockaddr_un, _: usize) {}
+
+/// BSD socket addresses, and QNX's and Haiku's, carry their own length.
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd", target_os="nto",
+          target_os="haiku"))]
+fn set_sockaddr_len(sockaddr: &mut sockaddr_un, len: usize) {
+    sockaddr.sun_len = len as u8;
+}
+
+/// Creates a listening socket for a server, returning it along with the path it is bound to. If
+/// no name is given, a fresh one is generated.
+///
+/// If a socket for the given name already exists but nobody is listening on it anymore -- because
+/// the server that created it crashed, say -- it is removed and the name reused. Servers binding
+/// to the same path take turns by way of a lock file next to it, which is left behind, so that
+/// one doesn't take the socket of another for stale before it starts listening, or remove it.
+///
+/// Names with NUL bytes in them fail with `EINVAL`.
+///
+/// If a mode is given, the socket's permissions are set to it. Abstract sockets have no
+/// permissions, so that fails for them.
+fn bind_server_socket(name: Option<&str>, mode: Option<mode_t>)
+                      -> Result<(c_int, CString),UnixError> {
+    let (path, _lock) = match name {
+        Some(name) => {
+            let path = match CString::new(socket_path(name)) {
+                Ok(path) => path,
+                Err(_) => return Err(UnixError(libc::EINVAL)),
+            };
+            let lock = if is_abstract_socket_path(&path) {
+                None
+            } else {
+                Some(try!(SocketLock::new(&path)))
+            };
+            (Some(path), lock)
+        }
+        None => (None, None),
+    };
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
+        if fd < 0 {
+            return Err(UnixError::last())
+        }
+        let path = match path {
+            Some(path) => {
+                let result = match bind_socket(fd, &path) {
+                    Err(UnixError(libc::EADDRINUSE)) if is_stale_socket(&path) => {
+                        libc::unlink(path.as_ptr());
+                        bind_socket(fd, &path)
+                    }
+                    result => result,
+                };
+                if let Err(error) = result {
+                    libc::close(fd);
+                    return Err(error)
+                }
+                path
+            }
+            None => {
+                let mut path;
+                loop {
+                    path = CString::new(generated_socket_path()).unwrap();
+                    match bind_socket(fd, &path) {
+                        Ok(()) => break,
+                        Err(UnixError(libc::EADDRINUSE)) => {}
+                        Err(error) => {
+                            libc::close(fd);
+                            return Err(error)
+                        }
+                    }
+                }
+                path
+            }
+        };
+
+        // Clients can't connect before we listen, so nobody slips in before access is restricted.
+        if let Some(mode) = mode {
+            let result = if is_abstract_socket_path(&path) {
+                Err(UnixError(libc::EINVAL))
+            } else if libc::chmod(path.as_ptr(), mode) != 0 {
+                Err(UnixError::last())
+            } else {
+                Ok(())
+            };
+            if let Err(error) = result {
+                close_server_socket(fd, &path);
+                return Err(error)
+            }
+        }
+
+        if libc::listen(fd, 10) != 0 {
+            let error = UnixError::last();
+            close_server_socket(fd, &path);
+            return Err(error)
+        }
+
+        leaks::track(HandleKind::Server, fd as i64);
+        Ok((fd, path))
+    }
+}
+
+fn bind_socket(fd: c_int, path: &CStr) -> Result<(),UnixError> {
+    let (sockaddr, len) = sockaddr_for_path(path);
+    if unsafe { libc::bind(fd, &sockaddr as *const _ as *const sockaddr, len) } != 0 {
+        return Err(UnixError::last())
+    }
+    Ok(())
+}
+
+/// Returns true if `path` is a socket that no server is listening on anymore.
+fn is_stale_socket(path: &CStr) -> bool {
+    unsafe {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::lstat(path.as_ptr(), &mut stat) != 0 ||
+                (stat.st_mode & libc::S_IFMT) != libc::S_IFSOCK {
+            return false
+        }
+
+        let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
+        if fd < 0 {
+            return false
+        }
+        let (sockaddr, len) = sockaddr_for_path(path);
+        let refused = libc::connect(fd, &sockaddr as *const _ as *const sockaddr, len) < 0 &&
+            UnixError::last().0 == libc::ECONNREFUSED;
+        libc::close(fd);
+        refused
+    }
+}
+
+/// An exclusive `flock()` on the lock file next to a server socket, released when dropped.
+struct SocketLock {
+    fd: c_int,
+}
+
+impl SocketLock {
+    fn new(path: &CStr) -> Result<SocketLock,UnixError> {
+        let mut lock_path = path.to_bytes().to_vec();
+        lock_path.extend_from_slice(b".lock");
+        let lock_path = CString::new(lock_path).unwrap();
+        unsafe {
+            let fd = libc::open(lock_path.as_ptr(),
+                                libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC,
+                                0o600);
+            if fd < 0 {
+                return Err(UnixError::last())
+            }
+            if retry_on_interrupt(|| libc::flock(fd, libc::LOCK_EX)) != 0 {
+                let error = UnixError::last();
+                libc::close(fd);
+                return Err(error)
+            }
+            Ok(SocketLock {
+                fd: fd,
+            })
+        }
+    }
+}
+
+impl Drop for SocketLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock.
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Closes a server's listening socket and removes it from the filesystem, so that the name can be
+/// reused and no stale socket is left behind.
+fn close_server_socket(fd: c_int, path: &CStr) {
+    leaks::untrack(HandleKind::Server, fd as i64);
+    unsafe {
+        if !is_abstract_socket_path(path) {
+            libc::unlink(path.as_ptr());
+        }
+        let result = libc::close(fd);
+        assert!(thread::panicking() || result == 0);
+    }
+}
+
+/// Accepts a client on the listening socket `fd` and receives its first message. If a deadline is
+/// given, the client has until then to start sending it.
+fn accept_client(fd: c_int, deadline: Option<Instant>)
+                 -> Result<(UnixReceiver,
+                            Vec<u8>,
+                            Vec<OpaqueUnixChannel>,
+                            Vec<UnixSharedMemory>),UnixError> {
+    unsafe {
+        let sockaddr: *mut sockaddr = ptr::null_mut();
+        let sockaddr_len: *mut socklen_t = ptr::null_mut();
+        let client_fd = retry_on_interrupt(|| libc::accept(fd, sockaddr, sockaddr_len));
+        if client_fd < 0 {
+            return Err(UnixError::last())
+        }
+        try!(make_socket_lingering(client_fd));
+
+        let receiver = UnixReceiver::from_fd(client_fd);
+        if let Some(deadline) = deadline {
+            try!(wait_readable(client_fd, deadline));
+        }
+        let (data, channels, shared_memory_regions) = try!(receiver.recv());
+        Ok((receiver, data, channels, shared_memory_regions))
+    }
+}
+
+/// Waits until `deadline` for `fd` to become readable: for a client to connect, if it is a
+/// listening socket, or for data to arrive otherwise.
+fn wait_readable(fd: c_int, deadline: Instant) -> Result<(),UnixError> {
+    let mut pollfd = pollfd {
+        fd: fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    // A signal mustn't restart the whole wait, so each retry only waits for what's left of it.
+    loop {
+        let now = Instant::now();
+        let timeout = if now < deadline { deadline - now } else { Duration::from_secs(0) };
+        match unsafe { poll(&mut pollfd, 1, duration_to_ms(timeout)) } {
+            result if result < 0 => {
+                let error = UnixError::last();
+                if error.0 != libc::EINTR || FAIL_ON_INTERRUPT.load(Ordering::Relaxed) {
+                    return Err(error)
+                }
+            }
+            0 => return Err(UnixError(libc::ETIMEDOUT)),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Converts `duration` to a `poll()` timeout, rounding down and saturating.
+fn duration_to_ms(duration: Duration) -> c_int {
+    let ms = duration.as_secs()
+                     .saturating_mul(1000)
+                     .saturating_add(duration.subsec_nanos() as u64 / 1_000_000);
+    cmp::min(ms, c_int::max_value() as u64) as c_int
+}
+
+// Make sure that the kernel doesn't return errors to readers if there's still data left after we
+// close our end.
+//
+// See, for example, https://github.com/servo/ipc-channel/issues/29
+fn make_socket_lingering(sockfd: c_int) -> Result<(),UnixError> {
+    let linger = linger {
+        l_onoff: 1,
+        l_linger: 30,
+    };
+    let err = unsafe {
+        setsockopt(sockfd,
+                   SOL_SOCKET,
+                   SO_LINGER,
+                   &linger as *const linger as *const c_void,
+                   mem::size_of::<linger>() as socklen_t)
+    };
+    if err < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(())
+}
+
+pub struct UnixSharedMemory {
+    ptr: *mut u8,
+    length: usize,
+    fd: c_int,
+}
+
+unsafe impl Send for UnixSharedMemory {}
+unsafe impl Sync for UnixSharedMemory {}
+
+impl Drop for UnixSharedMemory {
+    fn drop(&mut self) {
+        leaks::untrack(HandleKind::SharedMemory, self.fd as i64);
+        unsafe {
+            if !self.ptr.is_null() {
+                let result = libc::munmap(self.ptr as *mut c_void, self.length as size_t);
+                assert!(thread::panicking() || result == 0);
+            }
+            let result = libc::close(self.fd);
+            assert!(thread::panicking() || result == 0);
+        }
+    }
+}
+
+impl Clone for UnixSharedMemory {
+    fn clone(&self) -> UnixSharedMemory {
+        unsafe {
+            let new_fd = libc::dup(self.fd);
+            let (address, _) = map_file(new_fd, Some(self.length as size_t));
             UnixSharedMemory::from_raw_parts(address, self.length, new_fd)
         }
     }
@@ -615,6 +1545,7 @@ impl Deref for UnixSharedMemory {
 
 impl UnixSharedMemory {
     unsafe fn from_raw_parts(ptr: *mut u8, length: usize, fd: c_int) -> UnixSharedMemory {
+        leaks::track(HandleKind::SharedMemory, fd as i64);
         UnixSharedMemory {
             ptr: ptr,
             length: length,
@@ -646,6 +1577,46 @@ impl UnixSharedMemory {
             UnixSharedMemory::from_raw_parts(address, bytes.len(), fd)
         }
     }
+
+    /// Moves `length` bytes from the current position of `file` into a new region with
+    /// `sendfile()`, which splices them over inside the kernel rather than copying them through
+    /// userspace.
+    #[cfg(target_os="linux")]
+    pub fn from_file(file: &File, length: usize) -> Result<UnixSharedMemory,Error> {
+        unsafe {
+            let fd = create_memory_backing_store(length);
+            let mut copied = 0;
+            while copied < length {
+                let result = sendfile(fd, file.as_raw_fd(), ptr::null_mut(), length - copied);
+                if result <= 0 {
+                    let error = if result == 0 {
+                        Error::new(ErrorKind::UnexpectedEof, "file ended early")
+                    } else {
+                        UnixError::last().into()
+                    };
+                    libc::close(fd);
+                    return Err(error)
+                }
+                copied += result as usize;
+            }
+            let (address, _) = map_file(fd, Some(length as size_t));
+            Ok(UnixSharedMemory::from_raw_parts(address, length, fd))
+        }
+    }
+
+    /// Reads `length` bytes from the current position of `file` into a new region. `sendfile()`
+    /// can't write to ashmem, and on the BSDs it only writes to sockets.
+    #[cfg(not(target_os="linux"))]
+    pub fn from_file(file: &File, length: usize) -> Result<UnixSharedMemory,Error> {
+        let mut bytes = vec![0; length];
+        try!((&*file).read_exact(&mut bytes[..]));
+        Ok(UnixSharedMemory::from_bytes(&bytes[..]))
+    }
+
+    /// The descriptor of the region's backing store.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        Some(self.fd as i64)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -660,6 +1631,16 @@ impl UnixError {
     pub fn channel_is_closed(&self) -> bool {
         self.0 == libc::ECONNRESET
     }
+
+    pub fn is_unsupported(&self) -> bool {
+        self.0 == libc::ENOSYS || self.0 == libc::EOPNOTSUPP
+    }
+
+    /// Whether the kernel had no buffer space for a packet. The BSDs and macOS say so with
+    /// `ENOBUFS` more readily than Linux does, and a socket that isn't blocking says `EAGAIN`.
+    fn is_buffer_shortage(&self) -> bool {
+        self.0 == libc::ENOBUFS || self.0 == libc::EAGAIN
+    }
 }
 
 impl From<UnixError> for DeserializeError {
@@ -668,132 +1649,553 @@ impl From<UnixError> for DeserializeError {
     }
 }
 
-impl From<UnixError> for Error {
-    fn from(unix_error: UnixError) -> Error {
-        Error::from_raw_os_error(unix_error.0)
+impl From<UnixError> for Error {
+    fn from(unix_error: UnixError) -> Error {
+        Error::from_raw_os_error(unix_error.0)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum BlockingMode {
+    Blocking,
+    Nonblocking,
+}
+
+fn recv(fd: c_int, blocking_mode: BlockingMode, max_message_size: Option<usize>)
+        -> Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),UnixError> {
+    unsafe {
+        let mut cmsg = UnixCmsg::new(try!(maximum_recv_size(fd)));
+        let bytes_read = try!(cmsg.recv(fd, blocking_mode)) as usize;
+        finish_recv(&cmsg.data_buffer[..bytes_read], &cmsg.msghdr, max_message_size)
+    }
+}
+
+/// How many messages `recv_batch()` takes at most.
+const RECV_BATCH_SIZE: usize = 8;
+
+/// Receives all the messages queued up on `fd`, up to `RECV_BATCH_SIZE`, with a single system
+/// call. This may return no messages at all, if there weren't any after all. A message that can't
+/// be taken in, e.g. because it's bigger than `max_message_size`, is an error of its own in the
+/// batch, and the messages after it are still returned.
+///
+/// Unlike a `UnixReceiver`, which might be sent to another process at any time, a receiver set
+/// holds on to its sockets; so it can take more messages off them than it was asked for without
+/// any getting stranded.
+#[cfg(not(any(target_os="illumos", target_os="solaris", target_os="nto",
+              target_os="haiku")))]
+fn recv_batch(fd: c_int, buffers: &mut Vec<Vec<u8>>, max_message_size: Option<usize>)
+              -> Result<Vec<Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),
+                                  UnixError>>,
+                        UnixError> {
+    let maximum_recv_size = try!(maximum_recv_size(fd));
+    buffers.resize(RECV_BATCH_SIZE, vec![]);
+    let mut iovecs = Vec::with_capacity(RECV_BATCH_SIZE);
+    for buffer in buffers.iter_mut() {
+        if buffer.len() < maximum_recv_size {
+            buffer.resize(maximum_recv_size, 0);
+        }
+        iovecs.push(iovec {
+            iov_base: buffer.as_mut_ptr() as *mut c_char,
+            iov_len: buffer.len() as size_t,
+        });
+    }
+    let cmsg_length = max_fds_cmsg_space();
+    // `u64`s, to get the alignment right.
+    let mut cmsg_buffers: Vec<Vec<u64>> =
+        (0..RECV_BATCH_SIZE).map(|_| vec![0; (cmsg_length + 7) / 8]).collect();
+    let mut mmsghdrs = Vec::with_capacity(RECV_BATCH_SIZE);
+    for (iovec, cmsg_buffer) in iovecs.iter_mut().zip(cmsg_buffers.iter_mut()) {
+        mmsghdrs.push(mmsghdr {
+            msg_hdr: msghdr {
+                msg_name: ptr::null_mut(),
+                msg_namelen: 0,
+                msg_iov: iovec,
+                msg_iovlen: 1,
+                msg_control: cmsg_buffer.as_mut_ptr() as *mut c_void,
+                msg_controllen: cmsg_length as ControlLen,
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+    }
+
+    unsafe {
+        let count = retry_on_interrupt(|| {
+            recvmmsg(fd,
+                     mmsghdrs.as_mut_ptr(),
+                     mmsghdrs.len() as MmsgCount,
+                     MSG_DONTWAIT,
+                     ptr::null_mut())
+        });
+        if count < 0 {
+            return match UnixError::last() {
+                UnixError(libc::EAGAIN) => Ok(vec![]),
+                error => Err(error),
+            }
+        }
+        let mut messages = Vec::with_capacity(count as usize);
+        for (buffer, mmsghdr) in buffers.iter().zip(mmsghdrs.iter()).take(count as usize) {
+            if mmsghdr.msg_len == 0 {
+                // The other end hung up; we'll hear about it next time, if we got messages
+                // before that.
+                if messages.is_empty() {
+                    return Err(UnixError(libc::ECONNRESET))
+                }
+                break
+            }
+            messages.push(finish_recv(&buffer[..mmsghdr.msg_len as usize],
+                                      &mmsghdr.msg_hdr,
+                                      max_message_size));
+        }
+        Ok(messages)
+    }
+}
+
+/// There's no `recvmmsg()` on illumos, QNX or Haiku, so a batch is a single message.
+#[cfg(any(target_os="illumos", target_os="solaris", target_os="nto", target_os="haiku"))]
+fn recv_batch(fd: c_int, _: &mut Vec<Vec<u8>>, max_message_size: Option<usize>)
+              -> Result<Vec<Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),
+                                  UnixError>>,
+                        UnixError> {
+    match recv(fd, BlockingMode::Nonblocking, max_message_size) {
+        Ok(message) => Ok(vec![Ok(message)]),
+        Err(UnixError(libc::EAGAIN)) => Ok(vec![]),
+        // The message was taken off the socket, but was too big for us.
+        Err(error @ UnixError(libc::EMSGSIZE)) |
+        Err(error @ UnixError(libc::ENOBUFS)) => Ok(vec![Err(error)]),
+        Err(error) => Err(error),
+    }
+}
+
+fn maximum_recv_size(fd: c_int) -> Result<usize,UnixError> {
+    buffer_size(fd, libc::SO_RCVBUF)
+}
+
+/// Reads the `SO_SNDBUF` or `SO_RCVBUF` option of `fd`.
+fn buffer_size(fd: c_int, option: c_int) -> Result<usize,UnixError> {
+    unsafe {
+        let mut size: c_int = 0;
+        let mut size_len = mem::size_of::<c_int>() as socklen_t;
+        if getsockopt(fd,
+                      libc::SOL_SOCKET,
+                      option,
+                      &mut size as *mut c_int as *mut c_void,
+                      &mut size_len as *mut socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+        Ok(size as usize)
+    }
+}
+
+/// Asks how many bytes are queued up on `fd`, with `FIONREAD` or `TIOCOUTQ`. For Unix domain
+/// sockets, both count the whole queue of the receiving end.
+fn queued_bytes(fd: c_int, request: IoctlRequest) -> Result<usize,UnixError> {
+    let mut queued_bytes: c_int = 0;
+    unsafe {
+        if libc::ioctl(fd, request, &mut queued_bytes as *mut c_int) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(queued_bytes as usize)
+}
+
+/// Sets the `SO_SNDBUF` or `SO_RCVBUF` option of `fd`.
+fn set_buffer_size(fd: c_int, option: c_int, size: usize) -> Result<(),UnixError> {
+    if size > c_int::max_value() as usize {
+        return Err(UnixError(libc::EINVAL))
+    }
+    let size = size as c_int;
+    unsafe {
+        if setsockopt(fd,
+                      libc::SOL_SOCKET,
+                      option,
+                      &size as *const c_int as *const c_void,
+                      mem::size_of::<c_int>() as socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(())
+}
+
+/// Makes a message out of a `packet` we received with `msghdr`: takes over the file descriptors
+/// that came along with it, and if it's the first fragment of a bigger message, receives the
+/// rest. Messages bigger than `max_message_size` fail with `EMSGSIZE`.
+unsafe fn finish_recv(packet: &[u8], msghdr: &msghdr, max_message_size: Option<usize>)
+                      -> Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),
+                                UnixError> {
+    let cmsg_buffer = msghdr.msg_control as *const cmsghdr;
+    let cmsg_fds = CMSG_DATA(cmsg_buffer) as *const c_int;
+    let channel_length = if msghdr.msg_controllen == 0 {
+        0
+    } else {
+        (((*cmsg_buffer).cmsg_len as usize) - CMSG_LEN(0) as usize) / mem::size_of::<c_int>()
+    };
+    // See `FRAGMENT_HEADER_SIZE`.
+    let channel_count = if packet.len() < FRAGMENT_HEADER_SIZE {
+        0
+    } else {
+        LittleEndian::read_u32(&packet[0..4]) as usize
+    };
+    let (mut channels, mut shared_memory_regions) = (Vec::new(), Vec::new());
+    for index in 0..channel_length {
+        let fd = *cmsg_fds.offset(index as isize);
+        if index < channel_count {
+            channels.push(OpaqueUnixChannel::from_fd(fd));
+            continue
+        }
+        shared_memory_regions.push(UnixSharedMemory::from_fd(fd));
+    }
+
+    // The packet didn't fit our buffer, which can happen if the sender has a bigger send buffer
+    // than we have a receive buffer. Whatever handles it carried get closed on the way out.
+    if msghdr.msg_flags & MSG_TRUNC != 0 {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+
+    let (main_data_buffer, total_length, mut next_fragment_id) =
+        match try!(parse_first_fragment(packet, max_message_size)) {
+            // Fast path: no fragments.
+            FirstFragment::Whole(data) => {
+                return Ok((data.to_vec(), channels, shared_memory_regions))
+            }
+            FirstFragment::Partial { data, total_length, next_fragment_id } => {
+                (data, total_length, next_fragment_id)
+            }
+        };
+
+    // Reassemble fragments. They all come in through a dedicated channel, whose receive end came
+    // with the first one.
+    let _reservation = try!(ReceiveMemoryReservation::new(total_length));
+    let mut data = Vec::with_capacity(cmp::max(total_length, main_data_buffer.len()));
+    data.extend_from_slice(main_data_buffer);
+    let dedicated_rx = match channels.pop() {
+        Some(channel) => channel.to_receiver(),
+        None => return Err(UnixError(libc::EBADMSG)),
+    };
+    // The dedicated channel is made by the sending process, so it's the one on the other end.
+    let sender_pid = match peer_credentials(dedicated_rx.fd) {
+        Ok((pid, _, _)) if pid != 0 => Some(pid as libc::pid_t),
+        _ => None,
+    };
+    while next_fragment_id != 0 {
+        // Always use blocking mode for followup fragments,
+        // to make sure that once we start receiving a multi-fragment message,
+        // we don't abort in the middle of it...
+        try!(wait_for_fragment(dedicated_rx.fd, sender_pid));
+        let mut header = [0; FRAGMENT_HEADER_SIZE];
+        match recv_fragment(dedicated_rx.fd, &mut header, &mut data) {
+            Err(UnixError(libc::EBADMSG)) if !sender_pid.map_or(true, process_exists) => {
+                return Err(UnixError(libc::EOWNERDEAD))
+            }
+            result => try!(result),
+        }
+
+        next_fragment_id = try!(check_fragment_header(&header, next_fragment_id));
+    }
+
+    Ok((data, channels, shared_memory_regions))
+}
+
+/// The first packet of a message, taken apart.
+enum FirstFragment<'a> {
+    /// A message that fit in a single packet.
+    Whole(&'a [u8]),
+    /// The start of a message of `total_length` bytes, which goes on with `next_fragment_id`.
+    Partial {
+        data: &'a [u8],
+        total_length: usize,
+        next_fragment_id: u32,
+    },
+}
+
+/// Takes apart the headers of the first packet of a message, failing with `EMSGSIZE` if it's too
+/// short for them or the message is bigger than `max_message_size`.
+fn parse_first_fragment(packet: &[u8], max_message_size: Option<usize>)
+                        -> Result<FirstFragment,UnixError> {
+    if packet.len() < FRAGMENT_HEADER_SIZE {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+    let (fragment_info_buffer, main_data_buffer) = packet.split_at(FRAGMENT_HEADER_SIZE);
+    let max_message_size = max_message_size.unwrap_or(usize::MAX);
+    if main_data_buffer.len() > max_message_size {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+    let next_fragment_id = LittleEndian::read_u32(&fragment_info_buffer[4..8]);
+    if next_fragment_id == 0 {
+        return Ok(FirstFragment::Whole(main_data_buffer))
+    }
+
+    // The initial fragment carries the length of the whole message, so that we can receive
+    // the remaining fragments straight into a buffer of the right size.
+    if main_data_buffer.len() < mem::size_of::<u64>() {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+    let (total_length, main_data_buffer) = main_data_buffer.split_at(mem::size_of::<u64>());
+    // The length comes from the sender, so we don't allocate it before checking it.
+    let total_length = LittleEndian::read_u64(total_length);
+    if total_length > max_message_size as u64 {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+    Ok(FirstFragment::Partial {
+        data: main_data_buffer,
+        total_length: total_length as usize,
+        next_fragment_id: next_fragment_id,
+    })
+}
+
+/// Checks that `header` is that of fragment `expected_id`, and returns the ID of the fragment
+/// after it, or 0 if it's the last. Fragments out of order fail with `EBADMSG`.
+fn check_fragment_header(header: &[u8], expected_id: u32) -> Result<u32,UnixError> {
+    if LittleEndian::read_u32(&header[0..4]) != expected_id {
+        return Err(UnixError(libc::EBADMSG))
+    }
+    Ok(LittleEndian::read_u32(&header[4..8]))
+}
+
+/// Reassembles a message from the packets it was sent in, as a receiver does, but without a
+/// socket, so that a fuzzer can feed it packets of its own. Fails where a receiver would fail on
+/// the same packets, and with `EBADMSG` if they run out before the last fragment.
+#[cfg(any(test, feature="fuzzing"))]
+pub fn reassemble_fragments(packets: &[&[u8]], max_message_size: Option<usize>)
+                            -> Result<Vec<u8>,UnixError> {
+    let (first_packet, packets) = match packets.split_first() {
+        Some(packets) => packets,
+        None => return Err(UnixError(libc::EBADMSG)),
+    };
+    let (data, total_length, mut next_fragment_id) =
+        match try!(parse_first_fragment(first_packet, max_message_size)) {
+            FirstFragment::Whole(data) => return Ok(data.to_vec()),
+            FirstFragment::Partial { data, total_length, next_fragment_id } => {
+                (data, total_length, next_fragment_id)
+            }
+        };
+    // Trailing fragments that don't fit the buffer are truncated, as in `recv_fragment()`.
+    let capacity = cmp::max(total_length, data.len());
+    let mut message = data.to_vec();
+    let mut packets = packets.iter();
+    while next_fragment_id != 0 {
+        let packet = match packets.next() {
+            Some(packet) if !packet.is_empty() => packet,
+            _ => return Err(UnixError(libc::EBADMSG)),
+        };
+        if packet.len() < FRAGMENT_HEADER_SIZE ||
+                message.len() + packet.len() - FRAGMENT_HEADER_SIZE > capacity {
+            return Err(UnixError(libc::EMSGSIZE))
+        }
+        let (header, fragment) = packet.split_at(FRAGMENT_HEADER_SIZE);
+        next_fragment_id = try!(check_fragment_header(header, next_fragment_id));
+        message.extend_from_slice(fragment);
+    }
+    Ok(message)
+}
+
+/// Room taken out of `RECEIVE_MEMORY_BUDGET` for a message being reassembled, given back when
+/// dropped.
+struct ReceiveMemoryReservation(usize);
+
+impl ReceiveMemoryReservation {
+    /// Fails with `ENOBUFS` if the budget doesn't have `size` bytes left.
+    fn new(size: usize) -> Result<ReceiveMemoryReservation,UnixError> {
+        loop {
+            let in_use = RECEIVE_MEMORY_IN_USE.load(Ordering::SeqCst);
+            let budget = RECEIVE_MEMORY_BUDGET.load(Ordering::SeqCst);
+            let new_in_use = match in_use.checked_add(size) {
+                Some(new_in_use) if budget == 0 || new_in_use <= budget => new_in_use,
+                _ => return Err(UnixError(libc::ENOBUFS)),
+            };
+            if RECEIVE_MEMORY_IN_USE.compare_and_swap(in_use, new_in_use, Ordering::SeqCst) ==
+                    in_use {
+                return Ok(ReceiveMemoryReservation(size))
+            }
+        }
+    }
+}
+
+impl Drop for ReceiveMemoryReservation {
+    fn drop(&mut self) {
+        RECEIVE_MEMORY_IN_USE.fetch_sub(self.0, Ordering::SeqCst);
+    }
+}
+
+/// Waits until the next fragment of a message can be received from `fd`, failing with
+/// `EOWNERDEAD` if the process sending it dies first, or with `ETIMEDOUT` if it doesn't come
+/// within the reassembly timeout. The end of the channel it's sending on closes when it dies,
+/// unless some other process inherited it, in which case we'd wait forever without a timeout.
+fn wait_for_fragment(fd: c_int, sender_pid: Option<libc::pid_t>) -> Result<(),UnixError> {
+    let deadline = match REASSEMBLY_TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => None,
+        timeout_ms => Some(Instant::now() + Duration::from_millis(timeout_ms as u64)),
+    };
+    if sender_pid.is_none() && deadline.is_none() {
+        return Ok(())
+    }
+    let mut pollfd = pollfd {
+        fd: fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    loop {
+        let mut timeout_ms = if sender_pid.is_some() { SENDER_CHECK_INTERVAL_MS } else { -1 };
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UnixError(libc::ETIMEDOUT))
+            }
+            let remaining_ms = duration_to_ms(deadline - now);
+            if timeout_ms < 0 || remaining_ms < timeout_ms {
+                timeout_ms = remaining_ms
+            }
+        }
+        match retry_on_interrupt(|| unsafe { poll(&mut pollfd, 1, timeout_ms) }) {
+            0 => {
+                if !sender_pid.map_or(true, process_exists) {
+                    return Err(UnixError(libc::EOWNERDEAD))
+                }
+            }
+            result if result < 0 => return Err(UnixError::last()),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Returns true unless `pid` is gone. A process that died but hasn't been waited for yet still
+/// counts as there.
+fn process_exists(pid: libc::pid_t) -> bool {
+    unsafe {
+        libc::kill(pid, 0) == 0 || UnixError::last().0 != libc::ESRCH
     }
 }
 
-#[derive(Copy, Clone)]
-enum BlockingMode {
-    Blocking,
-    Nonblocking,
+/// Receives a trailing fragment of a message, appending its data to `data` without copying it
+/// through an intermediate buffer. `data` must have room for it.
+unsafe fn recv_fragment(fd: c_int, header: &mut [u8; FRAGMENT_HEADER_SIZE], data: &mut Vec<u8>)
+                        -> Result<(),UnixError> {
+    let length = data.len();
+    let mut iovecs = [
+        iovec {
+            iov_base: header.as_mut_ptr() as *mut c_char,
+            iov_len: header.len() as size_t,
+        },
+        iovec {
+            iov_base: data.as_mut_ptr().offset(length as isize) as *mut c_char,
+            iov_len: (data.capacity() - length) as size_t,
+        },
+    ];
+    let mut msghdr = msghdr {
+        msg_name: ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: iovecs.as_mut_ptr(),
+        msg_iovlen: iovecs.len() as IovLen,
+        msg_control: ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    let result = retry_on_interrupt(|| recvmsg(fd, &mut msghdr, 0));
+    if result < 0 {
+        return Err(UnixError::last())
+    }
+    if result == 0 {
+        // The sender went away before the last fragment, which isn't the same as the channel
+        // closing: other senders may still be there.
+        return Err(UnixError(libc::EBADMSG))
+    }
+    // The sender lied about the length of the message, or sent a runt packet.
+    if (result as usize) < FRAGMENT_HEADER_SIZE || msghdr.msg_flags & MSG_TRUNC != 0 {
+        return Err(UnixError(libc::EMSGSIZE))
+    }
+    data.set_len(length + result as usize - FRAGMENT_HEADER_SIZE);
+    Ok(())
 }
 
-fn recv(fd: c_int, blocking_mode: BlockingMode)
-        -> Result<(Vec<u8>, Vec<OpaqueUnixChannel>, Vec<UnixSharedMemory>),UnixError> {
-    unsafe {
-        let mut maximum_recv_size: usize = 0;
-        let mut maximum_recv_size_len = mem::size_of::<usize>() as socklen_t;
-        if getsockopt(fd,
-                      libc::SOL_SOCKET,
-                      libc::SO_RCVBUF,
-                      &mut maximum_recv_size as *mut usize as *mut c_void,
-                      &mut maximum_recv_size_len as *mut socklen_t) < 0 {
-            return Err(UnixError::last())
-        }
-
-        let mut cmsg = UnixCmsg::new(maximum_recv_size);
-        let bytes_read = try!(cmsg.recv(fd, blocking_mode)) as usize;
-
-        let cmsg_fds = cmsg.cmsg_buffer.offset(1) as *const u8 as *const c_int;
-        let cmsg_length = cmsg.msghdr.msg_controllen;
-        let channel_length = if cmsg_length == 0 {
-            0
-        } else {
-            ((cmsg.cmsg_len() as usize) - mem::size_of::<cmsghdr>()) / mem::size_of::<c_int>()
-        };
-        let (mut channels, mut shared_memory_regions) = (Vec::new(), Vec::new());
-        for index in 0..channel_length {
-            let fd = *cmsg_fds.offset(index as isize);
-            if is_socket(fd) {
-                channels.push(OpaqueUnixChannel::from_fd(fd));
-                continue
-            }
-            shared_memory_regions.push(UnixSharedMemory::from_fd(fd));
-        }
-
-        // Separate out the fragmentation frame.
-        let (fragment_info_buffer, main_data_buffer) = cmsg.data_buffer
-                                                           .split_at(mem::size_of::<u32>() * 2);
-        let mut main_data_buffer: Vec<u8> =
-            main_data_buffer[0..(bytes_read - mem::size_of::<u32>() * 2)].iter()
-                                                                         .cloned()
-                                                                         .collect();
-        let mut next_fragment_id =
-            (&fragment_info_buffer[mem::size_of::<u32>()..
-                                   (mem::size_of::<u32>() * 2)]).read_u32::<LittleEndian>()
-                                                                .unwrap();
-        if next_fragment_id == 0 {
-            // Fast path: no fragments.
-            return Ok((main_data_buffer, channels, shared_memory_regions))
-        }
+#[cfg(target_os="linux")]
+const TEMP_FILE_TEMPLATE: &'static str = "/tmp/ipc-channel-shared-memory.XXXXXX";
 
-        // Reassemble fragments.
-        //
-        // The initial fragment carries the receive end of a dedicated channel
-        // through which all the remaining fragments will be coming in.
-        let dedicated_rx = channels.pop().unwrap().to_receiver();
-        while next_fragment_id != 0 {
-            let mut cmsg = UnixCmsg::new(maximum_recv_size);
-            // Always use blocking mode for followup fragments,
-            // to make sure that once we start receiving a multi-fragment message,
-            // we don't abort in the middle of it...
-            let bytes_read = try!(cmsg.recv(dedicated_rx.fd, BlockingMode::Blocking)) as usize;
+#[cfg(target_os="linux")]
+unsafe fn create_memory_backing_store(length: usize) -> c_int {
+    let string = CString::new(TEMP_FILE_TEMPLATE).unwrap();
+    let string_buffer = strdup(string.as_ptr());
+    let fd = mkstemp(string_buffer);
+    assert!(fd >= 0);
+    assert!(libc::unlink(string_buffer) == 0);
+    libc::free(string_buffer as *mut c_void);
+    assert!(libc::ftruncate(fd, length as off_t) == 0);
+    fd
+}
 
-            let this_fragment_id =
-                (&cmsg.data_buffer[0..mem::size_of::<u32>()]).read_u32::<LittleEndian>().unwrap();
-            assert!(this_fragment_id == next_fragment_id);
+/// FreeBSD can make shared memory objects that never have a name at all.
+#[cfg(target_os="freebsd")]
+unsafe fn create_memory_backing_store(length: usize) -> c_int {
+    let fd = shm_open(SHM_ANON, libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC, 0o600);
+    assert!(fd >= 0);
+    assert!(libc::ftruncate(fd, length as off_t) == 0);
+    fd
+}
 
-            next_fragment_id =
-                (&cmsg.data_buffer[mem::size_of::<u32>()..
-                                   (mem::size_of::<u32>() * 2)]).read_u32::<LittleEndian>()
-                                                                .unwrap();
-            main_data_buffer.extend(
-                    cmsg.data_buffer[(mem::size_of::<u32>() * 2)..bytes_read].iter().cloned())
+/// Elsewhere, shared memory objects need a name, which we remove again straight away.
+#[cfg(any(target_os="openbsd", target_os="netbsd", target_os="illumos", target_os="solaris",
+          target_os="nto", target_os="haiku"))]
+unsafe fn create_memory_backing_store(length: usize) -> c_int {
+    loop {
+        let name = CString::new(format!("/ipc-channel-shared-memory.{}", Uuid::new_v4())).unwrap();
+        let fd = shm_open(name.as_ptr(),
+                          libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                          0o600);
+        if fd < 0 && UnixError::last().0 == libc::EEXIST {
+            continue
         }
-
-        Ok((main_data_buffer, channels, shared_memory_regions))
+        assert!(fd >= 0);
+        assert!(shm_unlink(name.as_ptr()) == 0);
+        assert!(libc::ftruncate(fd, length as off_t) == 0);
+        return fd
     }
 }
 
+/// On Android, shared memory is ashmem, which is what Binder and the platform libraries expect
+/// to be handed, and which works on every API level, unlike `memfd_create()`. We go through
+/// `ASharedMemory_create()` where the NDK has it (API level 26 and up), and talk to
+/// `/dev/ashmem` directly before that.
 #[cfg(target_os="android")]
-const TEMP_FILE_TEMPLATE: &'static str = "/sdcard/servo/ipc-channel-shared-memory.XXXXXX";
+unsafe fn create_memory_backing_store(length: usize) -> c_int {
+    let name = CString::new("ipc-channel-shared-memory").unwrap();
+    let symbol = CString::new("ASharedMemory_create").unwrap();
+    let create = libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr());
+    if !create.is_null() {
+        let create: extern fn(*const c_char, size_t) -> c_int = mem::transmute(create);
+        let fd = create(name.as_ptr(), length as size_t);
+        assert!(fd >= 0);
+        return fd
+    }
 
-#[cfg(not(target_os="android"))]
-const TEMP_FILE_TEMPLATE: &'static str = "/tmp/ipc-channel-shared-memory.XXXXXX";
+    let device = CString::new("/dev/ashmem").unwrap();
+    let fd = libc::open(device.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC);
+    assert!(fd >= 0);
+    let mut name_buffer = [0 as c_char; ASHMEM_NAME_LEN];
+    for (slot, byte) in name_buffer.iter_mut().zip(name.as_bytes().iter()) {
+        *slot = *byte as c_char;
+    }
+    assert!(libc::ioctl(fd, ASHMEM_SET_NAME, name_buffer.as_ptr()) == 0);
+    assert!(libc::ioctl(fd, ASHMEM_SET_SIZE, length as size_t) == 0);
+    fd
+}
 
+/// Returns the size of a backing store we received. Ashmem regions always look empty to
+/// `fstat()`.
 #[cfg(target_os="android")]
-fn maybe_unlink(_: *const c_char) -> c_int {
-    // Calling `unlink` on a file stored on an sdcard immediately deletes it.
-    // FIXME: use a better temporary directory than the sdcard via the Java APIs
-    // and threading that value into Servo.
-    // https://code.google.com/p/android/issues/detail?id=19017
-    0
+unsafe fn backing_store_size(fd: c_int) -> size_t {
+    let size = libc::ioctl(fd, ASHMEM_GET_SIZE);
+    assert!(size >= 0);
+    size as size_t
 }
 
 #[cfg(not(target_os="android"))]
-unsafe fn maybe_unlink(c: *const c_char) -> c_int {
-    libc::unlink(c)
-}
-
-unsafe fn create_memory_backing_store(length: usize) -> c_int {
-    let string = CString::new(TEMP_FILE_TEMPLATE).unwrap();
-    let string_buffer = strdup(string.as_ptr());
-    let fd = mkstemp(string_buffer);
-    assert!(fd >= 0);
-    assert!(maybe_unlink(string_buffer) == 0);
-    libc::free(string_buffer as *mut c_void);
-    assert!(libc::ftruncate(fd, length as off_t) == 0);
-    fd
+unsafe fn backing_store_size(fd: c_int) -> size_t {
+    let mut st = mem::uninitialized();
+    assert!(libc::fstat(fd, &mut st) == 0);
+    st.st_size as size_t
 }
 
 unsafe fn map_file(fd: c_int, length: Option<size_t>) -> (*mut u8, size_t) {
-    let length = length.unwrap_or_else(|| {
-        let mut st = mem::uninitialized();
-        assert!(libc::fstat(fd, &mut st) == 0);
-        st.st_size as size_t
-    });
+    let length = length.unwrap_or_else(|| backing_store_size(fd));
     if length == 0 {
         // This will cause `mmap` to fail, so handle it explicitly.
         return (ptr::null_mut(), length)
@@ -824,15 +2226,258 @@ impl Drop for UnixCmsg {
         unsafe {
             libc::free(self.cmsg_buffer as *mut c_void);
         }
+        give_back_recv_buffer(mem::replace(&mut self.data_buffer, vec![]));
+    }
+}
+
+thread_local! {
+    /// Buffers to receive into, so that we don't allocate (and zero) one of `SO_RCVBUF` bytes for
+    /// every message.
+    static RECV_BUFFERS: RefCell<Vec<Vec<u8>>> = RefCell::new(Vec::new())
+}
+
+lazy_static! {
+    /// How many receive buffers each thread keeps around.
+    static ref RECV_BUFFER_POOL_SIZE: AtomicUsize = AtomicUsize::new(2);
+}
+
+pub fn set_receive_buffer_pool_size(buffers: usize) {
+    RECV_BUFFER_POOL_SIZE.store(buffers, Ordering::SeqCst);
+}
+
+/// Messages always go through the socket, so there is nothing to send out of line.
+pub fn set_out_of_line_threshold(_: Option<usize>) {}
+
+pub fn set_retry_on_interrupt(retry: bool) {
+    FAIL_ON_INTERRUPT.store(!retry, Ordering::SeqCst)
+}
+
+/// Gives up on a fragmented message, failing with `ETIMEDOUT`, if the next fragment takes
+/// longer than `timeout` to come.
+pub fn set_reassembly_timeout(timeout: Option<Duration>) {
+    let timeout_ms = timeout.map_or(0, |timeout| cmp::max(duration_to_ms(timeout), 1));
+    REASSEMBLY_TIMEOUT_MS.store(timeout_ms as usize, Ordering::SeqCst)
+}
+
+/// Caps the memory that all the fragmented messages being reassembled at once may take. Messages
+/// that would go over fail with `ENOBUFS`.
+pub fn set_receive_memory_budget(bytes: Option<usize>) {
+    RECEIVE_MEMORY_BUDGET.store(bytes.unwrap_or(0), Ordering::SeqCst)
+}
+
+/// How sends keep a peer that hung up from raising `SIGPIPE`, which kills processes that neither
+/// handle nor ignore it. Whichever is picked, the send fails with `EPIPE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigpipeStrategy {
+    /// Passes `MSG_NOSIGNAL` to every send. The default on Linux, Android and the BSDs, and not
+    /// available elsewhere.
+    NoSignalFlag,
+    /// Sets `SO_NOSIGPIPE` on the socket before every send, at the cost of a system call. Only
+    /// FreeBSD and NetBSD have it.
+    SocketOption,
+    /// Blocks `SIGPIPE` in the sending thread for the duration of every send, and takes the signal
+    /// back if the send raised one. The default where there's no `MSG_NOSIGNAL`.
+    MaskSignal,
+}
+
+#[cfg(any(target_os="linux", target_os="android", target_os="freebsd", target_os="openbsd",
+          target_os="netbsd"))]
+const DEFAULT_SIGPIPE_STRATEGY: SigpipeStrategy = SigpipeStrategy::NoSignalFlag;
+#[cfg(not(any(target_os="linux", target_os="android", target_os="freebsd", target_os="openbsd",
+              target_os="netbsd")))]
+const DEFAULT_SIGPIPE_STRATEGY: SigpipeStrategy = SigpipeStrategy::MaskSignal;
+
+pub fn set_sigpipe_strategy(strategy: SigpipeStrategy) -> Result<(),UnixError> {
+    let supported = match strategy {
+        SigpipeStrategy::NoSignalFlag => MSG_NOSIGNAL.is_some(),
+        SigpipeStrategy::SocketOption => SO_NOSIGPIPE.is_some(),
+        SigpipeStrategy::MaskSignal => true,
+    };
+    if !supported {
+        return Err(UnixError(libc::EOPNOTSUPP))
+    }
+    SIGPIPE_STRATEGY.store(strategy as usize + 1, Ordering::SeqCst);
+    Ok(())
+}
+
+fn sigpipe_strategy() -> SigpipeStrategy {
+    match SIGPIPE_STRATEGY.load(Ordering::Relaxed) {
+        1 => SigpipeStrategy::NoSignalFlag,
+        2 => SigpipeStrategy::SocketOption,
+        3 => SigpipeStrategy::MaskSignal,
+        _ => DEFAULT_SIGPIPE_STRATEGY,
+    }
+}
+
+/// Makes `send`, a system call that sends on `fd` with the flags it's given, without letting it
+/// raise `SIGPIPE`, and retries it if it's interrupted.
+unsafe fn send_without_sigpipe<T,F>(fd: c_int, mut send: F) -> Result<T,UnixError>
+                                    where T: Copy + Default + PartialOrd, F: FnMut(c_int) -> T {
+    let result = match sigpipe_strategy() {
+        SigpipeStrategy::NoSignalFlag => {
+            retry_on_interrupt(|| send(MSG_NOSIGNAL.unwrap_or(0)))
+        }
+        SigpipeStrategy::SocketOption => {
+            let on: c_int = 1;
+            if setsockopt(fd,
+                          SOL_SOCKET,
+                          SO_NOSIGPIPE.unwrap_or(0),
+                          &on as *const c_int as *const c_void,
+                          mem::size_of::<c_int>() as socklen_t) < 0 {
+                return Err(UnixError::last())
+            }
+            retry_on_interrupt(|| send(0))
+        }
+        SigpipeStrategy::MaskSignal => {
+            let mut sigpipe: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut sigpipe);
+            libc::sigaddset(&mut sigpipe, libc::SIGPIPE);
+            // A `SIGPIPE` that was pending already isn't ours to take.
+            let was_pending = sigpipe_pending();
+            let mut old_mask: libc::sigset_t = mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_BLOCK, &sigpipe, &mut old_mask);
+            let result = retry_on_interrupt(|| send(0));
+            let error = UnixError::last();
+            if !was_pending && sigpipe_pending() {
+                let mut signal = 0;
+                libc::sigwait(&sigpipe, &mut signal);
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, ptr::null_mut());
+            if result < T::default() {
+                return Err(error)
+            }
+            result
+        }
+    };
+    if result < T::default() {
+        return Err(UnixError::last())
+    }
+    Ok(result)
+}
+
+unsafe fn sigpipe_pending() -> bool {
+    let mut pending: libc::sigset_t = mem::zeroed();
+    libc::sigpending(&mut pending);
+    libc::sigismember(&pending, libc::SIGPIPE) == 1
+}
+
+/// Makes a system call, which fails with a negative result and `errno`, and makes it again for as
+/// long as it fails with `EINTR`, unless `set_retry_on_interrupt(false)` says to let signals cut
+/// it short.
+fn retry_on_interrupt<T,F>(mut call: F) -> T where T: Copy + Default + PartialOrd,
+                                                    F: FnMut() -> T {
+    loop {
+        let result = call();
+        if result >= T::default() || FAIL_ON_INTERRUPT.load(Ordering::Relaxed) ||
+                Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return result
+        }
+    }
+}
+
+/// Restricts the calling thread to running on the given CPUs.
+#[cfg(any(target_os="linux", target_os="android"))]
+pub fn set_thread_affinity(cpus: &[usize]) -> Result<(),UnixError> {
+    // A `cpu_set_t`, which has room for 1024 CPUs.
+    let mut cpu_set: [c_ulong; 1024 / 64] = [0; 1024 / 64];
+    let bits = mem::size_of::<c_ulong>() * 8;
+    for &cpu in cpus {
+        if cpu >= cpu_set.len() * bits {
+            return Err(UnixError(libc::EINVAL))
+        }
+        cpu_set[cpu / bits] |= 1 << (cpu % bits);
+    }
+    unsafe {
+        if sched_setaffinity(0, mem::size_of_val(&cpu_set), cpu_set.as_ptr()) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(())
+}
+
+/// Restricts the calling thread to running on the given CPUs.
+#[cfg(target_os="freebsd")]
+pub fn set_thread_affinity(cpus: &[usize]) -> Result<(),UnixError> {
+    // A `cpuset_t`, which has room for 256 CPUs.
+    let mut cpu_set: [c_ulong; 256 / 64] = [0; 256 / 64];
+    let bits = mem::size_of::<c_ulong>() * 8;
+    for &cpu in cpus {
+        if cpu >= cpu_set.len() * bits {
+            return Err(UnixError(libc::EINVAL))
+        }
+        cpu_set[cpu / bits] |= 1 << (cpu % bits);
+    }
+    unsafe {
+        if cpuset_setaffinity(CPU_LEVEL_WHICH,
+                              CPU_WHICH_TID,
+                              -1,
+                              mem::size_of_val(&cpu_set),
+                              cpu_set.as_ptr()) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(())
+}
+
+/// Restricts the calling thread to running on the given CPUs. QNX's runmask only has room for
+/// 32 CPUs.
+#[cfg(target_os="nto")]
+pub fn set_thread_affinity(cpus: &[usize]) -> Result<(),UnixError> {
+    let mut runmask: c_uint = 0;
+    for &cpu in cpus {
+        if cpu >= mem::size_of::<c_uint>() * 8 {
+            return Err(UnixError(libc::EINVAL))
+        }
+        runmask |= 1 << cpu;
+    }
+    unsafe {
+        if ThreadCtl(_NTO_TCTL_RUNMASK, runmask as usize as *mut c_void) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os="openbsd", target_os="netbsd", target_os="illumos", target_os="solaris",
+          target_os="haiku"))]
+pub fn set_thread_affinity(_: &[usize]) -> Result<(),UnixError> {
+    Err(UnixError(libc::ENOSYS))
+}
+
+/// Sets the nice value of the calling thread: from -20, which runs first, to 19. On Linux each
+/// thread has its own; elsewhere this sets it for the whole process. Raising priority above the
+/// default requires `CAP_SYS_NICE`, or root elsewhere.
+pub fn set_thread_priority(priority: i32) -> Result<(),UnixError> {
+    unsafe {
+        if setpriority(PRIO_PROCESS, 0, priority) < 0 {
+            return Err(UnixError::last())
+        }
+    }
+    Ok(())
+}
+
+fn take_recv_buffer(size: usize) -> Vec<u8> {
+    let mut buffer = RECV_BUFFERS.with(|buffers| buffers.borrow_mut().pop()).unwrap_or(vec![]);
+    if buffer.len() < size {
+        buffer.resize(size, 0);
     }
+    buffer
+}
+
+fn give_back_recv_buffer(buffer: Vec<u8>) {
+    RECV_BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        if buffers.len() < RECV_BUFFER_POOL_SIZE.load(Ordering::Relaxed) {
+            buffers.push(buffer)
+        }
+    })
 }
 
 impl UnixCmsg {
     unsafe fn new(maximum_recv_size: usize) -> UnixCmsg {
-        let cmsg_length = mem::size_of::<cmsghdr>() + (MAX_FDS_IN_CMSG as usize) *
-            mem::size_of::<c_int>();
+        let cmsg_length = max_fds_cmsg_space();
         assert!(maximum_recv_size > cmsg_length);
-        let mut data_buffer: Vec<u8> = vec![0; maximum_recv_size];
+        let mut data_buffer = take_recv_buffer(maximum_recv_size);
         let cmsg_buffer = libc::malloc(cmsg_length as size_t) as *mut cmsghdr;
         let mut iovec = Box::new(iovec {
             iov_base: &mut data_buffer[0] as *mut _ as *mut c_char,
@@ -849,7 +2494,7 @@ impl UnixCmsg {
                 msg_iov: iovec_ptr,
                 msg_iovlen: 1,
                 msg_control: cmsg_buffer as *mut c_void,
-                msg_controllen: cmsg_length as size_t,
+                msg_controllen: cmsg_length as ControlLen,
                 msg_flags: 0,
             },
         }
@@ -863,7 +2508,8 @@ impl UnixCmsg {
             }
         }
 
-        let result = recvmsg(fd, &mut self.msghdr, 0);
+        let msghdr = &mut self.msghdr;
+        let result = retry_on_interrupt(|| recvmsg(fd, msghdr, 0));
         let result = if result > 0 {
             Ok(result)
         } else if result == 0 {
@@ -879,9 +2525,153 @@ impl UnixCmsg {
         }
         result
     }
+}
+
+/// Sends `data`, gathered from the given slices, as a single packet along with the given channels
+/// and shared memory regions.
+fn send_packet(fd: c_int,
+               data: &[&[u8]],
+               channels: &[UnixChannel],
+               shared_memory_regions: &[UnixSharedMemory])
+               -> Result<(),UnixError> {
+    let mut iovecs: Vec<iovec> = data.iter().map(|slice| {
+        iovec {
+            iov_base: slice.as_ptr() as *const c_char as *mut c_char,
+            iov_len: slice.len() as size_t,
+        }
+    }).collect();
+    unsafe {
+        let (cmsg_buffer, cmsg_space) = if channels.is_empty() && shared_memory_regions.is_empty() {
+            (ptr::null_mut(), 0)
+        } else {
+            construct_cmsg(channels, shared_memory_regions)
+        };
+        let msghdr = msghdr {
+            msg_name: ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: iovecs.as_mut_ptr(),
+            msg_iovlen: iovecs.len() as IovLen,
+            msg_control: cmsg_buffer as *mut c_void,
+            msg_controllen: cmsg_space,
+            msg_flags: 0,
+        };
+        let result = send_without_sigpipe(fd, |flags| sendmsg(fd, &msghdr, flags));
+        libc::free(cmsg_buffer as *mut c_void);
+        try!(result);
+        Ok(())
+    }
+}
+
+/// Builds an `SCM_RIGHTS` control message transferring the given channels and shared memory
+/// regions. Returns the buffer, which the caller must `free()`, and its size.
+unsafe fn construct_cmsg(channels: &[UnixChannel], shared_memory_regions: &[UnixSharedMemory])
+                         -> (*mut cmsghdr, ControlLen) {
+    let cmsg_length = (channels.len() + shared_memory_regions.len()) * mem::size_of::<c_int>();
+    let cmsg_buffer = libc::malloc(CMSG_SPACE(cmsg_length as size_t)) as *mut cmsghdr;
+    // Zero the padding, which some kernels check.
+    ptr::write_bytes(cmsg_buffer as *mut u8, 0, CMSG_SPACE(cmsg_length as size_t) as usize);
+    (*cmsg_buffer).cmsg_len = CMSG_LEN(cmsg_length as size_t) as CmsgLen;
+    (*cmsg_buffer).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg_buffer).cmsg_type = SCM_RIGHTS;
+
+    let mut fds = Vec::new();
+    for channel in channels.iter() {
+        fds.push(channel.fd());
+    }
+    for shared_memory_region in shared_memory_regions.iter() {
+        fds.push(shared_memory_region.fd);
+    }
+    ptr::copy_nonoverlapping(fds.as_ptr(), CMSG_DATA(cmsg_buffer) as *mut c_int, fds.len());
+    (cmsg_buffer, CMSG_SPACE(cmsg_length as size_t) as ControlLen)
+}
+
+/// The size of a control message buffer with room for `MAX_FDS_IN_CMSG` descriptors.
+fn max_fds_cmsg_space() -> usize {
+    CMSG_SPACE((MAX_FDS_IN_CMSG as usize * mem::size_of::<c_int>()) as size_t) as usize
+}
+
+#[cfg(any(target_os="linux", target_os="android", target_os="haiku"))]
+fn peer_credentials(fd: c_int) -> Result<(u32, u32, u32),UnixError> {
+    unsafe {
+        let mut credentials: ucred = mem::zeroed();
+        let mut credentials_len = mem::size_of::<ucred>() as socklen_t;
+        if getsockopt(fd,
+                      SOL_SOCKET,
+                      SO_PEERCRED,
+                      &mut credentials as *mut ucred as *mut c_void,
+                      &mut credentials_len as *mut socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+        Ok((credentials.pid as u32, credentials.uid as u32, credentials.gid as u32))
+    }
+}
+
+/// FreeBSD only fills in the pid since 13.0; before that, it comes back as 0.
+#[cfg(target_os="freebsd")]
+fn peer_credentials(fd: c_int) -> Result<(u32, u32, u32),UnixError> {
+    unsafe {
+        let mut credentials: xucred = mem::zeroed();
+        let mut credentials_len = mem::size_of::<xucred>() as socklen_t;
+        if getsockopt(fd,
+                      SOL_LOCAL,
+                      LOCAL_PEERCRED,
+                      &mut credentials as *mut xucred as *mut c_void,
+                      &mut credentials_len as *mut socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+        let gid = if credentials.cr_ngroups > 0 {
+            credentials.cr_groups[0]
+        } else {
+            0
+        };
+        Ok((credentials.cr_pid as u32, credentials.cr_uid as u32, gid as u32))
+    }
+}
+
+#[cfg(target_os="openbsd")]
+fn peer_credentials(fd: c_int) -> Result<(u32, u32, u32),UnixError> {
+    unsafe {
+        let mut credentials: sockpeercred = mem::zeroed();
+        let mut credentials_len = mem::size_of::<sockpeercred>() as socklen_t;
+        if getsockopt(fd,
+                      SOL_SOCKET,
+                      SO_PEERCRED,
+                      &mut credentials as *mut sockpeercred as *mut c_void,
+                      &mut credentials_len as *mut socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+        Ok((credentials.pid as u32, credentials.uid as u32, credentials.gid as u32))
+    }
+}
+
+#[cfg(any(target_os="netbsd", target_os="nto"))]
+fn peer_credentials(fd: c_int) -> Result<(u32, u32, u32),UnixError> {
+    unsafe {
+        let mut credentials: unpcbid = mem::zeroed();
+        let mut credentials_len = mem::size_of::<unpcbid>() as socklen_t;
+        if getsockopt(fd,
+                      SOL_LOCAL,
+                      LOCAL_PEEREID,
+                      &mut credentials as *mut unpcbid as *mut c_void,
+                      &mut credentials_len as *mut socklen_t) < 0 {
+            return Err(UnixError::last())
+        }
+        Ok((credentials.unp_pid as u32, credentials.unp_euid as u32, credentials.unp_egid as u32))
+    }
+}
 
-    unsafe fn cmsg_len(&self) -> size_t {
-        (*(self.msghdr.msg_control as *const cmsghdr)).cmsg_len
+#[cfg(any(target_os="illumos", target_os="solaris"))]
+fn peer_credentials(fd: c_int) -> Result<(u32, u32, u32),UnixError> {
+    unsafe {
+        let mut credentials = ptr::null_mut();
+        if getpeerucred(fd, &mut credentials) < 0 {
+            return Err(UnixError::last())
+        }
+        let result = (ucred_getpid(credentials) as u32,
+                      ucred_geteuid(credentials) as u32,
+                      ucred_getegid(credentials) as u32);
+        ucred_free(credentials);
+        Ok(result)
     }
 }
 
@@ -897,16 +2687,156 @@ fn is_socket(fd: c_int) -> bool {
 
 // FFI stuff follows:
 
+/// Bionic declares the request argument of `ioctl()` differently from glibc.
+#[cfg(target_os="android")]
+type IoctlRequest = c_int;
+#[cfg(not(target_os="android"))]
+type IoctlRequest = c_ulong;
+
+/// Other systems use narrower types than Linux for some lengths in `msghdr` and `cmsghdr`.
+#[cfg(any(target_os="linux", target_os="android"))]
+type IovLen = size_t;
+#[cfg(not(any(target_os="linux", target_os="android")))]
+type IovLen = c_int;
+#[cfg(any(target_os="linux", target_os="android"))]
+type ControlLen = size_t;
+#[cfg(not(any(target_os="linux", target_os="android")))]
+type ControlLen = socklen_t;
+#[cfg(any(target_os="linux", target_os="android"))]
+type CmsgLen = size_t;
+#[cfg(not(any(target_os="linux", target_os="android")))]
+type CmsgLen = socklen_t;
+
+/// The count argument of `recvmmsg()` and `sendmmsg()`.
+#[cfg(target_os="freebsd")]
+type MmsgCount = size_t;
+#[cfg(not(target_os="freebsd"))]
+type MmsgCount = c_uint;
+
+#[cfg(target_os="android")]
+const ASHMEM_NAME_LEN: usize = 256;
+#[cfg(target_os="android")]
+const ASHMEM_SET_NAME: IoctlRequest = 0x41007701;
+#[cfg(all(target_os="android", target_pointer_width="32"))]
+const ASHMEM_SET_SIZE: IoctlRequest = 0x40047703;
+#[cfg(all(target_os="android", target_pointer_width="64"))]
+const ASHMEM_SET_SIZE: IoctlRequest = 0x40087703;
+#[cfg(target_os="android")]
+const ASHMEM_GET_SIZE: IoctlRequest = 0x7704;
 const POLLIN: c_short = 0x01;
-const SCM_RIGHTS: c_int = 0x01;
-const SOCK_SEQPACKET: c_int = 0x05;
+#[cfg(not(target_os="haiku"))]
+const POLLHUP: c_short = 0x10;
+const PRIO_PROCESS: c_int = 0;
+
+// These are the numbers on x86 and ARM.
+#[cfg(any(target_os="linux", target_os="android"))]
+const FIONREAD: IoctlRequest = 0x541B;
+#[cfg(any(target_os="linux", target_os="android"))]
+const TIOCOUTQ: IoctlRequest = 0x5411;
+
+// These have the same numbers on all architectures.
+#[cfg(any(target_os="linux", target_os="android"))]
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+#[cfg(any(target_os="linux", target_os="android"))]
+const SYS_PIDFD_GETFD: libc::c_long = 438;
+#[cfg(any(target_os="linux", target_os="android"))]
 const SOL_SOCKET: c_int = 1;
+#[cfg(any(target_os="linux", target_os="android"))]
 const SO_LINGER: c_int = 13;
+#[cfg(any(target_os="linux", target_os="android"))]
+const SO_PEERCRED: c_int = 17;
+#[cfg(any(target_os="linux", target_os="android"))]
+const SO_PEERSEC: c_int = 31;
+#[cfg(any(target_os="linux", target_os="android"))]
+const MSG_TRUNC: c_int = 0x20;
+#[cfg(any(target_os="linux", target_os="android"))]
+const MSG_DONTWAIT: c_int = 0x40;
+#[cfg(not(any(target_os="illumos", target_os="solaris")))]
+const SOCK_SEQPACKET: c_int = 0x05;
+const SCM_RIGHTS: c_int = 0x01;
 const S_IFMT: mode_t = 0o00170000;
 const S_IFSOCK: mode_t = 0o0140000;
 
+// The BSDs, illumos and QNX agree on these.
+#[cfg(not(any(target_os="linux", target_os="android", target_os="haiku")))]
+const FIONREAD: IoctlRequest = 0x4004667f;
+#[cfg(not(any(target_os="linux", target_os="android", target_os="haiku")))]
+const SOL_SOCKET: c_int = 0xffff;
+#[cfg(not(any(target_os="linux", target_os="android", target_os="haiku")))]
+const SO_LINGER: c_int = 0x0080;
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+const MSG_DONTWAIT: c_int = 0x80;
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd", target_os="nto",
+          target_os="haiku"))]
+const MSG_TRUNC: c_int = 0x10;
+#[cfg(any(target_os="illumos", target_os="solaris"))]
+const MSG_TRUNC: c_int = 0x20;
+#[cfg(any(target_os="illumos", target_os="solaris"))]
+const SOCK_SEQPACKET: c_int = 0x06;
+#[cfg(any(target_os="freebsd", target_os="openbsd"))]
+const EVFILT_READ: i16 = -1;
+#[cfg(target_os="netbsd")]
+const EVFILT_READ: u32 = 0;
+#[cfg(any(target_os="freebsd", target_os="openbsd"))]
+const EV_ADD: u16 = 0x0001;
+#[cfg(target_os="netbsd")]
+const EV_ADD: u32 = 0x0001;
+
+#[cfg(any(target_os="freebsd", target_os="netbsd", target_os="nto"))]
+const SOL_LOCAL: c_int = 0;
+#[cfg(target_os="freebsd")]
+const LOCAL_PEERCRED: c_int = 1;
+#[cfg(target_os="freebsd")]
+const SHM_ANON: *const c_char = 1 as *const c_char;
+#[cfg(target_os="freebsd")]
+const CPU_LEVEL_WHICH: c_int = 3;
+#[cfg(target_os="freebsd")]
+const CPU_WHICH_TID: c_int = 1;
+#[cfg(target_os="openbsd")]
+const SO_PEERCRED: c_int = 0x1022;
+#[cfg(any(target_os="netbsd", target_os="nto"))]
+const LOCAL_PEEREID: c_int = 0x0003;
+#[cfg(target_os="nto")]
+const _NTO_TCTL_RUNMASK: c_int = 4;
+
+#[cfg(any(target_os="linux", target_os="android"))]
+const MSG_NOSIGNAL: Option<c_int> = Some(0x4000);
+#[cfg(target_os="freebsd")]
+const MSG_NOSIGNAL: Option<c_int> = Some(0x20000);
+#[cfg(any(target_os="openbsd", target_os="netbsd"))]
+const MSG_NOSIGNAL: Option<c_int> = Some(0x400);
+#[cfg(not(any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd")))]
+const MSG_NOSIGNAL: Option<c_int> = None;
+#[cfg(any(target_os="freebsd", target_os="netbsd"))]
+const SO_NOSIGPIPE: Option<c_int> = Some(0x0800);
+#[cfg(not(any(target_os="freebsd", target_os="netbsd")))]
+const SO_NOSIGPIPE: Option<c_int> = None;
+
+// Haiku numbers these its own way.
+#[cfg(target_os="haiku")]
+const FIONREAD: IoctlRequest = 0xbe000001;
+#[cfg(target_os="haiku")]
+const SOL_SOCKET: c_int = -1;
+#[cfg(target_os="haiku")]
+const SO_LINGER: c_int = 0x0200;
+#[cfg(target_os="haiku")]
+const SO_PEERCRED: c_int = 0x4000000b;
+#[cfg(target_os="haiku")]
+const POLLHUP: c_short = 0x0080;
+
 #[allow(non_camel_case_types)]
+#[cfg(not(any(target_os="freebsd", target_os="openbsd", target_os="netbsd", target_os="nto")))]
 type nfds_t = c_ulong;
+#[allow(non_camel_case_types)]
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd", target_os="nto"))]
+type nfds_t = c_uint;
+
+/// The count arguments of `kevent()`.
+#[cfg(any(target_os="freebsd", target_os="openbsd"))]
+type KeventCount = c_int;
+#[cfg(target_os="netbsd")]
+type KeventCount = size_t;
 
 #[allow(non_snake_case)]
 fn CMSG_LEN(length: size_t) -> size_t {
@@ -915,7 +2845,20 @@ fn CMSG_LEN(length: size_t) -> size_t {
 
 #[allow(non_snake_case)]
 fn CMSG_ALIGN(length: size_t) -> size_t {
-    (length + (mem::size_of::<size_t>() as size_t) - 1) & ((!(mem::size_of::<size_t>() - 1)) as size_t)
+    let alignment = cmsg_alignment() as size_t;
+    (length + alignment - 1) & !(alignment - 1)
+}
+
+/// Linux aligns control messages to a `size_t`, and the BSDs to a `long`, which is the same size.
+#[cfg(not(any(target_os="illumos", target_os="solaris")))]
+fn cmsg_alignment() -> usize {
+    mem::size_of::<size_t>()
+}
+
+/// illumos aligns control messages to an `int`.
+#[cfg(any(target_os="illumos", target_os="solaris"))]
+fn cmsg_alignment() -> usize {
+    mem::size_of::<c_int>()
 }
 
 #[allow(non_snake_case)]
@@ -923,12 +2866,23 @@ fn CMSG_SPACE(length: size_t) -> size_t {
     CMSG_ALIGN(length) + CMSG_ALIGN(mem::size_of::<cmsghdr>() as size_t)
 }
 
+/// Returns where the data of a control message starts. On Linux that's straight after the
+/// header, but a BSD `cmsghdr` is only 12 bytes long, so on 64-bit BSDs there's padding.
+#[allow(non_snake_case)]
+unsafe fn CMSG_DATA(cmsg: *const cmsghdr) -> *mut u8 {
+    (cmsg as *mut u8).offset(CMSG_ALIGN(mem::size_of::<cmsghdr>() as size_t) as isize)
+}
+
 #[allow(non_snake_case)]
 fn S_ISSOCK(mode: mode_t) -> bool {
     (mode & S_IFMT) == S_IFSOCK
 }
 
+// QNX and Haiku keep their socket functions in libraries of their own.
+#[cfg_attr(target_os="nto", link(name="socket"))]
+#[cfg_attr(target_os="haiku", link(name="network"))]
 extern {
+    #[cfg_attr(any(target_os="illumos", target_os="solaris"), link_name="__xnet_getsockopt")]
     fn getsockopt(sockfd: c_int,
                   level: c_int,
                   optname: c_int,
@@ -936,31 +2890,104 @@ extern {
                   optlen: *mut socklen_t)
                   -> c_int;
     fn mkstemp(template: *mut c_char) -> c_int;
-    fn mktemp(template: *mut c_char) -> *mut c_char;
     fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int;
+    fn recvmmsg(socket: c_int,
+                messages: *mut mmsghdr,
+                count: MmsgCount,
+                flags: c_int,
+                timeout: *mut c_void)
+                -> MmsgResult;
+    #[cfg_attr(any(target_os="illumos", target_os="solaris"), link_name="__xnet_recvmsg")]
     fn recvmsg(socket: c_int, message: *mut msghdr, flags: c_int) -> ssize_t;
+    #[cfg(any(target_os="linux", target_os="android"))]
+    fn sched_setaffinity(pid: libc::pid_t, cpusetsize: size_t, mask: *const c_ulong) -> c_int;
+    fn sendmmsg(socket: c_int, messages: *mut mmsghdr, count: MmsgCount, flags: c_int)
+                -> MmsgResult;
+    fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: size_t) -> ssize_t;
+    #[cfg_attr(any(target_os="illumos", target_os="solaris"), link_name="__xnet_sendmsg")]
     fn sendmsg(socket: c_int, message: *const msghdr, flags: c_int) -> ssize_t;
+    fn setpriority(which: c_int, who: c_uint, priority: c_int) -> c_int;
     fn setsockopt(socket: c_int,
                   level: c_int,
                   option_name: c_int,
                   option_value: *const c_void,
                   option_len: socklen_t)
                   -> c_int;
+    #[cfg_attr(any(target_os="illumos", target_os="solaris"), link_name="__xnet_socketpair")]
     fn socketpair(domain: c_int, socket_type: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
     fn strdup(string: *const c_char) -> *mut c_char;
 }
 
+#[cfg(any(target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+extern {
+    fn kqueue() -> c_int;
+    #[cfg_attr(target_os="netbsd", link_name="__kevent50")]
+    fn kevent(kq: c_int,
+              changelist: *const kevent,
+              nchanges: KeventCount,
+              eventlist: *mut kevent,
+              nevents: KeventCount,
+              timeout: *const libc::timespec)
+              -> c_int;
+    #[cfg(target_os="freebsd")]
+    fn cpuset_setaffinity(level: c_int,
+                          which: c_int,
+                          id: i64,
+                          setsize: size_t,
+                          mask: *const c_ulong)
+                          -> c_int;
+}
+
+#[cfg(not(any(target_os="linux", target_os="android")))]
+extern {
+    fn shm_open(name: *const c_char, oflag: c_int, mode: mode_t) -> c_int;
+    #[cfg(not(target_os="freebsd"))]
+    fn shm_unlink(name: *const c_char) -> c_int;
+}
+
+#[cfg(target_os="nto")]
+extern {
+    fn ThreadCtl(cmd: c_int, data: *mut c_void) -> c_int;
+}
+
+#[cfg(any(target_os="illumos", target_os="solaris"))]
+extern {
+    fn getpeerucred(fd: c_int, ucred: *mut *mut c_void) -> c_int;
+    fn ucred_free(ucred: *mut c_void);
+    fn ucred_getegid(ucred: *const c_void) -> libc::gid_t;
+    fn ucred_geteuid(ucred: *const c_void) -> libc::uid_t;
+    fn ucred_getpid(ucred: *const c_void) -> libc::pid_t;
+}
+
+/// What `recvmmsg()` and `sendmmsg()` return.
+#[cfg(target_os="freebsd")]
+type MmsgResult = ssize_t;
+#[cfg(not(target_os="freebsd"))]
+type MmsgResult = c_int;
+
 #[repr(C)]
 struct msghdr {
     msg_name: *mut c_void,
     msg_namelen: socklen_t,
     msg_iov: *mut iovec,
-    msg_iovlen: size_t,
+    msg_iovlen: IovLen,
     msg_control: *mut c_void,
-    msg_controllen: size_t,
+    msg_controllen: ControlLen,
     msg_flags: c_int,
 }
 
+#[repr(C)]
+struct mmsghdr {
+    msg_hdr: msghdr,
+    msg_len: MmsgLen,
+}
+
+/// FreeBSD keeps the length of each message in a `ssize_t`.
+#[cfg(target_os="freebsd")]
+type MmsgLen = ssize_t;
+#[cfg(not(target_os="freebsd"))]
+type MmsgLen = c_uint;
+
 #[repr(C)]
 struct iovec {
     iov_base: *mut c_char,
@@ -969,7 +2996,7 @@ struct iovec {
 
 #[repr(C)]
 struct cmsghdr {
-    cmsg_len: size_t,
+    cmsg_len: CmsgLen,
     cmsg_level: c_int,
     cmsg_type: c_int,
 }
@@ -987,3 +3014,63 @@ struct linger {
     l_linger: c_int,
 }
 
+#[cfg(any(target_os="linux", target_os="android", target_os="haiku"))]
+#[repr(C)]
+struct ucred {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+#[cfg(target_os="freebsd")]
+#[repr(C)]
+struct xucred {
+    cr_version: c_uint,
+    cr_uid: libc::uid_t,
+    cr_ngroups: c_short,
+    cr_groups: [libc::gid_t; 16],
+    /// `cr_pid` shares a union with a pointer, which aligns it.
+    _cr_align: [usize; 0],
+    cr_pid: libc::pid_t,
+}
+
+#[cfg(target_os="openbsd")]
+#[repr(C)]
+struct sockpeercred {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    pid: libc::pid_t,
+}
+
+#[cfg(any(target_os="netbsd", target_os="nto"))]
+#[repr(C)]
+struct unpcbid {
+    unp_pid: libc::pid_t,
+    unp_euid: libc::uid_t,
+    unp_egid: libc::gid_t,
+}
+
+#[cfg(any(target_os="freebsd", target_os="openbsd"))]
+#[repr(C)]
+struct kevent {
+    ident: usize,
+    filter: i16,
+    flags: u16,
+    fflags: c_uint,
+    data: i64,
+    udata: *mut c_void,
+    #[cfg(target_os="freebsd")]
+    ext: [u64; 4],
+}
+
+#[cfg(target_os="netbsd")]
+#[repr(C)]
+struct kevent {
+    ident: usize,
+    filter: u32,
+    flags: u32,
+    fflags: u32,
+    data: i64,
+    udata: *mut c_void,
+}
+
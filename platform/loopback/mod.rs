@@ -0,0 +1,99 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A transport for fuzzing, which delivers messages within the process, in order and without
+//! blocking, so that runs are reproducible. Messages are framed as on the TCP transport, table of
+//! shared memory regions included, and a fuzzer can mutate each frame before it's delivered, or
+//! inject frames of its own, to exercise the frame parser and deserialization on whatever comes
+//! out.
+
+use platform::tcp::{read_message, write_message};
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+struct Queue {
+    frames: VecDeque<Vec<u8>>,
+    mutator: Option<Box<FnMut(&mut Vec<u8>) + Send>>,
+}
+
+pub fn channel() -> (LoopbackSender, LoopbackReceiver) {
+    let queue = Arc::new(Mutex::new(Queue {
+        frames: VecDeque::new(),
+        mutator: None,
+    }));
+    (LoopbackSender {
+        queue: queue.clone(),
+    }, LoopbackReceiver {
+        queue: queue,
+    })
+}
+
+#[derive(Clone)]
+pub struct LoopbackSender {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Debug for LoopbackSender {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "LoopbackSender {{ .. }}")
+    }
+}
+
+impl LoopbackSender {
+    pub fn send(&self, data: &[u8], shared_memory_regions: &[&[u8]]) -> Result<(),Error> {
+        let mut frame = vec![];
+        try!(write_message(&mut frame, data, shared_memory_regions));
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(ref mut mutator) = queue.mutator {
+            mutator(&mut frame)
+        }
+        queue.frames.push_back(frame);
+        Ok(())
+    }
+
+    /// Queues `frame` as it is, without passing it through the mutator.
+    pub fn send_frame(&self, frame: Vec<u8>) {
+        self.queue.lock().unwrap().frames.push_back(frame)
+    }
+
+    /// Calls `mutator` on each frame sent from now on, before it's queued.
+    pub fn set_mutator<F>(&self, mutator: F) where F: FnMut(&mut Vec<u8>) + Send + 'static {
+        self.queue.lock().unwrap().mutator = Some(Box::new(mutator))
+    }
+}
+
+pub struct LoopbackReceiver {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Debug for LoopbackReceiver {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "LoopbackReceiver {{ .. }}")
+    }
+}
+
+impl LoopbackReceiver {
+    /// Returns the data of the next frame and the contents of its shared memory regions. Fails
+    /// with `WouldBlock` if there are none, rather than waiting for a sender that can't be running
+    /// on this thread.
+    pub fn recv(&self) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> {
+        let frame = match self.queue.lock().unwrap().frames.pop_front() {
+            Some(frame) => frame,
+            None => return Err(Error::new(ErrorKind::WouldBlock, "no frames queued")),
+        };
+        let mut remaining = &frame[..];
+        let message = try!(read_message(&mut remaining));
+        if !remaining.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "trailing bytes after the message"))
+        }
+        Ok(message)
+    }
+}
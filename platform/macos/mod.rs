@@ -10,19 +10,26 @@
 use platform::macos::mach_sys::{kern_return_t, mach_msg_body_t, mach_msg_header_t};
 use platform::macos::mach_sys::{mach_msg_ool_descriptor_t, mach_msg_port_descriptor_t};
 use platform::macos::mach_sys::{mach_msg_timeout_t, mach_port_limits_t, mach_port_msgcount_t};
-use platform::macos::mach_sys::{mach_port_right_t, mach_port_t, mach_task_self_, vm_inherit_t};
+use platform::macos::mach_sys::{mach_port_right_t, mach_port_t, mach_port_type_t, mach_task_self_};
+use platform::macos::mach_sys::{vm_inherit_t};
 
 use bincode::serde::DeserializeError;
-use libc::{self, c_char, c_uint, c_void, size_t};
+use libc::{self, c_char, c_int, c_uint, c_void, size_t};
+#[cfg(target_os="macos")]
 use rand::{self, Rng};
 use std::cell::Cell;
+use std::cmp;
 use std::ffi::CString;
 use std::fmt::{self, Debug, Formatter};
-use std::io::{Error, ErrorKind};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
 use std::mem;
 use std::ops::Deref;
 use std::ptr;
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::usize;
 
 mod mach_sys;
 
@@ -30,12 +37,32 @@ mod mach_sys;
 /// this, we retry and spill to the heap.
 const SMALL_MESSAGE_SIZE: usize = 4096;
 
+/// Messages at least this big are sent out of line by default.
+const DEFAULT_OUT_OF_LINE_THRESHOLD: usize = 32 * 1024;
+
+/// The `msgh_id` of messages whose data is in their last out-of-line descriptor.
+const OUT_OF_LINE_DATA_MESSAGE_ID: i32 = 1;
+
+lazy_static! {
+    static ref OUT_OF_LINE_THRESHOLD: AtomicUsize =
+        AtomicUsize::new(DEFAULT_OUT_OF_LINE_THRESHOLD);
+}
+
 /// A string to prepend to our bootstrap ports.
+#[cfg(target_os="macos")]
 static BOOTSTRAP_PREFIX: &'static str = "org.rust-lang.ipc-channel.";
 
+/// What a `MachServer` adds to its name when it registers it, so that `MachSender::connect()` can
+/// tell it from a one-shot server, whose clients send straight to its port.
+static MULTI_CLIENT_SUFFIX: &'static str = ".clients";
+
 const BOOTSTRAP_NAME_IN_USE: kern_return_t = 1101;
 const BOOTSTRAP_SUCCESS: kern_return_t = 0;
+const BOOTSTRAP_UNKNOWN_SERVICE: kern_return_t = 1102;
+const KERN_FAILURE: kern_return_t = 5;
+const KERN_INVALID_ARGUMENT: kern_return_t = 4;
 const KERN_INVALID_RIGHT: kern_return_t = 17;
+const KERN_NOT_SUPPORTED: kern_return_t = 46;
 const KERN_SUCCESS: kern_return_t = 0;
 const MACH_MSGH_BITS_COMPLEX: u32 = 0x80000000;
 const MACH_MSG_IPC_KERNEL: kern_return_t = 0x00000800;
@@ -59,9 +86,12 @@ const MACH_PORT_LIMITS_INFO: i32 = 1;
 const MACH_PORT_NULL: mach_port_t = 0;
 const MACH_PORT_QLIMIT_LARGE: mach_port_msgcount_t = 1024;
 const MACH_PORT_QLIMIT_MAX: mach_port_msgcount_t = MACH_PORT_QLIMIT_LARGE;
+const MACH_PORT_RIGHT_DEAD_NAME: mach_port_right_t = 4;
 const MACH_PORT_RIGHT_PORT_SET: mach_port_right_t = 3;
 const MACH_PORT_RIGHT_RECEIVE: mach_port_right_t = 1;
 const MACH_PORT_RIGHT_SEND: mach_port_right_t = 0;
+const MACH_PORT_TYPE_DEAD_NAME: mach_port_type_t = 1 << (MACH_PORT_RIGHT_DEAD_NAME + 16);
+const MACH_PORT_TYPE_RECEIVE: mach_port_type_t = 1 << (MACH_PORT_RIGHT_RECEIVE + 16);
 const MACH_RCV_BODY_ERROR: kern_return_t = 0x1000400c;
 const MACH_RCV_HEADER_ERROR: kern_return_t = 0x1000400b;
 const MACH_RCV_INTERRUPTED: kern_return_t = 0x10004005;
@@ -132,6 +162,58 @@ impl Drop for MachReceiver {
 }
 
 impl MachReceiver {
+    /// Port rights don't survive `exec`, so there is nothing to inherit.
+    pub fn inheritable_token(&self) -> Result<String,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_inherited_token(_: &str) -> Result<MachReceiver,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_process(_: u32, _: &str) -> Result<MachReceiver,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_raw_fd(_: c_int) -> Result<MachReceiver,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn peer_security_label(&self) -> Result<Option<String>,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn queued_bytes(&self) -> Result<usize,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn recv_buffer_size(&self) -> Result<usize,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    /// Mach messages are queued by count, not by size, so there is no buffer to resize.
+    pub fn set_recv_buffer_size(&self, _: usize) -> Result<(),MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    /// The kernel maps big messages into our address space itself, as out-of-line memory, so
+    /// there is no buffer sized by the sender to check.
+    pub fn set_max_message_size(&mut self, _: Option<usize>) {}
+
+    /// Identifies this receiver among the live ports of this task. This is the same ID that
+    /// `MachReceiverSet::add()` returns for it.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        Some(self.port.get() as i64)
+    }
+
     fn new() -> Result<MachReceiver,MachError> {
         let mut port: mach_port_t = 0;
         let os_result = unsafe {
@@ -193,7 +275,13 @@ impl MachReceiver {
         }
     }
 
-    fn register_bootstrap_name(&self) -> Result<String,MachError> {
+    /// Registers a send right to this port with the bootstrap server. If no name is given, a
+    /// fresh one is generated.
+    #[cfg(target_os="macos")]
+    /// Registers the port as `requested_name`, or a fresh name if none is given, followed by
+    /// `suffix`. Returns the name without the suffix.
+    fn register_bootstrap_name(&self, requested_name: Option<&str>, suffix: &str)
+                               -> Result<String,MachError> {
         let port = self.port.get();
         debug_assert!(port != MACH_PORT_NULL);
         unsafe {
@@ -221,10 +309,16 @@ impl MachReceiver {
             let mut os_result;
             let mut name;
             loop {
-                name = format!("{}{}", BOOTSTRAP_PREFIX, rand::thread_rng().gen::<i64>());
-                let c_name = CString::new(name.clone()).unwrap();
+                name = match requested_name {
+                    Some(requested_name) => requested_name.to_owned(),
+                    None => format!("{}{}", BOOTSTRAP_PREFIX, rand::thread_rng().gen::<i64>()),
+                };
+                let c_name = match CString::new(format!("{}{}", name, suffix)) {
+                    Ok(c_name) => c_name,
+                    Err(_) => return Err(MachError(KERN_INVALID_ARGUMENT)),
+                };
                 os_result = bootstrap_register2(bootstrap_port, c_name.as_ptr(), right, 0);
-                if os_result == BOOTSTRAP_NAME_IN_USE {
+                if os_result == BOOTSTRAP_NAME_IN_USE && requested_name.is_none() {
                     continue
                 }
                 if os_result != BOOTSTRAP_SUCCESS {
@@ -236,6 +330,7 @@ impl MachReceiver {
         }
     }
 
+    #[cfg(target_os="macos")]
     fn unregister_global_name(name: String) -> Result<(),MachError> {
         unsafe {
             let mut bootstrap_port = 0;
@@ -344,25 +439,75 @@ impl MachSender {
         }
     }
 
-    pub fn connect(name: String) -> Result<MachSender,MachError> {
-        unsafe {
-            let mut bootstrap_port = 0;
-            let os_result = mach_sys::task_get_special_port(mach_task_self(),
-                                                            TASK_BOOTSTRAP_PORT,
-                                                            &mut bootstrap_port);
-            if os_result != KERN_SUCCESS {
-                return Err(MachError(os_result))
-            }
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
 
-            let mut port = 0;
-            let c_name = CString::new(name).unwrap();
-            let os_result = bootstrap_look_up(bootstrap_port, c_name.as_ptr(), &mut port);
-            if os_result == BOOTSTRAP_SUCCESS {
-                Ok(MachSender::from_name(port))
-            } else {
-                Err(MachError(os_result))
+    pub fn queued_bytes(&self) -> Result<usize,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    /// Returns true once the receive right has been destroyed, which turns our send right into a
+    /// dead name.
+    pub fn receiver_is_closed(&self) -> Result<bool,MachError> {
+        let mut port_type = 0;
+        let os_result = unsafe {
+            mach_sys::mach_port_type(mach_task_self(), self.port, &mut port_type)
+        };
+        if os_result != KERN_SUCCESS {
+            return Err(MachError(os_result))
+        }
+        Ok((port_type & MACH_PORT_TYPE_DEAD_NAME) != 0)
+    }
+
+    pub fn send_buffer_size(&self) -> Result<usize,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    /// See `MachReceiver::set_recv_buffer_size()`.
+    pub fn set_send_buffer_size(&self, _: usize) -> Result<(),MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        Some(self.port as i64)
+    }
+
+    /// Port rights don't survive `exec`, so there is nothing to inherit.
+    pub fn inheritable_token(&self) -> Result<String,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_inherited_token(_: &str) -> Result<MachSender,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_process(_: u32, _: &str) -> Result<MachSender,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn from_raw_fd(_: c_int) -> Result<MachSender,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,MachError> {
+        Err(MachError(KERN_NOT_SUPPORTED))
+    }
+
+    pub fn connect(name: String) -> Result<MachSender,MachError> {
+        // A `MachServer` gives every client a dedicated channel, the receiving end of which we
+        // hand over to it, so that it can tell its clients apart and accept more than one.
+        match look_up_server(&format!("{}{}", name, MULTI_CLIENT_SUFFIX)) {
+            Ok(server_sender) => {
+                let (sender, receiver) = try!(channel());
+                try!(server_sender.send(&[], vec![MachChannel::Receiver(receiver)], vec![]));
+                return Ok(sender)
             }
+            Err(MachError(BOOTSTRAP_UNKNOWN_SERVICE)) => {}
+            Err(error) => return Err(error),
         }
+        // One-shot servers take the first message straight on their own port.
+        look_up_server(&name)
     }
 
     pub fn send(&self,
@@ -370,8 +515,68 @@ impl MachSender {
                 ports: Vec<MachChannel>,
                 shared_memory_regions: Vec<MachSharedMemory>)
                 -> Result<(),MachError> {
+        self.send_vectored(&[data], ports, shared_memory_regions)
+    }
+
+    /// Sends the concatenation of `data`, copying the slices straight into the message.
+    pub fn send_vectored(&self,
+                         data: &[&[u8]],
+                         ports: Vec<MachChannel>,
+                         shared_memory_regions: Vec<MachSharedMemory>)
+                         -> Result<(),MachError> {
+        let data_length = data.iter().fold(0, |size, slice| size + slice.len());
+        // Big messages go out of line, so that the kernel can map their pages into the receiver
+        // copy-on-write, instead of copying them in and out of the message.
+        let out_of_line = data_length >= OUT_OF_LINE_THRESHOLD.load(Ordering::Relaxed);
+        let mut result = self.send_message(data, &ports, &shared_memory_regions, out_of_line);
+        // When the kernel is short of buffer space, it refuses messages whose data it would have
+        // to copy, before taking anything from us; so the data can go out of line instead, where
+        // it's mapped rather than copied.
+        match result {
+            Err(MachError(MACH_SEND_NO_BUFFER)) | Err(MachError(MACH_SEND_TOO_LARGE))
+                    if !out_of_line && data_length > 0 => {
+                result = self.send_message(data, &ports, &shared_memory_regions, true);
+            }
+            _ => {}
+        }
+        // The rights and regions are the kernel's now, or were destroyed along with the message.
+        for port in ports {
+            mem::forget(port)
+        }
+        for shared_memory_region in shared_memory_regions {
+            mem::forget(shared_memory_region)
+        }
+        result
+    }
+
+    /// Builds and sends a message, leaving the ports and regions in it for the caller to give up.
+    fn send_message(&self,
+                    data: &[&[u8]],
+                    ports: &[MachChannel],
+                    shared_memory_regions: &[MachSharedMemory],
+                    out_of_line: bool)
+                    -> Result<(),MachError> {
+        let data_length = data.iter().fold(0, |size, slice| size + slice.len());
+        let concatenated_data;
+        let out_of_line_data = if !out_of_line {
+            None
+        } else if data.len() == 1 {
+            Some(data[0])
+        } else {
+            concatenated_data = data.concat();
+            Some(&concatenated_data[..])
+        };
+        let (data, data_length) = match out_of_line_data {
+            Some(_) => (&[][..], 0),
+            None => (data, data_length),
+        };
+        let descriptor_count = ports.len() + shared_memory_regions.len() +
+            out_of_line_data.iter().count();
+
         unsafe {
-            let size = Message::size_of(data.len(), ports.len(), shared_memory_regions.len());
+            let size = Message::size_of(data_length,
+                                        ports.len(),
+                                        descriptor_count - ports.len());
             let message = libc::malloc(size as size_t) as *mut Message;
             (*message).header.msgh_bits = (MACH_MSG_TYPE_COPY_SEND as u32) |
                 MACH_MSGH_BITS_COMPLEX;
@@ -379,48 +584,57 @@ impl MachSender {
             (*message).header.msgh_local_port = MACH_PORT_NULL;
             (*message).header.msgh_remote_port = self.port;
             (*message).header.msgh_reserved = 0;
-            (*message).header.msgh_id = 0;
-            (*message).body.msgh_descriptor_count =
-                (ports.len() + shared_memory_regions.len()) as u32;
+            (*message).header.msgh_id = if out_of_line_data.is_some() {
+                OUT_OF_LINE_DATA_MESSAGE_ID
+            } else {
+                0
+            };
+            (*message).body.msgh_descriptor_count = descriptor_count as u32;
 
             let mut port_descriptor_dest = message.offset(1) as *mut mach_msg_port_descriptor_t;
-            for outgoing_port in ports.into_iter() {
+            for outgoing_port in ports {
                 (*port_descriptor_dest).name = outgoing_port.port();
                 (*port_descriptor_dest).pad1 = 0;
 
-                (*port_descriptor_dest).disposition = match outgoing_port {
+                (*port_descriptor_dest).disposition = match *outgoing_port {
                     MachChannel::Sender(_) => MACH_MSG_TYPE_MOVE_SEND,
                     MachChannel::Receiver(_) => MACH_MSG_TYPE_MOVE_RECEIVE,
                 };
 
                 (*port_descriptor_dest).type_ = MACH_MSG_PORT_DESCRIPTOR;
                 port_descriptor_dest = port_descriptor_dest.offset(1);
-                mem::forget(outgoing_port);
             }
 
             let mut shared_memory_descriptor_dest =
                 port_descriptor_dest as *mut mach_msg_ool_descriptor_t;
-            for shared_memory_region in shared_memory_regions.into_iter() {
+            for shared_memory_region in shared_memory_regions {
                 (*shared_memory_descriptor_dest).address =
                     shared_memory_region.as_ptr() as *const c_void as *mut c_void;
                 (*shared_memory_descriptor_dest).size = shared_memory_region.len() as u32;
                 (*shared_memory_descriptor_dest).deallocate = 1;
                 (*shared_memory_descriptor_dest).copy = MACH_MSG_VIRTUAL_COPY as u8;
                 (*shared_memory_descriptor_dest).type_ = MACH_MSG_OOL_DESCRIPTOR;
-                mem::forget(shared_memory_region);
+                shared_memory_descriptor_dest = shared_memory_descriptor_dest.offset(1);
+            }
+
+            // The data is still ours, so the kernel must leave it mapped.
+            if let Some(out_of_line_data) = out_of_line_data {
+                (*shared_memory_descriptor_dest).address =
+                    out_of_line_data.as_ptr() as *const c_void as *mut c_void;
+                (*shared_memory_descriptor_dest).size = out_of_line_data.len() as u32;
+                (*shared_memory_descriptor_dest).deallocate = 0;
+                (*shared_memory_descriptor_dest).copy = MACH_MSG_VIRTUAL_COPY as u8;
+                (*shared_memory_descriptor_dest).type_ = MACH_MSG_OOL_DESCRIPTOR;
                 shared_memory_descriptor_dest = shared_memory_descriptor_dest.offset(1);
             }
 
             // Zero out the last word for paranoia's sake.
             *((message as *mut u8).offset(size as isize - 4) as *mut u32) = 0;
 
-            let data_dest = shared_memory_descriptor_dest as *mut u8;
-            ptr::copy_nonoverlapping(data.as_ptr(), data_dest, data.len());
-
-            let mut ptr = message as *const u32;
-            let end = (message as *const u8).offset(size as isize) as *const u32;
-            while ptr < end {
-                ptr = ptr.offset(1);
+            let mut data_dest = shared_memory_descriptor_dest as *mut u8;
+            for slice in data {
+                ptr::copy_nonoverlapping(slice.as_ptr(), data_dest, slice.len());
+                data_dest = data_dest.offset(slice.len() as isize);
             }
 
             let os_result = mach_sys::mach_msg(message as *mut _,
@@ -437,20 +651,74 @@ impl MachSender {
             Ok(())
         }
     }
+
+    pub fn send_batch(&self, messages: Vec<(Vec<u8>, Vec<MachChannel>, Vec<MachSharedMemory>)>)
+                      -> Result<(),MachError> {
+        for (data, ports, shared_memory_regions) in messages {
+            try!(self.send(&data[..], ports, shared_memory_regions));
+        }
+        Ok(())
+    }
 }
 
+#[derive(PartialEq, Debug)]
 pub enum MachChannel {
     Sender(MachSender),
     Receiver(MachReceiver),
 }
 
 impl MachChannel {
-    fn port(&self) -> mach_port_t {
+    /// Wraps an arbitrary file descriptor, taking ownership of it, in a fileport: a send right
+    /// that the kernel turns back into a descriptor in whichever task receives it.
+    pub fn from_fd(fd: c_int) -> Result<MachChannel,MachError> {
+        let mut port = MACH_PORT_NULL;
+        let result = unsafe {
+            let result = fileport_makeport(fd, &mut port);
+            libc::close(fd);
+            result
+        };
+        if result != 0 {
+            return Err(MachError(KERN_FAILURE))
+        }
+        Ok(MachChannel::Sender(MachSender::from_name(port)))
+    }
+
+    /// Takes ownership of one user reference to a right that we hold, so that it can be sent
+    /// along with a message.
+    pub fn from_port(port: mach_port_t, right: MachPortRight) -> MachChannel {
+        match right {
+            MachPortRight::Send => MachChannel::Sender(MachSender::from_name(port)),
+            MachPortRight::Receive => MachChannel::Receiver(MachReceiver::from_name(port)),
+        }
+    }
+
+    pub fn port(&self) -> mach_port_t {
         match *self {
             MachChannel::Sender(ref sender) => sender.port,
             MachChannel::Receiver(ref receiver) => receiver.port.get(),
         }
     }
+
+    pub fn right(&self) -> MachPortRight {
+        match *self {
+            MachChannel::Sender(_) => MachPortRight::Send,
+            MachChannel::Receiver(_) => MachPortRight::Receive,
+        }
+    }
+
+    /// Gives up ownership of our reference to the right, without releasing it.
+    pub fn into_port(self) -> mach_port_t {
+        let port = self.port();
+        mem::forget(self);
+        port
+    }
+}
+
+/// The kind of right a `MachChannel` holds to its port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachPortRight {
+    Send,
+    Receive,
 }
 
 #[derive(PartialEq, Debug)]
@@ -481,6 +749,35 @@ impl OpaqueMachChannel {
     pub fn to_receiver(&mut self) -> MachReceiver {
         MachReceiver::from_name(mem::replace(&mut self.port, MACH_PORT_NULL))
     }
+
+    /// Converts this channel into one that can be sent along with another message, asking the
+    /// kernel which kind of right we actually hold.
+    pub fn to_channel(&mut self) -> Result<MachChannel,MachError> {
+        let mut port_type = 0;
+        let os_result = unsafe {
+            mach_sys::mach_port_type(mach_task_self(), self.port, &mut port_type)
+        };
+        if os_result != KERN_SUCCESS {
+            return Err(MachError(os_result))
+        }
+        if (port_type & MACH_PORT_TYPE_RECEIVE) != 0 {
+            Ok(MachChannel::Receiver(self.to_receiver()))
+        } else {
+            Ok(MachChannel::Sender(self.to_sender()))
+        }
+    }
+
+    /// Turns a fileport back into a file descriptor. Other channels aren't files, and fail.
+    pub fn to_fd(&mut self) -> Result<c_int,MachError> {
+        let fd = unsafe {
+            fileport_makefd(self.port)
+        };
+        if fd < 0 {
+            return Err(MachError(KERN_FAILURE))
+        }
+        drop(self.to_sender());
+        Ok(fd)
+    }
 }
 
 pub struct MachReceiverSet {
@@ -546,6 +843,8 @@ impl MachSelectionResult {
 enum BlockingMode {
     Blocking,
     Nonblocking,
+    /// Blocks for at most this many milliseconds.
+    Timeout(mach_msg_timeout_t),
 }
 
 fn select(port: mach_port_t, blocking_mode: BlockingMode)
@@ -559,6 +858,9 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
         let (flags, timeout) = match blocking_mode {
             BlockingMode::Blocking => (MACH_RCV_MSG | MACH_RCV_LARGE, MACH_MSG_TIMEOUT_NONE),
             BlockingMode::Nonblocking => (MACH_RCV_MSG | MACH_RCV_LARGE | MACH_RCV_TIMEOUT, 0),
+            BlockingMode::Timeout(timeout) => {
+                (MACH_RCV_MSG | MACH_RCV_LARGE | MACH_RCV_TIMEOUT, timeout)
+            }
         };
         match mach_sys::mach_msg(message as *mut _,
                                  flags,
@@ -630,10 +932,18 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
             descriptors_remaining -= 1;
         }
 
-        let payload_ptr = shared_memory_descriptor as *mut u8;
-        let payload_size = message as usize + ((*message).header.msgh_size as usize) -
-            (shared_memory_descriptor as usize);
-        let payload = slice::from_raw_parts(payload_ptr, payload_size).to_vec();
+        let payload = if (*message).header.msgh_id == OUT_OF_LINE_DATA_MESSAGE_ID {
+            // Dropping the region unmaps it.
+            match shared_memory_regions.pop() {
+                Some(out_of_line_data) => out_of_line_data.to_vec(),
+                None => return Err(MachError(MACH_RCV_INVALID_DATA)),
+            }
+        } else {
+            let payload_ptr = shared_memory_descriptor as *mut u8;
+            let payload_size = message as usize + ((*message).header.msgh_size as usize) -
+                (shared_memory_descriptor as usize);
+            slice::from_raw_parts(payload_ptr, payload_size).to_vec()
+        };
 
         if let Some(allocated_buffer) = allocated_buffer {
             libc::free(allocated_buffer)
@@ -646,6 +956,35 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
     }
 }
 
+/// Servers are registered with the bootstrap server, so there is no directory to configure.
+pub fn set_server_socket_directory(_: &str) {}
+
+/// Small messages are received into a buffer on the stack, so there is nothing worth pooling.
+pub fn set_receive_buffer_pool_size(_: usize) {}
+
+/// Sets the size from which messages are sent as out-of-line memory rather than copied into the
+/// message; `None` always copies them.
+pub fn set_out_of_line_threshold(threshold: Option<usize>) {
+    OUT_OF_LINE_THRESHOLD.store(threshold.unwrap_or(usize::MAX), Ordering::Relaxed)
+}
+
+/// `mach_msg()` restarts interrupted sends and receives itself, as we never ask it not to.
+pub fn set_retry_on_interrupt(_: bool) {}
+
+/// See `MachReceiver::set_max_message_size()`.
+pub fn set_receive_memory_budget(_: Option<usize>) {}
+
+/// Mach messages arrive whole, so there are no fragments to wait for.
+pub fn set_reassembly_timeout(_: Option<Duration>) {}
+
+pub fn set_thread_affinity(_: &[usize]) -> Result<(),MachError> {
+    Err(MachError(KERN_NOT_SUPPORTED))
+}
+
+pub fn set_thread_priority(_: i32) -> Result<(),MachError> {
+    Err(MachError(KERN_NOT_SUPPORTED))
+}
+
 pub struct MachOneShotServer {
     receiver: Option<MachReceiver>,
     name: String,
@@ -653,26 +992,58 @@ pub struct MachOneShotServer {
 
 impl Drop for MachOneShotServer {
     fn drop(&mut self) {
-        drop(MachReceiver::unregister_global_name(mem::replace(&mut self.name, String::new())));
+        drop(release_server_name(mem::replace(&mut self.name, String::new())));
     }
 }
 
 impl MachOneShotServer {
     pub fn new() -> Result<(MachOneShotServer, String),MachError> {
-        let receiver = try!(MachReceiver::new());
-        let name = try!(receiver.register_bootstrap_name());
+        let (receiver, name) = try!(server_receiver(None, ""));
         Ok((MachOneShotServer {
             receiver: Some(receiver),
             name: name.clone(),
         }, name))
     }
 
-    pub fn accept(mut self) -> Result<(MachReceiver,
-                                       Vec<u8>,
-                                       Vec<OpaqueMachChannel>,
-                                       Vec<MachSharedMemory>),MachError> {
+    pub fn with_name(name: &str) -> Result<MachOneShotServer,MachError> {
+        let (receiver, name) = try!(server_receiver(Some(name), ""));
+        Ok(MachOneShotServer {
+            receiver: Some(receiver),
+            name: name,
+        })
+    }
+
+    /// Bootstrap names can't be restricted, so the mode is ignored.
+    pub fn with_name_and_mode(name: &str, _: u32) -> Result<MachOneShotServer,MachError> {
+        MachOneShotServer::with_name(name)
+    }
+
+    pub fn accept(self) -> Result<(MachReceiver,
+                                   Vec<u8>,
+                                   Vec<OpaqueMachChannel>,
+                                   Vec<MachSharedMemory>),MachError> {
+        self.accept_with_blocking_mode(BlockingMode::Blocking)
+    }
+
+    pub fn accept_timeout(self, timeout: Duration) -> Result<(MachReceiver,
+                                                              Vec<u8>,
+                                                              Vec<OpaqueMachChannel>,
+                                                              Vec<MachSharedMemory>),MachError> {
+        let timeout_ms = timeout.as_secs()
+                                .saturating_mul(1000)
+                                .saturating_add(timeout.subsec_nanos() as u64 / 1_000_000);
+        let timeout_ms = cmp::min(timeout_ms, mach_msg_timeout_t::max_value() as u64);
+        self.accept_with_blocking_mode(BlockingMode::Timeout(timeout_ms as mach_msg_timeout_t))
+    }
+
+    /// The client sends its first message straight to our port, which becomes the receiver.
+    fn accept_with_blocking_mode(mut self, blocking_mode: BlockingMode)
+                                 -> Result<(MachReceiver,
+                                            Vec<u8>,
+                                            Vec<OpaqueMachChannel>,
+                                            Vec<MachSharedMemory>),MachError> {
         let (bytes, channels, shared_memory_regions) =
-            try!(self.receiver.as_mut().unwrap().recv());
+            try!(self.receiver.as_ref().unwrap().recv_with_blocking_mode(blocking_mode));
         Ok((mem::replace(&mut self.receiver, None).unwrap(),
             bytes,
             channels,
@@ -680,6 +1051,136 @@ impl MachOneShotServer {
     }
 }
 
+/// Returns a sender to the port registered with the bootstrap server as `name`.
+fn look_up_server(name: &str) -> Result<MachSender,MachError> {
+    unsafe {
+        let mut bootstrap_port = 0;
+        let os_result = mach_sys::task_get_special_port(mach_task_self(),
+                                                        TASK_BOOTSTRAP_PORT,
+                                                        &mut bootstrap_port);
+        if os_result != KERN_SUCCESS {
+            return Err(MachError(os_result))
+        }
+
+        let mut port = 0;
+        let c_name = match CString::new(name) {
+            Ok(c_name) => c_name,
+            Err(_) => return Err(MachError(KERN_INVALID_ARGUMENT)),
+        };
+        let os_result = bootstrap_look_up(bootstrap_port, c_name.as_ptr(), &mut port);
+        if os_result != BOOTSTRAP_SUCCESS {
+            return Err(MachError(os_result))
+        }
+        Ok(MachSender::from_name(port))
+    }
+}
+
+/// Makes a port for a server, and publishes it with the bootstrap server under `requested_name`,
+/// or under a fresh name if none is given, followed by `suffix`. Returns the name without the
+/// suffix.
+#[cfg(target_os="macos")]
+fn server_receiver(requested_name: Option<&str>, suffix: &str)
+                   -> Result<(MachReceiver, String),MachError> {
+    let receiver = try!(MachReceiver::new());
+    let name = try!(receiver.register_bootstrap_name(requested_name, suffix));
+    Ok((receiver, name))
+}
+
+/// iOS doesn't let apps publish names of their own with the bootstrap server. All a server can
+/// do is check in under a name that launchd already has on record for it, the way XPC services
+/// do with the `MachServices` of their property list; so only named servers are supported.
+#[cfg(target_os="ios")]
+fn server_receiver(requested_name: Option<&str>, suffix: &str)
+                   -> Result<(MachReceiver, String),MachError> {
+    let name = match requested_name {
+        Some(name) => name,
+        None => return Err(MachError(KERN_NOT_SUPPORTED)),
+    };
+    unsafe {
+        let mut bootstrap_port = 0;
+        let os_result = mach_sys::task_get_special_port(mach_task_self(),
+                                                        TASK_BOOTSTRAP_PORT,
+                                                        &mut bootstrap_port);
+        if os_result != KERN_SUCCESS {
+            return Err(MachError(os_result))
+        }
+
+        let mut port = 0;
+        let c_name = match CString::new(format!("{}{}", name, suffix)) {
+            Ok(c_name) => c_name,
+            Err(_) => return Err(MachError(KERN_INVALID_ARGUMENT)),
+        };
+        let os_result = bootstrap_check_in(bootstrap_port, c_name.as_ptr(), &mut port);
+        if os_result != BOOTSTRAP_SUCCESS {
+            return Err(MachError(os_result))
+        }
+        Ok((MachReceiver::from_name(port), name.to_owned()))
+    }
+}
+
+#[cfg(target_os="macos")]
+fn release_server_name(name: String) -> Result<(),MachError> {
+    MachReceiver::unregister_global_name(name)
+}
+
+/// Names that were checked in stay with launchd.
+#[cfg(target_os="ios")]
+fn release_server_name(_: String) -> Result<(),MachError> {
+    Ok(())
+}
+
+/// A server that can accept any number of clients, each on its own connection. It's registered
+/// under its name followed by `MULTI_CLIENT_SUFFIX`; on iOS, that's the name launchd must know.
+pub struct MachServer {
+    receiver: MachReceiver,
+    name: String,
+}
+
+impl Drop for MachServer {
+    fn drop(&mut self) {
+        let name = mem::replace(&mut self.name, String::new());
+        drop(release_server_name(format!("{}{}", name, MULTI_CLIENT_SUFFIX)));
+    }
+}
+
+impl MachServer {
+    pub fn new() -> Result<(MachServer, String),MachError> {
+        let (receiver, name) = try!(server_receiver(None, MULTI_CLIENT_SUFFIX));
+        Ok((MachServer {
+            receiver: receiver,
+            name: name.clone(),
+        }, name))
+    }
+
+    pub fn with_name(name: &str) -> Result<MachServer,MachError> {
+        let (receiver, name) = try!(server_receiver(Some(name), MULTI_CLIENT_SUFFIX));
+        Ok(MachServer {
+            receiver: receiver,
+            name: name,
+        })
+    }
+
+    /// Bootstrap names can't be restricted, so the mode is ignored.
+    pub fn with_name_and_mode(name: &str, _: u32) -> Result<MachServer,MachError> {
+        MachServer::with_name(name)
+    }
+
+    pub fn accept(&self) -> Result<(MachReceiver,
+                                    Vec<u8>,
+                                    Vec<OpaqueMachChannel>,
+                                    Vec<MachSharedMemory>),MachError> {
+        // Waits for a client to hand us the receiving end of its dedicated channel (see
+        // `MachSender::connect()`), and then for the first message on that channel.
+        let (_, mut channels, _) = try!(self.receiver.recv());
+        let receiver = match channels.pop() {
+            Some(mut channel) => channel.to_receiver(),
+            None => return Err(MachError(MACH_RCV_BODY_ERROR)),
+        };
+        let (bytes, channels, shared_memory_regions) = try!(receiver.recv());
+        Ok((receiver, bytes, channels, shared_memory_regions))
+    }
+}
+
 pub struct MachSharedMemory {
     ptr: *mut u8,
     length: usize,
@@ -774,6 +1275,18 @@ impl MachSharedMemory {
             MachSharedMemory::from_raw_parts(address, bytes.len())
         }
     }
+
+    /// Reads `length` bytes from the current position of `file` into a new region.
+    pub fn from_file(file: &File, length: usize) -> Result<MachSharedMemory,Error> {
+        let mut bytes = vec![0; length];
+        try!((&*file).read_exact(&mut bytes[..]));
+        Ok(MachSharedMemory::from_bytes(&bytes[..]))
+    }
+
+    /// Regions are plain VM pages, with no port of their own until they're sent.
+    pub fn endpoint_id(&self) -> Option<i64> {
+        None
+    }
 }
 
 unsafe fn allocate_vm_pages(length: usize) -> *mut u8 {
@@ -825,6 +1338,10 @@ impl MachError {
     pub fn channel_is_closed(&self) -> bool {
         self.0 == MACH_NOTIFY_NO_SENDERS
     }
+
+    pub fn is_unsupported(&self) -> bool {
+        self.0 == KERN_NOT_SUPPORTED
+    }
 }
 
 impl From<MachError> for DeserializeError {
@@ -834,10 +1351,21 @@ impl From<MachError> for DeserializeError {
 }
 
 impl From<MachError> for Error {
-    /// These error descriptions are from `mach/message.h`.
+    /// These error descriptions are from `mach/message.h` and `servers/bootstrap_defs.h`.
     fn from(mach_error: MachError) -> Error {
         match mach_error.0 {
             MACH_MSG_SUCCESS => Error::new(ErrorKind::Other, "Success"),
+            BOOTSTRAP_NAME_IN_USE => {
+                Error::new(ErrorKind::AddrInUse, "The name is already registered.")
+            }
+            BOOTSTRAP_UNKNOWN_SERVICE => {
+                Error::new(ErrorKind::NotFound, "No service is registered under the name.")
+            }
+            KERN_NOT_SUPPORTED => Error::new(ErrorKind::Other, "Operation not supported."),
+            // Not an error code, but what we report when a no-senders notification arrives.
+            MACH_NOTIFY_NO_SENDERS => {
+                Error::new(ErrorKind::BrokenPipe, "All senders have been dropped.")
+            }
             MACH_MSG_IPC_SPACE => {
                 Error::new(ErrorKind::Other,
                            "No room in IPC name space for another capability name.")
@@ -958,9 +1486,15 @@ impl From<MachError> for Error {
 }
 
 extern {
+    #[cfg(target_os="macos")]
     fn bootstrap_register2(bp: mach_port_t, service_name: name_t, sp: mach_port_t, flags: u64)
                            -> kern_return_t;
+    fn fileport_makeport(fd: c_int, port: *mut mach_port_t) -> c_int;
+    fn fileport_makefd(port: mach_port_t) -> c_int;
     fn bootstrap_look_up(bp: mach_port_t, service_name: name_t, sp: *mut mach_port_t)
                          -> kern_return_t;
+    #[cfg(target_os="ios")]
+    fn bootstrap_check_in(bp: mach_port_t, service_name: name_t, sp: *mut mach_port_t)
+                          -> kern_return_t;
 }
 
@@ -7,70 +7,238 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::channel;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_server_socket_directory;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_receive_buffer_pool_size;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_out_of_line_threshold;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_retry_on_interrupt;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_receive_memory_budget;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_reassembly_timeout;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::{SigpipeStrategy, set_sigpipe_strategy};
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_thread_affinity;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::set_thread_priority;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixReceiver as OsIpcReceiver;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixSender as OsIpcSender;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixReceiverSet as OsIpcReceiverSet;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixSharedMemory as OsIpcSharedMemory;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixChannel as OsIpcChannel;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixSelectionResult as OsIpcSelectionResult;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::OpaqueUnixChannel as OsOpaqueIpcChannel;
-#[cfg(target_os="linux")]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 pub use platform::linux::UnixOneShotServer as OsIpcOneShotServer;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::UnixServer as OsIpcServer;
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::UnixError as OsIpcError;
 
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::channel;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_server_socket_directory;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_receive_buffer_pool_size;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_out_of_line_threshold;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_retry_on_interrupt;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_receive_memory_budget;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_reassembly_timeout;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_thread_affinity;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::set_thread_priority;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachReceiver as OsIpcReceiver;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachSender as OsIpcSender;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachReceiverSet as OsIpcReceiverSet;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachSharedMemory as OsIpcSharedMemory;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachChannel as OsIpcChannel;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachSelectionResult as OsIpcSelectionResult;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::OpaqueMachChannel as OsOpaqueIpcChannel;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 pub use platform::macos::MachOneShotServer as OsIpcOneShotServer;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::MachServer as OsIpcServer;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::MachError as OsIpcError;
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
+pub use platform::macos::MachPortRight;
 
-// Windows and Android use in-process mpsc channels IPC for now
-#[cfg(any(target_os="windows", target_os="android"))]
+// Windows uses in-process mpsc channels IPC for now
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::channel;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_server_socket_directory;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_receive_buffer_pool_size;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_out_of_line_threshold;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_retry_on_interrupt;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_receive_memory_budget;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_reassembly_timeout;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_thread_affinity;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::set_thread_priority;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscReceiver as OsIpcReceiver;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscSender as OsIpcSender;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscReceiverSet as OsIpcReceiverSet;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscSharedMemory as OsIpcSharedMemory;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscChannel as OsIpcChannel;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscSelectionResult as OsIpcSelectionResult;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::OpaqueMpscChannel as OsOpaqueIpcChannel;
-#[cfg(any(target_os="windows", target_os="android"))]
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
 pub use platform::inprocess::MpscOneShotServer as OsIpcOneShotServer;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::MpscServer as OsIpcServer;
+#[cfg(any(feature="force-inprocess", target_os="windows", target_arch="wasm32"))]
+pub use platform::inprocess::MpscError as OsIpcError;
+
+pub use platform::switch::{Backend, SwitchChannel, SwitchError, SwitchOneShotServer};
+pub use platform::switch::{SwitchOpaqueChannel, SwitchReceiver, SwitchReceiverSet};
+pub use platform::switch::{SwitchSelectionResult, SwitchSender, SwitchServer, SwitchSharedMemory};
+pub use platform::switch::Unsupported;
+pub use platform::switch::{backend, channel_with_backend, scheduled_channel, set_backend};
+pub use platform::switch::channel as switch_channel;
 
+pub use platform::inprocess::{Delivery, Schedule, Scheduler};
+pub use platform::inprocess::set_server_namespace;
+pub use platform::leaks::{HandleKind, LeakCheckpoint, LeakScope, LiveHandle, live_handles,
+                          scope_leaks};
+#[cfg(any(test, feature="fuzzing"))]
+pub use platform::loopback::{LoopbackReceiver, LoopbackSender};
+#[cfg(any(test, feature="fuzzing"))]
+pub use platform::loopback::channel as loopback_channel;
+#[cfg(all(any(test, feature="fuzzing"), not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+pub use platform::linux::reassemble_fragments;
+pub use platform::stdio::{StdioReceiver, StdioSender};
+pub use platform::tcp::{ChecksumMismatch, TcpReceiver, TcpSender, TcpServer};
+#[cfg(feature="websocket")]
+pub use platform::websocket::{WebSocketReceiver, WebSocketSender, WebSocketServer};
 #[cfg(target_os="linux")]
+pub use platform::vsock::{VMADDR_CID_HOST, VMADDR_CID_LOCAL};
+#[cfg(target_os="linux")]
+pub use platform::vsock::{VsockReceiver, VsockSender, VsockServer};
+
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
 mod linux;
-#[cfg(target_os="macos")]
+#[cfg(all(not(feature="force-inprocess"), any(target_os="macos", target_os="ios")))]
 mod macos;
-#[cfg(any(target_os="windows", target_os="android"))]
+// Always built, as any platform can switch to it at runtime; see `switch`.
+#[cfg_attr(not(any(feature="force-inprocess", target_os="windows", target_arch="wasm32")),
+           allow(dead_code))]
 mod inprocess;
+mod leaks;
+#[cfg(any(test, feature="fuzzing"))]
+mod loopback;
+mod stdio;
+mod switch;
+mod tcp;
+#[cfg(target_os="linux")]
+mod vsock;
+#[cfg(feature="websocket")]
+mod websocket;
 
 #[cfg(test)]
 mod test;
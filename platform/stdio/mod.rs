@@ -0,0 +1,108 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A transport over a pair of byte streams, usually a child process's stdin and stdout, for
+//! children that can't inherit sockets or handles. Messages are framed as on the TCP transport,
+//! with the same limits: channels can't be transferred, and shared memory regions are copied.
+//!
+//! Nothing else may write to the stream a sender writes to. In a child talking to its parent over
+//! its stdout, that rules out `println!()` and anything else that prints.
+
+use platform::tcp::{read_checksummed_message, read_message, write_checksummed_message,
+                    write_message};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, BufReader, Error, Read, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct StdioSender {
+    /// Shared by clones, and locked for the whole of each message so that their messages don't
+    /// interleave.
+    stream: Arc<Mutex<Box<Write + Send>>>,
+    checksums: bool,
+}
+
+impl Debug for StdioSender {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "StdioSender {{ .. }}")
+    }
+}
+
+impl StdioSender {
+    pub fn new<W>(stream: W) -> StdioSender where W: Write + Send + 'static {
+        StdioSender {
+            stream: Arc::new(Mutex::new(Box::new(stream) as Box<Write + Send>)),
+            checksums: false,
+        }
+    }
+
+    /// Sends to whoever is reading our stdout.
+    pub fn stdout() -> StdioSender {
+        StdioSender::new(io::stdout())
+    }
+
+    /// Follows each frame with its checksum, for streams that may corrupt what they carry, such
+    /// as serial lines. The receiver must expect them.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true
+    }
+
+    pub fn send(&self, data: &[u8], shared_memory_regions: &[&[u8]]) -> Result<(),Error> {
+        let mut stream = self.stream.lock().unwrap();
+        if self.checksums {
+            try!(write_checksummed_message(&mut *stream, data, shared_memory_regions));
+        } else {
+            try!(write_message(&mut *stream, data, shared_memory_regions));
+        }
+        // Pipes aren't buffered, but our own stdout is.
+        stream.flush()
+    }
+}
+
+pub struct StdioReceiver {
+    /// Buffered, as frames are read a few bytes at a time.
+    stream: Mutex<BufReader<Box<Read + Send>>>,
+    checksums: bool,
+}
+
+impl Debug for StdioReceiver {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "StdioReceiver {{ .. }}")
+    }
+}
+
+impl StdioReceiver {
+    pub fn new<R>(stream: R) -> StdioReceiver where R: Read + Send + 'static {
+        StdioReceiver {
+            stream: Mutex::new(BufReader::new(Box::new(stream) as Box<Read + Send>)),
+            checksums: false,
+        }
+    }
+
+    /// Receives from whoever is writing to our stdin.
+    pub fn stdin() -> StdioReceiver {
+        StdioReceiver::new(io::stdin())
+    }
+
+    /// Expects each frame to be followed by its checksum, as from a sender with checksums
+    /// enabled.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true
+    }
+
+    /// Returns the data of the next message and the contents of its shared memory regions.
+    pub fn recv(&self) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> {
+        let mut stream = self.stream.lock().unwrap();
+        if self.checksums {
+            read_checksummed_message(&mut *stream)
+        } else {
+            read_message(&mut *stream)
+        }
+    }
+}
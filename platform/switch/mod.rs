@@ -0,0 +1,1005 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Wrappers that put each channel on either the native backend or the in-process one, chosen at
+//! runtime. This is what `ipc` is built on, so that tests and single-process programs can keep
+//! using `IpcSender` and `IpcReceiver` without opening any sockets, ports or shared memory.
+//!
+//! The backend is picked whenever something new is created: a channel, a server, a receiver set
+//! or a shared memory region. Connecting to a server looks in the namespace of the current
+//! backend, and endpoints inherited from another process are always native. The two backends
+//! don't mix: a channel can't be sent over a channel of the other backend, nor added to a
+//! receiver set of the other backend. Shared memory regions are copied across instead.
+
+use bincode::serde::DeserializeError;
+use libc::c_int;
+use platform::{OsIpcChannel, OsIpcError, OsIpcOneShotServer, OsIpcReceiver, OsIpcReceiverSet};
+use platform::{OsIpcSelectionResult, OsIpcSender, OsIpcServer, OsIpcSharedMemory};
+use platform::OsOpaqueIpcChannel;
+use platform::inprocess::{self, MpscChannel, MpscError, MpscOneShotServer, MpscReceiver};
+use platform::inprocess::{MpscReceiverSet, MpscSelectionResult, MpscSender, MpscServer};
+use platform::inprocess::{MpscSharedMemory, OpaqueMpscChannel, Scheduler};
+#[cfg(windows)]
+use platform::inprocess::MpscHandle;
+use platform;
+use std::env;
+use std::error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::ops::Deref;
+#[cfg(windows)]
+use std::os::windows::io::RawHandle;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Environment variable that picks the initial backend: `inprocess` for the in-process one, and
+/// anything else for the native one. Child processes inherit it.
+const BACKEND_ENV_VAR: &'static str = "IPC_CHANNEL_BACKEND";
+
+/// Which transport new channels, servers and shared memory regions are created on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// The platform's own IPC mechanism, which crosses process boundaries.
+    Native,
+    /// Channels within this process, which allocate no OS resources.
+    InProcess,
+}
+
+lazy_static! {
+    static ref BACKEND: Mutex<Backend> = {
+        let backend = match env::var(BACKEND_ENV_VAR) {
+            Ok(ref value) if value == "inprocess" => Backend::InProcess,
+            _ => Backend::Native,
+        };
+        Mutex::new(backend)
+    };
+}
+
+pub fn set_backend(backend: Backend) {
+    *BACKEND.lock().unwrap() = backend;
+}
+
+pub fn backend() -> Backend {
+    *BACKEND.lock().unwrap()
+}
+
+pub fn channel() -> Result<(SwitchSender, SwitchReceiver),SwitchError> {
+    channel_with_backend(backend())
+}
+
+pub fn channel_with_backend(backend: Backend)
+                            -> Result<(SwitchSender, SwitchReceiver),SwitchError> {
+    match backend {
+        Backend::Native => {
+            let (sender, receiver) = try!(platform::channel().map_err(SwitchError::Native));
+            Ok((SwitchSender::Native(sender), SwitchReceiver::Native(receiver)))
+        }
+        Backend::InProcess => {
+            let (sender, receiver) = try!(inprocess::channel().map_err(SwitchError::InProcess));
+            Ok((SwitchSender::InProcess(sender), SwitchReceiver::InProcess(receiver)))
+        }
+    }
+}
+
+/// Creates an in-process channel whose deliveries `scheduler` records or replays.
+pub fn scheduled_channel(scheduler: &Scheduler)
+                         -> Result<(SwitchSender, SwitchReceiver),SwitchError> {
+    let (sender, receiver) = try!(scheduler.channel().map_err(SwitchError::InProcess));
+    Ok((SwitchSender::InProcess(sender), SwitchReceiver::InProcess(receiver)))
+}
+
+type NativeMessage = (Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+type InProcessMessage = (Vec<u8>, Vec<OpaqueMpscChannel>, Vec<MpscSharedMemory>);
+pub type SwitchMessage = (Vec<u8>, Vec<SwitchOpaqueChannel>, Vec<SwitchSharedMemory>);
+
+fn from_native_message((data, channels, shared_memory_regions): NativeMessage) -> SwitchMessage {
+    (data,
+     channels.into_iter().map(SwitchOpaqueChannel::Native).collect(),
+     shared_memory_regions.into_iter().map(SwitchSharedMemory::Native).collect())
+}
+
+fn from_in_process_message((data, channels, shared_memory_regions): InProcessMessage)
+                           -> SwitchMessage {
+    (data,
+     channels.into_iter().map(SwitchOpaqueChannel::InProcess).collect(),
+     shared_memory_regions.into_iter().map(SwitchSharedMemory::InProcess).collect())
+}
+
+fn native_channels(channels: Vec<SwitchChannel>) -> Result<Vec<OsIpcChannel>,SwitchError> {
+    channels.into_iter().map(SwitchChannel::into_native).collect()
+}
+
+fn in_process_channels(channels: Vec<SwitchChannel>) -> Result<Vec<MpscChannel>,SwitchError> {
+    channels.into_iter().map(|channel| {
+        match channel {
+            SwitchChannel::Sender(SwitchSender::InProcess(sender)) => {
+                Ok(MpscChannel::Sender(sender))
+            }
+            SwitchChannel::Receiver(SwitchReceiver::InProcess(receiver)) => {
+                Ok(MpscChannel::Receiver(receiver))
+            }
+            #[cfg(windows)]
+            SwitchChannel::Handle(handle) => Ok(MpscChannel::Handle(handle)),
+            _ => Err(SwitchError::MixedBackends),
+        }
+    }).collect()
+}
+
+fn native_shared_memory(shared_memory_regions: Vec<SwitchSharedMemory>)
+                        -> Vec<OsIpcSharedMemory> {
+    shared_memory_regions.into_iter().map(SwitchSharedMemory::into_native).collect()
+}
+
+fn in_process_shared_memory(shared_memory_regions: Vec<SwitchSharedMemory>)
+                            -> Vec<MpscSharedMemory> {
+    shared_memory_regions.into_iter().map(SwitchSharedMemory::into_in_process).collect()
+}
+
+#[derive(PartialEq, Debug)]
+pub enum SwitchReceiver {
+    Native(OsIpcReceiver),
+    InProcess(MpscReceiver),
+}
+
+impl SwitchReceiver {
+    pub fn consume(&self) -> SwitchReceiver {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => SwitchReceiver::Native(receiver.consume()),
+            SwitchReceiver::InProcess(ref receiver) => {
+                SwitchReceiver::InProcess(receiver.consume())
+            }
+        }
+    }
+
+    pub fn inheritable_token(&self) -> Result<String,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.inheritable_token().map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.inheritable_token().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn from_inherited_token(token: &str) -> Result<SwitchReceiver,SwitchError> {
+        OsIpcReceiver::from_inherited_token(token).map(SwitchReceiver::Native)
+                                                  .map_err(SwitchError::Native)
+    }
+
+    pub fn from_process(pid: u32, token: &str) -> Result<SwitchReceiver,SwitchError> {
+        OsIpcReceiver::from_process(pid, token).map(SwitchReceiver::Native)
+                                               .map_err(SwitchError::Native)
+    }
+
+    pub fn from_raw_fd(fd: c_int) -> Result<SwitchReceiver,SwitchError> {
+        OsIpcReceiver::from_raw_fd(fd).map(SwitchReceiver::Native).map_err(SwitchError::Native)
+    }
+
+    /// Windows channels are in-process for now, so there is no kind of handle to adopt.
+    #[cfg(windows)]
+    pub fn from_raw_handle(_: RawHandle) -> Result<SwitchReceiver,SwitchError> {
+        Err(SwitchError::Native(OsIpcError::UnsupportedError))
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,SwitchError> {
+        match self {
+            SwitchReceiver::Native(receiver) => receiver.into_raw_fd().map_err(SwitchError::Native),
+            SwitchReceiver::InProcess(receiver) => {
+                receiver.into_raw_fd().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    /// See `from_raw_handle()`.
+    #[cfg(windows)]
+    pub fn into_raw_handle(self) -> Result<RawHandle,SwitchError> {
+        Err(SwitchError::Native(OsIpcError::UnsupportedError))
+    }
+
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.peer_credentials().map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.peer_credentials().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn peer_security_label(&self) -> Result<Option<String>,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.peer_security_label().map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.peer_security_label().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => receiver.endpoint_id(),
+            SwitchReceiver::InProcess(ref receiver) => receiver.endpoint_id(),
+        }
+    }
+
+    pub fn queued_bytes(&self) -> Result<usize,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.queued_bytes().map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.queued_bytes().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn recv_buffer_size(&self) -> Result<usize,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.recv_buffer_size().map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.recv_buffer_size().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(),SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.set_recv_buffer_size(size).map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.set_recv_buffer_size(size).map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, size: Option<usize>) {
+        match *self {
+            SwitchReceiver::Native(ref mut receiver) => receiver.set_max_message_size(size),
+            SwitchReceiver::InProcess(ref mut receiver) => receiver.set_max_message_size(size),
+        }
+    }
+
+    pub fn recv(&self) -> Result<SwitchMessage,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.recv().map(from_native_message).map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.recv().map(from_in_process_message).map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<SwitchMessage,SwitchError> {
+        match *self {
+            SwitchReceiver::Native(ref receiver) => {
+                receiver.try_recv().map(from_native_message).map_err(SwitchError::Native)
+            }
+            SwitchReceiver::InProcess(ref receiver) => {
+                receiver.try_recv().map(from_in_process_message).map_err(SwitchError::InProcess)
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SwitchSender {
+    Native(OsIpcSender),
+    InProcess(MpscSender),
+}
+
+impl SwitchSender {
+    /// Connects to the server called `name` in the namespace of the current backend.
+    pub fn connect(name: String) -> Result<SwitchSender,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcSender::connect(name).map(SwitchSender::Native).map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscSender::connect(name).map(SwitchSender::InProcess)
+                                         .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn inheritable_token(&self) -> Result<String,SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.inheritable_token().map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.inheritable_token().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn from_inherited_token(token: &str) -> Result<SwitchSender,SwitchError> {
+        OsIpcSender::from_inherited_token(token).map(SwitchSender::Native)
+                                                .map_err(SwitchError::Native)
+    }
+
+    pub fn from_process(pid: u32, token: &str) -> Result<SwitchSender,SwitchError> {
+        OsIpcSender::from_process(pid, token).map(SwitchSender::Native)
+                                             .map_err(SwitchError::Native)
+    }
+
+    pub fn from_raw_fd(fd: c_int) -> Result<SwitchSender,SwitchError> {
+        OsIpcSender::from_raw_fd(fd).map(SwitchSender::Native).map_err(SwitchError::Native)
+    }
+
+    /// See `SwitchReceiver::from_raw_handle()`.
+    #[cfg(windows)]
+    pub fn from_raw_handle(_: RawHandle) -> Result<SwitchSender,SwitchError> {
+        Err(SwitchError::Native(OsIpcError::UnsupportedError))
+    }
+
+    pub fn into_raw_fd(self) -> Result<c_int,SwitchError> {
+        match self {
+            SwitchSender::Native(sender) => sender.into_raw_fd().map_err(SwitchError::Native),
+            SwitchSender::InProcess(sender) => sender.into_raw_fd().map_err(SwitchError::InProcess),
+        }
+    }
+
+    /// See `SwitchReceiver::from_raw_handle()`.
+    #[cfg(windows)]
+    pub fn into_raw_handle(self) -> Result<RawHandle,SwitchError> {
+        Err(SwitchError::Native(OsIpcError::UnsupportedError))
+    }
+
+    pub fn peer_credentials(&self) -> Result<(u32, u32, u32),SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.peer_credentials().map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.peer_credentials().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        match *self {
+            SwitchSender::Native(ref sender) => sender.endpoint_id(),
+            SwitchSender::InProcess(ref sender) => sender.endpoint_id(),
+        }
+    }
+
+    pub fn queued_bytes(&self) -> Result<usize,SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => sender.queued_bytes().map_err(SwitchError::Native),
+            SwitchSender::InProcess(ref sender) => {
+                sender.queued_bytes().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn receiver_is_closed(&self) -> Result<bool,SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.receiver_is_closed().map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.receiver_is_closed().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn send_buffer_size(&self) -> Result<usize,SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.send_buffer_size().map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.send_buffer_size().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(),SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.set_send_buffer_size(size).map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.set_send_buffer_size(size).map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn send(&self,
+                data: &[u8],
+                channels: Vec<SwitchChannel>,
+                shared_memory_regions: Vec<SwitchSharedMemory>)
+                -> Result<(),SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.send(data,
+                            try!(native_channels(channels)),
+                            native_shared_memory(shared_memory_regions))
+                      .map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.send(data,
+                            try!(in_process_channels(channels)),
+                            in_process_shared_memory(shared_memory_regions))
+                      .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn send_vectored(&self,
+                         data: &[&[u8]],
+                         channels: Vec<SwitchChannel>,
+                         shared_memory_regions: Vec<SwitchSharedMemory>)
+                         -> Result<(),SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                sender.send_vectored(data,
+                                     try!(native_channels(channels)),
+                                     native_shared_memory(shared_memory_regions))
+                      .map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                sender.send_vectored(data,
+                                     try!(in_process_channels(channels)),
+                                     in_process_shared_memory(shared_memory_regions))
+                      .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn send_batch(&self,
+                      messages: Vec<(Vec<u8>, Vec<SwitchChannel>, Vec<SwitchSharedMemory>)>)
+                      -> Result<(),SwitchError> {
+        match *self {
+            SwitchSender::Native(ref sender) => {
+                let mut native_messages = Vec::with_capacity(messages.len());
+                for (data, channels, shared_memory_regions) in messages {
+                    native_messages.push((data,
+                                          try!(native_channels(channels)),
+                                          native_shared_memory(shared_memory_regions)));
+                }
+                sender.send_batch(native_messages).map_err(SwitchError::Native)
+            }
+            SwitchSender::InProcess(ref sender) => {
+                let mut in_process_messages = Vec::with_capacity(messages.len());
+                for (data, channels, shared_memory_regions) in messages {
+                    in_process_messages.push((data,
+                                              try!(in_process_channels(channels)),
+                                              in_process_shared_memory(shared_memory_regions)));
+                }
+                sender.send_batch(in_process_messages).map_err(SwitchError::InProcess)
+            }
+        }
+    }
+}
+
+pub enum SwitchChannel {
+    Sender(SwitchSender),
+    Receiver(SwitchReceiver),
+    /// A Windows handle, which travels over channels of either backend, as both are in-process
+    /// there.
+    #[cfg(windows)]
+    Handle(MpscHandle),
+}
+
+impl SwitchChannel {
+    pub fn native(channel: OsIpcChannel) -> SwitchChannel {
+        match channel {
+            OsIpcChannel::Sender(sender) => SwitchChannel::Sender(SwitchSender::Native(sender)),
+            OsIpcChannel::Receiver(receiver) => {
+                SwitchChannel::Receiver(SwitchReceiver::Native(receiver))
+            }
+            #[cfg(windows)]
+            OsIpcChannel::Handle(handle) => SwitchChannel::Handle(handle),
+        }
+    }
+
+    /// Unwraps a native channel, failing for in-process ones.
+    pub fn into_native(self) -> Result<OsIpcChannel,SwitchError> {
+        match self {
+            SwitchChannel::Sender(SwitchSender::Native(sender)) => Ok(OsIpcChannel::Sender(sender)),
+            SwitchChannel::Receiver(SwitchReceiver::Native(receiver)) => {
+                Ok(OsIpcChannel::Receiver(receiver))
+            }
+            #[cfg(windows)]
+            SwitchChannel::Handle(handle) => Ok(OsIpcChannel::Handle(handle)),
+            _ => Err(SwitchError::MixedBackends),
+        }
+    }
+
+    /// File descriptors only travel over native channels, as in-process channels don't need them
+    /// and can't carry them.
+    pub fn from_fd(fd: c_int) -> Result<SwitchChannel,SwitchError> {
+        OsIpcChannel::from_fd(fd).map(SwitchChannel::native).map_err(SwitchError::Native)
+    }
+
+    /// Takes ownership of `handle`, to send it along with a message.
+    #[cfg(windows)]
+    pub fn from_handle(handle: RawHandle) -> SwitchChannel {
+        SwitchChannel::Handle(MpscHandle::new(handle))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum SwitchOpaqueChannel {
+    Native(OsOpaqueIpcChannel),
+    InProcess(OpaqueMpscChannel),
+}
+
+impl SwitchOpaqueChannel {
+    /// Makes the sending or receiving end of a new in-process channel, whose other end is gone,
+    /// to stand in for one received in a message.
+    #[cfg(any(test, feature="fuzzing"))]
+    pub fn in_process(sender: bool) -> Result<SwitchOpaqueChannel,SwitchError> {
+        let (tx, rx) = try!(inprocess::channel().map_err(SwitchError::InProcess));
+        let channel = if sender {
+            MpscChannel::Sender(tx)
+        } else {
+            MpscChannel::Receiver(rx)
+        };
+        Ok(SwitchOpaqueChannel::InProcess(OpaqueMpscChannel::new(channel)))
+    }
+
+    pub fn to_sender(&mut self) -> SwitchSender {
+        match *self {
+            SwitchOpaqueChannel::Native(ref mut channel) => {
+                SwitchSender::Native(channel.to_sender())
+            }
+            SwitchOpaqueChannel::InProcess(ref mut channel) => {
+                SwitchSender::InProcess(channel.to_sender())
+            }
+        }
+    }
+
+    pub fn to_receiver(&mut self) -> SwitchReceiver {
+        match *self {
+            SwitchOpaqueChannel::Native(ref mut channel) => {
+                SwitchReceiver::Native(channel.to_receiver())
+            }
+            SwitchOpaqueChannel::InProcess(ref mut channel) => {
+                SwitchReceiver::InProcess(channel.to_receiver())
+            }
+        }
+    }
+
+    pub fn to_channel(&mut self) -> Result<SwitchChannel,SwitchError> {
+        match *self {
+            SwitchOpaqueChannel::Native(ref mut channel) => {
+                channel.to_channel().map(SwitchChannel::native).map_err(SwitchError::Native)
+            }
+            SwitchOpaqueChannel::InProcess(ref mut channel) => {
+                match try!(channel.to_channel().map_err(SwitchError::InProcess)) {
+                    MpscChannel::Sender(sender) => {
+                        Ok(SwitchChannel::Sender(SwitchSender::InProcess(sender)))
+                    }
+                    MpscChannel::Receiver(receiver) => {
+                        Ok(SwitchChannel::Receiver(SwitchReceiver::InProcess(receiver)))
+                    }
+                    #[cfg(windows)]
+                    MpscChannel::Handle(handle) => Ok(SwitchChannel::Handle(handle)),
+                }
+            }
+        }
+    }
+
+    pub fn to_fd(&mut self) -> Result<c_int,SwitchError> {
+        match *self {
+            SwitchOpaqueChannel::Native(ref mut channel) => {
+                channel.to_fd().map_err(SwitchError::Native)
+            }
+            SwitchOpaqueChannel::InProcess(ref mut channel) => {
+                channel.to_fd().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn to_handle(&mut self) -> Result<RawHandle,SwitchError> {
+        match *self {
+            SwitchOpaqueChannel::Native(ref mut channel) => {
+                channel.to_handle().map_err(SwitchError::Native)
+            }
+            SwitchOpaqueChannel::InProcess(ref mut channel) => {
+                channel.to_handle().map_err(SwitchError::InProcess)
+            }
+        }
+    }
+}
+
+pub enum SwitchReceiverSet {
+    Native(OsIpcReceiverSet),
+    InProcess(MpscReceiverSet),
+}
+
+impl SwitchReceiverSet {
+    pub fn new() -> Result<SwitchReceiverSet,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcReceiverSet::new().map(SwitchReceiverSet::Native).map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscReceiverSet::new().map(SwitchReceiverSet::InProcess)
+                                      .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn add(&mut self, receiver: SwitchReceiver) -> Result<i64,SwitchError> {
+        match (self, receiver) {
+            (&mut SwitchReceiverSet::Native(ref mut set), SwitchReceiver::Native(receiver)) => {
+                set.add(receiver).map_err(SwitchError::Native)
+            }
+            (&mut SwitchReceiverSet::InProcess(ref mut set),
+             SwitchReceiver::InProcess(receiver)) => {
+                set.add(receiver).map_err(SwitchError::InProcess)
+            }
+            _ => Err(SwitchError::MixedBackends),
+        }
+    }
+
+    pub fn select(&mut self) -> Result<Vec<SwitchSelectionResult>,SwitchError> {
+        match *self {
+            SwitchReceiverSet::Native(ref mut set) => {
+                let results = try!(set.select().map_err(SwitchError::Native));
+                Ok(results.into_iter().map(|result| {
+                    match result {
+                        OsIpcSelectionResult::DataReceived(id, data, channels, regions) => {
+                            let (data, channels, regions) =
+                                from_native_message((data, channels, regions));
+                            SwitchSelectionResult::DataReceived(id, data, channels, regions)
+                        }
+                        OsIpcSelectionResult::ChannelClosed(id) => {
+                            SwitchSelectionResult::ChannelClosed(id)
+                        }
+                        #[cfg(all(not(feature="force-inprocess"),
+                                  any(target_os="linux", target_os="android",
+                                      target_os="freebsd", target_os="openbsd",
+                                      target_os="netbsd", target_os="illumos",
+                                      target_os="solaris", target_os="nto",
+                                      target_os="haiku")))]
+                        OsIpcSelectionResult::ReceiveFailed(id, error) => {
+                            SwitchSelectionResult::ReceiveFailed(id, SwitchError::Native(error))
+                        }
+                    }
+                }).collect())
+            }
+            SwitchReceiverSet::InProcess(ref mut set) => {
+                let results = try!(set.select().map_err(SwitchError::InProcess));
+                Ok(results.into_iter().map(|result| {
+                    match result {
+                        MpscSelectionResult::DataReceived(id, data, channels, regions) => {
+                            let (data, channels, regions) =
+                                from_in_process_message((data, channels, regions));
+                            SwitchSelectionResult::DataReceived(id, data, channels, regions)
+                        }
+                        MpscSelectionResult::ChannelClosed(id) => {
+                            SwitchSelectionResult::ChannelClosed(id)
+                        }
+                    }
+                }).collect())
+            }
+        }
+    }
+}
+
+pub enum SwitchSelectionResult {
+    DataReceived(i64, Vec<u8>, Vec<SwitchOpaqueChannel>, Vec<SwitchSharedMemory>),
+    ChannelClosed(i64),
+    ReceiveFailed(i64, SwitchError),
+}
+
+pub enum SwitchOneShotServer {
+    Native(OsIpcOneShotServer),
+    InProcess(MpscOneShotServer),
+}
+
+impl SwitchOneShotServer {
+    pub fn new() -> Result<(SwitchOneShotServer, String),SwitchError> {
+        match backend() {
+            Backend::Native => {
+                let (server, name) = try!(OsIpcOneShotServer::new().map_err(SwitchError::Native));
+                Ok((SwitchOneShotServer::Native(server), name))
+            }
+            Backend::InProcess => {
+                let (server, name) =
+                    try!(MpscOneShotServer::new().map_err(SwitchError::InProcess));
+                Ok((SwitchOneShotServer::InProcess(server), name))
+            }
+        }
+    }
+
+    pub fn with_name(name: &str) -> Result<SwitchOneShotServer,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcOneShotServer::with_name(name).map(SwitchOneShotServer::Native)
+                                                   .map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscOneShotServer::with_name(name).map(SwitchOneShotServer::InProcess)
+                                                  .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<SwitchOneShotServer,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcOneShotServer::with_name_and_mode(name, mode)
+                    .map(SwitchOneShotServer::Native)
+                    .map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscOneShotServer::with_name_and_mode(name, mode)
+                    .map(SwitchOneShotServer::InProcess)
+                    .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn accept(self) -> Result<(SwitchReceiver,
+                                   Vec<u8>,
+                                   Vec<SwitchOpaqueChannel>,
+                                   Vec<SwitchSharedMemory>),SwitchError> {
+        match self {
+            SwitchOneShotServer::Native(server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept().map_err(SwitchError::Native));
+                let (data, channels, regions) = from_native_message((data, channels, regions));
+                Ok((SwitchReceiver::Native(receiver), data, channels, regions))
+            }
+            SwitchOneShotServer::InProcess(server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept().map_err(SwitchError::InProcess));
+                let (data, channels, regions) =
+                    from_in_process_message((data, channels, regions));
+                Ok((SwitchReceiver::InProcess(receiver), data, channels, regions))
+            }
+        }
+    }
+
+    pub fn accept_timeout(self, timeout: Duration) -> Result<(SwitchReceiver,
+                                                              Vec<u8>,
+                                                              Vec<SwitchOpaqueChannel>,
+                                                              Vec<SwitchSharedMemory>),
+                                                             SwitchError> {
+        match self {
+            SwitchOneShotServer::Native(server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept_timeout(timeout).map_err(SwitchError::Native));
+                let (data, channels, regions) = from_native_message((data, channels, regions));
+                Ok((SwitchReceiver::Native(receiver), data, channels, regions))
+            }
+            SwitchOneShotServer::InProcess(server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept_timeout(timeout).map_err(SwitchError::InProcess));
+                let (data, channels, regions) =
+                    from_in_process_message((data, channels, regions));
+                Ok((SwitchReceiver::InProcess(receiver), data, channels, regions))
+            }
+        }
+    }
+}
+
+pub enum SwitchServer {
+    Native(OsIpcServer),
+    InProcess(MpscServer),
+}
+
+impl SwitchServer {
+    pub fn new() -> Result<(SwitchServer, String),SwitchError> {
+        match backend() {
+            Backend::Native => {
+                let (server, name) = try!(OsIpcServer::new().map_err(SwitchError::Native));
+                Ok((SwitchServer::Native(server), name))
+            }
+            Backend::InProcess => {
+                let (server, name) = try!(MpscServer::new().map_err(SwitchError::InProcess));
+                Ok((SwitchServer::InProcess(server), name))
+            }
+        }
+    }
+
+    pub fn with_name(name: &str) -> Result<SwitchServer,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcServer::with_name(name).map(SwitchServer::Native).map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscServer::with_name(name).map(SwitchServer::InProcess)
+                                           .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn with_name_and_mode(name: &str, mode: u32) -> Result<SwitchServer,SwitchError> {
+        match backend() {
+            Backend::Native => {
+                OsIpcServer::with_name_and_mode(name, mode).map(SwitchServer::Native)
+                                                           .map_err(SwitchError::Native)
+            }
+            Backend::InProcess => {
+                MpscServer::with_name_and_mode(name, mode).map(SwitchServer::InProcess)
+                                                          .map_err(SwitchError::InProcess)
+            }
+        }
+    }
+
+    pub fn accept(&self) -> Result<(SwitchReceiver,
+                                    Vec<u8>,
+                                    Vec<SwitchOpaqueChannel>,
+                                    Vec<SwitchSharedMemory>),SwitchError> {
+        match *self {
+            SwitchServer::Native(ref server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept().map_err(SwitchError::Native));
+                let (data, channels, regions) = from_native_message((data, channels, regions));
+                Ok((SwitchReceiver::Native(receiver), data, channels, regions))
+            }
+            SwitchServer::InProcess(ref server) => {
+                let (receiver, data, channels, regions) =
+                    try!(server.accept().map_err(SwitchError::InProcess));
+                let (data, channels, regions) =
+                    from_in_process_message((data, channels, regions));
+                Ok((SwitchReceiver::InProcess(receiver), data, channels, regions))
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SwitchSharedMemory {
+    Native(OsIpcSharedMemory),
+    InProcess(MpscSharedMemory),
+}
+
+impl Debug for SwitchSharedMemory {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        match *self {
+            SwitchSharedMemory::Native(ref region) => region.fmt(formatter),
+            SwitchSharedMemory::InProcess(ref region) => region.fmt(formatter),
+        }
+    }
+}
+
+impl Deref for SwitchSharedMemory {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match *self {
+            SwitchSharedMemory::Native(ref region) => &**region,
+            SwitchSharedMemory::InProcess(ref region) => &**region,
+        }
+    }
+}
+
+impl SwitchSharedMemory {
+    pub fn from_byte(byte: u8, length: usize) -> SwitchSharedMemory {
+        match backend() {
+            Backend::Native => {
+                SwitchSharedMemory::Native(OsIpcSharedMemory::from_byte(byte, length))
+            }
+            Backend::InProcess => {
+                SwitchSharedMemory::InProcess(MpscSharedMemory::from_byte(byte, length))
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> SwitchSharedMemory {
+        match backend() {
+            Backend::Native => SwitchSharedMemory::Native(OsIpcSharedMemory::from_bytes(bytes)),
+            Backend::InProcess => {
+                SwitchSharedMemory::InProcess(MpscSharedMemory::from_bytes(bytes))
+            }
+        }
+    }
+
+    pub fn from_file(file: &File, length: usize) -> Result<SwitchSharedMemory,Error> {
+        match backend() {
+            Backend::Native => {
+                OsIpcSharedMemory::from_file(file, length).map(SwitchSharedMemory::Native)
+            }
+            Backend::InProcess => {
+                MpscSharedMemory::from_file(file, length).map(SwitchSharedMemory::InProcess)
+            }
+        }
+    }
+
+    pub fn endpoint_id(&self) -> Option<i64> {
+        match *self {
+            SwitchSharedMemory::Native(ref region) => region.endpoint_id(),
+            SwitchSharedMemory::InProcess(ref region) => region.endpoint_id(),
+        }
+    }
+
+    /// Returns a native region with the same contents, copying it if it's an in-process one.
+    fn into_native(self) -> OsIpcSharedMemory {
+        match self {
+            SwitchSharedMemory::Native(region) => region,
+            SwitchSharedMemory::InProcess(region) => OsIpcSharedMemory::from_bytes(&region),
+        }
+    }
+
+    /// Returns an in-process region with the same contents, copying it if it's a native one.
+    fn into_in_process(self) -> MpscSharedMemory {
+        match self {
+            SwitchSharedMemory::Native(region) => MpscSharedMemory::from_bytes(&region),
+            SwitchSharedMemory::InProcess(region) => region,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SwitchError {
+    Native(OsIpcError),
+    InProcess(MpscError),
+    /// A channel of one backend was sent over, or added to a receiver set of, the other.
+    MixedBackends,
+}
+
+impl SwitchError {
+    pub fn is_unsupported(&self) -> bool {
+        match *self {
+            SwitchError::Native(ref error) => error.is_unsupported(),
+            SwitchError::InProcess(ref error) => error.is_unsupported(),
+            SwitchError::MixedBackends => false,
+        }
+    }
+}
+
+/// Wraps the error of an operation that the backend can't do, so that it can be told apart from
+/// other errors of the same kind.
+#[derive(Debug)]
+pub struct Unsupported(Error);
+
+impl Display for Unsupported {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        Display::fmt(&self.0, formatter)
+    }
+}
+
+impl error::Error for Unsupported {
+    fn description(&self) -> &str {
+        "operation not supported by this backend"
+    }
+}
+
+impl From<SwitchError> for DeserializeError {
+    fn from(switch_error: SwitchError) -> DeserializeError {
+        DeserializeError::IoError(switch_error.into())
+    }
+}
+
+impl From<SwitchError> for Error {
+    fn from(switch_error: SwitchError) -> Error {
+        let unsupported = switch_error.is_unsupported();
+        let error = match switch_error {
+            SwitchError::Native(error) => error.into(),
+            SwitchError::InProcess(error) => error.into(),
+            SwitchError::MixedBackends => {
+                Error::new(ErrorKind::InvalidInput,
+                           "channels of the native and in-process backends can't be mixed")
+            }
+        };
+        if unsupported {
+            Error::new(error.kind(), Unsupported(error))
+        } else {
+            error
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A transport over TCP, for channels that have to reach other machines or network namespaces.
+//! It's available on every platform, alongside the native one.
+//!
+//! Nothing but bytes can cross a TCP connection, so channels can't be transferred at all, and
+//! shared memory regions are copied over and turn up on the other side as new regions.
+//!
+//! Each message is framed as the length of its data, the number of regions, the length and
+//! contents of each region, and then the data, with all numbers little-endian. Channels with
+//! checksums turned on follow each frame with the CRC-32 of all of it.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+/// A message can't carry more regions than this; anything above is taken to be a corrupt frame.
+const MAX_SHARED_MEMORY_REGIONS: u32 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct TcpSender {
+    /// Shared by clones, and locked for the whole of each message so that their messages don't
+    /// interleave.
+    stream: Arc<Mutex<TcpStream>>,
+    checksums: bool,
+}
+
+impl TcpSender {
+    pub fn connect<A>(address: A) -> Result<TcpSender,Error> where A: ToSocketAddrs {
+        let stream = try!(TcpStream::connect(address));
+        try!(stream.set_nodelay(true));
+        Ok(TcpSender {
+            stream: Arc::new(Mutex::new(stream)),
+            checksums: false,
+        })
+    }
+
+    /// Follows each frame with its checksum. The receiver must expect them.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true
+    }
+
+    pub fn send(&self, data: &[u8], shared_memory_regions: &[&[u8]]) -> Result<(),Error> {
+        let mut stream = self.stream.lock().unwrap();
+        if self.checksums {
+            write_checksummed_message(&mut *stream, data, shared_memory_regions)
+        } else {
+            write_message(&mut *stream, data, shared_memory_regions)
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr,Error> {
+        self.stream.lock().unwrap().peer_addr()
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpReceiver {
+    stream: TcpStream,
+    checksums: bool,
+}
+
+impl TcpReceiver {
+    /// Expects each frame to be followed by its checksum, as from a sender with checksums
+    /// enabled.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true
+    }
+
+    /// Returns the data of the next message and the contents of its shared memory regions.
+    pub fn recv(&self) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> {
+        if self.checksums {
+            read_checksummed_message(&mut &self.stream)
+        } else {
+            read_message(&mut &self.stream)
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr,Error> {
+        self.stream.peer_addr()
+    }
+}
+
+/// Writes a message in the framing described above. This is shared with the other stream
+/// transports.
+pub fn write_message<W>(stream: &mut W, data: &[u8], shared_memory_regions: &[&[u8]])
+                        -> Result<(),Error> where W: Write {
+    // Frame the message in memory first, so that it goes out in as few segments as possible.
+    let mut frame = Vec::with_capacity(12 + data.len());
+    frame.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+    frame.write_u32::<LittleEndian>(shared_memory_regions.len() as u32).unwrap();
+    for region in shared_memory_regions {
+        frame.write_u64::<LittleEndian>(region.len() as u64).unwrap();
+        frame.extend_from_slice(region);
+    }
+    frame.extend_from_slice(data);
+    stream.write_all(&frame[..])
+}
+
+/// Reads a message written by `write_message()`.
+pub fn read_message<R>(stream: &mut R) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> where R: Read {
+    let data_length = try!(stream.read_u64::<LittleEndian>());
+    let region_count = try!(stream.read_u32::<LittleEndian>());
+    if region_count > MAX_SHARED_MEMORY_REGIONS {
+        return Err(Error::new(ErrorKind::InvalidData, "too many shared memory regions"))
+    }
+    let mut shared_memory_regions = Vec::with_capacity(region_count as usize);
+    for _ in 0..region_count {
+        let region_length = try!(stream.read_u64::<LittleEndian>());
+        shared_memory_regions.push(try!(read_exactly(stream, region_length)));
+    }
+    let data = try!(read_exactly(stream, data_length));
+    Ok((data, shared_memory_regions))
+}
+
+/// Reads `length` bytes, without trusting `length` enough to allocate it all up front.
+pub fn read_exactly<R>(stream: &mut R, length: u64) -> Result<Vec<u8>,Error> where R: Read {
+    let mut buffer = vec![];
+    let read = try!((&mut *stream).take(length).read_to_end(&mut buffer));
+    if (read as u64) < length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed mid-message"))
+    }
+    Ok(buffer)
+}
+
+/// Writes a message as `write_message()` does, followed by the CRC-32 of its frame.
+pub fn write_checksummed_message<W>(stream: &mut W, data: &[u8], shared_memory_regions: &[&[u8]])
+                                    -> Result<(),Error> where W: Write {
+    // As in `write_message()`, the whole frame goes out in one write.
+    let mut frame = Vec::with_capacity(16 + data.len());
+    try!(write_message(&mut frame, data, shared_memory_regions));
+    let checksum = crc32::checksum_ieee(&frame[..]);
+    frame.write_u32::<LittleEndian>(checksum).unwrap();
+    stream.write_all(&frame[..])
+}
+
+/// Reads a message written by `write_checksummed_message()`. If the checksum doesn't match, the
+/// message is dropped, and this fails with an `InvalidData` error wrapping a `ChecksumMismatch`.
+pub fn read_checksummed_message<R>(stream: &mut R) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error>
+                                   where R: Read {
+    let mut stream = ChecksummingStream::new(stream);
+    let message = try!(read_message(&mut stream));
+    let checksum = stream.checksum();
+    if try!(stream.stream.read_u32::<LittleEndian>()) != checksum {
+        return Err(Error::new(ErrorKind::InvalidData, ChecksumMismatch))
+    }
+    Ok(message)
+}
+
+/// A message didn't match the checksum it came with, so it was corrupted on the way.
+#[derive(Debug)]
+pub struct ChecksumMismatch;
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(),fmt::Error> {
+        write!(formatter, "message doesn't match its checksum")
+    }
+}
+
+impl error::Error for ChecksumMismatch {
+    fn description(&self) -> &str {
+        "message doesn't match its checksum"
+    }
+}
+
+/// Computes the CRC-32 of everything read from a stream.
+struct ChecksummingStream<'a, S> where S: 'a {
+    stream: &'a mut S,
+    crc: u32,
+}
+
+impl<'a, S> ChecksummingStream<'a, S> {
+    fn new(stream: &'a mut S) -> ChecksummingStream<'a, S> {
+        ChecksummingStream {
+            stream: stream,
+            crc: 0,
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        self.crc
+    }
+}
+
+impl<'a, S> Read for ChecksummingStream<'a, S> where S: Read {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize,Error> {
+        let count = try!(self.stream.read(buffer));
+        self.crc = crc32::update(self.crc, &crc32::IEEE_TABLE, &buffer[..count]);
+        Ok(count)
+    }
+}
+
+pub struct TcpServer {
+    listener: TcpListener,
+}
+
+impl TcpServer {
+    pub fn bind<A>(address: A) -> Result<TcpServer,Error> where A: ToSocketAddrs {
+        Ok(TcpServer {
+            listener: try!(TcpListener::bind(address)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr,Error> {
+        self.listener.local_addr()
+    }
+
+    pub fn accept(&self) -> Result<TcpReceiver,Error> {
+        let (stream, _) = try!(self.listener.accept());
+        Ok(TcpReceiver {
+            stream: stream,
+            checksums: false,
+        })
+    }
+}
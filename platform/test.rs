@@ -9,13 +9,22 @@
 
 use libc;
 use platform::{self, OsIpcChannel, OsIpcReceiverSet, OsIpcSender, OsIpcOneShotServer};
-use platform::{OsIpcSharedMemory};
+use platform::{OsIpcServer, OsIpcSharedMemory};
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+use platform::{OsIpcSelectionResult, SigpipeStrategy};
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+use std::io::{Error, ErrorKind};
 use std::mem;
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+use std::ptr;
 use std::sync::Arc;
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+use std::sync::mpsc;
 use std::time::{Duration, Instant};
 use std::thread;
+use uuid::Uuid;
 
-#[cfg(not(windows))]
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
 use test::{fork, Wait};
 
 #[test]
@@ -92,6 +101,18 @@ fn multisender_transfer() {
                (data, vec![], vec![]));
 }
 
+#[test]
+fn receive_buffer_reuse() {
+    let (tx, rx) = platform::channel().unwrap();
+    for &(byte, length) in &[(0xaa, 65536), (0xbb, 7), (0xcc, 1024)] {
+        let data = vec![byte; length];
+        tx.send(&data[..], vec![], vec![]).unwrap();
+        let (mut received_data, _, _) = rx.recv().unwrap();
+        received_data.truncate(length);
+        assert_eq!(received_data, data);
+    }
+}
+
 #[test]
 fn medium_data() {
     let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
@@ -123,6 +144,25 @@ fn medium_data_with_sender_transfer() {
                (data, vec![], vec![]));
 }
 
+#[test]
+fn medium_data_with_shared_memory() {
+    // On macOS, this much data goes out of line, next to the shared memory region.
+    let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+    let data: &[u8] = &data[..];
+    let (tx, rx) = platform::channel().unwrap();
+    let shmem_data = OsIpcSharedMemory::from_byte(0xba, 4096);
+    tx.send_vectored(&[&data[..1000], &data[1000..]], vec![], vec![shmem_data]).unwrap();
+    let (mut received_data, received_channels, mut received_shared_memory_regions) =
+        rx.recv().unwrap();
+    received_data.truncate(65536);
+    assert_eq!(&received_data[..], data);
+    assert!(received_channels.is_empty());
+    assert_eq!(received_shared_memory_regions.len(), 1);
+    let received_shmem_data = received_shared_memory_regions.pop().unwrap();
+    assert_eq!(received_shmem_data.len(), 4096);
+    assert!(received_shmem_data.iter().all(|byte| *byte == 0xba));
+}
+
 #[test]
 fn big_data() {
     let (tx, rx) = platform::channel().unwrap();
@@ -176,14 +216,15 @@ fn big_data_with_sender_transfer() {
 
 #[test]
 // This test only applies to platforms that need fragmentation.
-#[cfg(target_os="linux")]
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
 fn full_packet() {
     let (tx, rx) = platform::channel().unwrap();
 
     // Should be the biggest size that just fits in a single packet.
     //
     // 32 is the empirical minimal size of the "control message" header,
-    // which we presently always send along with the data;
+    // which we used to always send along with the data
+    // (now only when transferring file descriptors, so this errs on the safe side);
     // the rest is for the fragment header.
     //
     // Note that this calculation might become imprecise
@@ -287,6 +328,49 @@ fn concurrent_senders() {
     }
 }
 
+#[test]
+fn receiver_set_batch() {
+    let (tx, rx) = platform::channel().unwrap();
+    let mut rx_set = OsIpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+
+    let big_data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let thread_big_data = big_data.clone();
+    let thread = thread::spawn(move || {
+        for i in 0..20u8 {
+            if i == 10 {
+                tx.send(&thread_big_data[..], vec![], vec![]).unwrap();
+                continue
+            }
+            let channels = if i == 15 {
+                let (sub_tx, _) = platform::channel().unwrap();
+                vec![OsIpcChannel::Sender(sub_tx)]
+            } else {
+                vec![]
+            };
+            tx.send(&[i; 7], channels, vec![]).unwrap();
+        }
+    });
+
+    let mut received = 0;
+    while received < 20 {
+        for result in rx_set.select().unwrap().into_iter() {
+            let (received_id, mut received_data, received_channels, _) = result.unwrap();
+            assert_eq!(received_id, rx_id);
+            if received == 10 {
+                received_data.truncate(big_data.len());
+                assert_eq!(received_data, big_data);
+            } else {
+                received_data.truncate(7);
+                assert_eq!(received_data, [received as u8; 7]);
+                assert_eq!(received_channels.len(), if received == 15 { 1 } else { 0 });
+            }
+            received += 1;
+        }
+    }
+    thread.join().unwrap();
+}
+
 #[test]
 fn receiver_set() {
     let (tx0, rx0) = platform::channel().unwrap();
@@ -332,7 +416,7 @@ fn receiver_set() {
 
 #[test]
 //XXXjdm This hangs indefinitely on appveyor and warrants further investigation.
-#[cfg(not(windows))]
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
 fn server() {
     let (server, name) = OsIpcOneShotServer::new().unwrap();
     let data: &[u8] = b"1234567";
@@ -349,8 +433,115 @@ fn server() {
                (data, vec![], vec![]));
 }
 
+#[test]
+// Skipped on Windows like `server()`, and on iOS, where apps can't register server names.
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
+fn multi_shot_server() {
+    let (server, name) = OsIpcServer::new().unwrap();
+    let data: &[u8] = b"1234567";
+
+    for _ in 0..2 {
+        let name = name.clone();
+        thread::spawn(move || {
+            let tx = OsIpcSender::connect(name).unwrap();
+            tx.send(data, vec![], vec![]).unwrap();
+        });
+
+        let (_, mut received_data, received_channels, received_shared_memory_regions) =
+            server.accept().unwrap();
+        received_data.truncate(7);
+        assert_eq!((&received_data[..], received_channels, received_shared_memory_regions),
+                   (data, vec![], vec![]));
+    }
+}
+
+#[test]
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+fn abstract_socket_server() {
+    use std::path::Path;
+
+    let (server, name) = OsIpcOneShotServer::new().unwrap();
+    assert!(name.starts_with("@"));
+    assert!(!Path::new(&name[1..]).exists());
+    let data: &[u8] = b"1234567";
+    thread::spawn(move || {
+        let tx = OsIpcSender::connect(name).unwrap();
+        tx.send(data, vec![], vec![]).unwrap();
+    });
+    let (_, mut received_data, _, _) = server.accept().unwrap();
+    received_data.truncate(7);
+    assert_eq!(&received_data[..], data);
+}
+
+#[test]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="freebsd", target_os="openbsd", target_os="netbsd",
+              target_os="illumos", target_os="solaris", target_os="nto",
+              target_os="haiku")))]
+fn filesystem_socket_server() {
+    use std::path::Path;
+
+    let (server, name) = OsIpcOneShotServer::new().unwrap();
+    assert!(Path::new(&name).exists());
+    let data: &[u8] = b"1234567";
+    let client_name = name.clone();
+    thread::spawn(move || {
+        let tx = OsIpcSender::connect(client_name).unwrap();
+        tx.send(data, vec![], vec![]).unwrap();
+    });
+    let (_, mut received_data, _, _) = server.accept().unwrap();
+    received_data.truncate(7);
+    assert_eq!(&received_data[..], data);
+    assert!(!Path::new(&name).exists());
+}
+
+#[test]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+fn server_name_with_nul() {
+    let error: Error = OsIpcServer::with_name("ipc\0channel").err().unwrap().into();
+    assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+}
+
+/// A client that connects but never sends anything mustn't hold `accept_timeout()` up.
+#[test]
+#[cfg(all(not(feature="force-inprocess"),
+          any(target_os="linux", target_os="android", target_os="freebsd",
+              target_os="openbsd", target_os="netbsd", target_os="illumos",
+              target_os="solaris", target_os="nto", target_os="haiku")))]
+fn accept_timeout_silent_client() {
+    let (server, name) = OsIpcOneShotServer::new().unwrap();
+    let tx = OsIpcSender::connect(name).unwrap();
+    let start = Instant::now();
+    let result = server.accept_timeout(Duration::from_millis(100));
+    assert!(result.is_err());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    drop(tx);
+}
+
+///XXXjdm Windows' libc doesn't include fork.
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
+#[test]
+fn server_name_reuse() {
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
+    drop(OsIpcServer::with_name(&name).unwrap());
+    drop(OsIpcServer::with_name(&name).unwrap());
+
+    // A server that dies without cleaning up after itself mustn't keep the name taken.
+    let child_pid = unsafe { fork(|| {
+        mem::forget(OsIpcServer::with_name(&name).unwrap());
+        libc::exit(0);
+    })};
+    child_pid.wait();
+    OsIpcServer::with_name(&name).unwrap();
+}
+
 ///XXXjdm Windows' libc doesn't include fork.
-#[cfg(not(windows))]
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
 #[test]
 fn cross_process() {
     let (server, name) = OsIpcOneShotServer::new().unwrap();
@@ -370,8 +561,196 @@ fn cross_process() {
                (data, vec![], vec![]));
 }
 
+/// A message whose sender dies partway through is dropped as a whole, rather than handed over in
+/// part or taken for the channel closing, and the receiver can tell that the sender died.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn sender_death_mid_message() {
+    let (tx, rx) = platform::channel().unwrap();
+    let child_pid = unsafe { fork(|| {
+        // Far more than the socket buffers hold, so the child blocks after the first fragment.
+        let data = vec![0; 64 * 1024 * 1024];
+        tx.send(&data, vec![], vec![]).unwrap();
+        libc::exit(0);
+    })};
+    thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(child_pid, libc::SIGKILL);
+    }
+    child_pid.wait();
+    match rx.recv() {
+        Err(error) => assert_eq!(Error::from(error).raw_os_error(), Some(libc::EOWNERDEAD)),
+        Ok(_) => panic!("received a message whose sender died while sending it"),
+    }
+
+    let data: &[u8] = b"1234567";
+    tx.send(data, vec![], vec![]).unwrap();
+    let (mut received_data, _, _) = rx.recv().unwrap();
+    received_data.truncate(7);
+    assert_eq!(&received_data[..], data);
+}
+
+/// A fragmented message longer than the receiver allows is refused before it's allocated, and the
+/// channel carries on.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn max_message_size() {
+    let (tx, mut rx) = platform::channel().unwrap();
+    rx.set_max_message_size(Some(64 * 1024));
+    let thread = thread::spawn(move || {
+        let data = vec![0; 1024 * 1024];
+        // Fails once the receiver hangs up on the rest of the fragments.
+        drop(tx.send(&data, vec![], vec![]));
+        tx
+    });
+    match rx.recv() {
+        Err(error) => assert_eq!(Error::from(error).raw_os_error(), Some(libc::EMSGSIZE)),
+        Ok(_) => panic!("received a message over the receiver's limit"),
+    }
+    let tx = thread.join().unwrap();
+
+    let data: &[u8] = b"1234567";
+    tx.send(data, vec![], vec![]).unwrap();
+    let (mut received_data, _, _) = rx.recv().unwrap();
+    received_data.truncate(7);
+    assert_eq!(&received_data[..], data);
+}
+
+/// In a receiver set, a message over a receiver's limit is reported on its own, and neither the
+/// messages around it nor the receiver are lost.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn receiver_set_max_message_size() {
+    let (tx, mut rx) = platform::channel().unwrap();
+    rx.set_max_message_size(Some(16));
+    let mut rx_set = OsIpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+    tx.send(&[0; 7], vec![], vec![]).unwrap();
+    tx.send(&[1; 1024], vec![], vec![]).unwrap();
+    tx.send(&[2; 7], vec![], vec![]).unwrap();
+
+    let mut results = vec![];
+    while results.len() < 3 {
+        results.extend(rx_set.select().unwrap().into_iter());
+    }
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            OsIpcSelectionResult::ReceiveFailed(id, error) if index == 1 => {
+                assert_eq!(id, rx_id);
+                assert_eq!(Error::from(error).raw_os_error(), Some(libc::EMSGSIZE));
+            }
+            OsIpcSelectionResult::DataReceived(id, mut data, _, _) if index != 1 => {
+                assert_eq!(id, rx_id);
+                data.truncate(7);
+                assert_eq!(data, [index as u8; 7]);
+            }
+            _ => panic!("unexpected selection result {}", index),
+        }
+    }
+
+    drop(tx);
+    match rx_set.select().unwrap().pop() {
+        Some(OsIpcSelectionResult::ChannelClosed(id)) => assert_eq!(id, rx_id),
+        _ => panic!("receiver wasn't closed"),
+    }
+}
+
+/// A receiver gives up on a message whose sender stalls partway through it. This runs in a child
+/// process, so that the timeout doesn't apply to the other tests.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn reassembly_timeout() {
+    let child_pid = unsafe { fork(|| {
+        let (tx, rx) = platform::channel().unwrap();
+        let sender_pid = fork(|| {
+            // Far more than the socket buffers hold, so the sender blocks after a few fragments.
+            let data = vec![0; 64 * 1024 * 1024];
+            drop(tx.send(&data, vec![], vec![]));
+            libc::exit(0);
+        });
+        thread::sleep(Duration::from_millis(500));
+        libc::kill(sender_pid, libc::SIGSTOP);
+        platform::set_reassembly_timeout(Some(Duration::from_millis(200)));
+        let timed_out = match rx.recv() {
+            Err(error) => Error::from(error).kind() == ErrorKind::TimedOut,
+            Ok(_) => false,
+        };
+        libc::kill(sender_pid, libc::SIGKILL);
+        sender_pid.wait();
+        libc::exit(if timed_out { 0 } else { 1 });
+    })};
+    let mut status = 0;
+    unsafe {
+        libc::waitpid(child_pid, &mut status, 0);
+    }
+    assert_eq!(status, 0);
+}
+
+/// A signal arriving while a receive blocks doesn't make it fail.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn recv_interrupted_by_signal() {
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+    unsafe {
+        // Without `SA_RESTART`, so that the kernel returns `EINTR` rather than restarting the call.
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = ignore_signal as usize;
+        assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+    }
+
+    let (tx, rx) = platform::channel().unwrap();
+    let (thread_id_sender, thread_id_receiver) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        thread_id_sender.send(unsafe { libc::pthread_self() }).unwrap();
+        rx.recv().unwrap().0
+    });
+    let thread_id = thread_id_receiver.recv().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    unsafe {
+        libc::pthread_kill(thread_id, libc::SIGUSR1);
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    let data: &[u8] = b"1234567";
+    tx.send(data, vec![], vec![]).unwrap();
+    let mut received_data = thread.join().unwrap();
+    received_data.truncate(7);
+    assert_eq!(&received_data[..], data);
+}
+
+/// Sending to a peer that hung up fails, rather than killing the sender with `SIGPIPE`.
+#[cfg(all(target_os="linux", not(feature="force-inprocess")))]
+#[test]
+fn send_to_hung_up_peer() {
+    for &strategy in &[SigpipeStrategy::NoSignalFlag, SigpipeStrategy::MaskSignal] {
+        let child_pid = unsafe { fork(|| {
+            // Unlike Rust programs, embedders don't necessarily ignore `SIGPIPE`.
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+            platform::set_sigpipe_strategy(strategy).unwrap();
+            let (tx, rx) = platform::channel().unwrap();
+            drop(rx);
+            // The first send after the peer hangs up reports the reset; the ones after it are
+            // the ones that would raise `SIGPIPE`.
+            let data: &[u8] = b"1234567";
+            for _ in 0..2 {
+                match Error::from(tx.send(data, vec![], vec![]).unwrap_err()).kind() {
+                    ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {}
+                    _ => libc::exit(1),
+                }
+            }
+            libc::exit(0);
+        })};
+        let mut status = 0;
+        unsafe {
+            libc::waitpid(child_pid, &mut status, 0);
+        }
+        assert_eq!(status, 0);
+    }
+}
+
 ///XXXjdm Windows' libc doesn't include fork.
-#[cfg(not(windows))]
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature="force-inprocess", windows, target_os="ios")))]
 #[test]
 fn cross_process_sender_transfer() {
     let (server, name) = OsIpcOneShotServer::new().unwrap();
@@ -546,3 +925,89 @@ fn try_recv_large_delayed() {
         thread.join().unwrap();
     }
 }
+
+#[test]
+#[cfg(feature="websocket")]
+fn websocket_accept_key() {
+    use platform::websocket::accept_key;
+
+    // The example from RFC 6455.
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+/// Opens a WebSocket to `address` by hand, as a browser on a page from `origin` would, returning
+/// the connection and the status line of the server's answer.
+#[cfg(feature="websocket")]
+fn websocket_handshake(address: ::std::net::SocketAddr, origin: &str)
+                       -> (::std::net::TcpStream, String) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream,
+           "GET / HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Origin: {}\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+           address,
+           origin).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break
+        }
+    }
+    (stream, status_line)
+}
+
+#[test]
+#[cfg(feature="websocket")]
+fn websocket_origin() {
+    use platform::WebSocketServer;
+
+    let mut server = WebSocketServer::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let (_, status_line) = websocket_handshake(address, "https://evil.example");
+        assert!(status_line.starts_with("HTTP/1.1 403"));
+        let (_, status_line) = websocket_handshake(address, "https://example.com");
+        assert!(status_line.starts_with("HTTP/1.1 101"));
+    });
+    assert_eq!(server.accept().err().unwrap().kind(), ErrorKind::PermissionDenied);
+    server.allow_origin("https://example.com");
+    server.accept().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+#[cfg(feature="websocket")]
+fn websocket_malformed_frames() {
+    use platform::WebSocketServer;
+    use std::io::Write;
+
+    let mut server = WebSocketServer::bind("127.0.0.1:0").unwrap();
+    server.allow_origin("https://example.com");
+    let address = server.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        // An unmasked binary frame, which clients mustn't send.
+        let (mut stream, _) = websocket_handshake(address, "https://example.com");
+        stream.write_all(&[0x82, 0x01, 0x00]).unwrap();
+        // A masked ping with a 126-byte payload, which is too long for a control frame.
+        let (mut stream, _) = websocket_handshake(address, "https://example.com");
+        let mut frame = vec![0x89, 0x80 | 126, 0x00, 126, 0, 0, 0, 0];
+        frame.extend_from_slice(&[0; 126]);
+        stream.write_all(&frame).unwrap();
+    });
+    for _ in 0..2 {
+        let (_, receiver) = server.accept().unwrap();
+        assert_eq!(receiver.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+    thread.join().unwrap();
+}
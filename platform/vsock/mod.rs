@@ -0,0 +1,206 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A transport over `AF_VSOCK` stream sockets, for channels between a virtual machine and its
+//! host. Peers are addressed by context ID (CID) and port.
+//!
+//! Messages are framed as on the TCP transport, with the same limits: channels can't be
+//! transferred, and shared memory regions are copied.
+
+use libc::{self, c_int, c_uint, c_ushort, c_void, sockaddr, socklen_t};
+use platform::tcp::{read_message, write_message};
+use std::io::{Error, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// The CID of the host, as seen from a guest.
+pub const VMADDR_CID_HOST: u32 = 2;
+/// The CID of the local machine, for talking to ourselves. Requires Linux 5.6.
+pub const VMADDR_CID_LOCAL: u32 = 1;
+
+const AF_VSOCK: c_int = 40;
+const VMADDR_CID_ANY: c_uint = 0xffffffff;
+
+/// An open vsock stream socket.
+#[derive(Debug)]
+struct VsockStream {
+    fd: c_int,
+}
+
+impl Drop for VsockStream {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl VsockStream {
+    fn socket() -> Result<VsockStream,Error> {
+        let fd = unsafe {
+            libc::socket(AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(VsockStream {
+            fd: fd,
+        })
+    }
+}
+
+impl<'a> Read for &'a VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize,Error> {
+        loop {
+            let result = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len())
+            };
+            if result >= 0 {
+                return Ok(result as usize)
+            }
+            let error = Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EINTR) {
+                return Err(error)
+            }
+        }
+    }
+}
+
+impl<'a> Write for &'a VsockStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize,Error> {
+        loop {
+            let result = unsafe {
+                libc::send(self.fd, buf.as_ptr() as *const c_void, buf.len(), MSG_NOSIGNAL)
+            };
+            if result >= 0 {
+                return Ok(result as usize)
+            }
+            let error = Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EINTR) {
+                return Err(error)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(),Error> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VsockSender {
+    /// Shared by clones, and locked for the whole of each message so that their messages don't
+    /// interleave.
+    stream: Arc<Mutex<VsockStream>>,
+}
+
+impl VsockSender {
+    pub fn connect(cid: u32, port: u32) -> Result<VsockSender,Error> {
+        let stream = try!(VsockStream::socket());
+        let address = sockaddr_for(cid, port);
+        unsafe {
+            if libc::connect(stream.fd,
+                             &address as *const sockaddr_vm as *const sockaddr,
+                             mem::size_of::<sockaddr_vm>() as socklen_t) < 0 {
+                return Err(Error::last_os_error())
+            }
+        }
+        Ok(VsockSender {
+            stream: Arc::new(Mutex::new(stream)),
+        })
+    }
+
+    pub fn send(&self, data: &[u8], shared_memory_regions: &[&[u8]]) -> Result<(),Error> {
+        let stream = self.stream.lock().unwrap();
+        write_message(&mut &*stream, data, shared_memory_regions)
+    }
+}
+
+#[derive(Debug)]
+pub struct VsockReceiver {
+    stream: VsockStream,
+    /// The CID of the machine on the other end.
+    peer_cid: u32,
+}
+
+impl VsockReceiver {
+    /// Returns the data of the next message and the contents of its shared memory regions.
+    pub fn recv(&self) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> {
+        read_message(&mut &self.stream)
+    }
+
+    pub fn peer_cid(&self) -> u32 {
+        self.peer_cid
+    }
+}
+
+pub struct VsockServer {
+    listener: VsockStream,
+}
+
+impl VsockServer {
+    /// Listens on `port`, for connections to any of this machine's CIDs.
+    pub fn bind(port: u32) -> Result<VsockServer,Error> {
+        let listener = try!(VsockStream::socket());
+        let address = sockaddr_for(VMADDR_CID_ANY, port);
+        unsafe {
+            if libc::bind(listener.fd,
+                          &address as *const sockaddr_vm as *const sockaddr,
+                          mem::size_of::<sockaddr_vm>() as socklen_t) < 0 {
+                return Err(Error::last_os_error())
+            }
+            if libc::listen(listener.fd, 10) < 0 {
+                return Err(Error::last_os_error())
+            }
+        }
+        Ok(VsockServer {
+            listener: listener,
+        })
+    }
+
+    pub fn accept(&self) -> Result<VsockReceiver,Error> {
+        unsafe {
+            let mut address: sockaddr_vm = mem::zeroed();
+            let mut address_length = mem::size_of::<sockaddr_vm>() as socklen_t;
+            let fd = libc::accept(self.listener.fd,
+                                  &mut address as *mut sockaddr_vm as *mut sockaddr,
+                                  &mut address_length);
+            if fd < 0 {
+                return Err(Error::last_os_error())
+            }
+            Ok(VsockReceiver {
+                stream: VsockStream {
+                    fd: fd,
+                },
+                peer_cid: address.svm_cid,
+            })
+        }
+    }
+}
+
+fn sockaddr_for(cid: c_uint, port: c_uint) -> sockaddr_vm {
+    sockaddr_vm {
+        svm_family: AF_VSOCK as c_ushort,
+        svm_reserved1: 0,
+        svm_port: port,
+        svm_cid: cid,
+        svm_zero: [0; 4],
+    }
+}
+
+const MSG_NOSIGNAL: c_int = 0x4000;
+
+#[repr(C)]
+struct sockaddr_vm {
+    svm_family: c_ushort,
+    svm_reserved1: c_ushort,
+    svm_port: c_uint,
+    svm_cid: c_uint,
+    svm_zero: [u8; 4],
+}
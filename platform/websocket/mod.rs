@@ -0,0 +1,378 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A transport that tunnels channel messages over a WebSocket connection (RFC 6455), so that a
+//! frontend running in a browser, which can't open Unix sockets, can talk to a backend process.
+//! It's only built with the `websocket` feature.
+//!
+//! Each channel message is framed as on the TCP transport, and the frame goes out as a single
+//! binary WebSocket message. Channels can't be transferred, and shared memory regions are copied.
+//! A WebSocket connection carries messages both ways, so accepting or opening one yields both a
+//! sender and a receiver.
+//!
+//! Only what talking to browsers needs is here: no extensions, no subprotocols and no TLS. Put a
+//! reverse proxy in front for `wss://`.
+//!
+//! Any page a browser loads can open a WebSocket to any address, so servers only let in pages
+//! from the origins they were told to allow; see `WebSocketServer::allow_origin()`.
+
+use base64;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use platform::tcp::{read_exactly, read_message, write_message};
+use rand::{self, Rng};
+use ring::digest;
+use std::cmp;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+/// Appended to the client's key before hashing it into the server's answer.
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages bigger than this are refused, as their length comes from the peer.
+const MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+/// The longest handshake request or response we'll read.
+const MAX_HEAD_LENGTH: usize = 16 * 1024;
+
+/// The longest payload a control frame may have.
+const MAX_CONTROL_PAYLOAD_LENGTH: u64 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// The status code of a close frame for a connection that's done with.
+const CLOSE_NORMAL: [u8; 2] = [0x03, 0xe8];
+
+/// The writing half of a connection. It's shared by the sender and the receiver, which has to
+/// answer pings, and says goodbye when both are gone.
+#[derive(Debug)]
+struct FrameWriter {
+    stream: TcpStream,
+    /// Clients have to mask what they send; servers mustn't.
+    masked: bool,
+    closed: bool,
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        if !self.closed {
+            drop(self.write_frame(OPCODE_CLOSE, &CLOSE_NORMAL));
+        }
+    }
+}
+
+impl FrameWriter {
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(),Error> {
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else if payload.len() <= 0xffff {
+            frame.push(mask_bit | 126);
+            frame.write_u16::<BigEndian>(payload.len() as u16).unwrap();
+        } else {
+            frame.push(mask_bit | 127);
+            frame.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+        }
+        if self.masked {
+            let key: [u8; 4] = rand::thread_rng().gen();
+            frame.extend_from_slice(&key);
+            frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ key[index % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        if opcode == OPCODE_CLOSE {
+            self.closed = true;
+        }
+        self.stream.write_all(&frame[..])
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WebSocketSender {
+    /// Locked for the whole of each message, so that messages of clones don't interleave.
+    writer: Arc<Mutex<FrameWriter>>,
+}
+
+impl WebSocketSender {
+    /// Opens a WebSocket connection to the server at `address`, asking for `path`. Returns the
+    /// sending and receiving ends of the connection.
+    pub fn connect<A>(address: A, path: &str) -> Result<(WebSocketSender, WebSocketReceiver),Error>
+                      where A: ToSocketAddrs {
+        let stream = try!(TcpStream::connect(address));
+        try!(stream.set_nodelay(true));
+        let peer_addr = try!(stream.peer_addr());
+        let key_bytes: [u8; 16] = rand::thread_rng().gen();
+        let key = base64::encode(&key_bytes);
+        let request = format!("GET {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\r\n",
+                              path,
+                              peer_addr,
+                              key);
+        try!((&stream).write_all(request.as_bytes()));
+
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        let (status_line, fields) = try!(read_head(&mut reader));
+        let expected_accept_key = accept_key(&key);
+        if !status_line.starts_with("HTTP/1.1 101") ||
+                field(&fields, "sec-websocket-accept") != Some(&expected_accept_key[..]) {
+            return Err(Error::new(ErrorKind::ConnectionRefused, "WebSocket handshake refused"))
+        }
+        Ok(connection(stream, reader, peer_addr, true))
+    }
+
+    pub fn send(&self, data: &[u8], shared_memory_regions: &[&[u8]]) -> Result<(),Error> {
+        let mut message = vec![];
+        try!(write_message(&mut message, data, shared_memory_regions));
+        self.writer.lock().unwrap().write_frame(OPCODE_BINARY, &message[..])
+    }
+}
+
+#[derive(Debug)]
+pub struct WebSocketReceiver {
+    /// Buffered, as frames are read a few bytes at a time. This may also hold the start of the
+    /// first message, if it came in right behind the handshake.
+    stream: Mutex<BufReader<TcpStream>>,
+    writer: Arc<Mutex<FrameWriter>>,
+    peer_addr: SocketAddr,
+    /// Whether the peer is a client, which has to mask what it sends, where a server mustn't.
+    peer_masks: bool,
+}
+
+impl WebSocketReceiver {
+    /// Returns the data of the next message and the contents of its shared memory regions. Pings
+    /// are answered along the way. Once the peer closes the connection, this fails with a
+    /// `ConnectionAborted` error.
+    pub fn recv(&self) -> Result<(Vec<u8>, Vec<Vec<u8>>),Error> {
+        let mut stream = self.stream.lock().unwrap();
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            let buffered = message.as_ref().map_or(0, |message| message.len() as u64);
+            let (last, opcode, payload) =
+                try!(read_frame(&mut *stream, buffered, self.peer_masks));
+            match opcode {
+                OPCODE_PING => {
+                    try!(self.writer.lock().unwrap().write_frame(OPCODE_PONG, &payload[..]))
+                }
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // The peer expects its close to be echoed.
+                    let mut writer = self.writer.lock().unwrap();
+                    if !writer.closed {
+                        let status_code = &payload[..cmp::min(payload.len(), 2)];
+                        drop(writer.write_frame(OPCODE_CLOSE, status_code));
+                    }
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "WebSocket closed"))
+                }
+                OPCODE_BINARY if message.is_none() => message = Some(payload),
+                OPCODE_CONTINUATION if message.is_some() => {
+                    message.as_mut().unwrap().extend_from_slice(&payload[..])
+                }
+                OPCODE_TEXT => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          "WebSocket text messages aren't channel messages"))
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, "malformed WebSocket frame")),
+            }
+            if last && opcode != OPCODE_PING && opcode != OPCODE_PONG {
+                let message = message.take().unwrap();
+                return read_message(&mut &message[..])
+            }
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+pub struct WebSocketServer {
+    listener: TcpListener,
+    /// The origins of the pages that may connect; see `allow_origin()`.
+    allowed_origins: Vec<String>,
+}
+
+impl WebSocketServer {
+    pub fn bind<A>(address: A) -> Result<WebSocketServer,Error> where A: ToSocketAddrs {
+        Ok(WebSocketServer {
+            listener: try!(TcpListener::bind(address)),
+            allowed_origins: vec![],
+        })
+    }
+
+    /// Lets pages from `origin`, such as `https://example.com`, connect. Browsers say which page
+    /// opened a WebSocket in the `Origin` header, and until this is called, every page is turned
+    /// away, so that a site the user happens to visit can't talk to the backend. Clients that
+    /// aren't browsers send no `Origin`, and are let in regardless.
+    pub fn allow_origin(&mut self, origin: &str) {
+        self.allowed_origins.push(origin.to_owned())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr,Error> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for a client to connect and open a WebSocket, at any path. Returns the sending and
+    /// receiving ends of the connection. Clients that don't ask for a WebSocket are turned away
+    /// with a `400 Bad Request`, and this fails with an `InvalidData` error; pages from origins
+    /// that weren't allowed get a `403 Forbidden`, and this fails with `PermissionDenied`.
+    pub fn accept(&self) -> Result<(WebSocketSender, WebSocketReceiver),Error> {
+        let (stream, peer_addr) = try!(self.listener.accept());
+        try!(stream.set_nodelay(true));
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        let (request_line, fields) = try!(read_head(&mut reader));
+        let upgrade = field(&fields, "upgrade").map(|upgrade| upgrade.to_lowercase());
+        let key = match field(&fields, "sec-websocket-key") {
+            Some(key) if request_line.starts_with("GET ") &&
+                         upgrade.as_ref().map(|upgrade| &upgrade[..]) == Some("websocket") => {
+                key.to_owned()
+            }
+            _ => {
+                drop((&stream).write_all(b"HTTP/1.1 400 Bad Request\r\n\
+                                           Content-Length: 0\r\n\
+                                           Connection: close\r\n\r\n"));
+                return Err(Error::new(ErrorKind::InvalidData, "not a WebSocket handshake"))
+            }
+        };
+        if let Some(origin) = field(&fields, "origin") {
+            if !self.allowed_origins.iter().any(|allowed_origin| allowed_origin == origin) {
+                drop((&stream).write_all(b"HTTP/1.1 403 Forbidden\r\n\
+                                           Content-Length: 0\r\n\
+                                           Connection: close\r\n\r\n"));
+                return Err(Error::new(ErrorKind::PermissionDenied,
+                                      format!("WebSocket from disallowed origin {}", origin)))
+            }
+        }
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                Upgrade: websocket\r\n\
+                                Connection: Upgrade\r\n\
+                                Sec-WebSocket-Accept: {}\r\n\r\n",
+                               accept_key(&key));
+        try!((&stream).write_all(response.as_bytes()));
+        Ok(connection(stream, reader, peer_addr, false))
+    }
+}
+
+fn connection(stream: TcpStream,
+              reader: BufReader<TcpStream>,
+              peer_addr: SocketAddr,
+              masked: bool)
+              -> (WebSocketSender, WebSocketReceiver) {
+    let writer = Arc::new(Mutex::new(FrameWriter {
+        stream: stream,
+        masked: masked,
+        closed: false,
+    }));
+    let sender = WebSocketSender {
+        writer: writer.clone(),
+    };
+    let receiver = WebSocketReceiver {
+        stream: Mutex::new(reader),
+        writer: writer,
+        peer_addr: peer_addr,
+        peer_masks: !masked,
+    };
+    (sender, receiver)
+}
+
+/// Reads one frame, returning whether it's the last of its message, its opcode and its unmasked
+/// payload. `buffered` is how much of the message has come in already, which counts towards the
+/// limit. Frames must be masked if `peer_masks` is set, and mustn't be otherwise.
+fn read_frame<R>(stream: &mut R, buffered: u64, peer_masks: bool)
+                 -> Result<(bool, u8, Vec<u8>),Error>
+                 where R: Read {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed WebSocket frame");
+    let first = try!(stream.read_u8());
+    let second = try!(stream.read_u8());
+    let last = first & 0x80 != 0;
+    let opcode = first & 0x0f;
+    let masked = second & 0x80 != 0;
+    // No extensions were negotiated, so the reserved bits must be clear.
+    if first & 0x70 != 0 || masked != peer_masks {
+        return Err(malformed())
+    }
+    let length = match second & 0x7f {
+        126 => try!(stream.read_u16::<BigEndian>()) as u64,
+        127 => try!(stream.read_u64::<BigEndian>()),
+        length => length as u64,
+    };
+    // Control frames can't be fragmented, and are short.
+    if opcode & 0x08 != 0 && (!last || length > MAX_CONTROL_PAYLOAD_LENGTH) {
+        return Err(malformed())
+    }
+    if length > MAX_MESSAGE_SIZE - buffered {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket message too big"))
+    }
+    let mut key = [0; 4];
+    if masked {
+        try!(stream.read_exact(&mut key));
+    }
+    let mut payload = try!(read_exactly(stream, length));
+    if masked {
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[index % 4];
+        }
+    }
+    Ok((last, opcode, payload))
+}
+
+/// Reads the head of an HTTP request or response, returning its first line and its header
+/// fields, with their names in lowercase.
+fn read_head<R>(reader: &mut R) -> Result<(String, Vec<(String, String)>),Error>
+                where R: BufRead {
+    let mut lines = vec![];
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        let read = try!(reader.by_ref().take((MAX_HEAD_LENGTH - length) as u64)
+                                       .read_line(&mut line));
+        length += read;
+        if !line.ends_with('\n') {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed WebSocket handshake"))
+        }
+        let line = line.trim_right().to_owned();
+        if line.is_empty() {
+            break
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "malformed WebSocket handshake"))
+    }
+    let first_line = lines.remove(0);
+    let fields = lines.into_iter().filter_map(|line| {
+        line.find(':').map(|colon| {
+            (line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_owned())
+        })
+    }).collect();
+    Ok((first_line, fields))
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields.iter().find(|&&(ref field_name, _)| field_name == name).map(|&(_, ref value)| &value[..])
+}
+
+/// Returns what the server answers to the client's `key`, to show that it understood the
+/// handshake.
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    // SHA-1 isn't secure, but the handshake doesn't need it to be.
+    base64::encode(digest::digest(&digest::SHA1, &input).as_ref())
+}
@@ -0,0 +1,85 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Spawning child processes that are connected to us by IPC channels from the start.
+
+use ipc::{IpcOneShotServer, IpcReceiver, IpcSender, IpcStdioReceiver, IpcStdioSender};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// The environment variable through which `spawn()` tells the child how to reach its parent.
+pub const PARENT_SERVER_ENV_VAR: &'static str = "IPC_CHANNEL_PARENT_SERVER";
+
+/// Spawns `command` as a child process, and waits up to `timeout` for it to call
+/// `connect_to_parent()`. Returns the child, along with the receiving end of the channel it
+/// connected and the value it sent when connecting.
+///
+/// That value is how the channels between parent and child get set up: the child can put any
+/// number of `IpcSender`s and `IpcReceiver`s into it. If the child doesn't connect in time --
+/// because it failed to start up, say, or connected and then hung before sending that value -- it
+/// is killed, and this fails with a `TimedOut` error.
+pub fn spawn<T>(command: &mut Command, timeout: Duration)
+                -> Result<(Child, IpcReceiver<T>, T),Error>
+                where T: Deserialize + Serialize {
+    let (server, name) = try!(IpcOneShotServer::new());
+    let mut child = try!(command.env(PARENT_SERVER_ENV_VAR, name).spawn());
+    match server.accept_timeout(timeout) {
+        Ok((receiver, data)) => Ok((child, receiver, data)),
+        Err(error) => {
+            drop(child.kill());
+            drop(child.wait());
+            Err(Error::from(error))
+        }
+    }
+}
+
+/// Connects back to the parent that started us with `spawn()`, sending it `data`. Returns the
+/// sending end of the channel, which we can go on using to talk to the parent.
+///
+/// The environment variable the parent passed is left alone, since changing the environment
+/// isn't safe while other threads may be reading it. Children we start ourselves can't connect
+/// to our parent through it anyway, as its server only accepts us.
+pub fn connect_to_parent<T>(data: T) -> Result<IpcSender<T>,Error> where T: Serialize {
+    let name = match env::var(PARENT_SERVER_ENV_VAR) {
+        Ok(name) => name,
+        Err(_) => {
+            return Err(Error::new(ErrorKind::NotFound,
+                                  "not started by ipc_channel::process::spawn()"))
+        }
+    };
+    let sender = try!(IpcSender::connect(name));
+    try!(sender.send(data));
+    Ok(sender)
+}
+
+/// Spawns `command` as a child process that we talk to over its stdin and stdout, for when it
+/// can't inherit sockets or handles: say, because a sandbox launcher closes everything else.
+/// Returns the child, along with a sender that writes to its stdin and a receiver that reads from
+/// its stdout. The child gets its ends with `stdio_to_parent()`.
+///
+/// Only bytes cross these streams, so messages can't carry channels; see `IpcStdioSender`.
+pub fn spawn_with_stdio<T,U>(command: &mut Command)
+                             -> Result<(Child, IpcStdioSender<T>, IpcStdioReceiver<U>),Error>
+                             where T: Serialize, U: Deserialize + Serialize {
+    let mut child = try!(command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn());
+    let sender = IpcStdioSender::new(child.stdin.take().unwrap());
+    let receiver = IpcStdioReceiver::new(child.stdout.take().unwrap());
+    Ok((child, sender, receiver))
+}
+
+/// Returns the ends of the streams to the parent that started us with `spawn_with_stdio()`: a
+/// sender that writes to our stdout, and a receiver that reads from our stdin. Nothing else in
+/// the process may print to stdout from then on.
+pub fn stdio_to_parent<T,U>() -> (IpcStdioSender<T>, IpcStdioReceiver<U>)
+                            where T: Serialize, U: Deserialize + Serialize {
+    (IpcStdioSender::stdout(), IpcStdioReceiver::stdin())
+}
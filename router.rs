@@ -8,39 +8,190 @@
 // except according to those terms.
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::io::{Error, ErrorKind};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
 
 use ipc::{self, IpcReceiver, IpcReceiverSet, IpcSelectionResult, IpcSender, OpaqueIpcMessage};
 use ipc::{OpaqueIpcReceiver};
+use platform;
 use serde::{Deserialize, Serialize};
 
 lazy_static! {
-    pub static ref ROUTER: RouterProxy = RouterProxy::new();
+    pub static ref ROUTER: RouterProxy = {
+        let config = ROUTER_CONFIG.lock().unwrap().take().unwrap();
+        RouterProxy::with_config(config).unwrap()
+    };
+}
+
+// The configuration `ROUTER` starts with, until it does.
+lazy_static! {
+    static ref ROUTER_CONFIG: Mutex<Option<RouterConfig>> = Mutex::new(Some(RouterConfig {
+        name: Some("ipc-channel router".to_owned()),
+        cpu_affinity: None,
+        priority: None,
+        dead_letters: None,
+    }));
+}
+
+/// Sets up the thread that `ROUTER` runs on. This has to be done before `ROUTER` is first used;
+/// afterwards it fails.
+pub fn configure_router(config: RouterConfig) -> Result<(),Error> {
+    match *ROUTER_CONFIG.lock().unwrap() {
+        Some(ref mut router_config) => {
+            *router_config = config;
+            Ok(())
+        }
+        None => Err(Error::new(ErrorKind::Other, "the router is already running")),
+    }
+}
+
+/// How to set up a router's thread, so that it can be kept from competing with more important
+/// threads, or given precedence over them.
+#[derive(Clone, Debug, Default)]
+pub struct RouterConfig {
+    /// The thread's name.
+    pub name: Option<String>,
+    /// The CPUs the thread may run on. This is only supported on Linux.
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// The thread's nice value, from -20 (first) to 19 (last). Raising it above the default
+    /// usually requires privileges. This is only supported on Linux.
+    pub priority: Option<i32>,
+    /// Where to put messages the router can't deliver. Without it, messages that don't
+    /// deserialize are a panic on the router thread, and others are dropped.
+    pub dead_letters: Option<Sender<DeadLetter>>,
+}
+
+/// A message the router couldn't deliver, and why.
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    /// The message as it was received, unless it had already been handed over. Channels that were
+    /// taken out of it are gone.
+    pub message: Option<OpaqueIpcMessage>,
+}
+
+#[derive(Debug)]
+pub enum DeadLetterReason {
+    /// The message arrived on a receiver that has no route.
+    NoRoute,
+    /// The `Receiver` a convenience route sends to was dropped. The message had been deserialized
+    /// by then, so it isn't included.
+    ReceiverGone,
+    /// The message didn't deserialize to the type its route expects.
+    Deserialize(ipc::Error),
+    /// A message arrived on a routed receiver, but couldn't be received, e.g. because it was too
+    /// big. There's no message to include.
+    ReceiveFailed(ipc::Error),
+    /// The route couldn't pass the message on, e.g. because it carries channels that can't go
+    /// where the route sends it.
+    Rejected(ipc::Error),
+}
+
+/// What a router has done so far; see `RouterProxy::stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RouterStats {
+    /// The number of receivers being routed.
+    pub routes: usize,
+    /// The number of messages handed to routes, and their total size.
+    pub messages: usize,
+    pub bytes: usize,
+    /// The number of messages that couldn't be delivered, whether or not they went to a
+    /// dead-letter queue.
+    pub dead_letters: usize,
+}
+
+/// Kept up to date by the router thread and its routes, and read by `RouterProxy::stats()`.
+struct RouterCounters {
+    routes: AtomicUsize,
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    dead_letters: AtomicUsize,
+}
+
+impl RouterCounters {
+    fn new() -> RouterCounters {
+        RouterCounters {
+            routes: AtomicUsize::new(0),
+            messages: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            dead_letters: AtomicUsize::new(0),
+        }
+    }
 }
 
 pub struct RouterProxy {
-    comm: Mutex<RouterProxyComm>,
+    queue: Arc<RouterMsgQueue>,
+    counters: Arc<RouterCounters>,
+    /// Only taken to wake the router up, which happens once per batch of messages rather than
+    /// once per message.
+    wakeup_sender: Mutex<IpcSender<()>>,
+    dead_letters: Option<Mutex<Sender<DeadLetter>>>,
 }
 
 impl RouterProxy {
     pub fn new() -> RouterProxy {
-        let (msg_sender, msg_receiver) = mpsc::channel();
-        let (wakeup_sender, wakeup_receiver) = ipc::channel().unwrap();
-        thread::spawn(move || Router::new(msg_receiver, wakeup_receiver).run());
-        RouterProxy {
-            comm: Mutex::new(RouterProxyComm {
-                msg_sender: msg_sender,
-                wakeup_sender: wakeup_sender,
-            }),
+        RouterProxy::with_config(RouterConfig::default()).unwrap()
+    }
+
+    /// Starts a router on a thread set up according to `config`. This fails if the thread can't
+    /// be set up that way.
+    pub fn with_config(config: RouterConfig) -> Result<RouterProxy,Error> {
+        let queue = Arc::new(RouterMsgQueue::new());
+        let counters = Arc::new(RouterCounters::new());
+        let (wakeup_sender, wakeup_receiver) = try!(ipc::channel());
+        let router_queue = queue.clone();
+        let router_counters = counters.clone();
+        let mut builder = thread::Builder::new();
+        if let Some(name) = config.name {
+            builder = builder.name(name);
         }
+        let (cpu_affinity, priority) = (config.cpu_affinity, config.priority);
+        let dead_letters = config.dead_letters;
+        let router_dead_letters = dead_letters.clone();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        try!(builder.spawn(move || {
+            let mut result = Ok(());
+            if let Some(ref cpu_affinity) = cpu_affinity {
+                result = platform::set_thread_affinity(cpu_affinity).map_err(Error::from);
+            }
+            if let (true, Some(priority)) = (result.is_ok(), priority) {
+                result = platform::set_thread_priority(priority).map_err(Error::from);
+            }
+            let ready = result.is_ok();
+            ready_sender.send(result).unwrap();
+            if ready {
+                Router::new(router_queue, router_counters, wakeup_receiver, router_dead_letters)
+                    .run()
+            }
+        }));
+        try!(ready_receiver.recv().unwrap());
+        Ok(RouterProxy {
+            queue: queue,
+            counters: counters,
+            wakeup_sender: Mutex::new(wakeup_sender),
+            dead_letters: dead_letters.map(Mutex::new),
+        })
     }
 
     pub fn add_route(&self, receiver: OpaqueIpcReceiver, callback: RouterHandler) {
-        let comm = self.comm.lock().unwrap();
-        comm.msg_sender.send(RouterMsg::AddRoute(receiver, callback)).unwrap();
-        comm.wakeup_sender.send(()).unwrap();
+        if self.queue.push(RouterMsg::AddRoute(receiver, callback)) {
+            self.wakeup_sender.lock().unwrap().send(()).unwrap();
+        }
+    }
+
+    /// Returns what the router has done so far. The counts are kept with relaxed atomics, so they
+    /// cost next to nothing to keep, and may lag each other slightly.
+    pub fn stats(&self) -> RouterStats {
+        RouterStats {
+            routes: self.counters.routes.load(Ordering::Relaxed),
+            messages: self.counters.messages.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+            dead_letters: self.counters.dead_letters.load(Ordering::Relaxed),
+        }
     }
 
     /// A convenience function to route an `IpcReceiver<T>` to an existing `Sender<T>`.
@@ -51,8 +202,35 @@ impl RouterProxy {
                                                          Serialize +
                                                          Send +
                                                          'static {
+        let dead_letters = match self.dead_letters {
+            Some(ref dead_letters) => Some(dead_letters.lock().unwrap().clone()),
+            None => None,
+        };
+        let counters = self.counters.clone();
         self.add_route(ipc_receiver.to_opaque(), Box::new(move |message| {
-            drop(mpsc_sender.send(message.to::<T>().unwrap()))
+            let (reason, message) = match message.try_to::<T>() {
+                Ok(value) => {
+                    match mpsc_sender.send(value) {
+                        Ok(()) => return,
+                        Err(_) => (DeadLetterReason::ReceiverGone, None),
+                    }
+                }
+                Err((err, message)) => (DeadLetterReason::Deserialize(err), Some(message)),
+            };
+            counters.dead_letters.fetch_add(1, Ordering::Relaxed);
+            match dead_letters {
+                Some(ref dead_letters) => {
+                    drop(dead_letters.send(DeadLetter {
+                        reason: reason,
+                        message: message,
+                    }))
+                }
+                None => {
+                    if let DeadLetterReason::Deserialize(err) = reason {
+                        panic!("failed to deserialize a routed message: {:?}", err)
+                    }
+                }
+            }
         }))
     }
 
@@ -70,27 +248,101 @@ impl RouterProxy {
     }
 }
 
-struct RouterProxyComm {
-    msg_sender: Sender<RouterMsg>,
-    wakeup_sender: IpcSender<()>,
+/// Hands messages from any number of `RouterProxy` users to the router thread without locking:
+/// senders push onto a lock-free stack, and the router takes the whole stack at once.
+struct RouterMsgQueue {
+    head: AtomicPtr<RouterMsgNode>,
+    /// Set while a wakeup is on its way to the router, so that senders racing with each other
+    /// only send one.
+    wakeup_pending: AtomicBool,
+}
+
+struct RouterMsgNode {
+    msg: RouterMsg,
+    next: *mut RouterMsgNode,
 }
 
+impl RouterMsgQueue {
+    fn new() -> RouterMsgQueue {
+        RouterMsgQueue {
+            head: AtomicPtr::new(ptr::null_mut()),
+            wakeup_pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues `msg`, returning true if the caller needs to wake the router up.
+    fn push(&self, msg: RouterMsg) -> bool {
+        let node = Box::into_raw(Box::new(RouterMsgNode {
+            msg: msg,
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe {
+                (*node).next = head;
+            }
+            let previous = self.head.compare_and_swap(head, node, Ordering::SeqCst);
+            if previous == head {
+                break
+            }
+            head = previous;
+        }
+        !self.wakeup_pending.swap(true, Ordering::SeqCst)
+    }
+
+    /// Takes all queued messages, oldest first. Messages pushed after this returns come with a
+    /// wakeup of their own.
+    fn take_all(&self) -> Vec<RouterMsg> {
+        self.wakeup_pending.store(false, Ordering::SeqCst);
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::SeqCst);
+        let mut msgs = vec![];
+        while !node.is_null() {
+            let boxed_node = unsafe {
+                Box::from_raw(node)
+            };
+            node = boxed_node.next;
+            msgs.push(boxed_node.msg);
+        }
+        msgs.reverse();
+        msgs
+    }
+}
+
+impl Drop for RouterMsgQueue {
+    fn drop(&mut self) {
+        drop(self.take_all())
+    }
+}
+
+// The nodes are only ever touched by whoever pushes them, until they are published, and by
+// `take_all()`, after they have been unlinked.
+unsafe impl Send for RouterMsgQueue {}
+unsafe impl Sync for RouterMsgQueue {}
+
 struct Router {
-    msg_receiver: Receiver<RouterMsg>,
+    queue: Arc<RouterMsgQueue>,
+    counters: Arc<RouterCounters>,
     msg_wakeup_id: i64,
     ipc_receiver_set: IpcReceiverSet,
     handlers: HashMap<i64,RouterHandler>,
+    dead_letters: Option<Sender<DeadLetter>>,
 }
 
 impl Router {
-    fn new(msg_receiver: Receiver<RouterMsg>, wakeup_receiver: IpcReceiver<()>) -> Router {
+    fn new(queue: Arc<RouterMsgQueue>,
+           counters: Arc<RouterCounters>,
+           wakeup_receiver: IpcReceiver<()>,
+           dead_letters: Option<Sender<DeadLetter>>)
+           -> Router {
         let mut ipc_receiver_set = IpcReceiverSet::new().unwrap();
         let msg_wakeup_id = ipc_receiver_set.add(wakeup_receiver).unwrap();
         Router {
-            msg_receiver: msg_receiver,
+            queue: queue,
+            counters: counters,
             msg_wakeup_id: msg_wakeup_id,
             ipc_receiver_set: ipc_receiver_set,
             handlers: HashMap::new(),
+            dead_letters: dead_letters,
         }
     }
 
@@ -98,30 +350,55 @@ impl Router {
         loop {
             let results = match self.ipc_receiver_set.select() {
                 Ok(results) => results,
+                // A receiver that fails is reported in the results, so this is the set itself
+                // failing, and there's no selecting on it any more.
                 Err(_) => break,
             };
             for result in results.into_iter() {
                 match result {
                     IpcSelectionResult::MessageReceived(id, _) if id == self.msg_wakeup_id => {
-                        match self.msg_receiver.recv().unwrap() {
-                            RouterMsg::AddRoute(receiver, handler) => {
-                                let new_receiver_id = self.ipc_receiver_set
-                                                          .add_opaque(receiver)
-                                                          .unwrap();
-                                self.handlers.insert(new_receiver_id, handler);
+                        for msg in self.queue.take_all() {
+                            match msg {
+                                RouterMsg::AddRoute(receiver, handler) => {
+                                    let new_receiver_id = self.ipc_receiver_set
+                                                              .add_opaque(receiver)
+                                                              .unwrap();
+                                    self.handlers.insert(new_receiver_id, handler);
+                                    self.counters.routes.fetch_add(1, Ordering::Relaxed);
+                                }
                             }
                         }
                     }
                     IpcSelectionResult::MessageReceived(id, message) => {
-                        self.handlers.get_mut(&id).unwrap()(message)
+                        if self.handlers.contains_key(&id) {
+                            self.counters.messages.fetch_add(1, Ordering::Relaxed);
+                            self.counters.bytes.fetch_add(message.data().len(), Ordering::Relaxed);
+                            self.handlers.get_mut(&id).unwrap()(message)
+                        } else {
+                            self.dead_letter(DeadLetterReason::NoRoute, Some(message))
+                        }
                     }
                     IpcSelectionResult::ChannelClosed(id) => {
                         self.handlers.remove(&id).unwrap();
+                        self.counters.routes.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    IpcSelectionResult::ReceiveFailed(_, err) => {
+                        self.dead_letter(DeadLetterReason::ReceiveFailed(err), None)
                     }
                 }
             }
         }
     }
+
+    fn dead_letter(&self, reason: DeadLetterReason, message: Option<OpaqueIpcMessage>) {
+        self.counters.dead_letters.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref dead_letters) = self.dead_letters {
+            drop(dead_letters.send(DeadLetter {
+                reason: reason,
+                message: message,
+            }))
+        }
+    }
 }
 
 enum RouterMsg {
@@ -7,19 +7,34 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use ipc::{self, IpcOneShotServer, IpcReceiver, IpcReceiverSet, IpcSender, IpcSharedMemory};
-use ipc::{OpaqueIpcSender};
-use router::ROUTER;
+use ipc::{self, IpcOneShotServer, IpcReceiver, IpcReceiverSet, IpcSender, IpcServer};
+use ipc::{IpcChannelPairServer, IpcSharedMemory, OpaqueIpcSender, ReconnectPolicy};
+use ipc::{HandleLimit, HandleLimitExceeded, MessageAuthenticator, RateLimit, RateLimitPolicy};
+use ipc::{OutOfStep, SessionRole};
+use ipc::{ChunkedVec, IpcLazy, MessageDirection, MessageMetadata, ReconnectingSender};
+use ipc::{Backend, ChannelBuilder, IpcTcpSender, IpcTcpServer, RetryPolicy, TryRecvError};
+use ipc::{EndpointInfo, EndpointKind, Fault, Faults, LabeledError, Scheduler};
+use wire::{self, WireProblem};
+#[cfg(feature = "bench")]
+use bench;
+use router::{self, DeadLetterReason, ROUTER, RouterConfig, RouterProxy};
 use libc;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::iter;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "android",
+              target_os = "ios")))]
+use process;
 
 ///XXXjdm Windows' libc doesn't include fork.
-#[cfg(not(windows))]
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
 // I'm not actually sure invoking this is indeed unsafe -- but better safe than sorry...
 pub unsafe fn fork<F: FnOnce()>(child_func: F) -> libc::pid_t {
     match libc::fork() {
@@ -29,12 +44,12 @@ pub unsafe fn fork<F: FnOnce()>(child_func: F) -> libc::pid_t {
     }
 }
 
-#[cfg(not(windows))]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
 pub trait Wait {
     fn wait(self);
 }
 
-#[cfg(not(windows))]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
 impl Wait for libc::pid_t {
     fn wait(self) {
         unsafe {
@@ -85,6 +100,123 @@ fn simple() {
     assert_eq!(person, received_person);
 }
 
+#[test]
+fn inline_message_boundary() {
+    // Messages serialize inline up to a few hundred bytes, and spill onto the heap beyond.
+    let (tx, rx) = ipc::channel().unwrap();
+    for length in 200..320 {
+        let data: Vec<u8> = (0..length).map(|i| i as u8).collect();
+        tx.send(data.clone()).unwrap();
+        assert_eq!(rx.recv().unwrap(), data);
+    }
+}
+
+#[test]
+fn recv_parallel() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let big = ChunkedVec((0..1024 * 1024).map(|i| (i, format!("{}", i))).collect::<Vec<_>>());
+    let small = ChunkedVec(vec![(7, "7".to_owned())]);
+    let (thread_big, thread_small) = (big.clone(), small.clone());
+    let thread = thread::spawn(move || {
+        tx.send(thread_big.clone()).unwrap();
+        tx.send(thread_small).unwrap();
+        tx.send(thread_big).unwrap();
+    });
+    assert_eq!(rx.recv_parallel(4).unwrap(), big);
+    assert_eq!(rx.recv_parallel(4).unwrap(), small);
+    // Chunked vectors can be received the ordinary way, too.
+    assert_eq!(rx.recv().unwrap(), big);
+    thread.join().unwrap();
+}
+
+#[test]
+fn chunked_vec_serialization_error() {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::ser::Error as SerializerError;
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S>(&self, _: &mut S) -> Result<(),S::Error> where S: Serializer {
+            Err(S::Error::custom("unserializable"))
+        }
+    }
+
+    impl Deserialize for Unserializable {
+        fn deserialize<D>(_: &mut D) -> Result<Self,D::Error> where D: Deserializer {
+            Ok(Unserializable)
+        }
+    }
+
+    let (tx, _rx) = ipc::channel().unwrap();
+    assert!(tx.send(ChunkedVec(vec![Unserializable])).is_err());
+}
+
+#[test]
+fn lazy() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx0, rx0) = ipc::channel().unwrap();
+    let (tx1, rx1) = ipc::channel().unwrap();
+    let lazy_person = IpcLazy::new(person.clone()).unwrap();
+    let bytes = lazy_person.bytes().to_vec();
+    tx0.send(lazy_person).unwrap();
+    // Pass it on without looking at it.
+    let forwarded_person: IpcLazy<Person> = rx0.recv().unwrap();
+    assert_eq!(forwarded_person.bytes(), &bytes[..]);
+    tx1.send(forwarded_person).unwrap();
+    let mut received_person: IpcLazy<Person> = rx1.recv().unwrap();
+    assert_eq!(*received_person.get().unwrap(), person);
+    assert_eq!(received_person.into_inner().unwrap(), person);
+
+    let (sub_tx, _) = ipc::channel::<()>().unwrap();
+    assert!(IpcLazy::new(sub_tx).is_err());
+}
+
+#[test]
+fn single_serialization_pass() {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::cell::Cell;
+
+    thread_local!(static SERIALIZATIONS: Cell<usize> = Cell::new(0));
+
+    // Counts how many times it gets serialized.
+    struct Counted(Vec<u8>);
+
+    impl Serialize for Counted {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+            SERIALIZATIONS.with(|serializations| serializations.set(serializations.get() + 1));
+            self.0.serialize(serializer)
+        }
+    }
+
+    impl Deserialize for Counted {
+        fn deserialize<D>(deserializer: &mut D) -> Result<Self,D::Error>
+                          where D: Deserializer {
+            Ok(Counted(try!(Deserialize::deserialize(deserializer))))
+        }
+    }
+
+    let (tx, rx) = ipc::channel().unwrap();
+    let thread = thread::spawn(move || {
+        // Small, big enough to need fragmenting or shared memory, and batched.
+        for &length in &[7, 4 * 1024 * 1024] {
+            tx.send(Counted(vec![0; length])).unwrap();
+        }
+        let mut batch = tx.cork();
+        batch.send(Counted(vec![0; 7])).unwrap();
+        batch.send(Counted(vec![0; 7])).unwrap();
+        batch.uncork().unwrap();
+        SERIALIZATIONS.with(|serializations| serializations.get())
+    });
+    for _ in 0..4 {
+        rx.recv().unwrap();
+    }
+    assert_eq!(thread.join().unwrap(), 4);
+}
+
 #[test]
 fn embedded_senders() {
     let person = Person {
@@ -125,6 +257,45 @@ fn embedded_receivers() {
     assert_eq!(received_person, person);
 }
 
+#[test]
+fn in_process_backend() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (sub_tx, sub_rx) = ipc::channel_with_backend(Backend::InProcess).unwrap();
+    let person_and_sender = PersonAndSender {
+        person: person.clone(),
+        sender: sub_tx,
+    };
+    let (super_tx, super_rx) = ipc::channel_with_backend(Backend::InProcess).unwrap();
+    super_tx.send(person_and_sender.clone()).unwrap();
+    let received_person_and_sender = super_rx.recv().unwrap();
+    assert_eq!(received_person_and_sender.person, person);
+    received_person_and_sender.sender.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+
+    // In-process channels can't travel over native ones.
+    let (native_tx, _native_rx) = ipc::channel_with_backend(Backend::Native).unwrap();
+    assert_eq!(native_tx.send(person_and_sender).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn channel_builder() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ChannelBuilder::new().backend(Backend::InProcess).build().unwrap();
+    tx.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+
+    // The builder's backend wins over the current one.
+    let (native_tx, _native_rx) =
+        ChannelBuilder::new().backend(Backend::Native).build::<IpcSender<Person>>().unwrap();
+    assert_eq!(native_tx.send(tx).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
 #[test]
 fn select() {
     let (tx0, rx0) = ipc::channel().unwrap();
@@ -173,7 +344,8 @@ fn select() {
 
 #[test]
 ///XXXjdm Windows' libc doesn't include fork.
-#[cfg(not(windows))]
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
 fn cross_process_embedded_senders() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
@@ -198,229 +370,2113 @@ fn cross_process_embedded_senders() {
 }
 
 #[test]
-fn router_simple() {
+///XXXjdm Windows' libc doesn't include fork.
+/// iOS doesn't allow it either.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn cross_process_channel_pair() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (tx, rx) = ipc::channel().unwrap();
+    // Each side names its own types first, so they swap places between the two.
+    let (server, name) = IpcChannelPairServer::<Person,String>::new().unwrap();
+    let child_pid = unsafe { fork(|| {
+        let (tx, rx) = ipc::channel_pair_for::<String,Person>(name).unwrap();
+        let received_person = rx.recv().unwrap();
+        tx.send(received_person.name).unwrap();
+        libc::exit(0);
+    })};
+    let (tx, rx): (IpcSender<Person>, IpcReceiver<String>) = server.accept().unwrap();
     tx.send(person.clone()).unwrap();
-
-    let (callback_fired_sender, callback_fired_receiver) = mpsc::channel::<Person>();
-    ROUTER.add_route(rx.to_opaque(), Box::new(move |person| {
-        callback_fired_sender.send(person.to().unwrap()).unwrap()
-    }));
-    let received_person = callback_fired_receiver.recv().unwrap();
-    assert_eq!(received_person, person);
+    let received_name = rx.recv().unwrap();
+    child_pid.wait();
+    assert_eq!(received_name, person.name);
 }
 
 #[test]
-fn router_routing_to_new_mpsc_receiver() {
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn multi_shot_server() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (tx, rx) = ipc::channel().unwrap();
-    tx.send(person.clone()).unwrap();
+    let (server, name) = IpcServer::new().unwrap();
+    let threads: Vec<_> = (0..3).map(|i| {
+        let name = name.clone();
+        let person = Person {
+            name: person.name.clone(),
+            age: i,
+        };
+        thread::spawn(move || {
+            let tx = IpcSender::connect(name).unwrap();
+            tx.send(person.clone()).unwrap();
+            tx.send(person).unwrap();
+        })
+    }).collect();
 
-    let mpsc_receiver = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx);
-    let received_person = mpsc_receiver.recv().unwrap();
-    assert_eq!(received_person, person);
+    let mut received_ages = vec![];
+    for _ in 0..3 {
+        let (rx, received_person): (IpcReceiver<Person>, Person) = server.accept().unwrap();
+        assert_eq!(received_person.name, person.name);
+        // The second message must arrive on the same client's channel.
+        assert_eq!(rx.recv().unwrap(), received_person);
+        received_ages.push(received_person.age);
+    }
+    received_ages.sort();
+    assert_eq!(received_ages, vec![0, 1, 2]);
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
 }
 
 #[test]
-fn router_multiplexing() {
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn named_one_shot_server() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (tx0, rx0) = ipc::channel().unwrap();
-    tx0.send(person.clone()).unwrap();
-    let (tx1, rx1) = ipc::channel().unwrap();
-    tx1.send(person.clone()).unwrap();
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
+    let server = IpcOneShotServer::with_name(&name).unwrap();
+    assert!(IpcOneShotServer::<Person>::with_name(&name).is_err());
 
-    let mpsc_rx_0 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx0);
-    let mpsc_rx_1 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx1);
-    let received_person_0 = mpsc_rx_0.recv().unwrap();
-    let received_person_1 = mpsc_rx_1.recv().unwrap();
-    assert_eq!(received_person_0, person);
-    assert_eq!(received_person_1, person);
+    let person_for_thread = person.clone();
+    thread::spawn(move || {
+        let tx = IpcSender::connect(name).unwrap();
+        tx.send(person_for_thread).unwrap();
+    });
+    let (_, received_person): (_, Person) = server.accept().unwrap();
+    assert_eq!(received_person, person);
 }
 
 #[test]
-fn router_multithreaded_multiplexing() {
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn reconnecting_sender() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
 
-    let person_for_thread = person.clone();
-    let (tx0, rx0) = ipc::channel().unwrap();
-    thread::spawn(move || tx0.send(person_for_thread).unwrap());
-    let person_for_thread = person.clone();
-    let (tx1, rx1) = ipc::channel().unwrap();
-    thread::spawn(move || tx1.send(person_for_thread).unwrap());
-
-    let mpsc_rx_0 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx0);
-    let mpsc_rx_1 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx1);
-    let received_person_0 = mpsc_rx_0.recv().unwrap();
-    let received_person_1 = mpsc_rx_1.recv().unwrap();
-    assert_eq!(received_person_0, person);
-    assert_eq!(received_person_1, person);
-}
-
-#[test]
-fn router_drops_callbacks_on_sender_shutdown() {
-    struct Dropper {
-        sender: Sender<i32>,
-    }
+    let mut failing_tx = ReconnectingSender::new(name.clone(), ReconnectPolicy::Error);
+    assert!(failing_tx.send(person.clone()).is_err());
 
-    impl Drop for Dropper {
-        fn drop(&mut self) {
-            self.sender.send(42).unwrap()
-        }
-    }
+    // Nobody is listening yet, so these get queued up.
+    let mut tx = ReconnectingSender::new(name.clone(), ReconnectPolicy::Buffer(2));
+    tx.send(person.clone()).unwrap();
+    tx.send(person.clone()).unwrap();
+    assert!(tx.send(person.clone()).is_err());
 
-    let (tx0, rx0) = ipc::channel::<()>().unwrap();
-    let (drop_tx, drop_rx) = mpsc::channel();
-    let dropper = Dropper {
-        sender: drop_tx,
-    };
+    let server = IpcServer::with_name(&name).unwrap();
+    let thread = thread::spawn(move || {
+        tx.flush().unwrap();
+        tx
+    });
+    let (rx, received_person): (IpcReceiver<Person>, Person) = server.accept().unwrap();
+    assert_eq!(received_person, person);
+    assert_eq!(rx.recv().unwrap(), person);
 
-    ROUTER.add_route(rx0.to_opaque(), Box::new(move |_| drop(&dropper)));
-    drop(tx0);
-    assert_eq!(drop_rx.recv(), Ok(42));
+    let mut tx = thread.join().unwrap();
+    tx.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
 }
 
 #[test]
-fn router_drops_callbacks_on_cloned_sender_shutdown() {
-    struct Dropper {
-        sender: Sender<i32>,
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn reconnecting_sender_failed_message() {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::ser::Error as SerializerError;
+
+    /// Fails to serialize if it's true.
+    struct Flaky(bool);
+
+    impl Serialize for Flaky {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+            if self.0 {
+                return Err(S::Error::custom("flaky"))
+            }
+            self.0.serialize(serializer)
+        }
     }
 
-    impl Drop for Dropper {
-        fn drop(&mut self) {
-            self.sender.send(42).unwrap()
+    impl Deserialize for Flaky {
+        fn deserialize<D>(deserializer: &mut D) -> Result<Self,D::Error> where D: Deserializer {
+            Ok(Flaky(try!(Deserialize::deserialize(deserializer))))
         }
     }
 
-    let (tx0, rx0) = ipc::channel::<()>().unwrap();
-    let (drop_tx, drop_rx) = mpsc::channel();
-    let dropper = Dropper {
-        sender: drop_tx,
-    };
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
+    let mut tx = ReconnectingSender::new(name.clone(), ReconnectPolicy::Buffer(4));
+    tx.send(Flaky(true)).unwrap();
+    tx.send(Flaky(false)).unwrap();
 
-    ROUTER.add_route(rx0.to_opaque(), Box::new(move |_| drop(&dropper)));
-    let txs = vec![tx0.clone(), tx0.clone(), tx0.clone()];
-    drop(txs);
-    drop(tx0);
-    assert_eq!(drop_rx.recv(), Ok(42));
+    // Only the message that fails is dropped, and the ones after it get through.
+    let server = IpcServer::with_name(&name).unwrap();
+    let thread = thread::spawn(move || {
+        assert!(tx.send(Flaky(false)).is_err());
+        tx.flush().unwrap();
+    });
+    let (rx, first): (IpcReceiver<Flaky>, Flaky) = server.accept().unwrap();
+    assert!(!first.0);
+    assert!(!rx.recv().unwrap().0);
+    thread.join().unwrap();
 }
 
 #[test]
-fn router_big_data() {
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn connect_timeout() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let people: Vec<_> = iter::repeat(person).take(64 * 1024).collect();
-    let (tx, rx) = ipc::channel().unwrap();
-    let people_for_subthread = people.clone();
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
+    let error = IpcSender::<Person>::connect_timeout(name.clone(), Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+    let server_name = name.clone();
     let thread = thread::spawn(move || {
-        tx.send(people_for_subthread).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let server = IpcOneShotServer::<Person>::with_name(&server_name).unwrap();
+        let (_, received_person) = server.accept().unwrap();
+        received_person
     });
-
-    let (callback_fired_sender, callback_fired_receiver) = mpsc::channel::<Vec<Person>>();
-    ROUTER.add_route(rx.to_opaque(), Box::new(move |people| {
-        callback_fired_sender.send(people.to().unwrap()).unwrap()
-    }));
-    let received_people = callback_fired_receiver.recv().unwrap();
-    assert_eq!(received_people, people);
-    thread.join().unwrap();
+    let tx = IpcSender::connect_timeout(name, Duration::from_secs(10)).unwrap();
+    tx.send(person.clone()).unwrap();
+    assert_eq!(thread.join().unwrap(), person);
 }
 
 #[test]
-fn shared_memory() {
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn connect_with_retry() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let person_and_shared_memory = PersonAndSharedMemory {
-        person: person,
-        shared_memory: IpcSharedMemory::from_byte(0xba, 1024 * 1024),
+    let name = format!("org.rust-lang.ipc-channel.test.{}", Uuid::new_v4());
+    let policy = RetryPolicy {
+        max_attempts: Some(3),
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(20),
+        multiplier: 2,
     };
-    let (tx, rx) = ipc::channel().unwrap();
-    tx.send(person_and_shared_memory.clone()).unwrap();
-    let received_person_and_shared_memory = rx.recv().unwrap();
-    assert_eq!(received_person_and_shared_memory, person_and_shared_memory);
-    assert!(person_and_shared_memory.shared_memory.iter().all(|byte| *byte == 0xba));
-    assert!(received_person_and_shared_memory.shared_memory.iter().all(|byte| *byte == 0xba));
+    let error = IpcSender::<Person>::connect_with_retry(name.clone(), policy).unwrap_err();
+    assert!(error.kind() == ErrorKind::NotFound || error.kind() == ErrorKind::ConnectionRefused);
+
+    let server_name = name.clone();
+    let thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        let server = IpcOneShotServer::<Person>::with_name(&server_name).unwrap();
+        let (_, received_person) = server.accept().unwrap();
+        received_person
+    });
+    let tx = IpcSender::connect_with_retry(name, RetryPolicy::default()).unwrap();
+    tx.send(person.clone()).unwrap();
+    assert_eq!(thread.join().unwrap(), person);
 }
 
 #[test]
-fn opaque_sender() {
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn accept_timeout() {
+    let (server, _) = IpcOneShotServer::<Person>::new().unwrap();
+    match server.accept_timeout(Duration::from_millis(100)) {
+        Err(ipc::Error::TimedOut) => {}
+        _ => panic!("accepted a client that never connected"),
+    }
+
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (tx, rx) = ipc::channel().unwrap();
-    let opaque_tx = tx.to_opaque();
-    let tx: IpcSender<Person> = opaque_tx.to();
-    tx.send(person.clone()).unwrap();
-    let received_person = rx.recv().unwrap();
-    assert_eq!(person, received_person);
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let person_for_thread = person.clone();
+    let thread = thread::spawn(move || {
+        let tx = IpcSender::connect(name).unwrap();
+        tx.send(person_for_thread).unwrap();
+    });
+    let (_, received_person): (_, Person) =
+        server.accept_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(received_person, person);
+    thread.join().unwrap();
 }
 
 #[test]
-fn embedded_opaque_senders() {
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn inherited_channel() {
+    use std::env;
+    use std::mem;
+
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (sub_tx, sub_rx) = ipc::channel::<Person>().unwrap();
-    let person_and_sender = PersonAndOpaqueSender {
-        person: person.clone(),
-        sender: sub_tx.to_opaque(),
-    };
-    let (super_tx, super_rx) = ipc::channel().unwrap();
-    super_tx.send(person_and_sender).unwrap();
-    let received_person_and_sender = super_rx.recv().unwrap();
-    assert_eq!(received_person_and_sender.person, person);
-    received_person_and_sender.sender.to::<Person>().send(person.clone()).unwrap();
-    let received_person = sub_rx.recv().unwrap();
+    let (tx, rx) = ipc::channel().unwrap();
+    let tx_token = tx.inheritable_token().unwrap();
+    let rx_token = rx.inheritable_token().unwrap();
+    // Pretend that we are a child process that inherited both ends.
+    mem::forget(tx);
+    mem::forget(rx);
+    env::set_var("IPC_CHANNEL_TEST_INHERITED_RECEIVER", rx_token);
+    let tx = IpcSender::from_inherited_token(&tx_token).unwrap();
+    let rx = IpcReceiver::from_inherited_env("IPC_CHANNEL_TEST_INHERITED_RECEIVER").unwrap();
+    tx.send(person.clone()).unwrap();
+    let received_person: Person = rx.recv().unwrap();
     assert_eq!(received_person, person);
 }
 
 #[test]
-fn try_recv() {
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn raw_fds() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
+    let mut fds = [0; 2];
+    unsafe {
+        assert_eq!(libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, &mut fds[0]), 0);
+    }
+    let tx = unsafe { IpcSender::from_raw_fd(fds[0]) }.unwrap();
+    let rx = unsafe { IpcReceiver::from_raw_fd(fds[1]) }.unwrap();
+    tx.send(person.clone()).unwrap();
+    let received_person: Person = rx.recv().unwrap();
+    assert_eq!(received_person, person);
+
+    // And back again.
     let (tx, rx) = ipc::channel().unwrap();
-    assert!(rx.try_recv().is_err());
+    let tx = unsafe { IpcSender::from_raw_fd(tx.into_raw_fd().unwrap()) }.unwrap();
+    let rx = unsafe { IpcReceiver::from_raw_fd(rx.into_raw_fd().unwrap()) }.unwrap();
     tx.send(person.clone()).unwrap();
-    let received_person = rx.try_recv().unwrap();
-    assert_eq!(person, received_person);
-    assert!(rx.try_recv().is_err());
+    let received_person: Person = rx.recv().unwrap();
+    assert_eq!(received_person, person);
+
+    // Anything but a socket is turned away.
+    let mut pipe = [0; 2];
+    unsafe {
+        assert_eq!(libc::pipe(&mut pipe[0]), 0);
+        assert!(IpcReceiver::<Person>::from_raw_fd(pipe[0]).is_err());
+        libc::close(pipe[0]);
+        libc::close(pipe[1]);
+    }
 }
 
 #[test]
-fn multiple_paths_to_a_sender() {
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn channel_from_process() {
     let person = Person {
         name: "Patrick Walton".to_owned(),
         age: 29,
     };
-    let (sub_tx, sub_rx) = ipc::channel().unwrap();
-    let person_and_sender = Arc::new(PersonAndSender {
-        person: person.clone(),
-        sender: sub_tx,
-    });
-    let send_data = vec![
-        person_and_sender.clone(),
-        person_and_sender.clone(),
-        person_and_sender.clone()
-    ];
+    let (tx, rx) = ipc::channel().unwrap();
+    let token = tx.inheritable_token().unwrap();
+    let pid = unsafe { libc::getpid() } as u32;
+    let attached_tx = match IpcSender::from_process(pid, &token) {
+        Ok(attached_tx) => attached_tx,
+        // Kernels before 5.6 don't have `pidfd_getfd()`.
+        Err(ipc::Error::Unsupported) => return,
+        Err(error) => panic!("attaching failed: {}", error),
+    };
+    drop(tx);
+    attached_tx.send(person.clone()).unwrap();
+    let received_person: Person = rx.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "android",
+              target_os = "ios")))]
+fn process_spawn() {
+    use std::env;
+    use std::process::Command;
+
+    // Runs `process_spawn_child()` below in a fresh copy of the test binary.
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.arg("process_spawn_child");
+    let (mut child, _, (tx, greeting)): (_, IpcReceiver<(IpcSender<String>, String)>, _) =
+        process::spawn(&mut command, Duration::from_secs(30)).unwrap();
+    assert_eq!(greeting, "hello");
+    tx.send("goodbye".to_owned()).unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "android",
+              target_os = "ios")))]
+fn process_spawn_timeout() {
+    use std::process::Command;
+
+    let mut command = Command::new("sleep");
+    command.arg("30");
+    let result: Result<(_, IpcReceiver<String>, _),_> =
+        process::spawn(&mut command, Duration::from_millis(100));
+    match result {
+        Err(error) => assert_eq!(error.kind(), ErrorKind::TimedOut),
+        Ok(_) => panic!("spawned a child that never connected"),
+    }
+}
+
+#[test]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "android",
+              target_os = "ios")))]
+fn process_spawn_child() {
+    let (tx, rx) = ipc::channel().unwrap();
+    // Unless we were started by `process_spawn()`, there is nothing to do.
+    if process::connect_to_parent((tx, "hello".to_owned())).is_err() {
+        return
+    }
+    let farewell: String = rx.recv().unwrap();
+    assert_eq!(farewell, "goodbye");
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn server_socket_directory() {
+    use std::fs;
+    use std::path::Path;
+
+    let directory = format!("/tmp/ipc-channel-test.{}", Uuid::new_v4());
+    fs::create_dir(&directory).unwrap();
+    // The directory is process-wide, so change it in a child, not to upset other tests.
+    let child_pid = unsafe { fork(|| {
+        ipc::set_server_socket_directory(&directory);
+        let _server = IpcServer::<Person>::with_name("server").unwrap();
+        libc::exit(0);
+    })};
+    child_pid.wait();
+    assert!(Path::new(&directory).join("server").exists());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn accept_with_credentials() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let person_for_thread = person.clone();
+    thread::spawn(move || {
+        let tx = IpcSender::connect(name).unwrap();
+        tx.send(person_for_thread).unwrap();
+    });
+    let (_, received_person, credentials): (_, Person, _) =
+        server.accept_with_credentials().unwrap();
+    assert_eq!(received_person, person);
+    assert_eq!(credentials.pid, unsafe { libc::getpid() } as u32);
+    assert_eq!(credentials.uid, unsafe { libc::getuid() } as u32);
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn receiver_peer_credentials() {
+    use std::fs::File;
+    use std::io::Read;
+
+    let (server, name) = IpcOneShotServer::<()>::new().unwrap();
+    let child_pid = unsafe { fork(|| {
+        let tx = IpcSender::connect(name).unwrap();
+        tx.send(()).unwrap();
+        libc::exit(0);
+    })};
+    let (rx, _) = server.accept().unwrap();
+    child_pid.wait();
+    let credentials = rx.peer_credentials().unwrap();
+    assert_eq!(credentials.pid, child_pid as u32);
+    assert_eq!(credentials.uid, unsafe { libc::getuid() } as u32);
+    assert_eq!(credentials.gid, unsafe { libc::getgid() } as u32);
+    // We share our label with the child, if there is one.
+    let mut own_label = String::new();
+    if let Ok(mut file) = File::open("/proc/self/attr/current") {
+        file.read_to_string(&mut own_label).unwrap();
+    }
+    if let Some(label) = credentials.security_label {
+        assert_eq!(label, own_label.trim_right_matches(|c: char| c == '\0' || c == '\n'));
+    }
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn buffer_sizes() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let default_size = tx.send_buffer_size().unwrap();
+    tx.set_send_buffer_size(default_size / 4).unwrap();
+    rx.set_recv_buffer_size(default_size / 4).unwrap();
+    // The kernel doubles what we ask for, for its own bookkeeping.
+    assert!(tx.send_buffer_size().unwrap() < default_size);
+    assert!(rx.recv_buffer_size().unwrap() < default_size);
+
+    // Messages bigger than the buffers still get through, in more pieces.
+    let data: Vec<u8> = (0..default_size).map(|i| (i % 251) as u8).collect();
+    let thread_data = data.clone();
+    let thread = thread::spawn(move || tx.send(thread_data).unwrap());
+    assert_eq!(rx.recv().unwrap(), data);
+    thread.join().unwrap();
+
+    let (tx, rx) = ChannelBuilder::new().send_buffer_size(default_size / 4)
+                                        .recv_buffer_size(default_size / 4)
+                                        .build::<()>()
+                                        .unwrap();
+    assert!(tx.send_buffer_size().unwrap() < default_size);
+    assert!(rx.recv_buffer_size().unwrap() < default_size);
+}
+
+#[test]
+fn stats() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let (sub_tx, _sub_rx) = ipc::channel::<()>().unwrap();
+    let tx2 = tx.clone();
+    tx.send((vec![1u8; 100], None)).unwrap();
+    tx2.send((vec![2u8; 100], Some(sub_tx))).unwrap();
+
+    let sent = tx.stats();
+    assert_eq!(sent, tx2.stats());
+    assert_eq!(sent.messages, 2);
+    assert_eq!(sent.handles, 1);
+    assert!(sent.bytes >= 200);
+    if cfg!(all(target_os = "linux", not(feature = "force-inprocess"))) {
+        assert!(rx.stats().queued_bytes.unwrap() > 0);
+    }
+
+    let _: (Vec<u8>, Option<IpcSender<()>>) = rx.recv().unwrap();
+    let _: (Vec<u8>, Option<IpcSender<()>>) = rx.recv().unwrap();
+    let received = rx.stats();
+    assert_eq!(received.messages, 2);
+    assert_eq!(received.handles, 1);
+    assert_eq!(received.bytes, sent.bytes);
+    if cfg!(all(target_os = "linux", not(feature = "force-inprocess"))) {
+        assert_eq!(received.queued_bytes, Some(0));
+    }
+    assert_eq!(received.latency, None);
+}
+
+#[test]
+fn latency_histogram() {
+    let (tx, rx) = ChannelBuilder::new().latency_histogram().build().unwrap();
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    tx.send(person.clone()).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    tx.clone().send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+    assert_eq!(rx.recv().unwrap(), person);
+
+    let latency = rx.stats().latency.unwrap();
+    assert_eq!(latency.messages(), 2);
+    assert!(latency.total >= Duration::from_millis(20));
+    assert!(latency.percentile(100.0).unwrap() >= Duration::from_millis(20));
+    assert!(latency.mean().unwrap() >= Duration::from_millis(10));
+    assert_eq!(rx.stats().bytes, tx.stats().bytes);
+    assert_eq!(tx.stats().latency, None);
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn server_mode() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = format!("/tmp/ipc-channel-test.{}", Uuid::new_v4());
+    let _server = IpcServer::<Person>::with_name_and_mode(&name, 0o600).unwrap();
+    let mode = fs::metadata(&name).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn authentication_token() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (server, name) = IpcServer::new().unwrap();
+    let person_for_thread = person.clone();
+    let thread = thread::spawn(move || {
+        // A client that doesn't send a handshake at all is turned away too.
+        IpcSender::connect(name.clone()).unwrap().send(29u32).unwrap();
+        let error = IpcSender::<Person>::connect_with_token(name.clone(), "guess").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        let (tx, session) = IpcSender::connect_with_token(name, "secret").unwrap();
+        tx.send(person_for_thread).unwrap();
+        session
+    });
+    let (rx, session): (IpcReceiver<Person>, _) = server.accept_with_token("secret").unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+    assert_eq!(thread.join().unwrap(), session);
+}
+
+#[test]
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn authenticated_session() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (server, name) = IpcServer::new().unwrap();
+    let thread = thread::spawn(move || {
+        IpcSender::<Person>::connect_with_token(name, "secret").unwrap()
+    });
+    let (rx, session) = server.accept_with_token("secret").unwrap();
+    let (tx, client_session) = thread.join().unwrap();
+    let (client_sending, client_receiving) =
+        MessageAuthenticator::for_session("secret", &client_session, SessionRole::Client);
+    let (_, server_receiving) =
+        MessageAuthenticator::for_session("secret", &session, SessionRole::Server);
+    tx.send_authenticated(person.clone(), &client_sending).unwrap();
+    assert_eq!(rx.recv_authenticated(&server_receiving).unwrap(), person);
+
+    // A message can't be reflected back to its sender, even at the same position in the stream.
+    let (reflecting_tx, reflecting_rx) = ipc::channel().unwrap();
+    let (sending, _) =
+        MessageAuthenticator::for_session("secret", &client_session, SessionRole::Client);
+    reflecting_tx.send_authenticated(person.clone(), &sending).unwrap();
+    assert!(reflecting_rx.recv_authenticated(&client_receiving).is_err());
+}
+
+#[test]
+fn authenticated_messages() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    let sending_authenticator = MessageAuthenticator::new(b"secret");
+    let receiving_authenticator = MessageAuthenticator::new(b"secret");
+    for _ in 0..2 {
+        tx.send_authenticated(person.clone(), &sending_authenticator).unwrap();
+        assert_eq!(rx.recv_authenticated(&receiving_authenticator).unwrap(), person);
+    }
+
+    // Messages with the wrong key, or none at all, are caught.
+    tx.send_authenticated(person.clone(), &MessageAuthenticator::new(b"guess")).unwrap();
+    assert!(rx.recv_authenticated(&receiving_authenticator).is_err());
+    let (tx, rx) = ipc::channel().unwrap();
+    let receiving_authenticator = MessageAuthenticator::new(b"secret");
+    tx.send(person.clone()).unwrap();
+    assert!(rx.recv_authenticated(&receiving_authenticator).is_err());
+
+    // After that, the receiving authenticator has lost its place, so even a genuine message
+    // fails, and says why.
+    tx.send_authenticated(person.clone(), &MessageAuthenticator::new(b"secret")).unwrap();
+    match rx.recv_authenticated(&receiving_authenticator) {
+        Err(ipc::Error::Io(error)) => {
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert!(error.get_ref().unwrap().is::<OutOfStep>());
+        }
+        _ => panic!("checked a message after losing its place in the stream"),
+    }
+}
+
+#[test]
+fn refuse_handles() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    let rx = rx.refuse_handles();
+    tx.send(PersonAndSender {
+        person: person.clone(),
+        sender: ipc::channel().unwrap().0,
+    }).unwrap();
+    match rx.recv() {
+        Err(ipc::Error::Io(error)) => assert_eq!(error.kind(), ErrorKind::PermissionDenied),
+        _ => panic!("accepted a message carrying a channel"),
+    }
+
+    let (tx, rx) = ipc::channel().unwrap();
+    let rx = rx.refuse_handles();
+    tx.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+}
+
+#[test]
+fn limit_handles() {
+    let (tx, rx) = ipc::channel::<Vec<IpcSender<()>>>().unwrap();
+    let rx = rx.limit_handles(HandleLimit {
+        per_message: Some(2),
+        per_connection: Some(3),
+    });
+    let handle_limit_exceeded = |senders: Vec<IpcSender<()>>| {
+        tx.send(senders).unwrap();
+        match rx.recv() {
+            Err(ipc::Error::Io(error)) => {
+                assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+                *error.get_ref().unwrap().downcast_ref::<HandleLimitExceeded>().unwrap()
+            }
+            Ok(_) => panic!("peer went over its handle limit"),
+            Err(error) => panic!("unexpected error: {:?}", error),
+        }
+    };
+
+    let senders = (0..3).map(|_| ipc::channel().unwrap().0).collect();
+    assert_eq!(handle_limit_exceeded(senders),
+               HandleLimitExceeded::PerMessage { count: 3, limit: 2 });
+
+    tx.send((0..2).map(|_| ipc::channel().unwrap().0).collect()).unwrap();
+    assert_eq!(rx.recv().unwrap().len(), 2);
+    let senders = (0..2).map(|_| ipc::channel().unwrap().0).collect();
+    assert_eq!(handle_limit_exceeded(senders),
+               HandleLimitExceeded::PerConnection { count: 4, limit: 3 });
+}
+
+#[test]
+fn audit_hook() {
+    // Other tests run concurrently, so pick out our messages by their unusual size.
+    const SIZE: usize = 12345;
+    let audited = Arc::new(Mutex::new(vec![]));
+    let audited_clone = audited.clone();
+    ipc::set_audit_hook(move |metadata: &MessageMetadata| {
+        if metadata.size == SIZE {
+            audited_clone.lock().unwrap().push(*metadata)
+        }
+    });
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    tx.send(&[0; SIZE]).unwrap();
+    rx.recv().unwrap();
+    ipc::clear_audit_hook();
+
+    {
+        let audited = audited.lock().unwrap();
+        assert_eq!(audited.len(), 2);
+        assert_eq!(audited[0].direction, MessageDirection::Sent);
+        assert_eq!(audited[1].direction, MessageDirection::Received);
+        for metadata in audited.iter() {
+            assert_eq!(metadata.handles, 0);
+            if cfg!(all(target_os = "linux", not(feature = "force-inprocess"))) {
+                assert_eq!(metadata.peer_pid, Some(unsafe { libc::getpid() } as u32));
+            }
+        }
+    }
+
+    // A hook can remove itself without deadlocking.
+    let audited_clone = audited.clone();
+    ipc::set_audit_hook(move |metadata: &MessageMetadata| {
+        if metadata.size == SIZE {
+            audited_clone.lock().unwrap().push(*metadata);
+            ipc::clear_audit_hook()
+        }
+    });
+    tx.send(&[0; SIZE]).unwrap();
+    rx.recv().unwrap();
+    assert_eq!(audited.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn capture_and_replay() {
+    use ipc::CaptureReader;
+    use std::env;
+    use std::fs;
+
+    // Other tests run concurrently, so pick out our messages by their unusual size; serialized, a
+    // `Vec<u8>` is its length followed by its bytes.
+    const SIZE: usize = 23456;
+    let data = vec![7; SIZE];
+    let path = env::temp_dir().join(format!("ipc-channel-test.{}", Uuid::new_v4()));
+    ipc::start_capture(&path).unwrap();
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(data.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), data);
+    ipc::stop_capture().unwrap();
+
+    let frames: Vec<_> = CaptureReader::open(&path).unwrap()
+                                                   .map(|frame| frame.unwrap())
+                                                   .filter(|frame| frame.data.len() == SIZE + 8)
+                                                   .collect();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].direction, MessageDirection::Sent);
+    assert_eq!(frames[1].direction, MessageDirection::Received);
+    assert!(frames[0].timestamp <= frames[1].timestamp);
+    assert_eq!(frames[0].data, frames[1].data);
+
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    ipc::replay_capture(frames.into_iter().skip(1), &tx.to_opaque()).unwrap();
+    assert_eq!(rx.recv().unwrap(), data);
+}
+
+#[test]
+fn scheduled_channel_replay() {
+    // Four threads race to send on one channel; the replay must see the order the recording did.
+    fn run(scheduler: &Scheduler) -> Vec<u32> {
+        let (tx, rx) = ipc::scheduled_channel(scheduler).unwrap();
+        let threads: Vec<_> = (0..4).map(|index| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for value in 0..25 {
+                    tx.send(index * 100 + value).unwrap()
+                }
+            })
+        }).collect();
+        drop(tx);
+        let received = (0..100).map(|_| rx.recv().unwrap()).collect();
+        assert!(rx.recv().is_err());
+        for thread in threads {
+            thread.join().unwrap()
+        }
+        received
+    }
+
+    let recorder = Scheduler::record();
+    let recorded = run(&recorder);
+    let schedule = recorder.schedule();
+    assert_eq!(schedule.deliveries.len(), 101);
+    assert_eq!(schedule.deliveries[100].message, None);
+
+    let replayer = Scheduler::replay(schedule);
+    assert_eq!(run(&replayer), recorded);
+    assert!(replayer.schedule().deliveries.is_empty());
+}
+
+#[test]
+fn fault_injection() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let tx = tx.inject_faults(Faults::default());
+    tx.schedule_faults(vec![Some(Fault::Drop),
+                            Some(Fault::Duplicate),
+                            Some(Fault::Reorder),
+                            None,
+                            Some(Fault::Truncate(1))]);
+    for value in 0..5u32 {
+        tx.send(value).unwrap();
+    }
+    assert_eq!(rx.recv().unwrap(), 1);
+    assert_eq!(rx.recv().unwrap(), 1);
+    assert_eq!(rx.recv().unwrap(), 3);
+    assert_eq!(rx.recv().unwrap(), 2);
+    assert!(rx.recv().is_err());
+
+    // Random faults, repeatably.
+    let (tx, rx) = ipc::channel().unwrap();
+    let tx = tx.inject_faults(Faults {
+        drop: 1.0,
+        seed: Some(42),
+        ..Faults::default()
+    });
+    tx.send(5u32).unwrap();
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn registry() {
+    // Other tests run concurrently, so pick out our endpoints by their name.
+    fn ours() -> Vec<EndpointInfo> {
+        ipc::registered_endpoints().into_iter().filter(|endpoint| {
+            endpoint.stats.label == Some("registry-test".to_owned())
+        }).collect()
+    }
+
+    ipc::enable_registry();
+    let (tx, rx) = ipc::channel_named::<u32>("registry-test").unwrap();
+    ipc::disable_registry();
+    tx.send(1).unwrap();
+    rx.recv().unwrap();
+
+    let endpoints = ours();
+    assert_eq!(endpoints.len(), 2);
+    assert_eq!(endpoints[0].kind, EndpointKind::Sender);
+    assert_eq!(endpoints[1].kind, EndpointKind::Receiver);
+    for endpoint in &endpoints {
+        assert_eq!(endpoint.stats.messages, 1);
+    }
+
+    drop(tx);
+    assert_eq!(ours().len(), 1);
+    drop(rx);
+    assert!(ours().is_empty());
+}
+
+#[test]
+fn shared_memory_promotion() {
+    // Well over the default threshold.
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|byte| byte as u8).collect();
+    let (tx, rx) = ipc::channel().unwrap();
+    let mut receiver_set = IpcReceiverSet::new().unwrap();
+    receiver_set.add(rx).unwrap();
+    tx.send(data.clone()).unwrap();
+    let (_, message) = receiver_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    // The payload is read out of the region it was moved into: the bytes, after their length.
+    assert_eq!(&message.data()[8..], &data[..]);
+    assert_eq!(message.os_ipc_shared_memory_regions().len(), 1);
+    assert_eq!(message.to::<Vec<u8>>().unwrap(), data);
+
+    // So the router counts its bytes too.
+    let router = RouterProxy::new();
+    let (tx, rx) = ipc::channel().unwrap();
+    let mpsc_receiver = router.route_ipc_receiver_to_new_mpsc_receiver::<Vec<u8>>(rx);
+    tx.send(data.clone()).unwrap();
+    assert_eq!(mpsc_receiver.recv().unwrap(), data);
+    assert_eq!(router.stats().bytes, data.len() + 8);
+}
+
+#[test]
+fn cork() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    {
+        let mut batch = tx.cork();
+        for value in 0..3 {
+            batch.send((value, None)).unwrap();
+        }
+        batch.send((3, Some(sub_tx))).unwrap();
+        assert!(rx.try_recv().is_err());
+        batch.uncork().unwrap();
+    }
+    for value in 0..3 {
+        let (received_value, sender) = rx.recv().unwrap();
+        assert_eq!(received_value, value);
+        assert!(sender.is_none());
+    }
+    let (received_value, sender): (i32, Option<IpcSender<Person>>) = rx.recv().unwrap();
+    assert_eq!(received_value, 3);
+    sender.unwrap().send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+
+    // Dropping the guard sends the queued messages too.
+    tx.cork().send((4, None)).unwrap();
+    assert_eq!(rx.recv().unwrap().0, 4);
+}
+
+#[test]
+fn send_vectored() {
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    tx.send_vectored(&[&b"Hello, "[..], &b""[..], &b"world!"[..]]).unwrap();
+    assert_eq!(rx.recv().unwrap(), b"Hello, world!".to_vec());
+}
+
+#[test]
+fn send_with_payload() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let payload: Vec<u8> = (0..100000).map(|byte| byte as u8).collect();
+    let (tx, rx) = ipc::channel::<(Person, Vec<u8>)>().unwrap();
+    tx.send_with_payload(&person, &payload).unwrap();
+    assert_eq!(rx.recv().unwrap(), (person.clone(), payload));
+
+    // Channels in the header come along too.
+    let (super_tx, super_rx) = ipc::channel::<(IpcSender<Person>, Vec<u8>)>().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    super_tx.send_with_payload(&sub_tx, b"payload").unwrap();
+    let (sub_tx, payload) = super_rx.recv().unwrap();
+    assert_eq!(payload, b"payload".to_vec());
+    sub_tx.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+}
+
+#[test]
+fn rate_limit_refuse() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let rx = rx.rate_limit(RateLimit {
+        max_messages: Some(2),
+        max_bytes: None,
+        interval: Duration::from_secs(60),
+        policy: RateLimitPolicy::Refuse,
+    });
+    for value in 0..3 {
+        tx.send(value).unwrap();
+    }
+    assert_eq!(rx.recv().unwrap(), 0);
+    assert_eq!(rx.recv().unwrap(), 1);
+    match rx.recv() {
+        Err(ipc::Error::WouldBlock) => {}
+        _ => panic!("peer went over its rate limit"),
+    }
+}
+
+#[test]
+fn rate_limit_throttle() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let rx = rx.rate_limit(RateLimit {
+        max_messages: None,
+        max_bytes: Some(1),
+        interval: Duration::from_millis(100),
+        policy: RateLimitPolicy::Throttle,
+    });
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    let start = Instant::now();
+    assert_eq!(rx.recv().unwrap(), 1);
+    match rx.try_recv() {
+        Err(ipc::Error::WouldBlock) => {}
+        _ => panic!("peer wasn't throttled"),
+    }
+    assert_eq!(rx.recv().unwrap(), 2);
+    assert!(Instant::now().duration_since(start) >= Duration::from_millis(50));
+}
+
+#[test]
+#[cfg(feature = "bench")]
+fn bench_helpers() {
+    bench::round_trip(&bench::control_message(1), 10).unwrap();
+    let error = bench::round_trip(&bench::control_message(1), 0).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(bench::throughput(4096, 10).unwrap() > 0.0);
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Hello {
+    protocol: String,
+    version: u32,
+}
+
+#[test]
+// Skipped on Windows like the platform server tests, and on iOS, where apps can't register
+// server names.
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn handshake() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (server, name) = IpcServer::new().unwrap();
+    let person_for_thread = person.clone();
+    let thread = thread::spawn(move || {
+        let hello = Hello {
+            protocol: "person".to_owned(),
+            version: 1,
+        };
+        let error = IpcSender::<Person>::connect_with_handshake(name.clone(), hello).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+        let hello = Hello {
+            protocol: "person".to_owned(),
+            version: 2,
+        };
+        let tx = IpcSender::connect_with_handshake(name, hello).unwrap();
+        tx.send(person_for_thread).unwrap();
+    });
+
+    let (rx, hello): (IpcReceiver<Person>, Hello) = server.accept_with_handshake(|hello: &Hello| {
+        if hello.version == 2 {
+            Ok(())
+        } else {
+            Err(format!("unsupported version {}", hello.version))
+        }
+    }).unwrap();
+    assert_eq!(hello.version, 2);
+    assert_eq!(rx.recv().unwrap(), person);
+    thread.join().unwrap();
+}
+
+#[test]
+fn router_simple() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person.clone()).unwrap();
+
+    let (callback_fired_sender, callback_fired_receiver) = mpsc::channel::<Person>();
+    ROUTER.add_route(rx.to_opaque(), Box::new(move |person| {
+        callback_fired_sender.send(person.to().unwrap()).unwrap()
+    }));
+    let received_person = callback_fired_receiver.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn router_routing_to_new_mpsc_receiver() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person.clone()).unwrap();
+
+    let mpsc_receiver = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx);
+    let received_person = mpsc_receiver.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn router_multiplexing() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx0, rx0) = ipc::channel().unwrap();
+    tx0.send(person.clone()).unwrap();
+    let (tx1, rx1) = ipc::channel().unwrap();
+    tx1.send(person.clone()).unwrap();
+
+    let mpsc_rx_0 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx0);
+    let mpsc_rx_1 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx1);
+    let received_person_0 = mpsc_rx_0.recv().unwrap();
+    let received_person_1 = mpsc_rx_1.recv().unwrap();
+    assert_eq!(received_person_0, person);
+    assert_eq!(received_person_1, person);
+}
+
+#[test]
+fn router_multithreaded_multiplexing() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+
+    let person_for_thread = person.clone();
+    let (tx0, rx0) = ipc::channel().unwrap();
+    thread::spawn(move || tx0.send(person_for_thread).unwrap());
+    let person_for_thread = person.clone();
+    let (tx1, rx1) = ipc::channel().unwrap();
+    thread::spawn(move || tx1.send(person_for_thread).unwrap());
+
+    let mpsc_rx_0 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx0);
+    let mpsc_rx_1 = ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx1);
+    let received_person_0 = mpsc_rx_0.recv().unwrap();
+    let received_person_1 = mpsc_rx_1.recv().unwrap();
+    assert_eq!(received_person_0, person);
+    assert_eq!(received_person_1, person);
+}
+
+#[test]
+fn router_concurrent_routes() {
+    let threads: Vec<_> = (0..8).map(|thread_index| {
+        thread::spawn(move || {
+            let routes: Vec<_> = (0..25).map(|route_index| {
+                let (tx, rx) = ipc::channel().unwrap();
+                tx.send((thread_index, route_index)).unwrap();
+                ROUTER.route_ipc_receiver_to_new_mpsc_receiver(rx)
+            }).collect();
+            for (route_index, mpsc_rx) in routes.into_iter().enumerate() {
+                assert_eq!(mpsc_rx.recv().unwrap(), (thread_index, route_index));
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn router_config() {
+    let router = RouterProxy::with_config(RouterConfig {
+        name: Some("test router".to_owned()),
+        cpu_affinity: Some(vec![0]),
+        priority: Some(19),
+        dead_letters: None,
+    }).unwrap();
+    let (tx, rx) = ipc::channel::<()>().unwrap();
+    let (thread_name_sender, thread_name_receiver) = mpsc::channel();
+    router.add_route(rx.to_opaque(), Box::new(move |_| {
+        thread_name_sender.send(thread::current().name().map(|name| name.to_owned())).unwrap()
+    }));
+    tx.send(()).unwrap();
+    assert_eq!(thread_name_receiver.recv().unwrap(), Some("test router".to_owned()));
+
+    // The global router can only be configured before it starts.
+    let _ = &*ROUTER;
+    assert!(router::configure_router(RouterConfig::default()).is_err());
+}
+
+#[test]
+fn router_dead_letters() {
+    let (dead_letter_sender, dead_letter_receiver) = mpsc::channel();
+    let router = RouterProxy::with_config(RouterConfig {
+        dead_letters: Some(dead_letter_sender),
+        ..RouterConfig::default()
+    }).unwrap();
+
+    // A message that isn't a `Person`.
+    let (tx, rx) = ipc::channel::<Person>().unwrap();
+    let tx = tx.to_opaque().to::<u8>();
+    let mpsc_receiver = router.route_ipc_receiver_to_new_mpsc_receiver(rx);
+    tx.send(42).unwrap();
+    let dead_letter = dead_letter_receiver.recv().unwrap();
+    match dead_letter.reason {
+        DeadLetterReason::Deserialize(_) => {}
+        reason => panic!("unexpected reason: {:?}", reason),
+    }
+    assert_eq!(dead_letter.message.unwrap().to::<u8>().unwrap(), 42);
+    drop(mpsc_receiver);
+
+    // A message for a receiver that's gone.
+    let (tx, rx) = ipc::channel().unwrap();
+    drop(router.route_ipc_receiver_to_new_mpsc_receiver(rx));
+    tx.send(Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    }).unwrap();
+    let dead_letter = dead_letter_receiver.recv().unwrap();
+    match dead_letter.reason {
+        DeadLetterReason::ReceiverGone => {}
+        reason => panic!("unexpected reason: {:?}", reason),
+    }
+    assert!(dead_letter.message.is_none());
+
+    let stats = router.stats();
+    assert_eq!(stats.messages, 2);
+    assert_eq!(stats.dead_letters, 2);
+}
+
+#[test]
+fn router_drops_callbacks_on_sender_shutdown() {
+    struct Dropper {
+        sender: Sender<i32>,
+    }
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.sender.send(42).unwrap()
+        }
+    }
+
+    let (tx0, rx0) = ipc::channel::<()>().unwrap();
+    let (drop_tx, drop_rx) = mpsc::channel();
+    let dropper = Dropper {
+        sender: drop_tx,
+    };
+
+    ROUTER.add_route(rx0.to_opaque(), Box::new(move |_| drop(&dropper)));
+    drop(tx0);
+    assert_eq!(drop_rx.recv(), Ok(42));
+}
+
+#[test]
+fn router_drops_callbacks_on_cloned_sender_shutdown() {
+    struct Dropper {
+        sender: Sender<i32>,
+    }
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.sender.send(42).unwrap()
+        }
+    }
+
+    let (tx0, rx0) = ipc::channel::<()>().unwrap();
+    let (drop_tx, drop_rx) = mpsc::channel();
+    let dropper = Dropper {
+        sender: drop_tx,
+    };
+
+    ROUTER.add_route(rx0.to_opaque(), Box::new(move |_| drop(&dropper)));
+    let txs = vec![tx0.clone(), tx0.clone(), tx0.clone()];
+    drop(txs);
+    drop(tx0);
+    assert_eq!(drop_rx.recv(), Ok(42));
+}
+
+#[test]
+fn router_big_data() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let people: Vec<_> = iter::repeat(person).take(64 * 1024).collect();
+    let (tx, rx) = ipc::channel().unwrap();
+    let people_for_subthread = people.clone();
+    let thread = thread::spawn(move || {
+        tx.send(people_for_subthread).unwrap();
+    });
+
+    let (callback_fired_sender, callback_fired_receiver) = mpsc::channel::<Vec<Person>>();
+    ROUTER.add_route(rx.to_opaque(), Box::new(move |people| {
+        callback_fired_sender.send(people.to().unwrap()).unwrap()
+    }));
+    let received_people = callback_fired_receiver.recv().unwrap();
+    assert_eq!(received_people, people);
+    thread.join().unwrap();
+}
+
+#[test]
+fn shared_memory() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let person_and_shared_memory = PersonAndSharedMemory {
+        person: person,
+        shared_memory: IpcSharedMemory::from_byte(0xba, 1024 * 1024),
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    let received_person_and_shared_memory = rx.recv().unwrap();
+    assert_eq!(received_person_and_shared_memory, person_and_shared_memory);
+    assert!(person_and_shared_memory.shared_memory.iter().all(|byte| *byte == 0xba));
+    assert!(received_person_and_shared_memory.shared_memory.iter().all(|byte| *byte == 0xba));
+}
+
+#[test]
+fn tcp() {
+    let server = IpcTcpServer::bind("127.0.0.1:0").unwrap();
+    let tx = IpcTcpSender::connect(server.local_addr().unwrap()).unwrap();
+    let rx = server.accept().unwrap();
+
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let person_and_shared_memory = PersonAndSharedMemory {
+        person: person,
+        shared_memory: IpcSharedMemory::from_byte(0xba, 1024 * 1024),
+    };
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    tx.clone().send(person_and_shared_memory.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person_and_shared_memory);
+    assert_eq!(rx.recv().unwrap(), person_and_shared_memory);
+
+    // Channels can't cross the connection.
+    let tx = IpcTcpSender::connect(server.local_addr().unwrap()).unwrap();
+    let (sub_tx, _sub_rx) = ipc::channel::<()>().unwrap();
+    let error = tx.send(Some(sub_tx)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+#[cfg(not(any(windows, target_os = "android", target_os = "ios")))]
+fn stdio() {
+    use process::spawn_with_stdio;
+    use std::process::Command;
+
+    // `cat` sends everything straight back, which exercises the framing in both directions.
+    let (mut child, tx, rx) = spawn_with_stdio(&mut Command::new("cat")).unwrap();
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let person_and_shared_memory = PersonAndSharedMemory {
+        person: person,
+        shared_memory: IpcSharedMemory::from_byte(0xba, 1024 * 1024),
+    };
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    tx.clone().send(person_and_shared_memory.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person_and_shared_memory);
+    assert_eq!(rx.recv().unwrap(), person_and_shared_memory);
+
+    drop(tx);
+    child.wait().unwrap();
+}
+
+#[test]
+fn checksums() {
+    use ipc::{IpcStdioReceiver, IpcStdioSender};
+    use std::io::{self, Cursor, Write};
+
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> Result<usize,Error> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(),Error> {
+            Ok(())
+        }
+    }
+
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let tx = IpcStdioSender::new(SharedBuffer(buffer.clone())).with_checksums();
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    tx.send(person.clone()).unwrap();
+    tx.send(person.clone()).unwrap();
+    let frames = buffer.lock().unwrap().clone();
+
+    let rx = IpcStdioReceiver::new(Cursor::new(frames.clone())).with_checksums();
+    assert_eq!(rx.recv().unwrap(), person);
+    assert_eq!(rx.recv().unwrap(), person);
+
+    // Flip a bit in the first message. It's dropped, and the second still arrives.
+    let mut corrupted = frames;
+    let middle = corrupted.len() / 4;
+    corrupted[middle] ^= 1;
+    let rx = IpcStdioReceiver::<Person>::new(Cursor::new(corrupted)).with_checksums();
+    match rx.recv().unwrap_err() {
+        ipc::Error::Corrupted => {}
+        error => panic!("expected Corrupted, got {:?}", error),
+    }
+    assert_eq!(rx.recv().unwrap(), person);
+    assert_eq!(io::Error::from(ipc::Error::Corrupted).kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn metrics_samples() {
+    use metrics;
+
+    ipc::enable_registry();
+    let (tx, rx) = ipc::channel_named::<u32>("metrics-test").unwrap();
+    let tx2 = tx.clone();
+    ipc::disable_registry();
+    tx.send(1).unwrap();
+    tx2.send(2).unwrap();
+    rx.recv().unwrap();
+    rx.recv().unwrap();
+
+    // Other tests run concurrently, so pick out our channel by its name.
+    let samples: Vec<_> = metrics::channel_samples().into_iter().filter(|sample| {
+        sample.labels.contains(&("channel", "metrics-test".to_owned()))
+    }).collect();
+    let value = |name: &str, kind: &str| {
+        samples.iter()
+               .find(|sample| sample.name == name && sample.labels[0].1 == kind)
+               .unwrap()
+               .value
+    };
+    assert_eq!(value("ipc_channel_endpoints", "sender"), 2);
+    // The clone shares the counts of the sender it was cloned from.
+    assert_eq!(value("ipc_channel_messages_total", "sender"), 2);
+    assert_eq!(value("ipc_channel_messages_total", "receiver"), 2);
+
+    let text = metrics::prometheus_text(&samples);
+    assert!(text.contains("# TYPE ipc_channel_messages_total counter\n"));
+    assert!(text.contains(
+        "ipc_channel_messages_total{kind=\"receiver\",channel=\"metrics-test\"} 2\n"));
+}
+
+#[test]
+#[cfg(feature = "websocket")]
+fn websocket() {
+    use ipc::{IpcWebSocketReceiver, IpcWebSocketSender, IpcWebSocketServer};
+
+    let server = IpcWebSocketServer::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let (tx, rx): (IpcWebSocketSender<Person>, IpcWebSocketReceiver<Person>) =
+            server.accept().unwrap();
+        // Send everything straight back.
+        while let Ok(person) = rx.recv() {
+            tx.send(person).unwrap();
+        }
+    });
+
+    let (tx, rx) = IpcWebSocketSender::connect(address, "/channel").unwrap();
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    tx.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+
+    // Big enough to need a 64-bit length.
+    let big_person = Person {
+        name: iter::repeat('x').take(70000).collect(),
+        age: 29,
+    };
+    tx.clone().send(big_person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), big_person);
+
+    // Closing our end closes the server's, which ends its loop.
+    drop(tx);
+    drop(rx);
+    thread.join().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn vsock() {
+    use ipc::{IpcVsockSender, IpcVsockServer};
+    use platform::VMADDR_CID_LOCAL;
+
+    // Without vsock loopback support in the kernel, there's nothing to test.
+    let port = 0x10000 + (Uuid::new_v4().as_bytes()[0] as u32);
+    let server = match IpcVsockServer::bind(port) {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+    let tx = match IpcVsockSender::connect(VMADDR_CID_LOCAL, port) {
+        Ok(tx) => tx,
+        Err(_) => return,
+    };
+    let rx = server.accept().unwrap();
+    assert_eq!(rx.peer_cid(), VMADDR_CID_LOCAL);
+
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    tx.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+}
+
+#[test]
+fn loopback() {
+    let (tx, rx) = ipc::loopback_channel();
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let person_and_shared_memory = PersonAndSharedMemory {
+        person: person,
+        shared_memory: IpcSharedMemory::from_byte(0xba, 1024),
+    };
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person_and_shared_memory);
+    match rx.recv() {
+        Err(ipc::Error::WouldBlock) => {}
+        result => panic!("expected WouldBlock, got {:?}", result),
+    }
+
+    // Frames too short for what they claim to hold are turned away.
+    tx.set_mutator(|frame| {
+        let length = frame.len();
+        frame.truncate(length - 1)
+    });
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    assert!(rx.recv().is_err());
+    tx.send_frame(vec![0xff; 16]);
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn fuzz_decode() {
+    use ipc::SyntheticChannel;
+
+    // Each of them is serialized as its index into the message's channels or regions.
+    let channels = [SyntheticChannel::Sender];
+    let (_, shared_memory): (IpcSender<u32>, IpcSharedMemory) =
+        ipc::fuzz_decode(&[0; 16], &channels, &[&[0xba; 4][..]]).unwrap();
+    assert_eq!(&shared_memory[..], &[0xba; 4]);
+    assert!(ipc::fuzz_decode::<(IpcSender<u32>, IpcSharedMemory)>(&[0; 12], &channels, &[])
+                .is_err());
+}
+
+#[test]
+#[cfg(all(target_os = "linux", not(feature = "force-inprocess")))]
+fn fuzz_reassemble() {
+    // The first fragment holds 1 channel and is followed by fragment 2, of a 6 byte message.
+    let first = [1, 0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c'];
+    let last = [2, 0, 0, 0, 0, 0, 0, 0, b'd', b'e', b'f'];
+    let out_of_order = [3, 0, 0, 0, 0, 0, 0, 0, b'd', b'e', b'f'];
+    let error = |packets: &[&[u8]], max_message_size| {
+        format!("{:?}", ipc::fuzz_reassemble(packets, max_message_size).unwrap_err())
+    };
+    assert_eq!(ipc::fuzz_reassemble(&[&first[..], &last[..]], None).unwrap(), b"abcdef");
+    assert_eq!(error(&[&first[..], &last[..]], Some(4)), "MessageTooLarge");
+    assert_eq!(error(&[&first[..]], None), "TruncatedMessage");
+    assert_eq!(error(&[&first[..], &out_of_order[..]], None), "TruncatedMessage");
+}
+
+#[test]
+fn wire_validate() {
+    let first = [1, 0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c'];
+    let last = [2, 0, 0, 0, 0, 0, 0, 0, b'd', b'e', b'f'];
+    let mut report = wire::validate(&first);
+    assert_eq!((report.channels, report.length, report.received), (Some(1), Some(6), 3));
+    assert!(report.is_valid() && !report.is_complete());
+    report.check_descriptors(1);
+    report.add_fragment(&last);
+    assert!(report.is_complete());
+    assert_eq!(report.fragments, 2);
+
+    let whole = wire::validate(&[0, 0, 0, 0, 0, 0, 0, 0, b'x']);
+    assert!(whole.is_complete());
+    assert_eq!(whole.length, Some(1));
+
+    let mut report = wire::validate(&first);
+    report.check_descriptors(0);
+    report.add_fragment(&[3, 0, 0, 0, 0, 0, 0, 0, b'd']);
+    report.add_fragment(&last);
+    assert_eq!(report.problems, vec![
+        WireProblem::MissingDescriptors { counted: 1, received: 0 },
+        WireProblem::OutOfOrder { expected: 2, found: 3 },
+        WireProblem::Short { length: 6, received: 4 },
+        WireProblem::TrailingFragment,
+    ]);
+    assert_eq!(wire::validate(&[0, 0, 0, 0, 2, 0, 0, 0]).problems, vec![
+        WireProblem::MissingFragmentChannel,
+        WireProblem::TruncatedHeader { length: 8 },
+    ]);
+}
+
+#[test]
+fn wire_encode() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let whole = wire::encode(&person, None).unwrap();
+    assert_eq!(whole.packets.len(), 1);
+    assert!(wire::validate(&whole.packets[0]).is_complete());
+    assert_eq!(ipc::fuzz_decode::<Person>(&whole.packets[0][8..], &[], &[]).unwrap(), person);
+
+    let (sender, _receiver) = ipc::channel::<()>().unwrap();
+    let fragmented = wire::encode(&(person.clone(), sender), Some(5)).unwrap();
+    assert!(fragmented.packets.len() > 2);
+    let mut report = wire::validate(&fragmented.packets[0]);
+    for packet in &fragmented.packets[1..] {
+        report.add_fragment(packet);
+    }
+    assert!(report.is_complete());
+    assert_eq!(report.channels, Some(2));
+    assert_eq!(wire::encode(&person, Some(0)).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let path = env::temp_dir().join(format!("ipc-channel-test.{}", Uuid::new_v4()));
+    wire::write_packets(&path, &person, Some(5)).unwrap();
+    let mut bytes = vec![];
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    fs::remove_file(&path).unwrap();
+    let packets: Vec<Vec<u8>> =
+        wire::read_packets(&bytes).unwrap().iter().map(|packet| packet.to_vec()).collect();
+    assert_eq!(packets, wire::encode(&person, Some(5)).unwrap().packets);
+    assert!(wire::read_packets(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn shared_memory_deduplication() {
+    let shared_memory = IpcSharedMemory::from_byte(0xba, 1024 * 1024);
+    let shared_memory_regions = vec![shared_memory.clone(), shared_memory.clone(), shared_memory];
+    let (tx, rx) = ipc::channel().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx).unwrap();
+    tx.send(shared_memory_regions).unwrap();
+    let (_, received_message) = rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert_eq!(received_message.os_ipc_shared_memory_regions().len(), 1);
+    let received_shared_memory_regions: Vec<IpcSharedMemory> = received_message.to().unwrap();
+    assert_eq!(received_shared_memory_regions.len(), 3);
+    assert_eq!(received_shared_memory_regions[0].as_ptr(),
+               received_shared_memory_regions[2].as_ptr());
+    assert!(received_shared_memory_regions[1].iter().all(|byte| *byte == 0xba));
+}
+
+#[cfg(all(unix, not(feature = "force-inprocess")))]
+#[test]
+fn fd() {
+    use ipc::IpcFd;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let path = env::temp_dir().join(format!("ipc-channel-test.{}", Uuid::new_v4()));
+    File::create(&path).unwrap().write_all(b"Hello, fd!").unwrap();
+    let fd = IpcFd::new(File::open(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(("file".to_owned(), fd)).unwrap();
+    let (name, received_fd): (String, IpcFd) = rx.recv().unwrap();
+    assert_eq!(name, "file");
+    let mut file = unsafe {
+        File::from_raw_fd(received_fd.into_raw_fd())
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "Hello, fd!");
+}
+
+#[cfg(all(unix, not(feature = "force-inprocess")))]
+#[test]
+fn fd_missing() {
+    use ipc::IpcFd;
+
+    // A message that names a descriptor it doesn't carry fails to deserialize.
+    let (tx, rx) = ipc::channel::<IpcFd>().unwrap();
+    let tx: IpcSender<usize> = tx.to_opaque().to();
+    tx.send(3).unwrap();
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn channel_and_shared_memory_missing() {
+    // Likewise for channels and shared memory regions, as when they come over the bus.
+    let (tx, rx) = ipc::channel::<IpcSender<u8>>().unwrap();
+    let tx: IpcSender<usize> = tx.to_opaque().to();
+    tx.send(3).unwrap();
+    assert!(rx.recv().is_err());
+
+    let (tx, rx) = ipc::channel::<IpcSharedMemory>().unwrap();
+    let tx: IpcSender<usize> = tx.to_opaque().to();
+    tx.send(3).unwrap();
+    assert!(rx.recv().is_err());
+}
+
+#[cfg(windows)]
+#[test]
+fn handle() {
+    use ipc::IpcHandle;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+
+    let path = env::temp_dir().join(format!("ipc-channel-test.{}", Uuid::new_v4()));
+    File::create(&path).unwrap().write_all(b"Hello, handle!").unwrap();
+    let handle = IpcHandle::new(File::open(&path).unwrap());
+
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(("file".to_owned(), handle)).unwrap();
+    let (name, received_handle): (String, IpcHandle) = rx.recv().unwrap();
+    assert_eq!(name, "file");
+    let mut contents = String::new();
+    {
+        let mut file = unsafe {
+            File::from_raw_handle(received_handle.into_raw_handle())
+        };
+        file.read_to_string(&mut contents).unwrap();
+    }
+    assert_eq!(contents, "Hello, handle!");
+
+    // Handles can't cross a TCP connection.
+    let server = IpcTcpServer::bind("127.0.0.1:0").unwrap();
+    let tx = IpcTcpSender::connect(server.local_addr().unwrap()).unwrap();
+    let handle = IpcHandle::new(File::open(&path).unwrap());
+    assert_eq!(tx.send(handle).unwrap_err().kind(), ErrorKind::InvalidInput);
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "macos", target_os = "ios")))]
+#[test]
+fn mach_port() {
+    use ipc::{IpcMachPort, MachPortRight};
+
+    const MACH_PORT_RIGHT_RECEIVE: u32 = 1;
+    const MACH_MSG_TYPE_MAKE_SEND: u32 = 20;
+    extern {
+        static mach_task_self_: u32;
+        fn mach_port_allocate(task: u32, right: u32, name: *mut u32) -> i32;
+        fn mach_port_insert_right(task: u32, name: u32, right: u32, disposition: u32) -> i32;
+    }
+
+    let mut name = 0;
+    unsafe {
+        assert_eq!(mach_port_allocate(mach_task_self_, MACH_PORT_RIGHT_RECEIVE, &mut name), 0);
+        assert_eq!(mach_port_insert_right(mach_task_self_, name, name, MACH_MSG_TYPE_MAKE_SEND),
+                   0);
+    }
+    let ports = unsafe {
+        vec![IpcMachPort::new(name, MachPortRight::Send),
+             IpcMachPort::new(name, MachPortRight::Receive)]
+    };
+
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(ports).unwrap();
+    let received_ports: Vec<IpcMachPort> = rx.recv().unwrap();
+    assert_eq!(received_ports[0].right(), MachPortRight::Send);
+    assert_eq!(received_ports[1].right(), MachPortRight::Receive);
+    assert_eq!(received_ports[0].name(), received_ports[1].name());
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "macos", target_os = "ios")))]
+#[test]
+fn mach_port_missing() {
+    use ipc::IpcMachPort;
+
+    // A message that names a port it doesn't carry fails to deserialize.
+    let (tx, rx) = ipc::channel::<IpcMachPort>().unwrap();
+    let tx: IpcSender<usize> = tx.to_opaque().to();
+    tx.send(3).unwrap();
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn opaque_sender() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    let opaque_tx = tx.to_opaque();
+    let tx: IpcSender<Person> = opaque_tx.to();
+    tx.send(person.clone()).unwrap();
+    let received_person = rx.recv().unwrap();
+    assert_eq!(person, received_person);
+}
+
+#[test]
+fn embedded_opaque_senders() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (sub_tx, sub_rx) = ipc::channel::<Person>().unwrap();
+    let person_and_sender = PersonAndOpaqueSender {
+        person: person.clone(),
+        sender: sub_tx.to_opaque(),
+    };
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(person_and_sender).unwrap();
+    let received_person_and_sender = super_rx.recv().unwrap();
+    assert_eq!(received_person_and_sender.person, person);
+    received_person_and_sender.sender.to::<Person>().send(person.clone()).unwrap();
+    let received_person = sub_rx.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn opaque_message_forwarding() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    let person_and_sender = PersonAndSender {
+        person: person.clone(),
+        sender: sub_tx,
+    };
+    let (tx0, rx0) = ipc::channel().unwrap();
+    let (tx1, rx1) = ipc::channel::<PersonAndSender>().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx0).unwrap();
+    tx0.send(person_and_sender).unwrap();
+    let (_, received_message) = rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert_eq!(received_message.os_ipc_channels().len(), 1);
+    tx1.to_opaque().send_opaque(received_message).unwrap();
+    let received_person_and_sender = rx1.recv().unwrap();
+    assert_eq!(received_person_and_sender.person, person);
+    received_person_and_sender.sender.send(person.clone()).unwrap();
+    let received_person = sub_rx.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn opaque_message_to_ref() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx0, rx0) = ipc::channel().unwrap();
+    let (tx1, rx1) = ipc::channel::<Person>().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx0).unwrap();
+    tx0.send(person.clone()).unwrap();
+    let (_, received_message) = rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert_eq!(received_message.to_ref::<Person>().unwrap(), person);
+    tx1.to_opaque().send_opaque(received_message).unwrap();
+    assert_eq!(rx1.recv().unwrap(), person);
+
+    // Messages carrying channels can only be taken apart for good.
+    let (sub_tx, _) = ipc::channel::<Person>().unwrap();
+    let (tx2, rx2) = ipc::channel().unwrap();
+    let rx2_id = rx_set.add(rx2).unwrap();
+    tx2.send(sub_tx).unwrap();
+    let (received_id, received_message) =
+        rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert_eq!(received_id, rx2_id);
+    assert!(received_message.to_ref::<IpcSender<Person>>().is_err());
+    assert!(received_message.to::<IpcSender<Person>>().is_ok());
+}
+
+#[test]
+fn try_recv() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (tx, rx) = ipc::channel().unwrap();
+    assert!(rx.try_recv().is_err());
+    tx.send(person.clone()).unwrap();
+    let received_person = rx.try_recv().unwrap();
+    assert_eq!(person, received_person);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn error_causes() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    match rx.try_recv() {
+        Err(ipc::Error::WouldBlock) => {}
+        result => panic!("expected WouldBlock, got {:?}", result),
+    }
+    drop(rx);
+    match tx.send(1) {
+        Err(ipc::Error::Disconnected) => {}
+        result => panic!("expected Disconnected, got {:?}", result),
+    }
+    let error: Error = ipc::Error::Disconnected.into();
+    assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn try_recv_checked() {
+    let (tx, rx) = ipc::channel().unwrap();
+    match rx.try_recv_checked() {
+        Err(TryRecvError::Empty) => {}
+        result => panic!("expected Empty, got {:?}", result),
+    }
+    tx.send(7).unwrap();
+    drop(tx);
+    assert_eq!(rx.try_recv_checked().unwrap(), 7);
+    match rx.try_recv_checked() {
+        Err(TryRecvError::Disconnected) => {}
+        result => panic!("expected Disconnected, got {:?}", result),
+    }
+}
+
+#[test]
+fn channel_named() {
+    let (tx, rx) = ipc::channel_named::<u32>("compositor").unwrap();
+    assert_eq!(tx.label(), Some("compositor"));
+    assert_eq!(rx.label(), Some("compositor"));
+    assert_eq!(tx.stats().label, Some("compositor".to_owned()));
+    assert!(format!("{:?}", rx).contains("\"compositor\""));
+
+    // A message that fails authentication is an `Io` error underneath the label, and the label
+    // comes along when it's converted into an `io::Error`.
+    tx.send(1).unwrap();
+    let error = rx.recv_authenticated(&MessageAuthenticator::new(b"secret")).unwrap_err();
+    assert_eq!(error.label(), Some("compositor"));
+    match *error.unlabeled() {
+        ipc::Error::Io(ref error) => assert_eq!(error.kind(), ErrorKind::InvalidData),
+        ref error => panic!("expected an I/O error, got {:?}", error),
+    }
+    let error = Error::from(error);
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().starts_with("channel \"compositor\": "));
+    let labeled_error = error.get_ref().unwrap().downcast_ref::<LabeledError>().unwrap();
+    assert_eq!(labeled_error.label(), "compositor");
+
+    // Other errors are classified underneath the label.
+    drop(tx);
+    match rx.try_recv_checked() {
+        Err(TryRecvError::Disconnected) => {}
+        result => panic!("expected Disconnected, got {:?}", result),
+    }
+}
+
+#[test]
+fn channel_named_disconnected() {
+    let (tx, rx) = ipc::channel_named::<u32>("compositor").unwrap();
+    drop(rx);
+    match tx.send(1) {
+        Err(ipc::Error::Labeled(ref error)) => {
+            assert_eq!(error.label(), "compositor");
+            match *error.error() {
+                ipc::Error::Disconnected => {}
+                ref error => panic!("expected Disconnected, got {:?}", error),
+            }
+        }
+        result => panic!("expected a labeled error, got {:?}", result),
+    }
+
+    let (tx, rx) = ipc::channel_named::<u32>("compositor").unwrap();
+    drop(tx);
+    let error = rx.recv().unwrap_err();
+    assert_eq!(error.label(), Some("compositor"));
+    assert_eq!(error.to_string(), "channel \"compositor\": channel disconnected");
+    assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn debug_output() {
+    let (tx, rx) = ipc::channel_named::<u32>("debug").unwrap();
+    let sender = format!("{:?}", tx);
+    assert!(sender.starts_with("IpcSender { id: "));
+    assert!(sender.contains("label: Some(\"debug\")"));
+    assert!(format!("{:?}", rx).starts_with("IpcReceiver { id: "));
+
+    // Regions show their length, not their contents.
+    let shared_memory = format!("{:?}", IpcSharedMemory::from_byte(0xba, 1024));
+    assert!(shared_memory.ends_with("length: 1024 }"));
+    assert!(!shared_memory.contains("["));
+}
+
+#[test]
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux", target_os = "freebsd")))]
+fn debug_output_connected() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    assert!(format!("{:?}", tx).ends_with("connected: Some(true) }"));
+    drop(rx);
+    assert!(format!("{:?}", tx.to_opaque()).ends_with("connected: Some(false) }"));
+}
+
+#[test]
+#[cfg(all(feature = "leak-tracking", not(feature = "force-inprocess"),
+          any(target_os = "linux", target_os = "freebsd")))]
+fn leak_checkpoint() {
+    use ipc::{HandleKind, LeakCheckpoint};
+
+    // Other tests make handles at the same time, so only look at the ones made here.
+    let checkpoint = LeakCheckpoint::new();
+    let leaks = || -> Vec<HandleKind> {
+        checkpoint.leaks().into_iter()
+                          .filter(|leak| leak.backtrace.contains("leak_checkpoint"))
+                          .map(|leak| leak.kind)
+                          .collect()
+    };
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let shared_memory = IpcSharedMemory::from_byte(0xba, 4096);
+    assert_eq!(leaks(),
+               vec![HandleKind::Channel, HandleKind::Channel, HandleKind::SharedMemory]);
+
+    drop((tx, rx, shared_memory));
+    assert!(leaks().is_empty());
+}
+
+#[test]
+#[cfg(all(feature = "leak-tracking", not(feature = "force-inprocess"),
+          any(target_os = "linux", target_os = "freebsd")))]
+fn no_live_endpoints() {
+    use ipc::LeakScope;
+    use test_support::assert_no_live_endpoints;
+    use std::panic;
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    {
+        let scope = LeakScope::new();
+        let shared_memory = IpcSharedMemory::from_byte(0xba, 4096);
+        assert_eq!(scope.leaks().len(), 1);
+        assert!(panic::catch_unwind(assert_no_live_endpoints).is_err());
+        drop(shared_memory);
+
+        // The channel was made outside the scope.
+        assert_no_live_endpoints();
+    }
+    assert!(panic::catch_unwind(assert_no_live_endpoints).is_err());
+    drop((tx, rx));
+    assert_no_live_endpoints();
+}
+
+#[test]
+fn virtual_process() {
+    use test_support::VirtualProcess;
+
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let child_person = person.clone();
+    let child = VirtualProcess::spawn("child", move || {
+        let (tx, rx): (IpcSender<Person>, IpcReceiver<Person>) = ipc::channel().unwrap();
+        let tx0 = IpcSender::connect(name).unwrap();
+        tx0.send(tx).unwrap();
+        assert_eq!(rx.recv().unwrap(), child_person);
+    }).unwrap();
+    let (_, tx): (_, IpcSender<Person>) = server.accept().unwrap();
+    tx.send(person).unwrap();
+    assert!(child.wait().is_ok());
+}
+
+#[test]
+fn virtual_process_crash() {
+    use test_support::VirtualProcess;
+
+    let (to_child, child_rx) = ipc::channel::<u32>().unwrap();
+    let (child_tx, from_child) = ipc::channel::<u32>().unwrap();
+    let child = VirtualProcess::spawn("echo", move || {
+        loop {
+            child_tx.send(child_rx.recv().unwrap()).unwrap()
+        }
+    }).unwrap();
+    to_child.send(1).unwrap();
+    assert_eq!(from_child.recv().unwrap(), 1);
+
+    // The child is blocked in a receive, so it crashes once it gets this.
+    child.crash();
+    to_child.send(2).unwrap();
+    assert!(child.wait().unwrap_err().is_none());
+    assert!(from_child.recv().is_err());
+}
+
+#[test]
+#[cfg(any(feature = "force-inprocess", windows))]
+fn virtual_process_namespaces() {
+    use test_support::VirtualProcess;
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut children = vec![];
+    for _ in 0..2 {
+        let done_tx = done_tx.clone();
+        children.push(VirtualProcess::spawn("server", move || {
+            // Both processes name their servers the same.
+            let server = IpcServer::<u32>::with_name("service").unwrap();
+            done_tx.send(()).unwrap();
+            let (_, value) = server.accept().unwrap();
+            assert_eq!(value, 1);
+        }).unwrap());
+    }
+    done_rx.recv().unwrap();
+    done_rx.recv().unwrap();
+    for child in children {
+        let tx = IpcSender::<u32>::connect(child.server_name("service")).unwrap();
+        tx.send(1).unwrap();
+        assert!(child.wait().is_ok());
+    }
+}
+
+#[test]
+fn send_after_serialization_panic() {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// Embeds its channel, then panics.
+    struct PanicOnSerialize(IpcSender<()>);
+
+    impl Serialize for PanicOnSerialize {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+            try!(self.0.serialize(serializer));
+            panic!("serialization failed")
+        }
+    }
+
+    impl Deserialize for PanicOnSerialize {
+        fn deserialize<D>(deserializer: &mut D) -> Result<Self,D::Error> where D: Deserializer {
+            Ok(PanicOnSerialize(try!(Deserialize::deserialize(deserializer))))
+        }
+    }
+
+    let (tx, rx) = ipc::channel::<Option<PanicOnSerialize>>().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel::<()>().unwrap();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        tx.send(Some(PanicOnSerialize(sub_tx)))
+    }));
+    assert!(result.is_err());
+
+    // The copy of the channel that the failed message had embedded was closed...
+    match sub_rx.try_recv_checked() {
+        Err(TryRecvError::Disconnected) => {}
+        result => panic!("expected Disconnected, got {:?}", result),
+    }
+    // ...and the sender, on this thread, goes on working.
+    tx.send(None).unwrap();
+    assert!(rx.recv().unwrap().is_none());
+    let (other_tx, other_rx) = ipc::channel().unwrap();
+    let (embedded_tx, embedded_rx) = ipc::channel().unwrap();
+    other_tx.send(embedded_tx).unwrap();
+    other_rx.recv().unwrap().send(7).unwrap();
+    assert_eq!(embedded_rx.recv().unwrap(), 7);
+}
+
+#[test]
+fn multiple_paths_to_a_sender() {
+    let person = Person {
+        name: "Patrick Walton".to_owned(),
+        age: 29,
+    };
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    let person_and_sender = Arc::new(PersonAndSender {
+        person: person.clone(),
+        sender: sub_tx,
+    });
+    let send_data = vec![
+        person_and_sender.clone(),
+        person_and_sender.clone(),
+        person_and_sender.clone()
+    ];
     let (super_tx, super_rx) = ipc::channel().unwrap();
     super_tx.send(send_data).unwrap();
     let received_data = super_rx.recv().unwrap();
@@ -444,6 +2500,27 @@ fn bytes() {
     assert_eq!(&bytes, &received_bytes[..]);
 }
 
+#[test]
+fn bytes_from_file() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Seek, SeekFrom, Write};
+
+    let path = env::temp_dir().join(format!("ipc-channel-test.{}", Uuid::new_v4()));
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    File::create(&path).unwrap().write_all(&data[..]).unwrap();
+    let mut file = File::open(&path).unwrap();
+    file.seek(SeekFrom::Start(16)).unwrap();
+
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    tx.send_file(&file, 4096).unwrap();
+    tx.send_file(&file, 4096).unwrap();
+    assert_eq!(&rx.recv().unwrap()[..], &data[16..4112]);
+    assert_eq!(&rx.recv().unwrap()[..], &data[4112..8208]);
+    assert!(tx.send_file(&file, data.len()).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
 #[test]
 fn embedded_bytes_receivers() {
     let (sub_tx, sub_rx) = ipc::bytes_channel().unwrap();
@@ -456,6 +2533,23 @@ fn embedded_bytes_receivers() {
     assert_eq!(&bytes, &received_bytes[..]);
 }
 
+#[test]
+#[cfg(not(any(feature = "force-inprocess", windows, target_os = "ios")))]
+fn peer_death() {
+    let (tx, rx) = ipc::channel::<()>().unwrap();
+    assert!(!tx.receiver_is_closed().unwrap());
+    drop(rx);
+    assert!(tx.receiver_is_closed().unwrap());
+
+    // The receiver learns that its peers are gone from a recognizable error.
+    let (tx, rx) = ipc::channel::<()>().unwrap();
+    drop(tx);
+    match rx.recv() {
+        Err(ipc::Error::Disconnected) => {}
+        _ => panic!("expected the channel to be closed"),
+    }
+}
+
 #[test]
 fn test_so_linger() {
     let (sender, receiver) = ipc::channel().unwrap();
@@ -0,0 +1,138 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for the tests of code that uses channels.
+
+use platform;
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::Error;
+use std::panic;
+use std::sync::Arc;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Fails the test if any channel, server, receiver set or shared memory region made on this
+/// thread -- or, while a `LeakScope` is open on it, in that scope -- is still alive, listing
+/// where each was made. The test harness runs each test on a thread of its own, so called at
+/// the end of a test, this checks that the test tore down everything it set up.
+///
+/// Endpoints are only tracked where `ipc::live_handles()` tracks them; elsewhere this never
+/// fails.
+pub fn assert_no_live_endpoints() {
+    let leaks = platform::scope_leaks();
+    if !leaks.is_empty() {
+        panic!("{} endpoints are still alive:\n{:?}", leaks.len(), leaks)
+    }
+}
+
+/// Whether any virtual process was ever crashed, so that channel operations don't have to look
+/// up which process they're in until one has.
+static CRASHING: AtomicBool = ATOMIC_BOOL_INIT;
+
+static NEXT_PROCESS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct ProcessState {
+    /// The namespace of the servers the process names, which is also its thread's name.
+    namespace: String,
+    crashed: AtomicBool,
+}
+
+thread_local! {
+    /// The virtual process this thread runs, if any.
+    static CURRENT_PROCESS: RefCell<Option<Arc<ProcessState>>> = RefCell::new(None)
+}
+
+/// What a crashed virtual process unwinds with.
+struct Crashed;
+
+/// Unwinds the thread of a virtual process that was crashed. Every send and receive calls this
+/// first.
+#[inline]
+pub fn crash_point() {
+    if CRASHING.load(Ordering::Relaxed) {
+        crash_if_crashed()
+    }
+}
+
+fn crash_if_crashed() {
+    let crashed = CURRENT_PROCESS.with(|process| {
+        process.borrow().as_ref().map_or(false, |process| process.crashed.load(Ordering::SeqCst))
+    });
+    if crashed {
+        // Without the panic hook, so that crashes don't fill the test output with messages.
+        panic::resume_unwind(Box::new(Crashed))
+    }
+}
+
+/// A process simulated by a thread of this one, for testing cross-process scenarios where
+/// processes can't be forked, such as on Windows.
+///
+/// The servers a virtual process names are in a namespace of its own, so that processes can use
+/// the same names without colliding; see `server_name()`. This only applies to the in-process
+/// backend. A virtual process can be crashed, which drops every channel it holds, as the OS
+/// closes those of a process that dies. Threads the process starts are not part of it.
+pub struct VirtualProcess {
+    state: Arc<ProcessState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualProcess {
+    /// Starts a process running `main`, which exits when `main` returns. `name` is numbered to
+    /// tell processes with the same name apart.
+    pub fn spawn<F>(name: &str, main: F) -> Result<VirtualProcess,Error>
+                    where F: FnOnce() + Send + 'static {
+        let state = Arc::new(ProcessState {
+            namespace: format!("{}.{}", name, NEXT_PROCESS.fetch_add(1, Ordering::SeqCst)),
+            crashed: AtomicBool::new(false),
+        });
+        let process_state = state.clone();
+        let thread = try!(thread::Builder::new().name(state.namespace.clone()).spawn(move || {
+            platform::set_server_namespace(Some(process_state.namespace.clone()));
+            CURRENT_PROCESS.with(|process| *process.borrow_mut() = Some(process_state));
+            main()
+        }));
+        Ok(VirtualProcess {
+            state: state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the name by which others reach the server that this process names `name`.
+    pub fn server_name(&self, name: &str) -> String {
+        format!("{}/{}", self.state.namespace, name)
+    }
+
+    /// Crashes the process at its next send or receive, or once the one it's blocked in returns.
+    /// Its thread unwinds, dropping every channel the process holds, so that its peers see them
+    /// close.
+    pub fn crash(&self) {
+        self.state.crashed.store(true, Ordering::SeqCst);
+        CRASHING.store(true, Ordering::SeqCst)
+    }
+
+    /// Waits for the process to exit. Fails with what it panicked with if it panicked, or with
+    /// `None` if it crashed.
+    pub fn wait(mut self) -> Result<(),Option<Box<Any + Send>>> {
+        match self.thread.take().unwrap().join() {
+            Ok(()) => Ok(()),
+            Err(ref payload) if payload.is::<Crashed>() => Err(None),
+            Err(payload) => Err(Some(payload)),
+        }
+    }
+}
+
+/// A process that is dropped without being waited for is crashed, and left to unwind on its own.
+impl Drop for VirtualProcess {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.crash()
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checking packets of the Unix backend's wire format, for tools that inspect IPC traffic taken
+//! off a socket, so that a malformed message can be told from a misread one.
+//!
+//! Each packet starts with two little-endian `u32`s: the ID of the fragment it holds, and that of
+//! the next one, which is 0 in the last fragment. Messages that fit in one packet have no other
+//! fragments. The first packet of a message has no ID of its own; instead, its first `u32` counts
+//! the descriptors that came with it that are channels or `IpcFd`s, rather than shared memory
+//! regions. If more fragments follow, it goes on with the length of the whole message as a
+//! little-endian `u64`, and the last of its channels is the one the rest of the fragments come
+//! through. See also `IpcReceiver::into_raw_fd()`.
+//!
+//! `encode()` goes the other way, turning a message into the packets it would be sent in, to
+//! generate fuzzing corpora and golden files for protocol tests from real messages.
+//!
+//! This only handles bytes, so it works on every platform, whichever backend it uses itself.
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use ipc::serialize_message_parts;
+use std::path::Path;
+
+/// The size of the header every packet starts with.
+const HEADER_SIZE: usize = 8;
+
+/// The size of the message length that goes on the first packet of a fragmented message.
+const LENGTH_SIZE: usize = 8;
+
+/// Something wrong with a message, as found by `validate()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireProblem {
+    /// A packet of `length` bytes is too short for its headers.
+    TruncatedHeader { length: usize },
+    /// A fragmented message counts no channels, so the rest of it has no way to come.
+    MissingFragmentChannel,
+    /// The message came with fewer descriptors than its first packet counts channels.
+    MissingDescriptors { counted: usize, received: usize },
+    /// The message carries more data than its first packet says it's long.
+    Overlong { length: usize, received: usize },
+    /// The message ended with less data than its first packet says it's long.
+    Short { length: usize, received: usize },
+    /// A fragment came with the wrong ID.
+    OutOfOrder { expected: u32, found: u32 },
+    /// A fragment names itself as the next one, so the message would never end.
+    Loop { id: u32 },
+    /// A packet came after the message had ended.
+    TrailingFragment,
+}
+
+/// What `validate()` made of a message, so far as it has seen it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameReport {
+    /// How many of the descriptors that came with the message are channels or `IpcFd`s,
+    /// including the channel its other fragments come through; or `None` if the first packet was
+    /// truncated.
+    pub channels: Option<usize>,
+    /// How long the message is, as its first packet says.
+    pub length: Option<usize>,
+    /// How much of the message's data has been seen.
+    pub received: usize,
+    /// How many packets have been seen.
+    pub fragments: usize,
+    /// The ID of the fragment that comes next, or `None` once the message is complete.
+    pub next_fragment_id: Option<u32>,
+    /// What's wrong with the message, in the order it was found.
+    pub problems: Vec<WireProblem>,
+}
+
+/// Checks `bytes` as the first packet of a message: that its headers are all there and add up.
+/// If more fragments follow, check them with `FrameReport::add_fragment()`.
+pub fn validate(bytes: &[u8]) -> FrameReport {
+    let mut report = FrameReport {
+        channels: None,
+        length: None,
+        received: 0,
+        fragments: 1,
+        next_fragment_id: None,
+        problems: vec![],
+    };
+    if bytes.len() < HEADER_SIZE {
+        report.problems.push(WireProblem::TruncatedHeader { length: bytes.len() });
+        return report
+    }
+    let channels = LittleEndian::read_u32(&bytes[0..4]) as usize;
+    let next_fragment_id = LittleEndian::read_u32(&bytes[4..8]);
+    report.channels = Some(channels);
+    if next_fragment_id == 0 {
+        report.length = Some(bytes.len() - HEADER_SIZE);
+        report.received = bytes.len() - HEADER_SIZE;
+        return report
+    }
+
+    report.next_fragment_id = Some(next_fragment_id);
+    if channels == 0 {
+        report.problems.push(WireProblem::MissingFragmentChannel)
+    }
+    if bytes.len() < HEADER_SIZE + LENGTH_SIZE {
+        report.problems.push(WireProblem::TruncatedHeader { length: bytes.len() });
+        return report
+    }
+    let length = LittleEndian::read_u64(&bytes[HEADER_SIZE..HEADER_SIZE + LENGTH_SIZE]);
+    report.length = Some(length as usize);
+    report.received = bytes.len() - HEADER_SIZE - LENGTH_SIZE;
+    report.check_length(0);
+    report
+}
+
+impl FrameReport {
+    /// Returns true if nothing was found wrong with the message so far.
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Returns true once the last fragment of a valid message has been seen.
+    pub fn is_complete(&self) -> bool {
+        self.is_valid() && self.next_fragment_id.is_none()
+    }
+
+    /// Checks `bytes` as the next fragment of the message, which came through the channel that
+    /// came with the first packet.
+    pub fn add_fragment(&mut self, bytes: &[u8]) {
+        self.fragments += 1;
+        let expected = match self.next_fragment_id {
+            Some(expected) => expected,
+            None => {
+                self.problems.push(WireProblem::TrailingFragment);
+                return
+            }
+        };
+        if bytes.len() < HEADER_SIZE {
+            self.problems.push(WireProblem::TruncatedHeader { length: bytes.len() });
+            return
+        }
+        let id = LittleEndian::read_u32(&bytes[0..4]);
+        let next_fragment_id = LittleEndian::read_u32(&bytes[4..8]);
+        if id != expected {
+            self.problems.push(WireProblem::OutOfOrder {
+                expected: expected,
+                found: id,
+            })
+        }
+        if next_fragment_id == id {
+            self.problems.push(WireProblem::Loop { id: id })
+        }
+        let already_received = self.received;
+        self.received += bytes.len() - HEADER_SIZE;
+        self.next_fragment_id = if next_fragment_id == 0 { None } else { Some(next_fragment_id) };
+        self.check_length(already_received)
+    }
+
+    /// Checks the number of descriptors that came with the first packet against the channels it
+    /// counts. Descriptors beyond those are shared memory regions.
+    pub fn check_descriptors(&mut self, descriptors: usize) {
+        match self.channels {
+            Some(channels) if channels > descriptors => {
+                self.problems.push(WireProblem::MissingDescriptors {
+                    counted: channels,
+                    received: descriptors,
+                })
+            }
+            _ => {}
+        }
+    }
+
+    /// Checks how much data has been seen against the message's length, where `already_received`
+    /// had been seen before the latest packet, so that a message is only found overlong once.
+    fn check_length(&mut self, already_received: usize) {
+        let length = match self.length {
+            Some(length) => length,
+            None => return,
+        };
+        if self.received > length && already_received <= length {
+            self.problems.push(WireProblem::Overlong {
+                length: length,
+                received: self.received,
+            })
+        } else if self.received < length && self.next_fragment_id.is_none() {
+            self.problems.push(WireProblem::Short {
+                length: length,
+                received: self.received,
+            })
+        }
+    }
+}
+
+/// A message as the Unix backend sends it; see `encode()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedMessage {
+    /// The packets it goes in: the first, which goes through the channel, and then any further
+    /// fragments, which go through a channel of their own.
+    pub packets: Vec<Vec<u8>>,
+    /// The contents of the shared memory regions whose descriptors come with the first packet,
+    /// after those of its channels.
+    pub shared_memory_regions: Vec<Vec<u8>>,
+}
+
+/// Serializes `message` as `IpcSender::send()` does, and cuts it into the packets the Unix
+/// backend would send it in, putting at most `fragment_size` bytes of the message in each; `None`
+/// sends it in one packet. The backend fragments messages that don't fit the socket's send
+/// buffer, so the real size depends on the system.
+///
+/// The sender numbers fragments from a process-wide counter, while these are numbered from 1, so
+/// that the output is the same every time. Channels embedded in the message can't be encoded, so
+/// they are only counted, and closed. A `fragment_size` of 0 fails with `InvalidInput`.
+pub fn encode<T>(message: &T, fragment_size: Option<usize>) -> Result<EncodedMessage,Error>
+                 where T: Serialize {
+    if fragment_size == Some(0) {
+        return Err(Error::new(ErrorKind::InvalidInput, "fragments must hold some of the message"))
+    }
+    let (data, channels, shared_memory_regions) = try!(serialize_message_parts(message));
+    let data = &data[..];
+    let channels = channels as u32;
+    let fragment_size = match fragment_size {
+        Some(fragment_size) if data.len() > fragment_size => fragment_size,
+        _ => {
+            let mut packet = Vec::with_capacity(HEADER_SIZE + data.len());
+            try!(packet.write_u32::<LittleEndian>(channels));
+            try!(packet.write_u32::<LittleEndian>(0));
+            packet.extend_from_slice(data);
+            return Ok(EncodedMessage {
+                packets: vec![packet],
+                shared_memory_regions: shared_memory_regions,
+            })
+        }
+    };
+
+    // The first fragment counts the channel the others come through too, and goes on with the
+    // length of the whole message.
+    let mut packets = vec![];
+    let mut this_fragment_id = channels + 1;
+    for (index, fragment) in data.chunks(fragment_size).enumerate() {
+        let next_fragment_id = if (index + 1) * fragment_size >= data.len() {
+            0
+        } else {
+            index as u32 + 1
+        };
+        let mut packet = Vec::with_capacity(HEADER_SIZE + LENGTH_SIZE + fragment.len());
+        try!(packet.write_u32::<LittleEndian>(this_fragment_id));
+        try!(packet.write_u32::<LittleEndian>(next_fragment_id));
+        if index == 0 {
+            try!(packet.write_u64::<LittleEndian>(data.len() as u64));
+        }
+        packet.extend_from_slice(fragment);
+        packets.push(packet);
+        this_fragment_id = next_fragment_id;
+    }
+    Ok(EncodedMessage {
+        packets: packets,
+        shared_memory_regions: shared_memory_regions,
+    })
+}
+
+/// Writes the packets that `encode()` makes of `message` to a new file at `path`, each after its
+/// length as a little-endian `u32`; `read_packets()` splits them up again.
+pub fn write_packets<T, P>(path: P, message: &T, fragment_size: Option<usize>)
+                           -> Result<(),Error>
+                           where T: Serialize, P: AsRef<Path> {
+    let encoded = try!(encode(message, fragment_size));
+    let mut file = BufWriter::new(try!(File::create(path)));
+    for packet in &encoded.packets {
+        try!(file.write_u32::<LittleEndian>(packet.len() as u32));
+        try!(file.write_all(packet));
+    }
+    file.flush()
+}
+
+/// Splits what `write_packets()` wrote back into packets, failing with `UnexpectedEof` if it ends
+/// partway through one.
+pub fn read_packets(mut bytes: &[u8]) -> Result<Vec<&[u8]>,Error> {
+    let mut packets = vec![];
+    while !bytes.is_empty() {
+        let length = try!(bytes.read_u32::<LittleEndian>()) as usize;
+        if length > bytes.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "packet cut short"))
+        }
+        let (packet, rest) = bytes.split_at(length);
+        packets.push(packet);
+        bytes = rest;
+    }
+    Ok(packets)
+}